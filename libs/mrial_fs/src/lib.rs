@@ -1,9 +1,11 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::error::Error;
+use std::{error::Error, path::PathBuf};
 use storage::{StorageMulti, StorageMultiType};
+use vault::{Vault, VaultError};
 
 pub mod storage;
+pub mod vault;
 
 impl Default for Server {
     fn default() -> Self {
@@ -14,6 +16,8 @@ impl Default for Server {
             os: String::new(),
             username: String::new(),
             pass: String::new(),
+            sealed: None,
+            host_key: None,
         }
     }
 }
@@ -26,27 +30,116 @@ pub struct Server {
     pub os: String,
     pub username: String,
     pub pass: String,
+    /// Username and password sealed by the vault, both are left empty when set
+    #[serde(default)]
+    pub sealed: Option<String>,
+    /// Fingerprint of the host key trusted for the server, from a discovery
+    /// beacon or the first handshake. Servers presenting another are refused.
+    #[serde(default)]
+    pub host_key: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SealedCredentials {
+    username: String,
+    pass: String,
 }
 
 pub struct Servers {
     pub servers: StorageMulti<Server>,
+    vault: Vault,
+}
+
+impl Servers {
+    pub fn vault(&self) -> Vault {
+        self.vault.clone()
+    }
+
+    fn seal_credentials(&self, server: &mut Server) -> Result<(), VaultError> {
+        let credentials = SealedCredentials {
+            username: std::mem::take(&mut server.username),
+            pass: std::mem::take(&mut server.pass),
+        };
+        let json = serde_json::to_vec(&credentials).map_err(|_| VaultError::Corrupted)?;
+        server.sealed = Some(self.vault.seal(&json)?);
+
+        Ok(())
+    }
+
+    /// Returns the server with its credentials opened, requires the vault
+    /// to be unlocked if they are sealed.
+    pub fn unseal(&self, mut server: Server) -> Result<Server, VaultError> {
+        if let Some(sealed) = server.sealed.take() {
+            let credentials: SealedCredentials =
+                serde_json::from_slice(&self.vault.open(&sealed)?)
+                    .map_err(|_| VaultError::Corrupted)?;
+            server.username = credentials.username;
+            server.pass = credentials.pass;
+        }
+
+        Ok(server)
+    }
+
+    /// Seals the credentials of every server still stored in plain text,
+    /// used once the vault is created.
+    pub fn seal_all(&mut self) -> Result<(), Box<dyn Error>> {
+        for server in self.servers.get().unwrap_or_default() {
+            if server.sealed.is_some() {
+                continue;
+            }
+
+            let mut sealed_server = server.clone();
+            self.seal_credentials(&mut sealed_server)?;
+            self.servers.update(&|s| s.name == server.name, &mut |s| {
+                *s = sealed_server.clone();
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Trusts the host key fingerprint for the server if none is trusted yet,
+    /// returning whether it was stored.
+    pub fn trust_host_key(
+        &mut self,
+        name: &str,
+        fingerprint: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        match self.servers.find(&|s| s.name == name) {
+            Some(server) if server.host_key.is_none() => {}
+            _ => return Ok(false),
+        }
+
+        self.servers.update(&|s| s.name == name, &mut |s| {
+            s.host_key = Some(fingerprint.to_string());
+        })?;
+        self.servers.save()?;
+
+        Ok(true)
+    }
 }
 
 impl StorageMultiType<Server, String> for Servers {
     fn new() -> Self {
         Servers {
             servers: StorageMulti::new("servers.json".to_string()),
+            vault: Vault::new(),
         }
     }
 
     fn clone(&self) -> Servers {
         Servers {
             servers: self.servers.clone(),
+            vault: self.vault.clone(),
         }
     }
 
     fn load(&mut self) -> Result<(), Box<dyn Error>> {
-        self.servers.load()
+        // The server list is loaded even if the vault fails to,
+        // sealed credentials then just can't be opened
+        let vault_result = self.vault.load();
+        self.servers.load()?;
+        vault_result
     }
 
     fn save(&self) -> Result<(), Box<dyn Error>> {
@@ -61,19 +154,31 @@ impl StorageMultiType<Server, String> for Servers {
         self.servers.remove(&mut |s| s.name == server_id)
     }
 
+    /// An empty password is stored as is, marking the server
+    /// as one that authenticates with the local key pair.
+    /// Credentials are sealed when the vault is enabled, which then has to be unlocked.
     fn add(&mut self, server: Server) -> Result<(), Box<dyn Error>> {
-        let mut hasher = Sha256::new();
-        hasher.update(server.pass);
-        let hash = hasher.finalize();
-        let hex = hash.iter().map(|b| format!("{:x}", b)).collect::<String>();
-        self.servers.add(Server {
+        let hex = if server.pass.is_empty() {
+            String::new()
+        } else {
+            hash_password(&server.pass)
+        };
+        let mut server = Server {
             name: server.name,
             address: server.address,
             port: server.port,
             os: server.os,
             username: server.username,
             pass: hex,
-        })
+            sealed: None,
+            host_key: server.host_key,
+        };
+
+        if self.vault.is_enabled() {
+            self.seal_credentials(&mut server)?;
+        }
+
+        self.servers.add(server)
     }
 }
 
@@ -81,38 +186,95 @@ impl StorageMultiType<Server, String> for Servers {
 pub struct User {
     pub username: String,
     pub pass: String,
+    /// Base64 encoded Ed25519 public keys authorized to log in as this user
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub totp: Option<UserTotp>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserTotp {
+    /// Base32 encoded shared secret
+    pub secret: String,
+    /// Last time step a code was accepted for, used to reject replayed codes
+    #[serde(default)]
+    pub last_step: u64,
 }
 
 pub struct Users {
     pub users: StorageMulti<User>,
 }
 
+/// Hex of the SHA-256 of a password, as users and saved servers store it
+pub fn hash_password(pass: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(pass);
+    let hash = hasher.finalize();
+    hash.iter().map(|b| format!("{:x}", b)).collect::<String>()
+}
+
 impl Users {
+    pub fn new_with_custom_dir(file_dir: PathBuf) -> Self {
+        Users {
+            users: StorageMulti::new_with_custom_dir("users.json".to_string(), file_dir),
+        }
+    }
+
     pub fn find_user_by_credentials(&self, username: &String, pass: &String) -> Option<User> {
         self.users
             .find(&mut |u| u.username == *username && u.pass == *pass)
     }
+
+    pub fn set_totp(
+        &mut self,
+        username: &String,
+        totp: Option<UserTotp>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.users
+            .update(&|u| u.username == *username, &mut |u| u.totp = totp.clone())
+    }
+
+    pub fn add_key(&mut self, username: &String, key: String) -> Result<(), Box<dyn Error>> {
+        self.users.update(&|u| u.username == *username, &mut |u| {
+            if !u.keys.contains(&key) {
+                u.keys.push(key.clone());
+            }
+        })
+    }
 }
 
 #[cfg(target_os = "linux")]
 const ROOT_DATA_DIR: &'static str = "/var/lib/mrial_server";
 
-impl StorageMultiType<User, String> for Users {
-    #[cfg(not(target_os = "linux"))]
-    fn new() -> Self {
-        Users {
-            users: StorageMulti::new("users.json".to_string()),
-        }
+/// Overrides the directory of the files shared by the server and its CLI
+pub const SERVER_DATA_DIR_ENV: &str = "MRIAL_SERVER_DATA_DIR";
+
+/// Directory of the files shared by the server and its CLI, /var/lib/mrial_server on linux
+pub fn server_data_dir() -> PathBuf {
+    match std::env::var_os(SERVER_DATA_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => default_server_data_dir(),
     }
+}
 
-    #[cfg(target_os = "linux")]
-    fn new() -> Self {
-        use std::path::PathBuf;
-        let file_dir = PathBuf::from(ROOT_DATA_DIR);
+#[cfg(not(target_os = "linux"))]
+fn default_server_data_dir() -> PathBuf {
+    storage::data_dir()
+}
 
-        Users {
-            users: StorageMulti::new_with_custom_dir("users.json".to_string(), file_dir),
-        }
+#[cfg(target_os = "linux")]
+fn default_server_data_dir() -> PathBuf {
+    PathBuf::from(ROOT_DATA_DIR)
+}
+
+fn new_server_storage<T: Serialize + DeserializeOwned + Clone>(file_name: &str) -> StorageMulti<T> {
+    StorageMulti::new_with_custom_dir(file_name.to_string(), server_data_dir())
+}
+
+impl StorageMultiType<User, String> for Users {
+    fn new() -> Self {
+        Users::new_with_custom_dir(server_data_dir())
     }
 
     fn clone(&self) -> Users {
@@ -138,13 +300,425 @@ impl StorageMultiType<User, String> for Users {
     }
 
     fn add(&mut self, user: User) -> Result<(), Box<dyn Error>> {
-        let mut hasher = Sha256::new();
-        hasher.update(user.pass);
-        let hash = hasher.finalize();
-        let hex = hash.iter().map(|b| format!("{:x}", b)).collect::<String>();
         self.users.add(User {
             username: user.username,
-            pass: hex,
+            pass: hash_password(&user.pass),
+            keys: user.keys,
+            totp: user.totp,
+        })
+    }
+}
+
+/// Ed25519 key pair used by the player to authenticate with servers
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyPair {
+    pub name: String,
+    pub public_key: String,
+    pub secret_key: String,
+    /// Secret key sealed by the vault, `secret_key` is left empty when set
+    #[serde(default)]
+    pub sealed: Option<String>,
+}
+
+pub const DEFAULT_KEY_PAIR: &str = "default";
+
+/// Key pair identifying the server, its fingerprint is advertised to players on the LAN
+pub const HOST_KEY_PAIR: &str = "host";
+
+pub struct KeyPairs {
+    pub keys: StorageMulti<KeyPair>,
+    vault: Vault,
+}
+
+impl KeyPairs {
+    /// Key pairs of the server, stored in the server data directory
+    pub fn new_server() -> Self {
+        KeyPairs::new_server_with_custom_dir(server_data_dir())
+    }
+
+    pub fn new_server_with_custom_dir(file_dir: PathBuf) -> Self {
+        KeyPairs {
+            keys: StorageMulti::new_with_custom_dir("keys.json".to_string(), file_dir.clone()),
+            vault: Vault::new_with_custom_dir(file_dir),
+        }
+    }
+
+    /// Key pairs sealed by a vault shared with other storage,
+    /// so unlocking it once opens both.
+    pub fn with_vault(vault: Vault) -> Self {
+        KeyPairs {
+            keys: StorageMulti::new("keys.json".to_string()),
+            vault,
+        }
+    }
+
+    pub fn vault(&self) -> Vault {
+        self.vault.clone()
+    }
+
+    fn seal_secret_key(&self, key_pair: &mut KeyPair) -> Result<(), VaultError> {
+        let secret_key = std::mem::take(&mut key_pair.secret_key);
+        key_pair.sealed = Some(self.vault.seal(secret_key.as_bytes())?);
+
+        Ok(())
+    }
+
+    /// Returns the key pair with its secret key opened, requires the vault
+    /// to be unlocked if it is sealed.
+    pub fn unseal(&self, mut key_pair: KeyPair) -> Result<KeyPair, VaultError> {
+        if let Some(sealed) = key_pair.sealed.take() {
+            key_pair.secret_key =
+                String::from_utf8(self.vault.open(&sealed)?).map_err(|_| VaultError::Corrupted)?;
+        }
+
+        Ok(key_pair)
+    }
+
+    /// Seals the secret key of every key pair still stored in plain text,
+    /// used once the vault is created.
+    pub fn seal_all(&mut self) -> Result<(), Box<dyn Error>> {
+        for key_pair in self.keys.get().unwrap_or_default() {
+            if key_pair.sealed.is_some() {
+                continue;
+            }
+
+            let mut sealed_key_pair = key_pair.clone();
+            self.seal_secret_key(&mut sealed_key_pair)?;
+            self.keys.update(&|k| k.name == key_pair.name, &mut |k| {
+                *k = sealed_key_pair.clone();
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageMultiType<KeyPair, String> for KeyPairs {
+    fn new() -> Self {
+        KeyPairs::with_vault(Vault::new())
+    }
+
+    fn clone(&self) -> KeyPairs {
+        KeyPairs {
+            keys: self.keys.clone(),
+            vault: self.vault.clone(),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        // The key pairs are loaded even if the vault fails to,
+        // sealed secret keys then just can't be opened
+        let vault_result = self.vault.load();
+        self.keys.load()?;
+        vault_result
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.keys.save()
+    }
+
+    fn find(&self, name: String) -> Option<KeyPair> {
+        self.keys.find(&|k| k.name == name)
+    }
+
+    fn remove(&mut self, name: String) -> Result<(), Box<dyn Error>> {
+        self.keys.remove(&mut |k| k.name == name)
+    }
+
+    /// The secret key is sealed when the vault is enabled, which then has to be unlocked.
+    fn add(&mut self, mut key: KeyPair) -> Result<(), Box<dyn Error>> {
+        if self.vault.is_enabled() && key.sealed.is_none() {
+            self.seal_secret_key(&mut key)?;
+        }
+
+        self.keys.add(key)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+/// CIDR rule restricting which addresses may connect, optionally only for one user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessRule {
+    pub action: AccessAction,
+    pub cidr: String,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl std::fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let action = match self.action {
+            AccessAction::Allow => "allow",
+            AccessAction::Deny => "deny",
+        };
+
+        match &self.user {
+            Some(user) => write!(f, "{} {} (user: {})", action, self.cidr, user),
+            None => write!(f, "{} {}", action, self.cidr),
+        }
+    }
+}
+
+/// Access rules are keyed by their position, as rules are evaluated in order
+pub struct AccessRules {
+    pub rules: StorageMulti<AccessRule>,
+}
+
+impl StorageMultiType<AccessRule, usize> for AccessRules {
+    fn new() -> Self {
+        AccessRules {
+            rules: new_server_storage("access.json"),
+        }
+    }
+
+    fn clone(&self) -> AccessRules {
+        AccessRules {
+            rules: self.rules.clone(),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        self.rules.load()
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.rules.save()
+    }
+
+    fn find(&self, index: usize) -> Option<AccessRule> {
+        self.rules.get()?.get(index).cloned()
+    }
+
+    fn remove(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let mut position = 0;
+        self.rules.remove(&mut |_| {
+            position += 1;
+            position - 1 == index
         })
     }
+
+    fn add(&mut self, rule: AccessRule) -> Result<(), Box<dyn Error>> {
+        self.rules.add(rule)
+    }
+}
+
+/// STUN or TURN server WebRTC peers gather candidates from, TURN requires credentials
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IceServer {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub credential: Option<String>,
+}
+
+impl std::fmt::Display for IceServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.username {
+            Some(username) => write!(f, "{} (username: {})", self.url, username),
+            None => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// Inclusive range of ports
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+pub const SERVER_DEFAULT_PORT: u16 = 8554;
+
+fn default_bind() -> Vec<String> {
+    vec!["0.0.0.0".to_string()]
+}
+
+fn default_port() -> u16 {
+    SERVER_DEFAULT_PORT
+}
+
+/// Same as the keepalive defaults of mrial_proto
+fn default_keepalive_interval_ms() -> u64 {
+    2000
+}
+
+fn default_keepalive_timeout_ms() -> u64 {
+    6000
+}
+
+fn default_retransmit_cache_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_retransmit_cache_age_ms() -> u64 {
+    2000
+}
+
+fn default_discovery() -> bool {
+    true
+}
+
+/// Settings of the server, the sockets are bound with them when it starts
+/// while the rest are reloaded on SIGHUP.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerConfig {
+    /// Addresses the app socket binds to, with or without a port.
+    /// `[::]` binds IPv6 and IPv4 (dual-stack) on its own.
+    #[serde(default = "default_bind")]
+    pub bind: Vec<String>,
+    /// Port of bind addresses without one
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// UDP port WebRTC peers share, otherwise each peer uses a random port
+    #[serde(default)]
+    pub web_port: Option<u16>,
+    /// Group allowed to use the control socket, besides root
+    #[serde(default)]
+    pub control_group: Option<String>,
+    /// Interval the server and clients ping each other at
+    #[serde(default = "default_keepalive_interval_ms")]
+    pub keepalive_interval_ms: u64,
+    /// Time without packets until a client, or the server for the client, is unreachable
+    #[serde(default = "default_keepalive_timeout_ms")]
+    pub keepalive_timeout_ms: u64,
+    /// Bytes of video subpackets kept for retransmission, per client
+    #[serde(default = "default_retransmit_cache_bytes")]
+    pub retransmit_cache_bytes: usize,
+    /// Age after which cached subpackets are no longer retransmitted
+    #[serde(default = "default_retransmit_cache_age_ms")]
+    pub retransmit_cache_age_ms: u64,
+    /// Name advertised to players on the LAN, the hostname if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Answer discovery probes from players on the LAN
+    #[serde(default = "default_discovery")]
+    pub discovery: bool,
+    /// Relay players reach the server through by name, "host:port"
+    #[serde(default)]
+    pub relay: Option<String>,
+    /// Name registered with the relay, the advertised name if not set
+    #[serde(default)]
+    pub relay_name: Option<String>,
+    /// TCP port of the HTTP listener serving the web player, off if not set
+    #[serde(default)]
+    pub http_port: Option<u16>,
+    /// The HTTP listener is reached through a reverse proxy terminating TLS, so web clients
+    /// may sign in over it while it listens on anything but loopback
+    #[serde(default)]
+    pub http_tls_proxy: bool,
+    /// STUN and TURN servers of WebRTC peers, none by default so no third party
+    /// learns of the peers and only host candidates are gathered
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+    /// Only gather host candidates even if ICE servers are set, for networks without internet
+    #[serde(default)]
+    pub ice_host_only: bool,
+    /// UDP ports WebRTC peers pick from when they do not share the WebRTC port
+    #[serde(default)]
+    pub web_port_range: Option<PortRange>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_bind(),
+            port: default_port(),
+            web_port: None,
+            control_group: None,
+            keepalive_interval_ms: default_keepalive_interval_ms(),
+            keepalive_timeout_ms: default_keepalive_timeout_ms(),
+            retransmit_cache_bytes: default_retransmit_cache_bytes(),
+            retransmit_cache_age_ms: default_retransmit_cache_age_ms(),
+            name: None,
+            discovery: default_discovery(),
+            relay: None,
+            relay_name: None,
+            http_port: None,
+            http_tls_proxy: false,
+            ice_servers: Vec::new(),
+            ice_host_only: false,
+            web_port_range: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Replaces settings the server can not run with by their defaults, returning
+    /// what was replaced so it is logged when the config is loaded or reloaded.
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.keepalive_interval_ms == 0
+            || self.keepalive_timeout_ms <= self.keepalive_interval_ms
+        {
+            problems.push(format!(
+                "Invalid Keepalive Interval of {}ms with Timeout of {}ms, Using the Defaults",
+                self.keepalive_interval_ms, self.keepalive_timeout_ms
+            ));
+            self.keepalive_interval_ms = default_keepalive_interval_ms();
+            self.keepalive_timeout_ms = default_keepalive_timeout_ms();
+        }
+
+        if let (Some(web_port), Some(range)) = (self.web_port, self.web_port_range) {
+            problems.push(format!(
+                "WebRTC Peers Share the WebRTC Port {}, Ignoring the Port Range {}",
+                web_port, range
+            ));
+            self.web_port_range = None;
+        }
+
+        problems
+    }
+}
+
+/// Server config, stored as the only entry of config.json
+pub struct ServerConfigs {
+    pub configs: StorageMulti<ServerConfig>,
+}
+
+impl ServerConfigs {
+    pub fn new() -> Self {
+        ServerConfigs {
+            configs: new_server_storage("config.json"),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        self.configs.load()
+    }
+
+    /// Saved config, or the default one if none was saved
+    pub fn get(&self) -> ServerConfig {
+        self.configs
+            .get()
+            .and_then(|configs| configs.first().cloned())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the saved config, the configs must have been loaded
+    pub fn set(&mut self, config: ServerConfig) -> Result<(), Box<dyn Error>> {
+        while self.configs.remove(&mut |_| true).is_ok() {}
+
+        self.configs.add(config)?;
+        self.configs.save()
+    }
+}
+
+impl Default for ServerConfigs {
+    fn default() -> Self {
+        Self::new()
+    }
 }
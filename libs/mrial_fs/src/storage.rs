@@ -33,11 +33,15 @@ pub trait StorageMultiType<T: Serialize + DeserializeOwned + Clone, K> {
 
 const DB_PATH: &'static str = "Mrial/db";
 
+/// Default directory of the storage files, inside the OS data directory.
+pub fn data_dir() -> PathBuf {
+    let os_data_dir = dirs::data_dir().unwrap();
+    os_data_dir.join(DB_PATH)
+}
+
 impl<T: Serialize + DeserializeOwned + Clone> StorageMulti<T> {
     pub fn new(file_name: String) -> Self {
-        let os_data_dir = dirs::data_dir().unwrap();
-        let file_dir = os_data_dir.join(DB_PATH);
-        StorageMulti::new_with_custom_dir(file_name, file_dir)
+        StorageMulti::new_with_custom_dir(file_name, data_dir())
     }
 
     pub fn new_with_custom_dir(file_name: String, file_dir: PathBuf) -> Self {
@@ -92,6 +96,23 @@ impl<T: Serialize + DeserializeOwned + Clone> StorageMulti<T> {
         None
     }
 
+    pub fn update(
+        &self,
+        func: &dyn Fn(&T) -> bool,
+        update: &mut dyn FnMut(&mut T),
+    ) -> Result<(), Box<dyn Error>> {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(state) = state.as_mut() {
+                if let Some(item) = state.iter_mut().find(|item| func(item)) {
+                    update(item);
+                    return Ok(());
+                }
+            }
+        }
+
+        Err("Failed to Update Item".into())
+    }
+
     pub fn add(&self, item: T) -> Result<(), Box<dyn Error>> {
         if let Ok(mut state) = self.state.lock() {
             if let Some(state) = state.as_mut() {
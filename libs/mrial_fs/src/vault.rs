@@ -0,0 +1,259 @@
+use std::{
+    error::Error,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::{aead::Aead, AeadCore, ChaCha20Poly1305, KeyInit, Nonce};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StorageMulti;
+
+const VAULT_SALT_SIZE: usize = 16;
+const VAULT_KEY_SIZE: usize = 32;
+const VAULT_NONCE_SIZE: usize = 12;
+/// Sealed on creation, a passphrase is correct if it opens the verifier
+const VAULT_VERIFIER: &[u8] = b"mrial-vault";
+
+#[derive(Debug)]
+pub enum VaultError {
+    NotEnabled,
+    AlreadyEnabled,
+    Locked,
+    InvalidPassphrase,
+    Corrupted,
+    KeyDerivationFailed(String),
+    Storage(String),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VaultError::NotEnabled => write!(f, "Vault is Not Enabled"),
+            VaultError::AlreadyEnabled => write!(f, "Vault is Already Enabled"),
+            VaultError::Locked => write!(f, "Vault is Locked"),
+            VaultError::InvalidPassphrase => write!(f, "Invalid Vault Passphrase"),
+            VaultError::Corrupted => write!(f, "Corrupted Vault Data"),
+            VaultError::KeyDerivationFailed(e) => write!(f, "Failed to Derive Vault Key: {}", e),
+            VaultError::Storage(e) => write!(f, "Failed to Access Vault Storage: {}", e),
+        }
+    }
+}
+
+impl Error for VaultError {}
+
+/// Parameters needed to derive the vault key from the passphrase,
+/// stored so the defaults can change without breaking existing vaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VaultHeader {
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub verifier: String,
+}
+
+/// Seals data with a ChaCha20Poly1305 key derived from a master passphrase (Argon2id).
+/// Clones share the unlocked key, so the vault is unlocked once per launch.
+pub struct Vault {
+    header: StorageMulti<VaultHeader>,
+    key: Arc<Mutex<Option<ChaCha20Poly1305>>>,
+}
+
+impl Vault {
+    pub fn new() -> Self {
+        Self {
+            header: StorageMulti::new("vault.json".to_string()),
+            key: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn new_with_custom_dir(file_dir: PathBuf) -> Self {
+        Self {
+            header: StorageMulti::new_with_custom_dir("vault.json".to_string(), file_dir),
+            key: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header.load()
+    }
+
+    fn get_header(&self) -> Option<VaultHeader> {
+        self.header.get()?.first().cloned()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.get_header().is_some()
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.key.lock().map(|key| key.is_some()).unwrap_or(false)
+    }
+
+    fn derive_key(passphrase: &str, header: &VaultHeader) -> Result<ChaCha20Poly1305, VaultError> {
+        let salt = STANDARD
+            .decode(&header.salt)
+            .map_err(|_| VaultError::Corrupted)?;
+        let params = Params::new(
+            header.m_cost,
+            header.t_cost,
+            header.p_cost,
+            Some(VAULT_KEY_SIZE),
+        )
+        .map_err(|e| VaultError::KeyDerivationFailed(e.to_string()))?;
+
+        let mut key = [0u8; VAULT_KEY_SIZE];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| VaultError::KeyDerivationFailed(e.to_string()))?;
+
+        ChaCha20Poly1305::new_from_slice(&key).map_err(|_| VaultError::Corrupted)
+    }
+
+    fn seal_with(key: &ChaCha20Poly1305, data: &[u8]) -> Result<String, VaultError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(key.encrypt(&nonce, data).map_err(|_| VaultError::Corrupted)?);
+
+        Ok(STANDARD.encode(sealed))
+    }
+
+    fn open_with(key: &ChaCha20Poly1305, sealed: &str) -> Result<Vec<u8>, VaultError> {
+        let sealed = STANDARD.decode(sealed).map_err(|_| VaultError::Corrupted)?;
+        if sealed.len() < VAULT_NONCE_SIZE {
+            return Err(VaultError::Corrupted);
+        }
+
+        let (nonce, data) = sealed.split_at(VAULT_NONCE_SIZE);
+        key.decrypt(Nonce::from_slice(nonce), data)
+            .map_err(|_| VaultError::Corrupted)
+    }
+
+    fn set_key(&self, key: ChaCha20Poly1305) {
+        if let Ok(mut current) = self.key.lock() {
+            *current = Some(key);
+        }
+    }
+
+    /// Creates the vault with the passphrase and leaves it unlocked.
+    pub fn create(&self, passphrase: &str) -> Result<(), VaultError> {
+        if self.is_enabled() {
+            return Err(VaultError::AlreadyEnabled);
+        }
+
+        let mut salt = [0u8; VAULT_SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut header = VaultHeader {
+            salt: STANDARD.encode(salt),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            verifier: String::new(),
+        };
+        let key = Self::derive_key(passphrase, &header)?;
+        header.verifier = Self::seal_with(&key, VAULT_VERIFIER)?;
+
+        self.header
+            .add(header)
+            .and_then(|_| self.header.save())
+            .map_err(|e| VaultError::Storage(e.to_string()))?;
+        self.set_key(key);
+
+        Ok(())
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<(), VaultError> {
+        let header = self.get_header().ok_or(VaultError::NotEnabled)?;
+        let key = Self::derive_key(passphrase, &header)?;
+
+        match Self::open_with(&key, &header.verifier) {
+            Ok(verifier) if verifier == VAULT_VERIFIER => {
+                self.set_key(key);
+                Ok(())
+            }
+            _ => Err(VaultError::InvalidPassphrase),
+        }
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Result<String, VaultError> {
+        match self.key.lock().map_err(|_| VaultError::Locked)?.as_ref() {
+            Some(key) => Self::seal_with(key, data),
+            None => Err(VaultError::Locked),
+        }
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>, VaultError> {
+        match self.key.lock().map_err(|_| VaultError::Locked)?.as_ref() {
+            Some(key) => Self::open_with(key, sealed),
+            None => Err(VaultError::Locked),
+        }
+    }
+}
+
+impl Default for Vault {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for Vault {
+    fn clone(&self) -> Self {
+        Self {
+            header: self.header.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mrial-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn sealed_data_opens_after_unlock() {
+        let dir = test_dir("vault");
+        let mut vault = Vault::new_with_custom_dir(dir.clone());
+        vault.load().unwrap();
+
+        assert!(matches!(vault.seal(b"secret"), Err(VaultError::Locked)));
+        vault.create("passphrase").unwrap();
+        let sealed = vault.seal(b"secret").unwrap();
+        assert_eq!(vault.open(&sealed).unwrap(), b"secret");
+
+        let mut reopened = Vault::new_with_custom_dir(dir.clone());
+        reopened.load().unwrap();
+        assert!(reopened.is_enabled() && !reopened.is_unlocked());
+        assert!(matches!(
+            reopened.unlock("wrong"),
+            Err(VaultError::InvalidPassphrase)
+        ));
+        reopened.unlock("passphrase").unwrap();
+        assert_eq!(reopened.open(&sealed).unwrap(), b"secret");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn tampered_data_does_not_open() {
+        let key = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let sealed = Vault::seal_with(&key, b"secret").unwrap();
+        let mut sealed = STANDARD.decode(sealed).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+
+        assert!(Vault::open_with(&key, &STANDARD.encode(&sealed)).is_err());
+        assert!(Vault::open_with(&key, &STANDARD.encode([0u8; 4])).is_err());
+        assert!(Vault::open_with(&key, "not base64").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+pub const AUTH_CHALLENGE_SIZE: usize = 32;
+
+/// Prefixed to every challenge before signing so a signature
+/// produced for Mrial can not be replayed in another protocol.
+const AUTH_CHALLENGE_DOMAIN: &[u8] = b"mrial-auth-challenge";
+
+/// Prefixed to the session key a server signs with its host key, so the
+/// signature can not be passed off as the response to an auth challenge.
+const HOST_SESSION_KEY_DOMAIN: &[u8] = b"mrial-host-session-key";
+
+/// Prefixed to the relay nonce and name a server signs with its host key to register
+const RELAY_REGISTER_DOMAIN: &[u8] = b"mrial-relay-register";
+
+#[derive(Debug)]
+pub enum AuthKeyError {
+    InvalidEncoding,
+    InvalidKey,
+    InvalidSignature,
+}
+
+impl std::fmt::Display for AuthKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthKeyError::InvalidEncoding => write!(f, "Key is not valid Base64"),
+            AuthKeyError::InvalidKey => write!(f, "Key is not a valid Ed25519 Key"),
+            AuthKeyError::InvalidSignature => write!(f, "Signature is not a valid Ed25519 Signature"),
+        }
+    }
+}
+
+impl std::error::Error for AuthKeyError {}
+
+fn decode_key(key: &str) -> Result<[u8; 32], AuthKeyError> {
+    let bytes = STANDARD
+        .decode(key.trim())
+        .map_err(|_| AuthKeyError::InvalidEncoding)?;
+
+    bytes.try_into().map_err(|_| AuthKeyError::InvalidKey)
+}
+
+fn domain_message(domain: &[u8], data: &str) -> Vec<u8> {
+    let mut message = domain.to_vec();
+    message.extend_from_slice(data.as_bytes());
+    message
+}
+
+fn sign_message(secret_key: &str, message: &[u8]) -> Result<String, AuthKeyError> {
+    let signing_key = SigningKey::from_bytes(&decode_key(secret_key)?);
+    Ok(STANDARD.encode(signing_key.sign(message).to_bytes()))
+}
+
+fn verify_message(pub_key: &str, message: &[u8], signature: &str) -> Result<(), AuthKeyError> {
+    let verifying_key = parse_public_key(pub_key)?;
+    let signature_bytes = STANDARD
+        .decode(signature.trim())
+        .map_err(|_| AuthKeyError::InvalidEncoding)?;
+    let signature =
+        Signature::from_slice(&signature_bytes).map_err(|_| AuthKeyError::InvalidSignature)?;
+
+    verifying_key
+        .verify(message, &signature)
+        .map_err(|_| AuthKeyError::InvalidSignature)
+}
+
+/// Generates a new Ed25519 key pair, returned as (secret key, public key) in Base64.
+pub fn generate_key_pair() -> (String, String) {
+    let signing_key = SigningKey::generate(&mut OsRng);
+
+    (
+        STANDARD.encode(signing_key.to_bytes()),
+        STANDARD.encode(signing_key.verifying_key().to_bytes()),
+    )
+}
+
+pub fn public_key_from_secret(secret_key: &str) -> Result<String, AuthKeyError> {
+    let signing_key = SigningKey::from_bytes(&decode_key(secret_key)?);
+    Ok(STANDARD.encode(signing_key.verifying_key().to_bytes()))
+}
+
+pub fn parse_public_key(pub_key: &str) -> Result<VerifyingKey, AuthKeyError> {
+    VerifyingKey::from_bytes(&decode_key(pub_key)?).map_err(|_| AuthKeyError::InvalidKey)
+}
+
+/// SHA-256 fingerprint of a public key, formatted like OpenSSH as "SHA256:" and unpadded Base64.
+pub fn key_fingerprint(pub_key: &str) -> Result<String, AuthKeyError> {
+    let digest = Sha256::digest(parse_public_key(pub_key)?.to_bytes());
+    Ok(format!("SHA256:{}", STANDARD_NO_PAD.encode(digest)))
+}
+
+/// Random challenge the server sends to a client authenticating with a key.
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; AUTH_CHALLENGE_SIZE];
+    OsRng.fill_bytes(&mut challenge);
+
+    STANDARD.encode(challenge)
+}
+
+pub fn sign_challenge(secret_key: &str, challenge: &str) -> Result<String, AuthKeyError> {
+    sign_message(
+        secret_key,
+        &domain_message(AUTH_CHALLENGE_DOMAIN, challenge),
+    )
+}
+
+pub fn verify_challenge(
+    pub_key: &str,
+    challenge: &str,
+    signature: &str,
+) -> Result<(), AuthKeyError> {
+    verify_message(
+        pub_key,
+        &domain_message(AUTH_CHALLENGE_DOMAIN, challenge),
+        signature,
+    )
+}
+
+/// Signs the session key a server sends in its Shook UE with its host key,
+/// proving to the player that the key belongs to the server it trusts.
+pub fn sign_session_key(host_secret_key: &str, session_key: &str) -> Result<String, AuthKeyError> {
+    sign_message(
+        host_secret_key,
+        &domain_message(HOST_SESSION_KEY_DOMAIN, session_key),
+    )
+}
+
+pub fn verify_session_key(
+    host_key: &str,
+    session_key: &str,
+    signature: &str,
+) -> Result<(), AuthKeyError> {
+    verify_message(
+        host_key,
+        &domain_message(HOST_SESSION_KEY_DOMAIN, session_key),
+        signature,
+    )
+}
+
+/// The nonce is a fixed length challenge, so it can not run into the name
+fn relay_registration_message(nonce: &str, name: &str) -> Vec<u8> {
+    domain_message(RELAY_REGISTER_DOMAIN, &format!("{}{}", nonce, name))
+}
+
+/// Signs the nonce a relay challenges a server registering under the name with,
+/// proving to the relay that the name is claimed by the owner of the host key.
+pub fn sign_relay_registration(
+    host_secret_key: &str,
+    nonce: &str,
+    name: &str,
+) -> Result<String, AuthKeyError> {
+    sign_message(host_secret_key, &relay_registration_message(nonce, name))
+}
+
+pub fn verify_relay_registration(
+    host_key: &str,
+    nonce: &str,
+    name: &str,
+    signature: &str,
+) -> Result<(), AuthKeyError> {
+    verify_message(
+        host_key,
+        &relay_registration_message(nonce, name),
+        signature,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_signature_is_bound_to_the_key() {
+        let (secret_key, public_key) = generate_key_pair();
+        let (_, other_key) = generate_key_pair();
+        let challenge = generate_challenge();
+        let signature = sign_challenge(&secret_key, &challenge).unwrap();
+
+        assert!(verify_challenge(&public_key, &challenge, &signature).is_ok());
+        assert!(matches!(
+            verify_challenge(&other_key, &challenge, &signature),
+            Err(AuthKeyError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn tampered_challenge_is_rejected() {
+        let (secret_key, public_key) = generate_key_pair();
+        let challenge = generate_challenge();
+        let signature = sign_challenge(&secret_key, &challenge).unwrap();
+
+        let mut tampered = STANDARD.decode(&challenge).unwrap();
+        tampered[0] ^= 1;
+        let tampered = STANDARD.encode(tampered);
+
+        assert!(matches!(
+            verify_challenge(&public_key, &tampered, &signature),
+            Err(AuthKeyError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let (secret_key, public_key) = generate_key_pair();
+        let challenge = generate_challenge();
+        let signature = sign_challenge(&secret_key, &challenge).unwrap();
+
+        assert!(matches!(
+            verify_challenge("not base64!", &challenge, &signature),
+            Err(AuthKeyError::InvalidEncoding)
+        ));
+        assert!(matches!(
+            verify_challenge(&STANDARD.encode([0u8; 16]), &challenge, &signature),
+            Err(AuthKeyError::InvalidKey)
+        ));
+        assert!(matches!(
+            sign_challenge("not base64!", &challenge),
+            Err(AuthKeyError::InvalidEncoding)
+        ));
+        assert!(verify_challenge(&public_key, &challenge, "not base64!").is_err());
+    }
+
+    #[test]
+    fn session_key_signature_is_bound_to_the_host_key() {
+        let (secret_key, public_key) = generate_key_pair();
+        let (_, other_key) = generate_key_pair();
+        let signature = sign_session_key(&secret_key, "session key").unwrap();
+
+        assert!(verify_session_key(&public_key, "session key", &signature).is_ok());
+        assert!(verify_session_key(&public_key, "other session key", &signature).is_err());
+        assert!(verify_session_key(&other_key, "session key", &signature).is_err());
+        assert!(verify_challenge(&public_key, "session key", &signature).is_err());
+    }
+}
@@ -1,9 +1,12 @@
-use chacha20poly1305::{aead::AeadMut, AeadCore, ChaCha20Poly1305};
+use chacha20poly1305::{aead::AeadMut, AeadCore, ChaCha20Poly1305, KeyInit, Nonce};
 use rand::rngs::ThreadRng;
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
-use crate::video::EColorSpace;
+use crate::{
+    video::EColorSpace, KEEPALIVE_INTERVAL_MS, KEEPALIVE_MIN_INTERVAL_MS, KEEPALIVE_TIMEOUT_MS,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientStatePayload {
@@ -11,18 +14,57 @@ pub struct ClientStatePayload {
     pub height: u16,
     pub muted: bool,
     pub opus: bool,
-    pub csp: EColorSpace
+    pub csp: EColorSpace,
+    /// Name of the monitor to stream, `None` keeps the one being streamed.
+    /// Only followed for the client in control.
+    #[serde(default)]
+    pub monitor: Option<String>,
 }
 
 impl JSONPayloadSE for ClientStatePayload {}
 
+/// Monitor of the server, positioned on its virtual desktop
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MonitorPayload {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u16,
+    pub height: u16,
+    pub primary: bool,
+    /// Resolutions the monitor supports, empty in the server state,
+    /// where they follow in a `MonitorModesSE` packet per monitor
+    pub widths: Vec<u16>,
+    pub heights: Vec<u16>,
+}
+
+/// Resolutions of a monitor, sent apart from the server state so
+/// every monitor can list its modes in a packet of its own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MonitorModesSE {
+    pub name: String,
+    /// Whether the monitor is streamed, whose resolutions are also `widths` and `heights`
+    /// of the server state
+    pub streamed: bool,
+    pub widths: Vec<u16>,
+    pub heights: Vec<u16>,
+}
+
+impl JSONPayloadSE for MonitorModesSE {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerStatePayload {
+    /// Resolutions of the streamed monitor, fewer when they do not fit the packet
     pub widths: Vec<u16>,
     pub heights: Vec<u16>,
     pub width: u16,
     pub height: u16,
     pub version: String,
+    #[serde(default)]
+    pub monitors: Vec<MonitorPayload>,
+    /// Name of the monitor being streamed, whose resolutions are `widths` and `heights`
+    #[serde(default)]
+    pub monitor: Option<String>,
 }
 
 impl JSONPayloadSE for ServerStatePayload {}
@@ -30,28 +72,140 @@ impl JSONPayloadSE for ServerStatePayload {}
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerShookUE {
     pub pub_key: String,
+    /// Host key of the server, see `auth::key_fingerprint`
+    #[serde(default)]
+    pub host_key: Option<String>,
+    /// Signature of `pub_key` by the host key, see `auth::sign_session_key`
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
 impl JSONPayloadUE for ServerShookUE {}
 
+/// Role of a client in the session, input is only accepted from the controller
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ESessionRole {
+    #[default]
+    Controller,
+    Spectator,
+}
+
+/// An empty `pass` requests public key authentication,
+/// in which case the server replies with a `ServerChallengeSE`.
+/// Clients requesting control join as spectators when another client is in control.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientShakeAE {
     pub state: ClientStatePayload,
     pub username: String,
     pub pass: String,
     pub sym_key: String,
+    #[serde(default)]
+    pub role: ESessionRole,
 }
 
 impl JSONPayloadAE for ClientShakeAE {}
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EAuthMethod {
+    PublicKey,
+    /// Second factor requested after the first method succeeded, the challenge is empty
+    Totp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerChallengeSE {
+    pub method: EAuthMethod,
+    pub challenge: String,
+}
+
+impl JSONPayloadSE for ServerChallengeSE {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientResponseSE {
+    pub method: EAuthMethod,
+    #[serde(default)]
+    pub pub_key: String,
+    pub response: String,
+}
+
+impl JSONPayloadSE for ClientResponseSE {}
+
+/// Keepalive timing of the server, which the client follows as well
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeepaliveSettings {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl KeepaliveSettings {
+    /// Settings safe to follow whatever the other side sent, the interval is at least
+    /// `KEEPALIVE_MIN_INTERVAL_MS` and the timeout spans at least two intervals.
+    pub fn clamped(self) -> Self {
+        let interval_ms = self.interval_ms.max(KEEPALIVE_MIN_INTERVAL_MS);
+
+        Self {
+            interval_ms,
+            timeout_ms: self.timeout_ms.max(interval_ms.saturating_mul(2)),
+        }
+    }
+}
+
+impl Default for KeepaliveSettings {
+    fn default() -> Self {
+        Self {
+            interval_ms: KEEPALIVE_INTERVAL_MS,
+            timeout_ms: KEEPALIVE_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Client of the session, as shown to the other clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionMember {
+    pub address: String,
+    pub username: String,
+}
+
+/// Roles of the session as seen by a client, sent to every client whenever control changes
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionStateSE {
+    pub role: ESessionRole,
+    /// Client in control, if any
+    pub controller: Option<SessionMember>,
+    /// Spectators requesting control, only sent to the controller
+    pub requests: Vec<SessionMember>,
+}
+
+impl JSONPayloadSE for SessionStateSE {}
+
+/// Control messages clients send to request or hand over control of the session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SessionControlSE {
+    /// Sent by spectators, granted right away if no client is in control
+    Request,
+    /// Sent by the controller to hand control to a spectator
+    Grant { address: String },
+    /// Sent by the controller to give up control
+    Revoke,
+}
+
+impl JSONPayloadSE for SessionControlSE {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerShookSE {
     pub server_state: ServerStatePayload,
+    #[serde(default)]
+    pub keepalive: KeepaliveSettings,
+    #[serde(default)]
+    pub session: SessionStateSE,
 }
 
 impl JSONPayloadSE for ServerShookSE {}
 
 pub const SE_NONCE: usize = 12;
+/// Poly1305 tag appended to every encrypted SE payload
+pub const SE_TAG: usize = 16;
 
 #[derive(Debug)]
 struct JSONPayloadSEError;
@@ -62,7 +216,20 @@ impl std::fmt::Display for JSONPayloadSEError {
 }
 impl std::error::Error for JSONPayloadSEError {}
 
+/// Payloads encrypted with the session key of the client.
+///
+/// Layout: encrypted payload | nonce
+///
+/// Payloads are not fragmented, the serialized payload has to fit a single packet
+/// of `PAYLOAD` bytes along with the 16 byte tag and the nonce. Payloads that do not
+/// fit the buffer are not written.
 pub trait JSONPayloadSE: serde::Serialize + serde::de::DeserializeOwned {
+    /// Bytes `write_payload` writes for the payload
+    fn encrypted_len(payload: &Self) -> usize {
+        let serialized_len = serde_json::to_vec(payload).map_or(0, |bytes| bytes.len());
+        serialized_len + SE_TAG + SE_NONCE
+    }
+
     fn write_payload(
         buf: &mut [u8],
         sym_key: Option<ChaCha20Poly1305>,
@@ -79,6 +246,10 @@ pub trait JSONPayloadSE: serde::Serialize + serde::de::DeserializeOwned {
 
         let encrypted_payload: Vec<u8> = sym_key.encrypt(&nonce, bytes).unwrap();
         let payload_len: usize = encrypted_payload.len();
+        if payload_len + SE_NONCE > buf.len() {
+            return Err("SE Payload Exceeds the Buffer");
+        }
+
         buf[0..payload_len].copy_from_slice(&encrypted_payload);
         buf[payload_len..payload_len + SE_NONCE].copy_from_slice(&nonce);
 
@@ -89,6 +260,10 @@ pub trait JSONPayloadSE: serde::Serialize + serde::de::DeserializeOwned {
         buf: &[u8],
         sym_key: &mut ChaCha20Poly1305,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        if buf.len() < SE_NONCE {
+            return Err(Box::new(JSONPayloadSEError {}));
+        }
+
         let encrypted_payload = &buf[0..buf.len() - SE_NONCE];
         let nonce = &buf[buf.len() - 12..buf.len()];
         let nonce = nonce.try_into().map_err(|_| "Corrupted SE Nonce")?;
@@ -122,34 +297,248 @@ pub trait JSONPayloadUE: serde::Serialize + serde::de::DeserializeOwned {
     }
 }
 
+/// Size of the big endian length prefix of the wrapped key in an AE payload
+const AE_WRAPPED_KEY_LEN_SIZE: usize = 2;
+
+#[derive(Debug)]
+pub enum JSONPayloadAEError {
+    PayloadTooLarge(usize, usize),
+    KeyWrapFailed(String),
+    EncryptionFailed,
+    Corrupted,
+}
+
+impl std::fmt::Display for JSONPayloadAEError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JSONPayloadAEError::PayloadTooLarge(len, max) => {
+                write!(
+                    f,
+                    "AE Payload of {len} bytes exceeds the {max} bytes available"
+                )
+            }
+            JSONPayloadAEError::KeyWrapFailed(e) => write!(f, "Failed to Wrap AE Key: {e}"),
+            JSONPayloadAEError::EncryptionFailed => write!(f, "Failed to Encrypt AE Payload"),
+            JSONPayloadAEError::Corrupted => write!(f, "Corrupted AE Payload"),
+        }
+    }
+}
+
+impl std::error::Error for JSONPayloadAEError {}
+
+/// Payloads encrypted with a one-time ChaCha20Poly1305 key, which is wrapped
+/// with the RSA public key of the receiver (RSA-OAEP with SHA-256).
+///
+/// Layout: wrapped key length (u16) | wrapped key | nonce | encrypted payload
+///
+/// Payloads are not fragmented, the serialized payload has to fit a single packet
+/// of `PAYLOAD` bytes along with the wrapped key (256 bytes for the 2048-bit keys
+/// of the handshake), its length, the nonce and the 16 byte tag.
 pub trait JSONPayloadAE: serde::Serialize + serde::de::DeserializeOwned {
     fn write_payload(
         buf: &mut [u8],
         rng: &mut ThreadRng,
         pub_key: RsaPublicKey,
         payload: &Self,
-    ) -> usize {
-        let serialized_payload = serde_json::to_string(&payload).unwrap();
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let serialized_payload = serde_json::to_string(&payload)?;
 
-        // TODO: Allow larger payloads (current max is 256 bytes)
-        let encrypted_payload = pub_key
-            .encrypt(rng, Pkcs1v15Encrypt, serialized_payload.as_bytes())
-            .unwrap();
+        let key = ChaCha20Poly1305::generate_key(&mut *rng);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut *rng);
+        let encrypted_payload = ChaCha20Poly1305::new(&key)
+            .encrypt(&nonce, serialized_payload.as_bytes())
+            .map_err(|_| JSONPayloadAEError::EncryptionFailed)?;
 
-        let len = encrypted_payload.len();
-        buf[0..len].copy_from_slice(&encrypted_payload);
+        let wrapped_key = pub_key
+            .encrypt(rng, Oaep::new::<Sha256>(), &key)
+            .map_err(|e| JSONPayloadAEError::KeyWrapFailed(e.to_string()))?;
 
-        len
+        let len = AE_WRAPPED_KEY_LEN_SIZE + wrapped_key.len() + SE_NONCE + encrypted_payload.len();
+        if len > buf.len() {
+            return Err(Box::new(JSONPayloadAEError::PayloadTooLarge(
+                len,
+                buf.len(),
+            )));
+        }
+
+        let mut offset = 0;
+        for part in [
+            &(wrapped_key.len() as u16).to_be_bytes()[..],
+            &wrapped_key,
+            &nonce,
+            &encrypted_payload,
+        ] {
+            buf[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+
+        Ok(len)
     }
 
     fn from_payload(
         buf: &[u8],
         priv_key: RsaPrivateKey,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let unencypted_payload = priv_key.decrypt(Pkcs1v15Encrypt, &buf)?;
+        if buf.len() < AE_WRAPPED_KEY_LEN_SIZE {
+            return Err(Box::new(JSONPayloadAEError::Corrupted));
+        }
+
+        let wrapped_key_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        let nonce_start = AE_WRAPPED_KEY_LEN_SIZE + wrapped_key_len;
+        if buf.len() < nonce_start + SE_NONCE {
+            return Err(Box::new(JSONPayloadAEError::Corrupted));
+        }
+
+        let key = priv_key.decrypt(
+            Oaep::new::<Sha256>(),
+            &buf[AE_WRAPPED_KEY_LEN_SIZE..nonce_start],
+        )?;
+        let mut cipher =
+            ChaCha20Poly1305::new_from_slice(&key).map_err(|_| JSONPayloadAEError::Corrupted)?;
+
+        let nonce = Nonce::from_slice(&buf[nonce_start..nonce_start + SE_NONCE]);
+        let unencypted_payload = cipher
+            .decrypt(nonce, &buf[nonce_start + SE_NONCE..])
+            .map_err(|_| JSONPayloadAEError::Corrupted)?;
         let serialized_payload = std::str::from_utf8(&unencypted_payload)?;
         let payload: Self = serde_json::from_str(serialized_payload)?;
 
         Ok(payload)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PAYLOAD;
+
+    fn test_keys() -> (RsaPrivateKey, RsaPublicKey) {
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let pub_key = RsaPublicKey::from(&priv_key);
+
+        (priv_key, pub_key)
+    }
+
+    #[test]
+    fn keepalive_is_clamped() {
+        let keepalive = KeepaliveSettings {
+            interval_ms: 0,
+            timeout_ms: 0,
+        }
+        .clamped();
+        assert_eq!(keepalive.interval_ms, KEEPALIVE_MIN_INTERVAL_MS);
+        assert_eq!(keepalive.timeout_ms, 2 * KEEPALIVE_MIN_INTERVAL_MS);
+
+        let keepalive = KeepaliveSettings {
+            interval_ms: u64::MAX,
+            timeout_ms: 1,
+        }
+        .clamped();
+        assert_eq!(keepalive.timeout_ms, u64::MAX);
+
+        assert_eq!(
+            KeepaliveSettings::default().clamped(),
+            KeepaliveSettings::default()
+        );
+    }
+
+    #[test]
+    fn se_payload_shorter_than_nonce_is_rejected() {
+        let mut sym_key =
+            ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut rand::thread_rng()));
+
+        for len in 0..=SE_NONCE {
+            assert!(ServerChallengeSE::from_payload(&vec![0; len], &mut sym_key).is_err());
+        }
+    }
+
+    #[test]
+    fn se_payload_larger_than_buffer_is_rejected() {
+        let sym_key =
+            ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut rand::thread_rng()));
+        let state = SessionStateSE {
+            role: ESessionRole::Controller,
+            controller: None,
+            requests: vec![
+                SessionMember {
+                    address: "192.168.1.2:50000".to_string(),
+                    username: "u".repeat(64),
+                };
+                32
+            ],
+        };
+
+        let len = SessionStateSE::encrypted_len(&state);
+        let mut buf = vec![0u8; len];
+        assert!(
+            SessionStateSE::write_payload(&mut buf[..len - 1], Some(sym_key.clone()), &state)
+                .is_err()
+        );
+        assert_eq!(
+            SessionStateSE::write_payload(&mut buf, Some(sym_key), &state),
+            Ok(len)
+        );
+    }
+
+    #[test]
+    fn monitor_modes_fit_a_packet() {
+        let mut sym_key =
+            ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut rand::thread_rng()));
+        let modes = MonitorModesSE {
+            name: "HDMI-A-1".to_string(),
+            streamed: true,
+            widths: vec![3840; 64],
+            heights: vec![2160; 64],
+        };
+
+        let mut buf = [0u8; PAYLOAD];
+        let len = MonitorModesSE::write_payload(&mut buf, Some(sym_key.clone()), &modes).unwrap();
+        assert_eq!(len, MonitorModesSE::encrypted_len(&modes));
+        assert_eq!(
+            MonitorModesSE::from_payload(&buf[..len], &mut sym_key).unwrap(),
+            modes
+        );
+    }
+
+    #[test]
+    fn ae_payload_truncated_is_rejected() {
+        let (priv_key, pub_key) = test_keys();
+        let payload = TestPayloadAE {
+            response: "response".to_string(),
+        };
+
+        let mut buf = [0u8; 1024];
+        let len =
+            TestPayloadAE::write_payload(&mut buf, &mut rand::thread_rng(), pub_key, &payload)
+                .unwrap();
+
+        for truncated_len in [0, 1, AE_WRAPPED_KEY_LEN_SIZE, 100, len - 1] {
+            assert!(TestPayloadAE::from_payload(&buf[..truncated_len], priv_key.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn ae_payload_round_trip() {
+        let (priv_key, pub_key) = test_keys();
+        let payload = TestPayloadAE {
+            response: "r".repeat(512),
+        };
+
+        let mut buf = [0u8; crate::PAYLOAD];
+        let len =
+            TestPayloadAE::write_payload(&mut buf, &mut rand::thread_rng(), pub_key, &payload)
+                .unwrap();
+
+        assert_eq!(
+            TestPayloadAE::from_payload(&buf[..len], priv_key).unwrap(),
+            payload
+        );
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TestPayloadAE {
+        response: String,
+    }
+
+    impl JSONPayloadAE for TestPayloadAE {}
+}
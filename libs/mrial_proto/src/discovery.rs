@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// UDP port servers answer discovery probes on, players broadcast probes to it
+pub const DISCOVERY_PORT: u16 = 8553;
+
+/// DNS-SD style name of the service, probes start with it and beacons carry it
+pub const DISCOVERY_SERVICE: &str = "_mrial._udp";
+
+/// Probes are padded to this size and beacons never exceed it,
+/// so the responder can not be used to amplify traffic.
+pub const DISCOVERY_PROBE_SIZE: usize = 512;
+
+/// Longest server name a beacon carries, longer names are truncated
+pub const DISCOVERY_MAX_NAME: usize = 64;
+
+pub fn write_discovery_probe(buf: &mut [u8; DISCOVERY_PROBE_SIZE]) {
+    buf.fill(0);
+    buf[..DISCOVERY_SERVICE.len()].copy_from_slice(DISCOVERY_SERVICE.as_bytes());
+}
+
+#[inline]
+pub fn is_discovery_probe(buf: &[u8]) -> bool {
+    buf.len() >= DISCOVERY_PROBE_SIZE && buf.starts_with(DISCOVERY_SERVICE.as_bytes())
+}
+
+/// Sent by a server in response to a probe, the player pre-fills the server form with it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiscoveryBeacon {
+    pub service: String,
+    pub name: String,
+    pub version: String,
+    /// Port the app socket is bound to
+    pub port: u16,
+    /// Fingerprint of the host key, see `auth::key_fingerprint`
+    pub fingerprint: String,
+}
+
+impl DiscoveryBeacon {
+    pub fn new(name: &str, version: &str, port: u16, fingerprint: String) -> Self {
+        Self {
+            service: DISCOVERY_SERVICE.to_string(),
+            name: name.chars().take(DISCOVERY_MAX_NAME).collect(),
+            version: version.to_string(),
+            port,
+            fingerprint,
+        }
+    }
+
+    /// Serialized beacon, None if it does not fit in a probe
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(self)
+            .ok()
+            .filter(|buf| buf.len() <= DISCOVERY_PROBE_SIZE)
+    }
+
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<Self>(buf)
+            .ok()
+            .filter(|beacon| beacon.service == DISCOVERY_SERVICE)
+    }
+}
@@ -1,11 +1,18 @@
+pub mod auth;
 pub mod conn;
 pub mod deploy;
+pub mod discovery;
 pub mod input;
 pub mod packet;
+pub mod relay;
 pub mod video;
 
 pub use conn::*;
 pub use packet::*;
 
-pub const SERVER_PING_TOLERANCE: u64 = 6;
-pub const CLIENT_PING_FREQUENCY: u64 = (SERVER_PING_TOLERANCE / 3) as u64;
+/// Default time without packets from the other side until it is considered unreachable (Milliseconds)
+pub const KEEPALIVE_TIMEOUT_MS: u64 = 6000;
+/// Default interval both sides ping each other at, independent of media (Milliseconds)
+pub const KEEPALIVE_INTERVAL_MS: u64 = KEEPALIVE_TIMEOUT_MS / 3;
+/// Shortest interval followed, so a misconfigured side can not flood the other with pings
+pub const KEEPALIVE_MIN_INTERVAL_MS: u64 = 100;
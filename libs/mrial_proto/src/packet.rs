@@ -21,6 +21,27 @@ impl From<u8> for ENalVariant {
     }
 }
 
+/// Why the server disconnected the client, sent in the
+/// packet type variant bits of a Disconnect packet.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum EDisconnectReason {
+    Unspecified = 0,
+    /// The server is stopping or restarting
+    Shutdown = 1,
+    /// An administrator disconnected the client
+    Kicked = 2,
+}
+
+impl From<u8> for EDisconnectReason {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => EDisconnectReason::Shutdown,
+            2 => EDisconnectReason::Kicked,
+            _ => EDisconnectReason::Unspecified,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum EPacketType {
     /// Header (Unecrypted)
@@ -40,15 +61,27 @@ pub enum EPacketType {
     InputState = 7,
     ClientState = 8,
     ServerState = 9,
-    /// Header (Unecrypted)
+    /// Header (Unecrypted), the variant is the EDisconnectReason when sent by the server
     Disconnect = 10,
-    /// Header (Unecrypted)
+    /// Header (Unecrypted), sent by both sides every keepalive interval
     Ping = 11,
     Alive = 12,
     XOR = 13,
     Retransmit = 14,
     RNAL = 15, // Retransmitted NAL
-    // TODO: Add Server Pings in addition to Client Pings
+    /// Header (Unecrypted) + JSON Containing Auth Challenge (Symmetrically Encrypted)
+    ShakeChallenge = 16,
+    /// Header (Unecrypted) + JSON Containing Challenge Response (Symmetrically Encrypted)
+    ShakeResponse = 17,
+    /// Header (Unecrypted) + JSON Containing Session Control (Symmetrically Encrypted), sent by clients
+    SessionControl = 18,
+    /// Header (Unecrypted) + JSON Containing Session State (Symmetrically Encrypted), sent by the server
+    SessionState = 19,
+    /// Header (Unecrypted) + JSON Containing Relay Message (Unencrypted), exchanged with a relay and while hole punching
+    Relay = 20,
+    /// Header (Unecrypted) + JSON Containing the Resolutions of a Monitor (Symmetrically Encrypted),
+    /// sent by the server for every monitor after Shook SE
+    MonitorModes = 21,
     InternalEOL = 30,
     Unknown = 31,
 }
@@ -72,6 +105,12 @@ impl From<u8> for EPacketType {
             13 => EPacketType::XOR,
             14 => EPacketType::Retransmit,
             15 => EPacketType::RNAL,
+            16 => EPacketType::ShakeChallenge,
+            17 => EPacketType::ShakeResponse,
+            18 => EPacketType::SessionControl,
+            19 => EPacketType::SessionState,
+            20 => EPacketType::Relay,
+            21 => EPacketType::MonitorModes,
             30 => EPacketType::InternalEOL,
             _ => EPacketType::Unknown,
         }
@@ -139,6 +178,17 @@ pub fn write_header(
     write_packets_remaining(packets_remaining, buf);
 }
 
+/// Writes a Disconnect packet (Header only) with the reason the server disconnected the client.
+/// ## Returns
+/// The number of bytes written.
+#[inline]
+pub fn write_disconnect(reason: EDisconnectReason, buf: &mut [u8]) -> usize {
+    write_header(EPacketType::Disconnect, 0, HEADER as u32, 0, buf);
+    write_packet_type_variant(reason as u8, buf);
+
+    HEADER
+}
+
 #[inline]
 /// Writes the retransmit body.
 /// The first 4 bytes are reserved for the real packet size.
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{parse_packet_type, write_header, EPacketType, JSONPayloadUE, HEADER};
+
+/// Port the relay listens on by default
+pub const RELAY_DEFAULT_PORT: u16 = 8556;
+
+/// Interval servers refresh their registration at, which also keeps their NAT mapping open
+pub const RELAY_REGISTER_INTERVAL_MS: u64 = 10_000;
+
+/// Registrations not refreshed within this time are dropped, freeing the name
+pub const RELAY_REGISTRATION_TTL_MS: u64 = 3 * RELAY_REGISTER_INTERVAL_MS;
+
+/// Time both sides punch for, after which the player falls back to the relayed address
+pub const RELAY_PUNCH_TIMEOUT_MS: u64 = 2000;
+
+/// Interval punches are sent at while punching
+pub const RELAY_PUNCH_INTERVAL_MS: u64 = 100;
+
+/// Relayed sessions are closed after this time without datagrams
+pub const RELAY_SESSION_TIMEOUT_MS: u64 = 30_000;
+
+/// Longest name a server can register under
+pub const RELAY_MAX_NAME: usize = 64;
+
+/// Time a server has to answer a registration challenge
+pub const RELAY_CHALLENGE_TTL_MS: u64 = RELAY_REGISTER_INTERVAL_MS;
+
+/// Messages exchanged with a relay, and between a server and a player while hole punching.
+/// Everything else sent through a relay is forwarded as is, still encrypted end to end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RelayMessage {
+    /// Sent by a server to claim a name, refreshed every register interval.
+    /// A name is only registered once the server signs a challenge with its host key,
+    /// refreshes from the registered address need no signature.
+    Register {
+        name: String,
+        host_key: String,
+        /// Signature of the challenge nonce and name, see `auth::sign_relay_registration`
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// Sent by the relay to a server registering without a signature
+    Challenge {
+        nonce: String,
+    },
+    Registered {
+        name: String,
+    },
+    /// Sent by a player to reach the server registered under the name
+    Connect {
+        name: String,
+    },
+    /// Sent by the relay to the server and the player, with the address the other side
+    /// was seen at to punch, and the port of the relay session to fall back to.
+    /// The player is also sent the host key the server registered with, while the server
+    /// checks access and locks out relayed players by the direct address they were seen at.
+    Peer {
+        token: u64,
+        direct: SocketAddr,
+        relay_port: u16,
+        #[serde(default)]
+        host_key: Option<String>,
+    },
+    /// Sent to the direct address of the other side and to the relay session while punching,
+    /// the relay learns the address of each side in the session from it.
+    Punch {
+        token: u64,
+        server: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl JSONPayloadUE for RelayMessage {}
+
+impl RelayMessage {
+    /// Writes the message as a Relay packet, returning its length
+    pub fn write_packet(&self, buf: &mut [u8]) -> usize {
+        let len = HEADER + Self::write_payload(&mut buf[HEADER..], self);
+        write_header(EPacketType::Relay, 0, len as u32, 0, buf);
+
+        len
+    }
+
+    /// Message of a Relay packet, None for any other packet
+    pub fn parse_packet(buf: &[u8]) -> Option<Self> {
+        if buf.len() <= HEADER || parse_packet_type(buf) != EPacketType::Relay {
+            return None;
+        }
+
+        let payload = std::str::from_utf8(&buf[HEADER..]).ok()?;
+        serde_json::from_str(payload).ok()
+    }
+}
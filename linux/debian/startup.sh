@@ -2,4 +2,5 @@
 
 export RUST_LOG=mrial_server=debug
 
-/usr/bin/mrial_server
\ No newline at end of file
+# exec so systemd signals reach the server directly
+exec /usr/bin/mrial_server
\ No newline at end of file
@@ -1,6 +1,8 @@
+mod relay;
+
 use std::{
     fmt,
-    net::{SocketAddr, UdpSocket},
+    net::{Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
     sync::{Arc, RwLock},
     thread,
     time::Duration,
@@ -10,12 +12,18 @@ use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
 use chacha20poly1305::{aead::KeyInit, ChaCha20Poly1305};
 use kanal::Sender;
 use log::{debug, info};
-use mrial_fs::Server;
-use mrial_proto::{video::EColorSpace, *};
+use mrial_fs::{KeyPair, Server};
+use mrial_proto::{
+    auth::{key_fingerprint, sign_challenge, verify_session_key},
+    video::EColorSpace,
+    *,
+};
 use rsa::{pkcs1::DecodeRsaPublicKey, RsaPublicKey};
 
 use crate::{ClientState, ConnectionAction};
 
+use relay::parse_relay_address;
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ConnectionState {
     Disconnected,
@@ -30,10 +38,15 @@ impl Default for ClientMetaData {
             height: 0,
             widths: vec![],
             heights: vec![],
+            monitors: vec![],
+            monitor: None,
             muted: false,
             opus: true,
             colorspace: EColorSpace::YUV444,
             server: Server::default(),
+            keepalive: KeepaliveSettings::default(),
+            requested_role: ESessionRole::Controller,
+            session: SessionStateSE::default(),
         }
     }
 }
@@ -44,18 +57,29 @@ pub struct ClientMetaData {
     pub height: usize,
     pub widths: Vec<u16>,
     pub heights: Vec<u16>,
+    /// Monitors of the server and the one streamed, resolutions follow the streamed monitor
+    pub monitors: Vec<MonitorPayload>,
+    pub monitor: Option<String>,
     pub muted: bool,
     pub opus: bool,
     pub colorspace: EColorSpace,
     pub server: Server,
+    pub keepalive: KeepaliveSettings,
+    /// Role requested when connecting, the server assigns spectator if another client is in control
+    pub requested_role: ESessionRole,
+    pub session: SessionStateSE,
 }
 
 pub struct Client {
     socket_address: String,
+    /// Name the server is registered under, when it is reached through the relay at the socket address
+    relay_name: Option<String>,
     socket: Option<UdpSocket>,
     state: ConnectionState,
     meta: Arc<RwLock<ClientMetaData>>,
     sym_key: Arc<RwLock<Option<ChaCha20Poly1305>>>,
+    key_pair: Option<KeyPair>,
+    totp_code: Option<String>,
     conn_sender: Sender<ConnectionAction>,
 }
 
@@ -71,6 +95,16 @@ pub enum HandshakeError {
     SocketNotInitialized,
     FailedToReceiveShookUE(String),
     InvalidShakeUEPayload(String),
+    FailedToWriteShakeAE(String),
+    KeyPairNotFound,
+    SymmetricKeyNotFound,
+    FailedToSignChallenge(String),
+    TotpRequired,
+    Relay(String),
+    HostKeyNotProven,
+    InvalidHostKey(String),
+    /// Fingerprint trusted for the server and the one it presented
+    HostKeyMismatch(String, String),
 }
 
 impl fmt::Display for HandshakeError {
@@ -79,6 +113,9 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InvalidShakeUEPayload(err) => {
                 write!(f, "Invalid Shake UE Payload: {err}")
             }
+            HandshakeError::FailedToWriteShakeAE(err) => {
+                write!(f, "Failed to Write Shake AE Payload: {err}")
+            }
             HandshakeError::FailedToReceiveShookUE(err) => {
                 write!(f, "Failed to Receive Shook UE Packet: {err}")
             }
@@ -106,6 +143,33 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InvalidPublicKey(err) => {
                 write!(f, "Invalid Public Key: {err}")
             }
+            HandshakeError::KeyPairNotFound => {
+                write!(f, "Key Pair Not Found, Unable to Authenticate with Key")
+            }
+            HandshakeError::SymmetricKeyNotFound => {
+                write!(f, "Symmetric Key Not Found, Key Exchange Not Completed")
+            }
+            HandshakeError::FailedToSignChallenge(err) => {
+                write!(f, "Failed to Sign Auth Challenge: {err}")
+            }
+            HandshakeError::TotpRequired => {
+                write!(f, "TOTP Code Required to Authenticate")
+            }
+            HandshakeError::Relay(err) => {
+                write!(f, "Relay Failed: {err}")
+            }
+            HandshakeError::HostKeyNotProven => {
+                write!(f, "Server Did Not Prove Its Host Key")
+            }
+            HandshakeError::InvalidHostKey(err) => {
+                write!(f, "Invalid Host Key: {err}")
+            }
+            HandshakeError::HostKeyMismatch(trusted, received) => {
+                write!(
+                    f,
+                    "Host Key Mismatch: {received} (Server) != {trusted} (Trusted)",
+                )
+            }
             HandshakeError::Other(err) => {
                 write!(f, "{err}")
             }
@@ -117,14 +181,27 @@ impl Client {
     pub fn new(meta: ClientMetaData, conn_sender: Sender<ConnectionAction>) -> Client {
         Client {
             socket_address: String::new(),
+            relay_name: None,
             socket: None,
             state: ConnectionState::Disconnected,
             meta: Arc::new(RwLock::new(meta)),
             sym_key: Arc::new(RwLock::new(None)),
+            key_pair: None,
+            totp_code: None,
             conn_sender,
         }
     }
 
+    /// Code answering the TOTP challenge of the next handshake, it is used at most once
+    pub fn set_totp_code(&mut self, code: String) {
+        self.totp_code = Some(code);
+    }
+
+    /// Key pair used to answer auth challenges for servers saved without a password
+    pub fn set_key_pair(&mut self, key_pair: KeyPair) {
+        self.key_pair = Some(key_pair);
+    }
+
     pub fn get_meta_clone(&self) -> Arc<RwLock<ClientMetaData>> {
         self.meta.clone()
     }
@@ -141,12 +218,96 @@ impl Client {
         }
     }
 
+    /// Selects the monitor of the server to stream by its name, returning the
+    /// dimensions of the monitor, `None` if the server has no such monitor.
+    pub fn select_monitor(&self, name: &str) -> Option<(u16, u16)> {
+        let mut meta_handle = self.meta.write().ok()?;
+        let monitor = meta_handle
+            .monitors
+            .iter()
+            .find(|monitor| monitor.name == name)?
+            .clone();
+
+        meta_handle.widths = monitor.widths;
+        meta_handle.heights = monitor.heights;
+        meta_handle.monitor = Some(monitor.name);
+        drop(meta_handle);
+
+        let _ = self.conn_sender.send(ConnectionAction::UpdateState);
+        Some((monitor.width, monitor.height))
+    }
+
     pub fn get_meta(&self) -> std::sync::RwLockReadGuard<ClientMetaData> {
         self.meta.read().unwrap()
     }
 
-    pub fn set_socket_address(&mut self, ip_addr: &String, port: u16) {
-        self.socket_address = format!("{}:{}", ip_addr, port);
+    /// Sets the address of the server, an IPv4 or IPv6 address (with or without brackets) or a hostname.
+    /// A server registered with a relay is written as "name@relay", with the port of the relay.
+    pub fn set_socket_address(&mut self, ip_addr: &str, port: u16) {
+        let ip_addr = match parse_relay_address(ip_addr) {
+            Some((name, relay)) => {
+                self.relay_name = Some(name.to_string());
+                relay
+            }
+            None => {
+                self.relay_name = None;
+                ip_addr
+            }
+        };
+
+        let host = ip_addr.trim_start_matches('[').trim_end_matches(']');
+
+        self.socket_address = match host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]:{}", ip, port),
+            Err(_) => format!("{}:{}", host, port),
+        };
+    }
+
+    /// Binds a socket to the first address the server resolves to, hostnames may resolve to IPv6 (AAAA)
+    fn bind_socket(&self) -> Result<UdpSocket, HandshakeError> {
+        let addresses = match self.socket_address.to_socket_addrs() {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                return Err(HandshakeError::Other(format!(
+                    "Failed to Resolve Server Address {}: {}",
+                    &self.socket_address, e
+                )));
+            }
+        };
+
+        for address in addresses {
+            let client_address = match address {
+                SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+            };
+
+            let socket = match UdpSocket::bind(client_address) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!("Failed to Bind Socket for {}: {}", address, e);
+                    continue;
+                }
+            };
+
+            let address = match &self.relay_name {
+                Some(name) => {
+                    let (address, host_key) = relay::rendezvous(&socket, address, name)?;
+                    self.trust_host_key(&host_key)?;
+                    address
+                }
+                None => address,
+            };
+
+            match socket.connect(address) {
+                Ok(_) => return Ok(socket),
+                Err(e) => debug!("Socket Failed to Connect to {}: {}", address, e),
+            }
+        }
+
+        Err(HandshakeError::Other(format!(
+            "Socket Failed to Connect to Server: {}",
+            &self.socket_address
+        )))
     }
 
     pub fn set_state(&mut self, state: ConnectionState) {
@@ -155,24 +316,11 @@ impl Client {
 
     pub fn connect(&mut self) -> Result<(), HandshakeError> {
         if !self.socket_connected() && self.state == ConnectionState::Connecting {
-            let client_address = SocketAddr::from(([0, 0, 0, 0], 0));
-            let socket = match UdpSocket::bind(client_address) {
-                Ok(socket) => socket,
+            match self.bind_socket() {
+                Ok(socket) => self.socket = Some(socket),
                 Err(e) => {
-                    return Err(HandshakeError::Other(format!(
-                        "Failed to Bind Socket: {}",
-                        e
-                    )));
-                }
-            };
-            match socket.connect(&self.socket_address) {
-                Ok(_) => self.socket = Some(socket),
-                Err(_e) => {
                     thread::sleep(Duration::from_millis(1000));
-                    return Err(HandshakeError::Other(format!(
-                        "Socket Failed to Connect to Server: {}",
-                        &self.socket_address
-                    )));
+                    return Err(e);
                 }
             }
         }
@@ -264,18 +412,24 @@ impl Client {
             let socket = socket.try_clone().unwrap();
             return Client {
                 socket_address: self.socket_address.clone(),
+                relay_name: self.relay_name.clone(),
                 socket: Some(socket),
                 state: self.state,
                 meta: self.meta.clone(),
                 sym_key: self.sym_key.clone(),
+                key_pair: self.key_pair.clone(),
+                totp_code: None,
                 conn_sender: self.conn_sender.clone(),
             };
         }
 
         Client {
             socket_address: self.socket_address.clone(),
+            relay_name: self.relay_name.clone(),
             socket: None,
             sym_key: self.sym_key.clone(),
+            key_pair: self.key_pair.clone(),
+            totp_code: None,
             state: ConnectionState::Disconnected,
             meta: self.meta.clone(),
             conn_sender: self.conn_sender.clone(),
@@ -313,16 +467,175 @@ impl Client {
         }
     }
 
+    fn update_session(&self, session: SessionStateSE) {
+        if let Ok(mut meta_handle) = self.meta.write() {
+            meta_handle.session = session;
+
+            let _ = &self.conn_sender.send(ConnectionAction::UpdateSession);
+        }
+    }
+
+    /// Stores the session state the server sends whenever control changes
+    pub fn session_state(&self, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sym_key = match self.sym_key.read().unwrap().clone() {
+            Some(sym_key) => sym_key,
+            None => return Err("No Symmetric Key".into()),
+        };
+
+        self.update_session(SessionStateSE::from_payload(payload, &mut sym_key)?);
+        Ok(())
+    }
+
+    /// Stores the resolutions of a monitor, which the server sends for every monitor after Shook SE
+    pub fn monitor_modes(&self, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sym_key = match self.sym_key.read().unwrap().clone() {
+            Some(sym_key) => sym_key,
+            None => return Err("No Symmetric Key".into()),
+        };
+        let modes = MonitorModesSE::from_payload(payload, &mut sym_key)?;
+
+        if let Ok(mut meta_handle) = self.meta.write() {
+            let selected = match &meta_handle.monitor {
+                Some(name) => *name == modes.name,
+                None => modes.streamed,
+            };
+            if selected {
+                meta_handle.widths = modes.widths.clone();
+                meta_handle.heights = modes.heights.clone();
+            }
+
+            let monitor = meta_handle
+                .monitors
+                .iter_mut()
+                .find(|monitor| monitor.name == modes.name);
+            if let Some(monitor) = monitor {
+                monitor.widths = modes.widths;
+                monitor.heights = modes.heights;
+            }
+
+            let _ = &self.conn_sender.send(ConnectionAction::UpdateState);
+        }
+
+        Ok(())
+    }
+
     fn update_client_conn_state(&self, payload: ServerStatePayload) {
         if let Ok(mut meta_handle) = self.meta.write() {
             meta_handle.widths = payload.widths;
             meta_handle.heights = payload.heights;
+            meta_handle.monitors = payload.monitors;
+            meta_handle.monitor = payload.monitor;
 
             let _ = &self.conn_sender.send(ConnectionAction::UpdateState);
         }
     }
 
+    fn answer_challenge(
+        &self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        amt: usize,
+        totp_code: Option<&str>,
+    ) -> Result<(), HandshakeError> {
+        let mut sym_key = match self.sym_key.read().unwrap().clone() {
+            Some(sym_key) => sym_key,
+            None => return Err(HandshakeError::SymmetricKeyNotFound),
+        };
+        let challenge = match ServerChallengeSE::from_payload(&buf[HEADER..amt], &mut sym_key) {
+            Ok(challenge) => challenge,
+            Err(e) => return Err(HandshakeError::Other(format!("Invalid Auth Challenge: {e}"))),
+        };
+        debug!("Received {:?} Auth Challenge", challenge.method);
+
+        let response = match challenge.method {
+            EAuthMethod::PublicKey => {
+                let key_pair = match &self.key_pair {
+                    Some(key_pair) => key_pair,
+                    None => return Err(HandshakeError::KeyPairNotFound),
+                };
+
+                let signature = sign_challenge(&key_pair.secret_key, &challenge.challenge)
+                    .map_err(|e| HandshakeError::FailedToSignChallenge(e.to_string()))?;
+
+                ClientResponseSE {
+                    method: challenge.method,
+                    pub_key: key_pair.public_key.clone(),
+                    response: signature,
+                }
+            }
+            EAuthMethod::Totp => match totp_code {
+                Some(code) => ClientResponseSE {
+                    method: challenge.method,
+                    pub_key: String::new(),
+                    response: code.to_string(),
+                },
+                None => return Err(HandshakeError::TotpRequired),
+            },
+        };
+
+        let payload_len = match ClientResponseSE::write_payload(
+            &mut buf[HEADER..],
+            Some(sym_key),
+            &response,
+        ) {
+            Ok(len) => len,
+            Err(e) => return Err(HandshakeError::Other(e.to_string())),
+        };
+
+        write_header(
+            EPacketType::ShakeResponse,
+            0,
+            (HEADER + payload_len) as u32,
+            0,
+            buf,
+        );
+        let _ = socket.send(&buf[0..HEADER + payload_len]);
+        debug!("Sent Auth Challenge Response");
+
+        Ok(())
+    }
+
+    /// Checks that the session key is signed by the host key of the server and that the
+    /// host key is the one trusted for it, trusting it on first use if none is yet.
+    fn verify_host_key(&self, payload: &ServerShookUE) -> Result<(), HandshakeError> {
+        let (host_key, signature) = match (&payload.host_key, &payload.signature) {
+            (Some(host_key), Some(signature)) => (host_key, signature),
+            _ => return Err(HandshakeError::HostKeyNotProven),
+        };
+
+        if let Err(e) = verify_session_key(host_key, &payload.pub_key, signature) {
+            return Err(HandshakeError::InvalidHostKey(e.to_string()));
+        }
+
+        self.trust_host_key(host_key)
+    }
+
+    /// Checks the host key is the one trusted for the server, trusting it if none is yet.
+    /// A server reached through a relay is checked against the key it registered with
+    /// first, which the handshake then requires it to prove.
+    fn trust_host_key(&self, host_key: &str) -> Result<(), HandshakeError> {
+        let fingerprint = match key_fingerprint(host_key) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => return Err(HandshakeError::InvalidHostKey(e.to_string())),
+        };
+
+        let mut meta = self.meta.write().unwrap();
+        match &meta.server.host_key {
+            Some(trusted) if *trusted != fingerprint => Err(HandshakeError::HostKeyMismatch(
+                trusted.clone(),
+                fingerprint,
+            )),
+            Some(_) => Ok(()),
+            None => {
+                info!("Trusting Host Key {} on First Use", fingerprint);
+                meta.server.host_key = Some(fingerprint);
+                Ok(())
+            }
+        }
+    }
+
     pub fn send_handshake(&mut self) -> Result<(), HandshakeError> {
+        let totp_code = self.totp_code.take();
         let socket = match &self.socket {
             Some(socket) => socket,
             None => return Err(HandshakeError::SocketNotInitialized),
@@ -340,9 +653,13 @@ impl Client {
         };
         debug!("Sent Initial Shake UE Packet");
 
-        let (amt, _src) = match socket.recv_from(&mut buf) {
-            Ok(v) => v,
-            Err(e) => return Err(HandshakeError::FailedToReceiveShakeUE(e.to_string())),
+        // Punches still arriving from a server reached through a relay are skipped
+        let (amt, _src) = loop {
+            match socket.recv_from(&mut buf) {
+                Ok(_) if parse_packet_type(&buf) == EPacketType::Relay => continue,
+                Ok(v) => break v,
+                Err(e) => return Err(HandshakeError::FailedToReceiveShakeUE(e.to_string())),
+            }
         };
 
         match parse_packet_type(&buf) {
@@ -362,6 +679,8 @@ impl Client {
             Err(e) => return Err(HandshakeError::InvalidShakeUEPayload(e.to_string())),
         };
 
+        self.verify_host_key(&shookue_payload)?;
+
         let pub_key = match RsaPublicKey::from_pkcs1_pem(&shookue_payload.pub_key) {
             Ok(key) => key,
             Err(e) => {
@@ -378,6 +697,8 @@ impl Client {
                 muted: meta.muted,
                 opus: meta.opus,
                 csp: meta.colorspace,
+                // The monitor being streamed is kept, the server lists its monitors once shook
+                monitor: None,
             },
             Err(e) => {
                 return Err(HandshakeError::Other(format!(
@@ -393,7 +714,7 @@ impl Client {
         let key_vec = key.to_vec();
         let key_base64 = STANDARD_NO_PAD.encode(&key_vec);
 
-        let payload_len = ClientShakeAE::write_payload(
+        let payload_len = match ClientShakeAE::write_payload(
             &mut buf[HEADER..],
             &mut rng,
             pub_key,
@@ -402,8 +723,12 @@ impl Client {
                 pass: self.meta.read().unwrap().server.pass.clone(),
                 sym_key: key_base64,
                 state: client_state,
+                role: self.meta.read().unwrap().requested_role,
             },
-        );
+        ) {
+            Ok(len) => len,
+            Err(e) => return Err(HandshakeError::FailedToWriteShakeAE(e.to_string())),
+        };
 
         write_header(
             EPacketType::ShakeAE,
@@ -424,18 +749,25 @@ impl Client {
                 }
             };
 
-            if parse_packet_type(&buf) != EPacketType::ShookSE {
-                continue;
+            match parse_packet_type(&buf) {
+                EPacketType::ShookSE => {}
+                EPacketType::ShakeChallenge => {
+                    self.answer_challenge(socket, &mut buf, amt, totp_code.as_deref())?;
+                    continue;
+                }
+                _ => continue,
             }
 
             if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(5000))) {
                 return Err(HandshakeError::FailedToSetTimeout(e.to_string()));
             };
 
-            if let Ok(payload) = ServerShookSE::from_payload(
-                &mut buf[HEADER..amt],
-                self.sym_key.read().unwrap().clone().as_mut().unwrap(),
-            ) {
+            let mut sym_key = match self.sym_key.read().unwrap().clone() {
+                Some(sym_key) => sym_key,
+                None => return Err(HandshakeError::SymmetricKeyNotFound),
+            };
+
+            if let Ok(payload) = ServerShookSE::from_payload(&mut buf[HEADER..amt], &mut sym_key) {
                 debug!("Received Valid Shook SE Packet");
 
                 if payload.server_state.version != env!("CARGO_PKG_VERSION").to_string() {
@@ -445,6 +777,15 @@ impl Client {
                     ));
                 }
 
+                // Wake up every keepalive interval, the keepalive decides when the server is gone
+                let keepalive = payload.keepalive.clamped();
+                let interval = Duration::from_millis(keepalive.interval_ms);
+                if let Err(e) = socket.set_read_timeout(Some(interval)) {
+                    return Err(HandshakeError::FailedToSetTimeout(e.to_string()));
+                };
+
+                self.meta.write().unwrap().keepalive = keepalive;
+                self.update_session(payload.session);
                 self.update_client_conn_state(payload.server_state);
                 self.state = ConnectionState::Connected;
                 return Ok(());
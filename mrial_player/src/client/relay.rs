@@ -0,0 +1,159 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use mrial_proto::{relay::*, MTU};
+
+use super::HandshakeError;
+
+/// Times the player asks the relay for the server before giving up
+const RELAY_CONNECT_ATTEMPTS: usize = 3;
+
+/// Splits a server address written as "name@relay" into the name and the relay
+pub fn parse_relay_address(address: &str) -> Option<(&str, &str)> {
+    address
+        .split_once('@')
+        .filter(|(name, relay)| !name.is_empty() && !relay.is_empty())
+}
+
+fn send(socket: &UdpSocket, message: &RelayMessage, target: SocketAddr) {
+    let mut buf = [0u8; MTU];
+    let len = message.write_packet(&mut buf);
+
+    if let Err(e) = socket.send_to(&buf[..len], target) {
+        debug!("Failed to Send Relay Message to {}: {}", target, e);
+    }
+}
+
+/// Receives the next relay message from the address, None once the read timeout passes
+fn recv_from(
+    socket: &UdpSocket,
+    address: SocketAddr,
+) -> Result<Option<RelayMessage>, HandshakeError> {
+    let mut buf = [0u8; MTU];
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) if src == address => {
+                if let Some(message) = RelayMessage::parse_packet(&buf[..len]) {
+                    return Ok(Some(message));
+                }
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(None)
+            }
+            Err(e) => return Err(HandshakeError::Relay(e.to_string())),
+        }
+    }
+}
+
+fn set_read_timeout(socket: &UdpSocket, timeout: Duration) -> Result<(), HandshakeError> {
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| HandshakeError::FailedToSetTimeout(e.to_string()))
+}
+
+/// Server introduced by the relay
+struct RelayPeer {
+    direct: SocketAddr,
+    /// Relay session to fall back to
+    relayed: SocketAddr,
+    token: u64,
+    host_key: String,
+}
+
+/// Asks the relay for the server registered under the name
+fn request_peer(
+    socket: &UdpSocket,
+    relay: SocketAddr,
+    name: &str,
+) -> Result<RelayPeer, HandshakeError> {
+    set_read_timeout(socket, Duration::from_millis(1000))?;
+
+    let connect = RelayMessage::Connect {
+        name: name.to_string(),
+    };
+
+    for _ in 0..RELAY_CONNECT_ATTEMPTS {
+        send(socket, &connect, relay);
+
+        while let Some(message) = recv_from(socket, relay)? {
+            match message {
+                RelayMessage::Peer {
+                    token,
+                    direct,
+                    relay_port,
+                    host_key: Some(host_key),
+                } => {
+                    return Ok(RelayPeer {
+                        direct,
+                        relayed: SocketAddr::new(relay.ip(), relay_port),
+                        token,
+                        host_key,
+                    })
+                }
+                RelayMessage::Peer { host_key: None, .. } => {
+                    return Err(HandshakeError::Relay(
+                        "Relay Did Not Send the Host Key of the Server".to_string(),
+                    ))
+                }
+                RelayMessage::Error { message } => return Err(HandshakeError::Relay(message)),
+                _ => {}
+            }
+        }
+    }
+
+    Err(HandshakeError::Relay(format!(
+        "No Response from Relay {}",
+        relay
+    )))
+}
+
+/// Finds the address to reach the server registered with the relay under the name at,
+/// returned with the host key the server registered with. Both sides punch through
+/// to each other first, and the relay session is used if no punch from the server
+/// arrives in time.
+pub fn rendezvous(
+    socket: &UdpSocket,
+    relay: SocketAddr,
+    name: &str,
+) -> Result<(SocketAddr, String), HandshakeError> {
+    let RelayPeer {
+        direct,
+        relayed,
+        token,
+        host_key,
+    } = request_peer(socket, relay, name)?;
+    debug!("Relay Introduced Server {}, Punching Through", direct);
+
+    let punch = RelayMessage::Punch {
+        token,
+        server: false,
+    };
+    let interval = Duration::from_millis(RELAY_PUNCH_INTERVAL_MS);
+    let deadline = Instant::now() + Duration::from_millis(RELAY_PUNCH_TIMEOUT_MS);
+
+    set_read_timeout(socket, interval)?;
+
+    while Instant::now() < deadline {
+        send(socket, &punch, direct);
+        send(socket, &punch, relayed);
+
+        while let Some(message) = recv_from(socket, direct)? {
+            if matches!(message, RelayMessage::Punch { token: t, server: true } if t == token) {
+                info!("Punched Through to Server {}", direct);
+                return Ok((direct, host_key));
+            }
+        }
+    }
+
+    info!(
+        "Failed to Punch Through to Server, Connecting Through Relay {}",
+        relayed
+    );
+    Ok((relayed, host_key))
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use mrial_proto::discovery::*;
+
+/// Time servers are given to answer a probe
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Server that answered a discovery probe
+#[derive(Debug, Clone)]
+pub struct NearbyServer {
+    pub address: IpAddr,
+    pub beacon: DiscoveryBeacon,
+}
+
+/// Addresses probes are broadcast to on the LAN, and sent to on the loopback
+/// address for a server on the same machine.
+pub fn discovery_targets(port: u16) -> [SocketAddr; 2] {
+    [
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), port),
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+    ]
+}
+
+/// Sends a probe to each target, collecting the beacons received within the timeout.
+pub fn discover(targets: &[SocketAddr], timeout: Duration) -> io::Result<Vec<NearbyServer>> {
+    let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+    socket.set_broadcast(true)?;
+
+    let mut probe = [0u8; DISCOVERY_PROBE_SIZE];
+    write_discovery_probe(&mut probe);
+
+    for target in targets {
+        if let Err(e) = socket.send_to(&probe, target) {
+            debug!("Failed to Send Discovery Probe to {}: {}", target, e);
+        }
+    }
+
+    let mut servers: Vec<NearbyServer> = Vec::new();
+    let mut buf = [0u8; DISCOVERY_PROBE_SIZE];
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                break
+            }
+            Err(e) => {
+                debug!("Failed to Receive Discovery Beacon: {}", e);
+                continue;
+            }
+        };
+
+        let beacon = match DiscoveryBeacon::parse(&buf[..len]) {
+            Some(beacon) => beacon,
+            None => continue,
+        };
+
+        // A server on this machine answers both the broadcast and the loopback probe
+        let address = src.ip();
+        if servers.iter().any(|server| {
+            server.beacon.fingerprint == beacon.fingerprint && server.beacon.port == beacon.port
+        }) {
+            continue;
+        }
+
+        debug!("Discovered Server \"{}\" at {}", beacon.name, address);
+        servers.push(NearbyServer { address, beacon });
+    }
+
+    servers.sort_by(|a, b| a.beacon.name.cmp(&b.beacon.name));
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn discovers_a_local_responder() {
+        let responder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = responder.local_addr().unwrap();
+
+        let beacon = DiscoveryBeacon::new("Test", "0.0.0", 8554, "SHA256:test".to_string());
+        let answer = beacon.to_bytes().unwrap();
+        let answering = thread::spawn(move || {
+            let mut buf = [0u8; DISCOVERY_PROBE_SIZE];
+            let (len, src) = responder.recv_from(&mut buf).unwrap();
+            assert!(is_discovery_probe(&buf[..len]));
+            responder.send_to(&answer, src).unwrap();
+        });
+
+        let servers = discover(&[target], Duration::from_millis(500)).unwrap();
+        answering.join().unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].address, target.ip());
+        assert_eq!(servers[0].beacon, beacon);
+    }
+}
@@ -53,9 +53,18 @@ impl Input {
                     }
                 };
                 
-                if parse_packet_type(&next_input) == EPacketType::InternalEOL {
-                    inner_client.disconnect();
-                    break;
+                match parse_packet_type(&next_input) {
+                    EPacketType::InternalEOL => {
+                        inner_client.disconnect();
+                        break;
+                    }
+                    // Spectators watch without sending input, the server drops it anyway
+                    EPacketType::InputState
+                        if inner_client.get_meta().session.role != ESessionRole::Controller =>
+                    {
+                        continue;
+                    }
+                    _ => {}
                 }
                 
                 if let Err(e) = inner_client.send(&next_input) {
@@ -344,6 +353,56 @@ impl Input {
                     buf[HEADER + 9] = 0; // Reset key
                 });
 
+            let session_sender = sender.clone();
+            let session_connected = connected.clone();
+            let session_sym_key = client.get_sym_key();
+            let session_meta = client.get_meta_clone();
+
+            app_weak
+                .unwrap()
+                .global::<CPFunctions>()
+                .on_session_control(move |action| {
+                    if !*session_connected.lock().unwrap() {
+                        return;
+                    }
+
+                    let control = match action.as_str() {
+                        "request" => SessionControlSE::Request,
+                        "revoke" => SessionControlSE::Revoke,
+                        "grant" => match session_meta.read().unwrap().session.requests.first() {
+                            Some(member) => SessionControlSE::Grant {
+                                address: member.address.clone(),
+                            },
+                            None => return,
+                        },
+                        _ => return,
+                    };
+
+                    let mut buf = [0; MTU];
+                    let sym_key = session_sym_key.read().unwrap().clone();
+                    let size = match SessionControlSE::write_payload(
+                        &mut buf[HEADER..],
+                        sym_key,
+                        &control,
+                    ) {
+                        Ok(size) => size,
+                        Err(e) => {
+                            debug!("Error writing session control payload: {:?}", e);
+                            return;
+                        }
+                    };
+
+                    write_header(
+                        EPacketType::SessionControl,
+                        0,
+                        (HEADER + size) as u32,
+                        0,
+                        &mut buf,
+                    );
+
+                    session_sender.send(buf[0..HEADER + size].to_vec()).unwrap();
+                });
+
             let client_state_sender: Sender<Vec<u8>> = sender.clone();
             let client_state_sender_connected = connected.clone();
             let sym_key = client.get_sym_key();
@@ -362,7 +421,16 @@ impl Input {
                         .split("x")
                         .map(|x| x.parse::<u16>().unwrap())
                         .collect();
-                    let (width, height) = (items[0], items[1]);
+                    let (mut width, mut height) = (items[0], items[1]);
+
+                    // A newly selected monitor is requested at its own resolution
+                    let mut monitor = None;
+                    if !state.monitor.is_empty() {
+                        if let Some((w, h)) = client_clone.select_monitor(&state.monitor) {
+                            (width, height) = (w, h);
+                            monitor = Some(state.monitor.to_string());
+                        }
+                    }
 
                     let mut buf = [0; MTU];
                     client_clone.set_meta_via_state(&state);
@@ -377,6 +445,7 @@ impl Input {
                             "full" => EColorSpace::YUV444,
                             _ => EColorSpace::YUV444,
                         },
+                        monitor,
                     };
 
                     if let Ok(sym_key) = sym_key.read() {
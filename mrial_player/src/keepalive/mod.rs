@@ -0,0 +1,79 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use kanal::Sender;
+use log::{debug, warn};
+use mrial_proto::*;
+
+use crate::{client::Client, ConnectionAction};
+
+/// Pings the server every keepalive interval, independent of the video and audio
+/// being received, and reports the server as unreachable once it stops sending packets.
+pub struct Keepalive {
+    last_received: Arc<Mutex<Instant>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self {
+            last_received: Arc::new(Mutex::new(Instant::now())),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Records that a packet was received from the server
+    #[inline]
+    pub fn received(&self) {
+        *self.last_received.lock().unwrap() = Instant::now();
+    }
+
+    pub fn start(&mut self, client: &Client, conn_sender: Sender<ConnectionAction>) {
+        self.stop();
+        self.received();
+
+        // Each run gets its own flag, so a stopped thread never outlives its flag
+        self.running = Arc::new(AtomicBool::new(true));
+
+        let keepalive = client.get_meta().keepalive.clamped();
+        let interval = Duration::from_millis(keepalive.interval_ms);
+        let timeout = Duration::from_millis(keepalive.timeout_ms);
+
+        let inner_client = client.clone();
+        let running = self.running.clone();
+        let last_received = self.last_received.clone();
+
+        thread::spawn(move || {
+            let mut ping_buf = [0u8; HEADER];
+            write_header(EPacketType::Ping, 0, HEADER as u32, 0, &mut ping_buf);
+
+            while running.load(Ordering::Relaxed) {
+                if let Err(e) = inner_client.send(&ping_buf) {
+                    debug!("Failed to Send Ping: {}", e);
+                }
+
+                thread::sleep(interval);
+
+                if !running.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                if last_received.lock().unwrap().elapsed() >= timeout {
+                    warn!("Host Unreachable, No Packets for {}ms", timeout.as_millis());
+                    let _ = conn_sender.send(ConnectionAction::HostUnreachable);
+                    break;
+                }
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
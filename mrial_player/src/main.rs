@@ -1,18 +1,26 @@
 mod audio;
 mod client;
+mod discovery;
 mod input;
+mod keepalive;
 mod video;
 
 use audio::{AudioClientThread, AudioPacket};
 use cli_clipboard::{ClipboardContext, ClipboardProvider};
 use client::{Client, ClientMetaData, ConnectionState, HandshakeError};
+use discovery::{NearbyServer, DISCOVERY_TIMEOUT};
 use input::Input;
+use keepalive::Keepalive;
 use mrial_fs::Server;
-use mrial_fs::{storage::StorageMultiType, Servers, User, Users};
+use mrial_fs::{
+    storage::StorageMultiType, KeyPair, KeyPairs, Servers, User, Users, DEFAULT_KEY_PAIR,
+};
+use mrial_proto::discovery::DISCOVERY_PORT;
 use mrial_proto::video::EColorSpace;
 use mrial_proto::*;
 use video::VideoThread;
 
+use std::io::ErrorKind;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{rc::Rc, thread};
@@ -31,8 +39,11 @@ pub enum ConnectionAction {
     Reconnect,
     Handshake,
     UpdateState,
+    UpdateSession,
     CloseApplication,
     Volume,
+    Totp,
+    HostUnreachable,
 }
 
 fn populate_users(users: Vec<User>, app_weak: &slint::Weak<MainWindow>) {
@@ -68,6 +79,67 @@ fn populate_servers(servers: Vec<Server>, app_weak: &slint::Weak<MainWindow>) {
         .set_servers(slint_servers.into());
 }
 
+fn populate_nearby_servers(servers: Vec<NearbyServer>, app_weak: &slint::Weak<MainWindow>) {
+    let slint_servers = Rc::new(VecModel::default());
+    for server in servers {
+        slint_servers.push(INearbyServer {
+            name: SharedString::from(server.beacon.name),
+            address: SharedString::from(server.address.to_string()),
+            port: SharedString::from(server.beacon.port.to_string()),
+            version: SharedString::from(server.beacon.version),
+            fingerprint: SharedString::from(server.beacon.fingerprint),
+        });
+    }
+    let app = app_weak.unwrap();
+    let adapter = app.global::<CreateServerAdapter>();
+    adapter.set_nearby(slint_servers.into());
+    adapter.set_discovering(false);
+}
+
+/// Looks for servers on the LAN in the background, listing them as nearby servers.
+fn discover_servers(app_weak: &slint::Weak<MainWindow>) {
+    app_weak
+        .unwrap()
+        .global::<CreateServerAdapter>()
+        .set_discovering(true);
+
+    let app_weak = app_weak.clone();
+    thread::spawn(move || {
+        let targets = discovery::discovery_targets(DISCOVERY_PORT);
+        let servers = discovery::discover(&targets, DISCOVERY_TIMEOUT).unwrap_or_else(|e| {
+            error!("Failed to Discover Servers: {}", e);
+            Vec::new()
+        });
+
+        let app_weak_clone = app_weak.clone();
+        let _ = app_weak.upgrade_in_event_loop(move |_| {
+            populate_nearby_servers(servers, &app_weak_clone);
+        });
+    });
+}
+
+/// Loads the local key pair, generating and saving one on first launch.
+/// A sealed secret key is opened, which requires the vault to be unlocked.
+fn load_key_pair(key_pairs: &mut KeyPairs) -> Result<KeyPair, Box<dyn std::error::Error>> {
+    if let Some(key_pair) = key_pairs.find(DEFAULT_KEY_PAIR.to_string()) {
+        return Ok(key_pairs.unseal(key_pair)?);
+    }
+
+    let (secret_key, public_key) = auth::generate_key_pair();
+    let key_pair = KeyPair {
+        name: DEFAULT_KEY_PAIR.to_string(),
+        public_key,
+        secret_key,
+        sealed: None,
+    };
+
+    key_pairs.add(key_pair.clone())?;
+    key_pairs.save()?;
+    debug!("Generated New Key Pair");
+
+    Ok(key_pair)
+}
+
 fn attempt_connection(client: &mut Client, app_weak: &slint::Weak<MainWindow>) {
     if let Err(e) = client.connect() {
         error!("{}", &e);
@@ -87,6 +159,31 @@ fn attempt_connection(client: &mut Client, app_weak: &slint::Weak<MainWindow>) {
                         );
                 });
             }
+            HandshakeError::HostKeyNotProven
+            | HandshakeError::InvalidHostKey(_)
+            | HandshakeError::HostKeyMismatch(_, _) => {
+                // Retrying can not change the host key the server presents
+                client.disconnect();
+                let message = e.to_string();
+                let app_weak_clone: slint::Weak<MainWindow> = app_weak.clone();
+                let _ = app_weak.upgrade_in_event_loop(move |_| {
+                    app_weak_clone
+                        .unwrap()
+                        .global::<VideoState>()
+                        .set_error_message(SharedString::from(message));
+                });
+            }
+            HandshakeError::TotpRequired => {
+                // Wait for the user to enter a code before handshaking again
+                client.set_state(ConnectionState::Disconnected);
+                let app_weak_clone: slint::Weak<MainWindow> = app_weak.clone();
+                let _ = app_weak.upgrade_in_event_loop(move |_| {
+                    app_weak_clone
+                        .unwrap()
+                        .global::<VideoState>()
+                        .set_totp_required(true);
+                });
+            }
             _ => {}
         }
     }
@@ -164,6 +261,8 @@ fn main() {
                 match users_storage_copy.add(User {
                     username: username.to_string(),
                     pass: pass.to_string(),
+                    keys: Vec::new(),
+                    totp: None,
                 }) {
                     Ok(_) => {
                         if let Err(e) = users_storage_copy.save() {
@@ -225,9 +324,41 @@ fn main() {
         }
     }
 
+    let vault = servers_storage.vault();
+
+    // Sealed by the same vault as the server credentials
+    let mut key_pairs = KeyPairs::with_vault(vault.clone());
+    if let Err(e) = key_pairs.load() {
+        debug!("Failed to Load Key Pairs: {}", e);
+    }
+
+    match load_key_pair(&mut key_pairs) {
+        Ok(key_pair) => client.set_key_pair(key_pair),
+        Err(e) => error!("Failed to Load Key Pair: {}", e),
+    }
+
+    if let Some(key_pair) = key_pairs.find(DEFAULT_KEY_PAIR.to_string()) {
+        app_weak
+            .unwrap()
+            .global::<CreateServerAdapter>()
+            .set_public_key(SharedString::from(key_pair.public_key));
+    }
+    let mut key_pairs_vault_clone = key_pairs.clone();
+    app_weak
+        .unwrap()
+        .global::<VaultAdapter>()
+        .set_enabled(vault.is_enabled());
+    app_weak
+        .unwrap()
+        .global::<VaultAdapter>()
+        .set_locked(vault.is_enabled() && !vault.is_unlocked());
+
     let server_id = Arc::new(Mutex::new(String::new()));
     let server_id_clone = server_id.clone();
 
+    let totp_code = Arc::new(Mutex::new(String::new()));
+    let totp_code_clone = totp_code.clone();
+
     slint::invoke_from_event_loop(move || {
         let conn_sender_clone = conn_sender.clone();
         let app_weak_clone = app_weak.clone();
@@ -247,6 +378,20 @@ fn main() {
                 conn_sender_clone.send(ConnectionAction::Connect).unwrap();
             });
 
+        let conn_sender_clone = conn_sender.clone();
+        let app_weak_clone = app_weak.clone();
+        app_weak
+            .unwrap()
+            .global::<VideoFunctions>()
+            .on_submit_totp(move |code| {
+                app_weak_clone
+                    .unwrap()
+                    .global::<VideoState>()
+                    .set_totp_required(false);
+                *totp_code_clone.lock().unwrap() = code.to_string();
+                conn_sender_clone.send(ConnectionAction::Totp).unwrap();
+            });
+
         let conn_sender_clone = conn_sender.clone();
         app_weak
             .unwrap()
@@ -265,16 +410,82 @@ fn main() {
                 conn_sender.send(ConnectionAction::Volume).unwrap();
             });
 
+        let app_weak_clone = app_weak.clone();
+        let vault_clone = vault.clone();
+        app_weak
+            .unwrap()
+            .global::<VaultFunctions>()
+            .on_unlock(move |passphrase| {
+                let app = app_weak_clone.unwrap();
+                let adapter = app.global::<VaultAdapter>();
+                match vault_clone.unlock(&passphrase) {
+                    Ok(_) => {
+                        adapter.set_locked(false);
+                        adapter.set_error_message(SharedString::from(""));
+                        debug!("Vault Unlocked");
+                    }
+                    Err(e) => {
+                        adapter.set_error_message(SharedString::from(e.to_string()));
+                        debug!("Failed to Unlock Vault: {}", e);
+                    }
+                }
+            });
+
+        let app_weak_clone = app_weak.clone();
+        let mut servers_storage_vault_clone = servers_storage_clone.clone();
+        app_weak
+            .unwrap()
+            .global::<VaultFunctions>()
+            .on_create(move |passphrase| {
+                let app = app_weak_clone.unwrap();
+                let adapter = app.global::<VaultAdapter>();
+                let result = vault
+                    .create(&passphrase)
+                    .map_err(|e| e.into())
+                    .and_then(|_| {
+                        servers_storage_vault_clone.seal_all()?;
+                        servers_storage_vault_clone.save()?;
+                        key_pairs_vault_clone.seal_all()?;
+                        key_pairs_vault_clone.save()
+                    });
+
+                match result {
+                    Ok(_) => {
+                        adapter.set_enabled(true);
+                        adapter.set_locked(false);
+                        adapter.set_error_message(SharedString::from(""));
+                        debug!("Vault Created");
+                    }
+                    Err(e) => {
+                        // reload servers from disk because of error
+                        let _ = servers_storage_vault_clone.load();
+                        let _ = key_pairs_vault_clone.load();
+                        adapter.set_enabled(vault.is_enabled());
+                        adapter.set_error_message(SharedString::from(e.to_string()));
+                        debug!("Failed to Create Vault: {}", e);
+                    }
+                }
+            });
+
+        let app_weak_clone = app_weak.clone();
+        app_weak
+            .unwrap()
+            .global::<CreateServerFunctions>()
+            .on_discover(move || discover_servers(&app_weak_clone));
+        discover_servers(&app_weak);
+
         let app_weak_clone = app_weak.clone();
         let mut servers_storage_remove_clone = servers_storage_clone.clone();
         app_weak.unwrap().global::<CreateServerFunctions>().on_add(
-            move |name, ip_addr, port, username, pass| match servers_storage_clone.add(Server {
+            move |name, ip_addr, port, username, pass, fp| match servers_storage_clone.add(Server {
                 name: name.to_string(),
                 address: ip_addr.to_string(),
                 port: port.parse::<u16>().unwrap(),
                 os: "ubuntu".to_string(),
                 username: username.to_string(),
                 pass: pass.to_string(),
+                sealed: None,
+                host_key: (!fp.is_empty()).then(|| fp.to_string()),
             }) {
                 Ok(_) => {
                     if let Err(e) = servers_storage_clone.save() {
@@ -346,6 +557,8 @@ fn main() {
         let mut input = Input::new();
         input.capture(app_weak.clone(), client.clone());
 
+        let mut keepalive = Keepalive::new();
+
         loop {
             if !client.connected() || conn_channel.1.len() > 0 {
                 match conn_channel.1.try_recv_realtime().unwrap() {
@@ -362,6 +575,16 @@ fn main() {
                         let meta_lock = client.get_meta();
                         let widths = meta_lock.widths.clone();
                         let heights = meta_lock.heights.clone();
+                        let monitors: Vec<String> = meta_lock
+                            .monitors
+                            .iter()
+                            .map(|monitor| monitor.name.clone())
+                            .collect();
+                        let monitor_index = meta_lock
+                            .monitor
+                            .as_ref()
+                            .and_then(|name| monitors.iter().position(|m| m == name))
+                            .unwrap_or(0);
                         let username = meta_lock.server.username.clone();
                         let opus = meta_lock.opus;
                         let csp = meta_lock.colorspace;
@@ -379,6 +602,14 @@ fn main() {
                                 });
                             });
 
+                            let monitors_model = Rc::new(VecModel::default());
+                            monitors.iter().for_each(|name| {
+                                monitors_model.push(IMrialDropdownItem {
+                                    label: SharedString::from(name),
+                                    value: SharedString::from(name),
+                                });
+                            });
+
                             match csp {
                                 EColorSpace::YUV444 => {
                                     app_weak_clone
@@ -398,6 +629,14 @@ fn main() {
                                 .unwrap()
                                 .global::<ControlPanelAdapter>()
                                 .set_resolutions(resolutions_model.into());
+                            app_weak_clone
+                                .unwrap()
+                                .global::<ControlPanelAdapter>()
+                                .set_monitors(monitors_model.into());
+                            app_weak_clone
+                                .unwrap()
+                                .global::<ControlPanelAdapter>()
+                                .set_monitor_index(monitor_index as i32);
                             app_weak_clone
                                 .unwrap()
                                 .global::<ControlPanelAdapter>()
@@ -416,9 +655,55 @@ fn main() {
                                 .set_server_name(SharedString::from(server_name));
                         });
                     }
+                    Some(ConnectionAction::UpdateSession) => {
+                        let session = client.get_meta().session.clone();
+                        let controller = session.role == ESessionRole::Controller;
+                        let controller_name = match (&session.controller, controller) {
+                            (_, true) => "You".to_string(),
+                            (Some(member), false) => member.username.clone(),
+                            (None, false) => "Nobody".to_string(),
+                        };
+                        let control_request = session
+                            .requests
+                            .first()
+                            .map(|member| member.username.clone())
+                            .unwrap_or_default();
+
+                        let app_weak_clone = app_weak.clone();
+                        let _ = slint::invoke_from_event_loop(move || {
+                            let adapter = app_weak_clone.unwrap();
+                            let adapter = adapter.global::<ControlPanelAdapter>();
+
+                            adapter.set_controller(controller);
+                            adapter.set_controller_name(SharedString::from(controller_name));
+                            adapter.set_control_request(SharedString::from(control_request));
+                        });
+                    }
                     Some(ConnectionAction::Connect) => {
                         let server_id = server_id.lock().unwrap().clone();
                         if let Some(server) = servers_storage.find(server_id) {
+                            let server = match servers_storage.unseal(server) {
+                                Ok(server) => server,
+                                Err(e) => {
+                                    error!("Failed to Open Server Credentials: {}", e);
+                                    let app_weak_clone = app_weak.clone();
+                                    let _ = slint::invoke_from_event_loop(move || {
+                                        app_weak_clone
+                                            .unwrap()
+                                            .global::<VideoState>()
+                                            .set_error_message(SharedString::from(format!(
+                                                "Failed to Open Server Credentials: {}",
+                                                e
+                                            )));
+                                    });
+                                    continue;
+                                }
+                            };
+                            // The secret key can only be opened once the vault is unlocked
+                            if let Ok(key_pair) = load_key_pair(&mut key_pairs) {
+                                client.set_key_pair(key_pair);
+                            }
+
                             client.set_socket_address(&server.address, server.port);
                             let meta = client.get_meta_clone();
                             meta.write().unwrap().server = server.clone();
@@ -445,7 +730,17 @@ fn main() {
 
                         match client.connection_state() {
                             ConnectionState::Connected => {
+                                let server = client.get_meta().server.clone();
+                                if let Some(host_key) = &server.host_key {
+                                    match servers_storage.trust_host_key(&server.name, host_key) {
+                                        Ok(true) => info!("Trusted Host Key of {}", server.name),
+                                        Ok(false) => {}
+                                        Err(e) => error!("Failed to Save Host Key: {}", e),
+                                    }
+                                }
+
                                 input.send_loop(&client);
+                                keepalive.start(&client, conn_channel.0.clone());
                                 let app_weak_clone: slint::Weak<MainWindow> = app_weak.clone();
                                 let _ = app_weak.upgrade_in_event_loop(move |_| {
                                     app_weak_clone
@@ -467,10 +762,19 @@ fn main() {
                             _ => continue,
                         }
                     }
+                    Some(ConnectionAction::Totp) => {
+                        let code = totp_code.lock().unwrap().clone();
+                        client.set_totp_code(code);
+                        client.set_state(ConnectionState::Connecting);
+                        conn_channel.0.send(ConnectionAction::Handshake).unwrap();
+                        continue;
+                    }
                     Some(ConnectionAction::Reconnect) => {
                         attempt_connection(&mut client, &app_weak);
 
                         if client.connected() {
+                            keepalive.start(&client, conn_channel.0.clone());
+
                             let app_weak_clone= app_weak.clone();
                             let _ = app_weak.upgrade_in_event_loop(move |_| {
                                 app_weak_clone
@@ -481,6 +785,8 @@ fn main() {
                         }
                     }
                     Some(ConnectionAction::CloseApplication) => {
+                        keepalive.stop();
+
                         if client.connected() {
                             input.close_send_loop();
                         }
@@ -494,6 +800,8 @@ fn main() {
                         break;
                     }
                     Some(ConnectionAction::Disconnect) => {
+                        keepalive.stop();
+
                         if client.connected() {
                             input.close_send_loop();
                         }
@@ -516,11 +824,38 @@ fn main() {
                         }
                         continue;
                     }
+                    Some(ConnectionAction::HostUnreachable) => {
+                        keepalive.stop();
+
+                        if client.connected() {
+                            input.close_send_loop();
+                        }
+
+                        // Keep handshaking until the server answers again
+                        client.set_state(ConnectionState::Connecting);
+                        conn_channel.0.send(ConnectionAction::Handshake).unwrap();
+
+                        let app_weak_clone = app_weak.clone();
+                        let _ = slint::invoke_from_event_loop(move || {
+                            app_weak_clone
+                                .unwrap()
+                                .global::<VideoState>()
+                                .set_connected(false);
+                            app_weak_clone
+                                .unwrap()
+                                .global::<VideoState>()
+                                .set_error_message(SharedString::from(
+                                    "Host Unreachable, Reconnecting...",
+                                ));
+                        });
+                        continue;
+                    }
                 }
             }
 
             match client.recv_from(&mut buf) {
                 Ok((number_of_bytes, _)) => {
+                    keepalive.received();
                     let packet_type = parse_packet_type(&buf);
 
                     match packet_type {
@@ -533,10 +868,61 @@ fn main() {
                                 debug!("Failed to play audio: {}", e);
                             }
                         }
+                        EPacketType::SessionState => {
+                            if let Err(e) = client.session_state(&buf[HEADER..number_of_bytes]) {
+                                debug!("Failed to Read Session State: {}", e);
+                            }
+                        }
+                        EPacketType::MonitorModes => {
+                            if let Err(e) = client.monitor_modes(&buf[HEADER..number_of_bytes]) {
+                                debug!("Failed to Read Monitor Modes: {}", e);
+                            }
+                        }
+                        EPacketType::Disconnect => {
+                            let reason = EDisconnectReason::from(parse_packet_type_variant(&buf));
+                            info!("Disconnected by Server: {:?}", reason);
+
+                            keepalive.stop();
+                            if client.connected() {
+                                input.close_send_loop();
+                            }
+
+                            let error_message = match reason {
+                                EDisconnectReason::Kicked => {
+                                    client.disconnect();
+                                    "Disconnected by the Server"
+                                }
+                                // Wait for the server to come back, such as when it is restarted
+                                _ => {
+                                    client.set_state(ConnectionState::Connecting);
+                                    conn_channel.0.send(ConnectionAction::Handshake).unwrap();
+                                    "Server Shut Down, Reconnecting..."
+                                }
+                            };
+
+                            let app_weak_clone = app_weak.clone();
+                            let _ = slint::invoke_from_event_loop(move || {
+                                app_weak_clone
+                                    .unwrap()
+                                    .global::<VideoState>()
+                                    .set_connected(false);
+                                app_weak_clone
+                                    .unwrap()
+                                    .global::<VideoState>()
+                                    .set_error_message(SharedString::from(error_message));
+                            });
+                        }
                         _ => {}
                     }
                 }
+                // Nothing received this keepalive interval, the keepalive reports unreachable hosts
+                Err(e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
                 Err(_e) => {
+                    keepalive.stop();
                     client.set_state(ConnectionState::Disconnected);
                     debug!("Lost Connection, Reconnecting...");
 
@@ -22,28 +22,14 @@ use crate::client::Client;
 pub struct VideoThread {
     pub channel: (Sender<Vec<u8>>, Receiver<Vec<u8>>),
     packet_constructor: NALPacketConstructor,
-    clock: std::time::Instant,
-    ping_buf: [u8; HEADER],
     file: Option<File>,
 }
 
 impl VideoThread {
     pub fn new() -> VideoThread {
-        let mut ping_buf = [0u8; HEADER];
-
-        write_header(
-            crate::EPacketType::Ping,
-            0,
-            HEADER.try_into().unwrap(),
-            0,
-            &mut ping_buf,
-        );
-
         VideoThread {
             packet_constructor: NALPacketConstructor::new(),
-            clock: std::time::Instant::now(),
             channel: unbounded(),
-            ping_buf,
             file: None,
         }
     }
@@ -380,7 +366,7 @@ impl VideoThread {
         );
 
         match state {
-            EAssemblerState::Waiting => return,
+            EAssemblerState::Waiting => {}
             EAssemblerState::Queue(queue) => {
                 #[cfg(feature = "stat")]
                 debug!("Video Queue Size: {}", queue.len());
@@ -390,10 +376,5 @@ impl VideoThread {
             }
             _ => {}
         }
-
-        if self.clock.elapsed().as_secs() > CLIENT_PING_FREQUENCY {
-            self.clock = std::time::Instant::now();
-            client.send(&self.ping_buf).unwrap();
-        }
     }
 }
@@ -0,0 +1,80 @@
+mod relay;
+
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use mrial_proto::relay::RELAY_DEFAULT_PORT;
+use relay::RelayServer;
+
+fn print_help() {
+    println!(
+        "
+Usage: mrial_relay [options]
+
+Lets players reach Mrial servers behind NAT by name, without port forwarding.
+Servers register with \"mrial_server config relay [address] [name]\".
+
+Flags:
+
+    --bind [address]\tAddress to listen on, \"0.0.0.0:{}\" by default
+    --help\t\tShow this help message
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n",
+        RELAY_DEFAULT_PORT
+    );
+}
+
+/// Address to listen on, from the `--bind` flag
+fn parse_args(args: &[String]) -> Result<Option<SocketAddr>, String> {
+    let mut address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), RELAY_DEFAULT_PORT);
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        if flag == "--help" {
+            print_help();
+            return Ok(None);
+        }
+
+        let value = match args.next() {
+            Some(value) if flag == "--bind" => value,
+            _ => {
+                return Err(format!(
+                    "Invalid flag \"{}\", use `mrial_relay --help` for more information.",
+                    flag
+                ))
+            }
+        };
+
+        address = value
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid address \"{}\": {}", value, e))?;
+    }
+
+    Ok(Some(address))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().collect();
+
+    let address = match parse_args(&args[1..]) {
+        Ok(Some(address)) => address,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            println!("{}", e);
+            return Ok(());
+        }
+    };
+
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
+    println!("Starting Mrial Relay Version {}\n", VERSION);
+
+    pretty_env_logger::init_timed();
+
+    let mut relay = RelayServer::bind(address).await?;
+    relay.run().await;
+
+    Ok(())
+}
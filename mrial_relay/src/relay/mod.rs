@@ -0,0 +1,480 @@
+pub mod session;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use mrial_proto::{
+    auth::{generate_challenge, verify_relay_registration},
+    relay::*,
+    MTU,
+};
+use session::RelaySession;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+/// Servers that can be registered at once
+const MAX_REGISTRATIONS: usize = 1024;
+
+/// Relay sessions that can be open at once, each holds a socket
+const MAX_SESSIONS: usize = 256;
+
+/// Registration challenges that can be pending at once
+const MAX_CHALLENGES: usize = MAX_REGISTRATIONS;
+
+/// Connects an address may send within the connect window, the rest are dropped
+const MAX_CONNECTS_PER_IP: u32 = 10;
+
+const CONNECT_WINDOW: Duration = Duration::from_secs(10);
+
+struct Registration {
+    address: SocketAddr,
+    host_key: String,
+    refreshed: Instant,
+}
+
+impl Registration {
+    fn is_expired(&self) -> bool {
+        self.refreshed.elapsed() >= Duration::from_millis(RELAY_REGISTRATION_TTL_MS)
+    }
+}
+
+struct Challenge {
+    nonce: String,
+    issued: Instant,
+}
+
+impl Challenge {
+    fn is_expired(&self) -> bool {
+        self.issued.elapsed() >= Duration::from_millis(RELAY_CHALLENGE_TTL_MS)
+    }
+}
+
+struct ConnectWindow {
+    count: u32,
+    started: Instant,
+}
+
+/// Rendezvous point of servers and players. Servers register under a name, players
+/// connecting by that name are introduced to the server to punch through to each other,
+/// and get a relay session to fall back to if punching fails.
+pub struct RelayServer {
+    socket: UdpSocket,
+    registrations: HashMap<String, Registration>,
+    /// Nonces sent to servers registering, by the address they were sent to
+    challenges: HashMap<SocketAddr, Challenge>,
+    connects: HashMap<IpAddr, ConnectWindow>,
+    sessions: Vec<JoinHandle<()>>,
+}
+
+impl RelayServer {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let relay = Self {
+            socket: UdpSocket::bind(address).await?,
+            registrations: HashMap::new(),
+            challenges: HashMap::new(),
+            connects: HashMap::new(),
+            sessions: Vec::new(),
+        };
+        info!("Relay Listening on {}", relay.local_addr()?);
+
+        Ok(relay)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    async fn send(&self, message: &RelayMessage, target: SocketAddr) {
+        let mut buf = [0u8; MTU];
+        let len = message.write_packet(&mut buf);
+
+        if let Err(e) = self.socket.send_to(&buf[..len], target).await {
+            debug!("Failed to Send Relay Message to {}: {}", target, e);
+        }
+    }
+
+    async fn send_error(&self, message: String, target: SocketAddr) {
+        debug!("Relay Error for {}: {}", target, message);
+        self.send(&RelayMessage::Error { message }, target).await;
+    }
+
+    /// Sends the server a nonce to sign with its host key
+    async fn challenge(&mut self, src: SocketAddr) {
+        self.challenges
+            .retain(|_, challenge| !challenge.is_expired());
+        if self.challenges.len() >= MAX_CHALLENGES && !self.challenges.contains_key(&src) {
+            self.send_error("Relay is full".to_string(), src).await;
+            return;
+        }
+
+        let nonce = generate_challenge();
+        self.challenges.insert(
+            src,
+            Challenge {
+                nonce: nonce.clone(),
+                issued: Instant::now(),
+            },
+        );
+
+        self.send(&RelayMessage::Challenge { nonce }, src).await;
+    }
+
+    /// Whether the signature is the host key's over the nonce last sent to the address
+    fn verify_challenge(
+        &mut self,
+        src: SocketAddr,
+        name: &str,
+        host_key: &str,
+        signature: &str,
+    ) -> bool {
+        let challenge = match self.challenges.remove(&src) {
+            Some(challenge) if !challenge.is_expired() => challenge,
+            _ => return false,
+        };
+
+        verify_relay_registration(host_key, &challenge.nonce, name, signature).is_ok()
+    }
+
+    async fn register(
+        &mut self,
+        src: SocketAddr,
+        name: String,
+        host_key: String,
+        signature: Option<String>,
+    ) {
+        let name = name.trim().to_string();
+        if name.is_empty() || name.len() > RELAY_MAX_NAME {
+            self.send_error(format!("Name must be 1 to {} bytes", RELAY_MAX_NAME), src)
+                .await;
+            return;
+        }
+
+        self.registrations
+            .retain(|_, registration| !registration.is_expired());
+
+        let full = self.registrations.len() >= MAX_REGISTRATIONS;
+
+        // A name stays with the host key that registered it until it expires
+        match self.registrations.get_mut(&name) {
+            Some(registration) if registration.host_key != host_key => {
+                self.send_error(format!("Name \"{}\" is taken", name), src)
+                    .await;
+                return;
+            }
+            Some(registration) if registration.address == src => {
+                registration.refreshed = Instant::now();
+                self.send(&RelayMessage::Registered { name }, src).await;
+                return;
+            }
+            None if full => {
+                self.send_error("Relay is full".to_string(), src).await;
+                return;
+            }
+            _ => {}
+        }
+
+        // Registering, or moving to another address, requires proof of the host key
+        let proven = match &signature {
+            Some(signature) => self.verify_challenge(src, &name, &host_key, signature),
+            None => false,
+        };
+
+        if !proven {
+            if signature.is_some() {
+                debug!("Invalid Registration Signature from {}", src);
+            }
+            self.challenge(src).await;
+            return;
+        }
+
+        info!("Registered Server \"{}\" at {}", name, src);
+        self.registrations.insert(
+            name.clone(),
+            Registration {
+                address: src,
+                host_key,
+                refreshed: Instant::now(),
+            },
+        );
+
+        self.send(&RelayMessage::Registered { name }, src).await;
+    }
+
+    /// Counts a connect from the address, false once it sent too many within the window
+    fn allow_connect(&mut self, ip: IpAddr) -> bool {
+        self.connects
+            .retain(|_, window| window.started.elapsed() < CONNECT_WINDOW);
+
+        let window = self.connects.entry(ip).or_insert(ConnectWindow {
+            count: 0,
+            started: Instant::now(),
+        });
+        window.count += 1;
+
+        window.count <= MAX_CONNECTS_PER_IP
+    }
+
+    /// Opens a relay session and introduces the player and the server to each other
+    async fn connect(&mut self, src: SocketAddr, name: String) {
+        // Dropped without an answer, so the relay can not be used to flood an address
+        if !self.allow_connect(src.ip()) {
+            debug!("Dropped Connect from {}, Too Many Attempts", src);
+            return;
+        }
+
+        let (server, host_key) = match self.registrations.get(&name) {
+            Some(registration) if !registration.is_expired() => {
+                (registration.address, registration.host_key.clone())
+            }
+            _ => {
+                self.send_error(format!("No server registered as \"{}\"", name), src)
+                    .await;
+                return;
+            }
+        };
+
+        self.sessions.retain(|session| !session.is_finished());
+        if self.sessions.len() >= MAX_SESSIONS {
+            self.send_error("Relay is full".to_string(), src).await;
+            return;
+        }
+
+        let local_address = match self.socket.local_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("Failed to Read Relay Address: {}", e);
+                return;
+            }
+        };
+
+        let socket = match UdpSocket::bind(SocketAddr::new(local_address.ip(), 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed to Bind Relay Session Socket: {}", e);
+                self.send_error("Relay is unavailable".to_string(), src)
+                    .await;
+                return;
+            }
+        };
+
+        let relay_port = match socket.local_addr() {
+            Ok(address) => address.port(),
+            Err(e) => {
+                warn!("Failed to Read Relay Session Address: {}", e);
+                return;
+            }
+        };
+
+        let token = rand::random::<u64>();
+
+        info!(
+            "Player {} Connecting to \"{}\" at {}, Relay Session on Port {}",
+            src, name, server, relay_port
+        );
+
+        self.sessions
+            .push(RelaySession::run(socket, token, name.clone()));
+
+        self.send(
+            &RelayMessage::Peer {
+                token,
+                direct: src,
+                relay_port,
+                host_key: None,
+            },
+            server,
+        )
+        .await;
+        self.send(
+            &RelayMessage::Peer {
+                token,
+                direct: server,
+                relay_port,
+                host_key: Some(host_key),
+            },
+            src,
+        )
+        .await;
+    }
+
+    pub async fn run(&mut self) {
+        let mut buf = [0u8; MTU];
+
+        loop {
+            let (len, src) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Failed to Receive Relay Message: {}", e);
+                    continue;
+                }
+            };
+
+            match RelayMessage::parse_packet(&buf[..len]) {
+                Some(RelayMessage::Register {
+                    name,
+                    host_key,
+                    signature,
+                }) => self.register(src, name, host_key, signature).await,
+                Some(RelayMessage::Connect { name }) => self.connect(src, name).await,
+                Some(message) => debug!("Unexpected Relay Message from {}: {:?}", src, message),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use mrial_proto::auth::{generate_key_pair, sign_relay_registration};
+    use tokio::time::timeout;
+
+    use super::*;
+
+    async fn send(socket: &UdpSocket, message: &RelayMessage, target: SocketAddr) {
+        let mut buf = [0u8; MTU];
+        let len = message.write_packet(&mut buf);
+        socket.send_to(&buf[..len], target).await.unwrap();
+    }
+
+    async fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0u8; MTU];
+        let (len, src) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        (buf[..len].to_vec(), src)
+    }
+
+    async fn recv_message(socket: &UdpSocket) -> RelayMessage {
+        RelayMessage::parse_packet(&recv(socket).await.0).unwrap()
+    }
+
+    async fn local_socket() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn registers_connects_and_forwards() {
+        let mut relay = RelayServer::bind((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap();
+        let relay_address = relay.local_addr().unwrap();
+        tokio::spawn(async move { relay.run().await });
+
+        let (secret_key, host_key) = generate_key_pair();
+        let server = local_socket().await;
+        let register = |signature| RelayMessage::Register {
+            name: "test".to_string(),
+            host_key: host_key.clone(),
+            signature,
+        };
+
+        // The first registration is challenged, and a wrong signature is challenged again
+        send(&server, &register(None), relay_address).await;
+        let nonce = match recv_message(&server).await {
+            RelayMessage::Challenge { nonce } => nonce,
+            other => panic!("Expected a Challenge, got {:?}", other),
+        };
+        let signature = sign_relay_registration(&secret_key, &nonce, "other").unwrap();
+        send(&server, &register(Some(signature)), relay_address).await;
+        let nonce = match recv_message(&server).await {
+            RelayMessage::Challenge { nonce } => nonce,
+            other => panic!("Expected a Challenge, got {:?}", other),
+        };
+
+        let signature = sign_relay_registration(&secret_key, &nonce, "test").unwrap();
+        send(&server, &register(Some(signature)), relay_address).await;
+        assert_eq!(
+            recv_message(&server).await,
+            RelayMessage::Registered {
+                name: "test".to_string()
+            }
+        );
+
+        // Another host key can not take the name
+        let (_, other_key) = generate_key_pair();
+        let other = local_socket().await;
+        let claim = RelayMessage::Register {
+            name: "test".to_string(),
+            host_key: other_key,
+            signature: None,
+        };
+        send(&other, &claim, relay_address).await;
+        assert!(matches!(
+            recv_message(&other).await,
+            RelayMessage::Error { .. }
+        ));
+
+        let player = local_socket().await;
+        let connect = RelayMessage::Connect {
+            name: "test".to_string(),
+        };
+        send(&player, &connect, relay_address).await;
+
+        let (token, relay_port) = match recv_message(&player).await {
+            RelayMessage::Peer {
+                token,
+                direct,
+                relay_port,
+                host_key: Some(peer_key),
+            } => {
+                assert_eq!(direct, server.local_addr().unwrap());
+                assert_eq!(peer_key, host_key);
+                (token, relay_port)
+            }
+            other => panic!("Expected a Peer, got {:?}", other),
+        };
+        assert!(matches!(
+            recv_message(&server).await,
+            RelayMessage::Peer { token: t, host_key: None, .. } if t == token
+        ));
+
+        // Both sides bind themselves to the session with a punch, then datagrams are forwarded
+        let session = SocketAddr::new(relay_address.ip(), relay_port);
+        let punch = |server| RelayMessage::Punch { token, server };
+        send(&server, &punch(true), session).await;
+        send(&player, &punch(false), session).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        player.send_to(b"to server", session).await.unwrap();
+        assert_eq!(recv(&server).await, (b"to server".to_vec(), session));
+        server.send_to(b"to player", session).await.unwrap();
+        assert_eq!(recv(&player).await, (b"to player".to_vec(), session));
+    }
+
+    #[tokio::test]
+    async fn connects_are_rate_limited() {
+        let mut relay = RelayServer::bind((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap();
+        let relay_address = relay.local_addr().unwrap();
+        tokio::spawn(async move { relay.run().await });
+
+        let player = local_socket().await;
+        let connect = RelayMessage::Connect {
+            name: "missing".to_string(),
+        };
+
+        for _ in 0..MAX_CONNECTS_PER_IP {
+            send(&player, &connect, relay_address).await;
+            assert!(matches!(
+                recv_message(&player).await,
+                RelayMessage::Error { .. }
+            ));
+        }
+
+        send(&player, &connect, relay_address).await;
+        let mut buf = [0u8; MTU];
+        assert!(
+            timeout(Duration::from_millis(300), player.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::{net::SocketAddr, time::Duration};
+
+use log::{debug, info};
+use mrial_proto::relay::*;
+use tokio::{
+    net::UdpSocket,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+
+/// Largest datagram forwarded, above the MTU so handshakes are never cut short
+const MAX_DATAGRAM: usize = 2048;
+
+/// Forwards the datagrams of a server and a player that failed to punch through to each other.
+/// Each session has its own socket, so the server tells relayed players apart by port.
+/// The datagrams are forwarded as is, they stay encrypted with keys only the two sides know.
+pub struct RelaySession {
+    socket: UdpSocket,
+    token: u64,
+    name: String,
+    server: Option<SocketAddr>,
+    player: Option<SocketAddr>,
+    forwarded: u64,
+}
+
+impl RelaySession {
+    /// Binds the address of a side from its punch, punches with another token are ignored.
+    /// Returns true if the datagram was a punch.
+    fn punched(&mut self, buf: &[u8], src: SocketAddr) -> bool {
+        let (token, server) = match RelayMessage::parse_packet(buf) {
+            Some(RelayMessage::Punch { token, server }) => (token, server),
+            Some(_) => return true,
+            None => return false,
+        };
+
+        if token != self.token {
+            return true;
+        }
+
+        let side = if server {
+            &mut self.server
+        } else {
+            &mut self.player
+        };
+
+        if *side != Some(src) {
+            debug!(
+                "Relay Session \"{}\" Bound {} at {}",
+                self.name,
+                if server { "Server" } else { "Player" },
+                src
+            );
+            *side = Some(src);
+        }
+
+        true
+    }
+
+    async fn forward_loop(&mut self) {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let idle_timeout = Duration::from_millis(RELAY_SESSION_TIMEOUT_MS);
+        let mut last_active = Instant::now();
+
+        loop {
+            let (len, src) =
+                match timeout_at(last_active + idle_timeout, self.socket.recv_from(&mut buf)).await
+                {
+                    Ok(Ok(received)) => received,
+                    Ok(Err(e)) => {
+                        debug!("Failed to Receive Relayed Datagram: {}", e);
+                        continue;
+                    }
+                    Err(_) => break,
+                };
+
+            if self.punched(&buf[..len], src) {
+                if Some(src) == self.server || Some(src) == self.player {
+                    last_active = Instant::now();
+                }
+                continue;
+            }
+
+            // Datagrams from anyone but the two sides are dropped
+            let target = if Some(src) == self.server {
+                self.player
+            } else if Some(src) == self.player {
+                self.server
+            } else {
+                None
+            };
+
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
+
+            last_active = Instant::now();
+            match self.socket.send_to(&buf[..len], target).await {
+                Ok(_) => self.forwarded += len as u64,
+                Err(e) => debug!("Failed to Forward Datagram to {}: {}", target, e),
+            }
+        }
+    }
+
+    pub fn run(socket: UdpSocket, token: u64, name: String) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            let mut session = RelaySession {
+                socket,
+                token,
+                name,
+                server: None,
+                player: None,
+                forwarded: 0,
+            };
+
+            session.forward_loop().await;
+
+            info!(
+                "Relay Session \"{}\" Closed After Forwarding {} Bytes",
+                session.name, session.forwarded
+            );
+        })
+    }
+}
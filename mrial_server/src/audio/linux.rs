@@ -123,10 +123,11 @@ impl IAudioStream for AudioServerTask {
                         if let Some(samples) = data.data() {
                             // TODO: find a better solution to detect if audio is not playings
                             if AudioServerTask::is_zero(&samples[0..32]) {
-                                if conn.is_opus().await {
-                                    if let Some(remaining_len) =
+                                // The remainder is too short for an Opus frame, web clients skip it
+                                if let Some(remaining_len) =
                                     opus_encoder.flush_raw(&mut flushed_pcm)
-                                    {
+                                {
+                                    if conn.is_opus().await {
                                         broadcast_app_audio(
                                             &conn,
                                             EPacketType::AudioPCM,
@@ -144,20 +145,40 @@ impl IAudioStream for AudioServerTask {
 
                             let sample: &[u8] = &samples[0..chunk_len as usize];
 
-                            if conn.is_opus().await && conn.has_app_clients().await {
+                            let is_opus = conn.is_opus().await;
+                            let has_app_clients = conn.has_app_clients().await;
+                            let has_web_clients = conn.has_web_clients().await;
+
+                            // Web clients are always sent Opus, over their audio track
+                            if has_web_clients || (is_opus && has_app_clients) {
                                 match opus_encoder.encode_f32(&sample, &mut compressed_audio) {
                                     Ok(Some(compressed_len)) => {
-                                        broadcast_app_audio(
-                                            &conn,
-                                            EPacketType::AudioOpus,
-                                            &compressed_audio[..compressed_len],
-                                        )
-                                        .await;
+                                        let compressed = &compressed_audio[..compressed_len];
+
+                                        if is_opus && has_app_clients {
+                                            broadcast_app_audio(
+                                                &conn,
+                                                EPacketType::AudioOpus,
+                                                compressed,
+                                            )
+                                            .await;
+                                        }
+
+                                        if has_web_clients {
+                                            broadcast_web_audio(
+                                                &conn,
+                                                EPacketType::AudioOpus,
+                                                compressed,
+                                            )
+                                            .await;
+                                        }
                                     }
                                     Err(e) => error!("Failed to encode audio: {}", e),
                                     _ => {}
                                 }
-                            } else if conn.has_app_clients().await {
+                            }
+
+                            if !is_opus && has_app_clients {
                                 broadcast_app_audio(
                                     &conn,
                                     EPacketType::AudioPCM,
@@ -165,11 +186,6 @@ impl IAudioStream for AudioServerTask {
                                 )
                                 .await;
                             }
-
-                            // TODO: Support OPUS for web clients
-                            if conn.has_web_clients().await {
-                                broadcast_web_audio(&conn, EPacketType::AudioPCM, sample).await;
-                            }
                         }
                     });
                 }
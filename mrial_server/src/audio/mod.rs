@@ -33,13 +33,12 @@ async fn broadcast_web_audio(conn: &ConnectionManager, packet_type: EPacketType,
             BroadcastTaskError::TransferFailed(msg) => {
                 error!("Web Broadcast Send Error: {msg}");
             }
-            _ => {}
         }
     }
 }
 
 async fn broadcast_app_audio(conn: &ConnectionManager, packet_type: EPacketType, bytes: &[u8]) {
-    if let Err(e) = conn.app_encrypted_broadcast(packet_type, 0, bytes).await {
+    if let Err(e) = conn.app_encrypted_broadcast(packet_type, 0, bytes, None).await {
         match e {
             BroadcastTaskError::TaskNotRunning => {
                 error!("App Broadcast Task Not Running");
@@ -50,9 +49,6 @@ async fn broadcast_app_audio(conn: &ConnectionManager, packet_type: EPacketType,
             BroadcastTaskError::TransferFailed(msg) => {
                 error!("App Broadcast Send Error: {msg}");
             }
-            BroadcastTaskError::EncryptionFailed(msg) => {
-                error!("App Broadcast Encryption Error: {msg}");
-            }
         }
     }
 }
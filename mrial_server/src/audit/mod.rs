@@ -0,0 +1,323 @@
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use chrono::{DateTime, Utc};
+use kanal::Sender;
+use log::{debug, warn};
+use mrial_fs::server_data_dir;
+use serde::{Deserialize, Serialize};
+
+const AUDIT_LOG_FILE_NAME: &str = "audit.log";
+/// The log is rotated once it grows past this size
+const AUDIT_LOG_MAX_SIZE: u64 = 5 * 1024 * 1024; // 5 MiB
+/// Number of rotated logs kept next to the current one (audit.log.1 being the newest)
+const AUDIT_LOG_MAX_ROTATIONS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    HandshakeAttempt {
+        method: String,
+    },
+    HandshakeSuccess {
+        width: u16,
+        height: u16,
+    },
+    HandshakeFailure {
+        reason: String,
+    },
+    Disconnect {
+        duration: u64,
+    },
+    IdleTimeout {
+        duration: u64,
+    },
+    ConfigChange {
+        width: u16,
+        height: u16,
+        muted: bool,
+        opus: bool,
+    },
+    ControlGranted {
+        by: String,
+    },
+    ControlRevoked {
+        by: String,
+    },
+    /// Input sent by a client that is not in control
+    RoleViolation {
+        role: String,
+    },
+}
+
+impl AuditEvent {
+    pub fn name(&self) -> &str {
+        match self {
+            AuditEvent::HandshakeAttempt { .. } => "handshake_attempt",
+            AuditEvent::HandshakeSuccess { .. } => "handshake_success",
+            AuditEvent::HandshakeFailure { .. } => "handshake_failure",
+            AuditEvent::Disconnect { .. } => "disconnect",
+            AuditEvent::IdleTimeout { .. } => "idle_timeout",
+            AuditEvent::ConfigChange { .. } => "config_change",
+            AuditEvent::ControlGranted { .. } => "control_granted",
+            AuditEvent::ControlRevoked { .. } => "control_revoked",
+            AuditEvent::RoleViolation { .. } => "role_violation",
+        }
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            AuditEvent::HandshakeAttempt { method } => format!("method: {}", method),
+            AuditEvent::HandshakeSuccess { width, height } => format!("{}x{}", width, height),
+            AuditEvent::HandshakeFailure { reason } => reason.clone(),
+            AuditEvent::Disconnect { duration } | AuditEvent::IdleTimeout { duration } => {
+                format!("session: {}s", duration)
+            }
+            AuditEvent::ConfigChange {
+                width,
+                height,
+                muted,
+                opus,
+            } => format!("{}x{} muted: {} opus: {}", width, height, muted, opus),
+            AuditEvent::ControlGranted { by } | AuditEvent::ControlRevoked { by } => {
+                format!("by: {}", by)
+            }
+            AuditEvent::RoleViolation { role } => format!("input as {}", role.to_lowercase()),
+        }
+    }
+}
+
+/// A single line of the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+fn log_path(file_dir: &Path, rotation: usize) -> PathBuf {
+    match rotation {
+        0 => file_dir.join(AUDIT_LOG_FILE_NAME),
+        n => file_dir.join(format!("{}.{}", AUDIT_LOG_FILE_NAME, n)),
+    }
+}
+
+enum AuditMessage {
+    Record(AuditRecord),
+    /// Answered once every record sent before it is synced to disk
+    Flush(Sender<Result<(), String>>),
+}
+
+/// Owns the log files on the writer thread, so recording never blocks on disk I/O.
+struct AuditWriter {
+    file_dir: PathBuf,
+}
+
+impl AuditWriter {
+    fn path(&self, rotation: usize) -> PathBuf {
+        log_path(&self.file_dir, rotation)
+    }
+
+    fn rotate(&self) -> Result<(), Box<dyn Error>> {
+        let oldest = self.path(AUDIT_LOG_MAX_ROTATIONS);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+
+        for rotation in (0..AUDIT_LOG_MAX_ROTATIONS).rev() {
+            let path = self.path(rotation);
+            if path.exists() {
+                fs::rename(path, self.path(rotation + 1))?;
+            }
+        }
+        debug!("Rotated Audit Log @ {:?}", self.file_dir);
+
+        Ok(())
+    }
+
+    fn append(&self, record: &AuditRecord) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.file_dir)?;
+
+        let path = self.path(0);
+        if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.len() >= AUDIT_LOG_MAX_SIZE {
+                self.rotate()?;
+            }
+        }
+
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Box<dyn Error>> {
+        match File::open(self.path(0)) {
+            Ok(file) => Ok(file.sync_all()?),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn run(self, receiver: kanal::Receiver<AuditMessage>) {
+        while let Ok(message) = receiver.recv() {
+            match message {
+                AuditMessage::Record(record) => {
+                    if let Err(e) = self.append(&record) {
+                        warn!("Failed to Write Audit Record {:?}: {}", record, e);
+                    }
+                }
+                AuditMessage::Flush(reply) => {
+                    let _ = reply.send(self.flush().map_err(|e| e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Append-only JSON lines log of client sessions, kept next to the server storage.
+/// Records are written by a dedicated thread, clones send them to the same one.
+pub struct AuditLog {
+    file_dir: PathBuf,
+    writer: Option<Sender<AuditMessage>>,
+}
+
+impl AuditLog {
+    /// Log that can only be read, records are dropped until a writer is started
+    pub fn new() -> Self {
+        Self::new_with_custom_dir(server_data_dir())
+    }
+
+    pub fn new_with_custom_dir(file_dir: PathBuf) -> Self {
+        Self {
+            file_dir,
+            writer: None,
+        }
+    }
+
+    /// Starts the thread records are written by
+    pub fn with_writer(mut self) -> Self {
+        let (sender, receiver) = kanal::unbounded();
+        let writer = AuditWriter {
+            file_dir: self.file_dir.clone(),
+        };
+
+        thread::spawn(move || writer.run(receiver));
+        self.writer = Some(sender);
+
+        self
+    }
+
+    fn path(&self, rotation: usize) -> PathBuf {
+        log_path(&self.file_dir, rotation)
+    }
+
+    /// Queues the event for the writer, logging instead of failing as auditing
+    /// should never interrupt a session.
+    pub fn record(&self, src: Option<SocketAddr>, user: Option<String>, event: AuditEvent) {
+        let record = AuditRecord {
+            time: Utc::now(),
+            address: src.map(|src| src.to_string()),
+            user,
+            event,
+        };
+
+        let result = match &self.writer {
+            Some(writer) => writer.send(AuditMessage::Record(record)),
+            None => {
+                warn!("Audit Writer Not Started, Dropped Record {:?}", record);
+                return;
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to Queue Audit Record: {}", e);
+        }
+    }
+
+    /// Waits for the queued records to be written and syncs the current log to disk,
+    /// so records survive the server being stopped. The reply of the writer thread
+    /// is awaited, so the runtime is not blocked on disk I/O.
+    pub async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+
+        let (reply_sender, reply_receiver) = kanal::bounded(1);
+        writer.send(AuditMessage::Flush(reply_sender))?;
+
+        Ok(reply_receiver.to_async().recv().await??)
+    }
+
+    /// Reads every record, from the oldest rotated log to the current one.
+    /// Lines that can not be parsed are skipped.
+    pub fn read(&self) -> Result<Vec<AuditRecord>, Box<dyn Error>> {
+        let mut records = Vec::new();
+
+        for rotation in (0..=AUDIT_LOG_MAX_ROTATIONS).rev() {
+            let file = match File::open(self.path(rotation)) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+
+            for line in BufReader::new(file).lines() {
+                if let Ok(record) = serde_json::from_str::<AuditRecord>(&line?) {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+impl Clone for AuditLog {
+    fn clone(&self) -> Self {
+        Self {
+            file_dir: self.file_dir.clone(),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[tokio::test]
+    async fn records_are_written_once_flushed() {
+        let dir = TestDir::new("audit");
+        let audit = AuditLog::new_with_custom_dir(dir.path().to_path_buf()).with_writer();
+
+        audit.record(
+            Some("127.0.0.1:50000".parse().unwrap()),
+            Some("alice".to_string()),
+            AuditEvent::HandshakeFailure {
+                reason: "Invalid Credentials".to_string(),
+            },
+        );
+        audit.flush().await.unwrap();
+
+        let records = AuditLog::new_with_custom_dir(dir.path().to_path_buf())
+            .read()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].address.as_deref(), Some("127.0.0.1:50000"));
+        assert_eq!(records[0].user.as_deref(), Some("alice"));
+        assert_eq!(records[0].event.name(), "handshake_failure");
+    }
+}
@@ -0,0 +1,320 @@
+use mrial_proto::ESessionRole;
+use serde_json::Value;
+
+use crate::{
+    conn::{app::AppClientInfo, web::WebClientInfo, StreamProfile},
+    control::{
+        ClientsList, EncoderParams, GrantParams, KickParams, RpcError, METHOD_CLIENTS_KICK,
+        METHOD_CLIENTS_LIST, METHOD_SESSION_GRANT, METHOD_SESSION_REVOKE, METHOD_STREAM_ENCODER,
+        METHOD_STREAM_KEYFRAME, METHOD_USERS_RELOAD,
+    },
+    video::simulcast::EncoderSettings,
+};
+
+#[cfg(unix)]
+fn call(method: &str, params: Value) -> Result<Value, Box<dyn std::error::Error>> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    use crate::control::{control_socket_path, RpcRequest, RpcResponse};
+
+    let path = control_socket_path();
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        format!(
+            "Failed to connect to {:?}, is the server running? {}",
+            path, e
+        )
+    })?;
+
+    let mut buf = serde_json::to_vec(&RpcRequest::new(1, method, params))?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    if line.is_empty() {
+        return Err(
+            "The server closed the connection, check you are root or in the control group.".into(),
+        );
+    }
+
+    let response: RpcResponse = serde_json::from_str(&line)?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(Box::new(error)),
+        (Some(result), None) => Ok(result),
+        (None, None) => Err(Box::new(RpcError::new(0, "Empty response"))),
+    }
+}
+
+#[cfg(not(unix))]
+fn call(_method: &str, _params: Value) -> Result<Value, Box<dyn std::error::Error>> {
+    Err("The control socket is only supported on Linux and macOS.".into())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_profile(profile: &Option<StreamProfile>) -> String {
+    match profile {
+        Some(profile) if profile.width == 0 || profile.height == 0 => {
+            format!("capture {:?}", profile.csp)
+        }
+        Some(profile) => format!("{}x{} {:?}", profile.width, profile.height, profile.csp),
+        None => "-".to_string(),
+    }
+}
+
+fn print_app_client(i: usize, client: &AppClientInfo) {
+    println!(
+        "{}. {} ({}, {})\n   Profile: {}{}\n   Connected: {}s, Last Ping: {}s ago\n   Sent: {} in {} packets\n   Retransmitted: {}, {} no longer cached, {} cached",
+        i + 1,
+        client.address,
+        client.username.as_deref().unwrap_or("-"),
+        match (client.role, client.control_requested) {
+            (ESessionRole::Controller, _) => "in control",
+            (ESessionRole::Spectator, true) => "spectator, requested control",
+            (ESessionRole::Spectator, false) => "spectator",
+        },
+        format_profile(&client.profile),
+        if client.muted { ", muted" } else { "" },
+        client.duration,
+        client.last_ping,
+        format_bytes(client.bytes_sent),
+        client.packets_sent,
+        client.retransmit_hits,
+        client.retransmit_misses,
+        format_bytes(client.retransmit_cache_bytes)
+    );
+}
+
+fn print_web_client(i: usize, client: &WebClientInfo) {
+    println!(
+        "{}. {} ({}, {}, {})\n   Peer: {}, Connected: {}s\n   Sent: {} in {} messages\n   Streamed: {} in {} RTP packets, {} NACKs, {} PLIs",
+        i + 1,
+        client.address.as_deref().unwrap_or("-"),
+        client.username,
+        match client.role {
+            ESessionRole::Controller => "in control",
+            ESessionRole::Spectator => "spectator",
+        },
+        client.state,
+        client.id,
+        client.duration,
+        format_bytes(client.bytes_sent),
+        client.messages_sent,
+        format_bytes(client.rtp_bytes_sent),
+        client.rtp_packets_sent,
+        client.nack_count,
+        client.pli_count
+    );
+}
+
+fn handle_ctl_clients_cli() -> Result<(), Box<dyn std::error::Error>> {
+    let clients: ClientsList = serde_json::from_value(call(METHOD_CLIENTS_LIST, Value::Null)?)?;
+
+    println!("App Clients:\n");
+    for (i, client) in clients.app.iter().enumerate() {
+        print_app_client(i, client);
+    }
+    if clients.app.is_empty() {
+        println!("No app clients connected.");
+    }
+
+    println!("\nWeb Clients:\n");
+    for (i, client) in clients.web.iter().enumerate() {
+        print_web_client(i, client);
+    }
+    if clients.web.is_empty() {
+        println!("No web clients connected.");
+    }
+
+    Ok(())
+}
+
+fn handle_ctl_kick_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() != 1 {
+        println!(
+            "
+\"mrial_server ctl kick\" requires 1 argument.
+
+Usage \"mrial_server ctl kick [address]\"
+
+The address is either an app client such as \"192.168.1.2:50000\",
+or an IP, which kicks every app and web client at the address.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return Ok(());
+    }
+
+    let params = KickParams {
+        address: args[0].clone(),
+    };
+    call(METHOD_CLIENTS_KICK, serde_json::to_value(params)?)?;
+
+    println!("Client kicked successfully.");
+    Ok(())
+}
+
+fn handle_ctl_grant_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() != 1 {
+        println!(
+            "
+\"mrial_server ctl grant\" requires 1 argument.
+
+Usage \"mrial_server ctl grant [address]\"
+
+The address is the app client given control, such as \"192.168.1.2:50000\",
+or the peer ID of the web client given control, as listed by \"mrial_server ctl clients\".
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return Ok(());
+    }
+
+    let params = GrantParams {
+        address: args[0].clone(),
+    };
+    call(METHOD_SESSION_GRANT, serde_json::to_value(params)?)?;
+
+    println!("Control granted successfully.");
+    Ok(())
+}
+
+fn handle_ctl_revoke_cli() -> Result<(), Box<dyn std::error::Error>> {
+    match call(METHOD_SESSION_REVOKE, Value::Null)? {
+        Value::Bool(true) => println!("Control revoked successfully."),
+        _ => println!("No client is in control."),
+    }
+
+    Ok(())
+}
+
+fn handle_ctl_encoder_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut params = EncoderParams::default();
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => {
+                println!("Missing value for \"{}\".", flag);
+                return Ok(());
+            }
+        };
+
+        if flag == "--preset" {
+            params.preset = Some(value.clone());
+        } else if flag == "--crf" {
+            match value.parse::<u8>() {
+                Ok(crf) => params.crf = Some(crf),
+                Err(_) => {
+                    println!("Invalid CRF \"{}\".", value);
+                    return Ok(());
+                }
+            }
+        } else {
+            println!(
+                "Invalid flag \"{}\", use `--help` for more information.",
+                flag
+            );
+            return Ok(());
+        }
+    }
+
+    let updated = params.preset.is_some() || params.crf.is_some();
+    let settings: EncoderSettings =
+        serde_json::from_value(call(METHOD_STREAM_ENCODER, serde_json::to_value(params)?)?)?;
+
+    if updated {
+        println!("Encoder updated successfully.");
+    }
+    println!("Preset: {}\nCRF: {}", settings.preset, settings.crf);
+
+    Ok(())
+}
+
+pub fn handle_ctl_cli(args: &[String]) {
+    if args.is_empty() {
+        print_ctl_help();
+        return;
+    }
+
+    let cmd = &args[0];
+
+    let result = if cmd == "clients" {
+        handle_ctl_clients_cli()
+    } else if cmd == "kick" {
+        handle_ctl_kick_cli(&args[1..])
+    } else if cmd == "grant" {
+        handle_ctl_grant_cli(&args[1..])
+    } else if cmd == "revoke" {
+        handle_ctl_revoke_cli()
+    } else if cmd == "keyframe" {
+        call(METHOD_STREAM_KEYFRAME, Value::Null)
+            .map(|_| println!("Keyframe requested successfully."))
+    } else if cmd == "encoder" {
+        handle_ctl_encoder_cli(&args[1..])
+    } else if cmd == "reload" {
+        call(METHOD_USERS_RELOAD, Value::Null)
+            .map(|_| println!("Users and access rules reloaded successfully."))
+    } else if cmd == "--help" {
+        print_ctl_help();
+        Ok(())
+    } else {
+        println!(
+            "
+Invalid Option.
+
+Use `--help` for more information.
+"
+        );
+        Ok(())
+    };
+
+    if let Err(e) = result {
+        println!("Error: {}", e);
+    }
+}
+
+fn print_ctl_help() {
+    println!(
+        "
+Usage: mrial_server ctl [options]
+
+Controls the running server, as root or a member of the control group.
+
+Commands:
+
+    clients\t\tList connected clients and their stats
+    kick [address]\tDisconnect a client by \"ip:port\" or every client at an IP
+    grant [address]\tGive control of the session to an app client by \"ip:port\"
+           \t\tor to a web client by its peer ID
+    revoke\t\tTake control away from the client in control
+    keyframe\t\tSend a key frame to every client
+    encoder\t\tShow the encoder settings, or change them with
+           \t\t--preset [preset] and --crf [0-51]
+    reload\t\tReload the users and access rules
+
+Flags:
+
+    --help\t\tShow this help message
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n"
+    );
+}
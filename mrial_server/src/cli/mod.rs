@@ -1,4 +1,23 @@
-use mrial_fs::{storage::StorageMultiType, User, Users};
+mod ctl;
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use mrial_fs::{
+    storage::StorageMultiType, AccessAction, AccessRule, AccessRules, IceServer, KeyPairs,
+    PortRange, ServerConfig, ServerConfigs, User, UserTotp, Users, HOST_KEY_PAIR,
+};
+use mrial_proto::{
+    auth::{key_fingerprint, parse_public_key},
+    relay::{RELAY_DEFAULT_PORT, RELAY_MAX_NAME},
+};
+
+use crate::{
+    audit::{AuditLog, AuditRecord},
+    conn::{access, relay::relay_name, sockets::parse_bind_address, totp},
+    discovery::{server_name, unlock_server_vault, VAULT_PASSPHRASE_ENV},
+    http::SIGNALING_PATH,
+};
+
+use ctl::handle_ctl_cli;
 
 fn handle_user_add_cli(args: &[String], users: &mut Users) {
     if args.len() == 0 || args.len() != 2 {
@@ -25,6 +44,8 @@ For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mri
     let new_user = User {
         username: username.clone(),
         pass: pass.clone(),
+        keys: Vec::new(),
+        totp: None,
     };
 
     if let Err(e) = users.add(new_user) {
@@ -74,6 +95,108 @@ For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mri
     println!("User removed successfully.");
 }
 
+fn handle_user_add_key_cli(args: &[String], users: &mut Users) {
+    if args.len() != 2 {
+        println!(
+            "
+\"mrial_server user add-key\" requires 2 arguments.
+
+Usage \"mrial_server user add-key [username] [public key]\"
+
+The public key is the Base64 encoded Ed25519 key shown by Mrial Player.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return;
+    }
+
+    let username = &args[0];
+    let pub_key = args[1].trim();
+
+    if let Err(e) = parse_public_key(pub_key) {
+        println!("Invalid public key: {}", e);
+        return;
+    }
+
+    if users.find(username.to_string()).is_none() {
+        println!("User not found.");
+        return;
+    }
+
+    if let Err(e) = users.add_key(username, pub_key.to_string()) {
+        println!("Error adding key: {}", e);
+        return;
+    }
+
+    if let Err(e) = users.save() {
+        println!("Error saving users: {}", e);
+        return;
+    }
+
+    println!("Key added successfully.");
+}
+
+fn handle_user_totp_cli(args: &[String], users: &mut Users) {
+    if args.len() != 2 || (args[0] != "enable" && args[0] != "disable") {
+        println!(
+            "
+\"mrial_server user totp\" requires 2 arguments.
+
+Usage \"mrial_server user totp [enable|disable] [username]\"
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return;
+    }
+
+    let enable = args[0] == "enable";
+    let username = &args[1];
+
+    if users.find(username.to_string()).is_none() {
+        println!("User not found.");
+        return;
+    }
+
+    let user_totp = match enable {
+        true => Some(UserTotp {
+            secret: totp::generate_secret(),
+            last_step: 0,
+        }),
+        false => None,
+    };
+
+    let uri = match &user_totp {
+        Some(user_totp) => match totp::provisioning_uri(username, &user_totp.secret) {
+            Ok(uri) => Some(uri),
+            Err(e) => {
+                println!("Error generating provisioning URI: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if let Err(e) = users.set_totp(username, user_totp) {
+        println!("Error updating user: {}", e);
+        return;
+    }
+
+    if let Err(e) = users.save() {
+        println!("Error saving users: {}", e);
+        return;
+    }
+
+    match uri {
+        Some(uri) => println!(
+            "TOTP enabled. Add the following URI to your authenticator app:\n\n{}\n",
+            uri
+        ),
+        None => println!("TOTP disabled successfully."),
+    }
+}
+
 fn handle_user_cli(args: &[String]) {
     if args.len() == 0 {
         print_user_help();
@@ -93,7 +216,7 @@ fn handle_user_cli(args: &[String]) {
 
         if let Some(users) = &users.users.get() {
             for i in 0..users.len() {
-                println!("{}. {}", (i + 1), users[i].username);
+                println!("{}. {} ({} keys)", (i + 1), users[i].username, users[i].keys.len());
             }
             if users.len() == 0 {
                 println!("No users found.");
@@ -105,6 +228,10 @@ fn handle_user_cli(args: &[String]) {
         handle_user_add_cli(&args[1..], &mut users);
     } else if cmd == "rm" {
         handle_user_rm_cli(&args[1..], &mut users);
+    } else if cmd == "add-key" {
+        handle_user_add_key_cli(&args[1..], &mut users);
+    } else if cmd == "totp" {
+        handle_user_totp_cli(&args[1..], &mut users);
     } else if cmd == "--help" {
         print_user_help();
     } else {
@@ -128,6 +255,8 @@ Commands:
     ls\t\tList authenticated users
     add\t\tAdd a new user
     rm\t\tRemove a user
+    add-key\tAuthorize a public key for a user
+    totp\t\tEnable or disable TOTP for a user
 
 Flags:
 
@@ -137,37 +266,1136 @@ For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mri
     );
 }
 
-fn print_help() {
+fn handle_access_add_cli(args: &[String], rules: &mut AccessRules, action: AccessAction) {
+    let cmd = match action {
+        AccessAction::Allow => "allow",
+        AccessAction::Deny => "deny",
+    };
+
+    if args.is_empty() || args.len() > 2 {
+        println!(
+            "
+\"mrial_server access {cmd}\" requires 1 or 2 arguments.
+
+Usage \"mrial_server access {cmd} [cidr] [username (optional)]\"
+
+Rules without a username apply to every client, including web clients.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return;
+    }
+
+    let cidr = args[0].trim();
+
+    if let Err(e) = access::parse_cidr(cidr) {
+        println!("Invalid CIDR: {}", e);
+        return;
+    }
+
+    let user = args.get(1).cloned();
+    if let Some(username) = &user {
+        let mut users = Users::new();
+        if let Err(e) = users.load() {
+            println!("Error loading users: {}", e);
+            return;
+        }
+
+        if users.find(username.to_string()).is_none() {
+            println!("User not found.");
+            return;
+        }
+    }
+
+    let rule = AccessRule {
+        action,
+        cidr: cidr.to_string(),
+        user,
+    };
+
+    if let Err(e) = rules.add(rule) {
+        println!("Error adding rule: {}", e);
+        return;
+    }
+
+    if let Err(e) = rules.save() {
+        println!("Error saving rules: {}", e);
+        return;
+    }
+
+    println!("Rule added successfully.");
+}
+
+fn handle_access_rm_cli(args: &[String], rules: &mut AccessRules) {
+    let index = match args.first().and_then(|index| index.parse::<usize>().ok()) {
+        Some(index) if index > 0 => index - 1,
+        _ => {
+            println!(
+                "
+\"mrial_server access rm\" requires 1 argument.
+
+Usage \"mrial_server access rm [number]\"
+
+The number is the one shown by \"mrial_server access ls\".
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return;
+        }
+    };
+
+    if rules.find(index).is_none() {
+        println!("Rule not found.");
+        return;
+    }
+
+    if let Err(e) = rules.remove(index) {
+        println!("Error removing rule: {}", e);
+        return;
+    }
+
+    if let Err(e) = rules.save() {
+        println!("Error saving rules: {}", e);
+        return;
+    }
+
+    println!("Rule removed successfully.");
+}
+
+fn handle_access_cli(args: &[String]) {
+    if args.is_empty() {
+        print_access_help();
+        return;
+    }
+
+    let cmd = &args[0];
+
+    let mut rules = AccessRules::new();
+    if let Err(e) = rules.load() {
+        println!("Error loading rules: {}", e);
+        return;
+    }
+
+    if cmd == "ls" {
+        println!("Access Rules (first match applies):\n");
+
+        if let Some(rules) = &rules.rules.get() {
+            for (i, rule) in rules.iter().enumerate() {
+                println!("{}. {}", (i + 1), rule);
+            }
+            if rules.is_empty() {
+                println!("No rules found, all addresses are allowed.");
+            }
+        } else {
+            println!("Failed to get rules.");
+        }
+    } else if cmd == "allow" {
+        handle_access_add_cli(&args[1..], &mut rules, AccessAction::Allow);
+    } else if cmd == "deny" {
+        handle_access_add_cli(&args[1..], &mut rules, AccessAction::Deny);
+    } else if cmd == "rm" {
+        handle_access_rm_cli(&args[1..], &mut rules);
+    } else if cmd == "--help" {
+        print_access_help();
+    } else {
+        println!(
+            "
+Invalid Option.
+
+Use `--help` for more information.
+"
+        );
+    }
+}
+
+fn print_access_help() {
     println!(
         "
-Usage: mrial_server [command] [options]
+Usage: mrial_server access [options]
 
 Commands:
 
-    user\t\tManage authenticated users
+    ls\t\tList access rules
+    allow\t\tAllow a CIDR, optionally only for a user
+    deny\t\tDeny a CIDR, optionally only for a user
+    rm\t\tRemove an access rule
+
+Rules are evaluated in order and the first matching rule applies.
+If allow rules exist, addresses not matching any of them are denied.
 
 Flags:
 
     --help\t\tShow this help message
 
-    For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial"
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n"
     );
 }
 
-pub fn handle_cli(args: &Vec<String>) {
-    let cmd = &args[1];
+/// Parses an RFC 3339 time, a date, a date with time (UTC),
+/// or a time relative to now such as "30m", "12h" or "7d".
+fn parse_audit_time(time: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.with_timezone(&Utc));
+    }
 
-    if cmd == "user" {
-        let user_args = &args[2..];
-        handle_user_cli(user_args);
-    } else if cmd == "--help" {
-        print_help();
+    if let Ok(time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+        return Some(time.and_utc());
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+
+    let (unit_index, _) = time.char_indices().last()?;
+    let amount = time[..unit_index].parse::<i64>().ok()?;
+    let duration = match &time[unit_index..] {
+        "m" => Duration::try_minutes(amount)?,
+        "h" => Duration::try_hours(amount)?,
+        "d" => Duration::try_days(amount)?,
+        _ => return None,
+    };
+
+    Some(Utc::now() - duration)
+}
+
+fn print_audit_record(record: &AuditRecord) {
+    println!(
+        "{}  {:<18} {:<12} {:<22} {}",
+        record.time.format("%Y-%m-%d %H:%M:%S"),
+        record.event.name(),
+        record.user.as_deref().unwrap_or("-"),
+        record.address.as_deref().unwrap_or("-"),
+        record.event.details()
+    );
+}
+
+fn handle_audit_cli(args: &[String]) {
+    let mut user: Option<&String> = None;
+    let mut since: Option<DateTime<Utc>> = None;
+    let mut until: Option<DateTime<Utc>> = None;
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--json" {
+            json = true;
+            continue;
+        }
+
+        if arg == "--help" {
+            print_audit_help();
+            return;
+        }
+
+        let value = match args.next() {
+            Some(value) => value,
+            None => {
+                println!("Missing value for \"{}\".\n\nUse `--help` for more information.", arg);
+                return;
+            }
+        };
+
+        if arg == "--user" {
+            user = Some(value);
+        } else if arg == "--since" || arg == "--until" {
+            let time = match parse_audit_time(value) {
+                Some(time) => time,
+                None => {
+                    println!("Invalid time: {}", value);
+                    return;
+                }
+            };
+
+            match arg == "--since" {
+                true => since = Some(time),
+                false => until = Some(time),
+            }
+        } else {
+            println!(
+                "
+Invalid Option.
+
+Use `--help` for more information.
+"
+            );
+            return;
+        }
+    }
+
+    let records = match AuditLog::new().read() {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Error reading audit log: {}", e);
+            return;
+        }
+    };
+
+    let records = records.iter().filter(|record| {
+        user.is_none_or(|user| record.user.as_ref() == Some(user))
+            && since.is_none_or(|since| record.time >= since)
+            && until.is_none_or(|until| record.time <= until)
+    });
+
+    let mut count = 0;
+    for record in records {
+        match json {
+            true => match serde_json::to_string(record) {
+                Ok(line) => println!("{}", line),
+                Err(e) => println!("Error serializing record: {}", e),
+            },
+            false => print_audit_record(record),
+        }
+        count += 1;
+    }
+
+    if count == 0 && !json {
+        println!("No audit records found.");
+    }
+}
+
+fn print_audit_help() {
+    println!(
+        "
+Usage: mrial_server audit [options]
+
+Options:
+
+    --user [username]\tOnly show records of a user
+    --since [time]\tOnly show records at or after the time
+    --until [time]\tOnly show records at or before the time
+    --json\t\tPrint the raw JSON lines
+
+Times are RFC 3339, \"YYYY-MM-DD\", \"YYYY-MM-DD HH:MM\" (UTC),
+or relative to now such as \"30m\", \"12h\" or \"7d\".
+
+Flags:
+
+    --help\t\tShow this help message
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n"
+    );
+}
+
+fn print_config(config: &ServerConfig) {
+    println!("Bind Addresses:\n");
+    for (i, address) in config.bind.iter().enumerate() {
+        println!("{}. {}", (i + 1), address);
+    }
+    if config.bind.is_empty() {
+        println!("No bind addresses, the server will fail to start.");
+    }
+
+    println!("\nPort: {}", config.port);
+    match (config.web_port, config.web_port_range) {
+        (Some(web_port), _) => println!("WebRTC Port: {}", web_port),
+        (None, Some(range)) => println!("WebRTC Port: {} per peer", range),
+        (None, None) => println!("WebRTC Port: random per peer"),
+    }
+    match config.http_port {
+        Some(http_port) => println!(
+            "HTTP Port: {}, serving the web player with signaling at {}",
+            http_port, SIGNALING_PATH
+        ),
+        None => println!("HTTP Port: off"),
+    }
+    println!(
+        "HTTP TLS Proxy: {}",
+        match config.http_tls_proxy {
+            true => "on, web clients may sign in on any bind address",
+            false => "off, web clients may only sign in on loopback",
+        }
+    );
+    println!(
+        "Control Group: {}",
+        config.control_group.as_deref().unwrap_or("none, root only")
+    );
+    println!(
+        "Keepalive: every {}ms, unreachable after {}ms",
+        config.keepalive_interval_ms, config.keepalive_timeout_ms
+    );
+    println!(
+        "Retransmit Cache: {} MB per client, up to {}ms old",
+        config.retransmit_cache_bytes / (1024 * 1024),
+        config.retransmit_cache_age_ms
+    );
+    println!(
+        "Name: {}{}",
+        server_name(config),
+        if config.discovery {
+            ", advertised on the LAN"
+        } else {
+            ""
+        }
+    );
+
+    match &config.relay {
+        Some(relay) => println!("Relay: {} as \"{}\"", relay, relay_name(config)),
+        None => println!("Relay: off"),
+    }
+
+    if config.ice_host_only {
+        println!("ICE Servers: off, host candidates only");
+    } else if config.ice_servers.is_empty() {
+        println!("ICE Servers: none");
     } else {
+        let servers: Vec<String> = config.ice_servers.iter().map(|s| s.to_string()).collect();
+        println!("ICE Servers: {}", servers.join(", "));
+    }
+
+    // The host key is generated by the server on its first start
+    let mut key_pairs = KeyPairs::new_server();
+    let fingerprint = key_pairs
+        .load()
+        .ok()
+        .and_then(|_| key_pairs.find(HOST_KEY_PAIR.to_string()))
+        .and_then(|key_pair| key_fingerprint(&key_pair.public_key).ok());
+    println!(
+        "Host Key: {}",
+        fingerprint.as_deref().unwrap_or("not generated yet")
+    );
+}
+
+fn parse_port(port: &str) -> Option<u16> {
+    port.parse::<u16>().ok().filter(|port| *port > 0)
+}
+
+fn handle_config_bind_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    if args.len() != 2 || (args[0] != "add" && args[0] != "rm") {
         println!(
             "
-Invalid Option.
+\"mrial_server config bind\" requires 2 arguments.
 
-Use `--help` for more information."
+Usage \"mrial_server config bind [add|rm] [address]\"
+
+Addresses may include a port, such as \"0.0.0.0\", \"[::]\" or \"192.168.1.2:8554\".
+\"[::]\" accepts both IPv6 and IPv4 clients.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
         );
+        return false;
+    }
+
+    let address = &args[1];
+
+    if args[0] == "add" {
+        if let Err(e) = parse_bind_address(address, config.port) {
+            println!("Invalid address \"{}\": {}", address, e);
+            return false;
+        }
+
+        if config.bind.contains(address) {
+            println!("Bind address already exists.");
+            return false;
+        }
+
+        config.bind.push(address.clone());
+        return true;
+    }
+
+    match config.bind.iter().position(|bind| bind == address) {
+        Some(index) => {
+            config.bind.remove(index);
+            true
+        }
+        None => {
+            println!("Bind address not found.");
+            false
+        }
+    }
+}
+
+/// Sets the app port, or the optional WebRTC and HTTP ports, by the config command
+fn handle_config_port_cli(args: &[String], config: &mut ServerConfig, cmd: &str) -> bool {
+    let port = match args.first() {
+        Some(port) if cmd != "port" && port == "off" => None,
+        Some(port) => match parse_port(port) {
+            Some(port) => Some(port),
+            None => {
+                println!("Invalid port \"{}\".", port);
+                return false;
+            }
+        },
+        None => {
+            println!(
+                "
+\"mrial_server config {}\" requires 1 argument.
+
+Usage \"mrial_server config port [port]\", \"mrial_server config web-port [port|off]\"
+or \"mrial_server config http-port [port|off]\"
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+",
+                cmd
+            );
+            return false;
+        }
+    };
+
+    match (cmd, port) {
+        ("web-port", Some(_)) if config.web_port_range.is_some() => {
+            println!("WebRTC peers pick from the port range, turn it off first with \"mrial_server config web-port-range off\".");
+            return false;
+        }
+        ("web-port", port) => config.web_port = port,
+        ("http-port", port) => config.http_port = port,
+        (_, Some(port)) => config.port = port,
+        (_, None) => return false,
+    }
+
+    true
+}
+
+fn handle_config_control_group_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args.first() {
+        Some(group) if group == "off" => config.control_group = None,
+        Some(group) => config.control_group = Some(group.clone()),
+        None => {
+            println!(
+                "
+\"mrial_server config control-group\" requires 1 argument.
+
+Usage \"mrial_server config control-group [group|off]\"
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+fn handle_config_keepalive_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    if args.len() != 2 {
+        println!(
+            "
+\"mrial_server config keepalive\" requires 2 arguments.
+
+Usage \"mrial_server config keepalive [interval_ms] [timeout_ms]\"
+
+The server and players ping each other every interval, and give up on
+each other after the timeout passes without any packets.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return false;
+    }
+
+    let (interval_ms, timeout_ms) = match (args[0].parse::<u64>(), args[1].parse::<u64>()) {
+        (Ok(interval_ms), Ok(timeout_ms)) => (interval_ms, timeout_ms),
+        _ => {
+            println!("Invalid interval or timeout, both must be in milliseconds.");
+            return false;
+        }
+    };
+
+    if interval_ms == 0 || timeout_ms <= interval_ms {
+        println!("The interval must be above 0 and the timeout must be above the interval.");
+        return false;
+    }
+
+    config.keepalive_interval_ms = interval_ms;
+    config.keepalive_timeout_ms = timeout_ms;
+
+    true
+}
+
+fn handle_config_retransmit_cache_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    if args.len() != 2 {
+        println!(
+            "
+\"mrial_server config retransmit-cache\" requires 2 arguments.
+
+Usage \"mrial_server config retransmit-cache [budget_mb] [max_age_ms]\"
+
+Video sent to each client is kept up to the budget for retransmission,
+lost packets older than the max age are no longer retransmitted.
+Hits and misses are listed by \"mrial_server ctl clients\".
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+        );
+        return false;
+    }
+
+    let (budget_mb, max_age_ms) = match (args[0].parse::<usize>(), args[1].parse::<u64>()) {
+        (Ok(budget_mb), Ok(max_age_ms)) if budget_mb > 0 && max_age_ms > 0 => {
+            (budget_mb, max_age_ms)
+        }
+        _ => {
+            println!("Invalid budget or max age, both must be above 0.");
+            return false;
+        }
+    };
+
+    config.retransmit_cache_bytes = budget_mb * 1024 * 1024;
+    config.retransmit_cache_age_ms = max_age_ms;
+
+    true
+}
+
+fn handle_config_name_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args.first() {
+        Some(name) if name == "off" => config.name = None,
+        Some(name) if !name.trim().is_empty() => config.name = Some(name.clone()),
+        _ => {
+            println!(
+                "
+\"mrial_server config name\" requires 1 argument.
+
+Usage \"mrial_server config name [name|off]\"
+
+The name players see the server as on the LAN, \"off\" uses the hostname.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+fn handle_config_discovery_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("on") => config.discovery = true,
+        Some("off") => config.discovery = false,
+        _ => {
+            println!(
+                "
+\"mrial_server config discovery\" requires 1 argument.
+
+Usage \"mrial_server config discovery [on|off]\"
+
+When on, the server answers players looking for nearby servers on the LAN.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+fn handle_config_http_tls_proxy_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("on") => config.http_tls_proxy = true,
+        Some("off") => config.http_tls_proxy = false,
+        _ => {
+            println!(
+                "
+\"mrial_server config http-tls-proxy\" requires 1 argument.
+
+Usage \"mrial_server config http-tls-proxy [on|off]\"
+
+Turn it on when a reverse proxy terminates TLS in front of the HTTP listener.
+Web clients send their password when they sign in, so while it is off they
+may only sign in when the listener is bound to loopback.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+fn handle_config_relay_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args {
+        [relay] if relay == "off" => {
+            config.relay = None;
+            config.relay_name = None;
+        }
+        [relay] | [relay, _] if !relay.trim().is_empty() => {
+            if let Some(name) = args.get(1) {
+                if name.trim().is_empty() || name.len() > RELAY_MAX_NAME {
+                    println!("The name must be 1 to {} bytes.", RELAY_MAX_NAME);
+                    return false;
+                }
+            }
+
+            config.relay = Some(relay.clone());
+            config.relay_name = args.get(1).cloned();
+        }
+        _ => {
+            println!(
+                "
+\"mrial_server config relay\" requires 1 or 2 arguments.
+
+Usage \"mrial_server config relay [address] [name]\" or \"mrial_server config relay off\"
+
+The server registers with the relay (\"host:port\", port {} by default) under the name,
+or the advertised name if left out. Players then connect to \"name@host\" with the
+relay port, directly if hole punching succeeds and through the relay otherwise.
+The access rules apply to the address the relay saw a relayed player at.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+",
+                RELAY_DEFAULT_PORT
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Parses a range of ports, such as "50000-50100"
+fn parse_port_range(range: &str) -> Option<PortRange> {
+    let (min, max) = range.split_once('-')?;
+    let (min, max) = (parse_port(min)?, parse_port(max)?);
+
+    (min <= max).then_some(PortRange { min, max })
+}
+
+fn handle_config_web_port_range_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args.first() {
+        Some(range) if range == "off" => config.web_port_range = None,
+        Some(range) => match parse_port_range(range) {
+            Some(_) if config.web_port.is_some() => {
+                println!("WebRTC peers share the WebRTC port, turn it off first with \"mrial_server config web-port off\".");
+                return false;
+            }
+            Some(range) => config.web_port_range = Some(range),
+            None => {
+                println!("Invalid port range \"{}\".", range);
+                return false;
+            }
+        },
+        None => {
+            println!(
+                "
+\"mrial_server config web-port-range\" requires 1 argument.
+
+Usage \"mrial_server config web-port-range [min-max|off]\"
+
+WebRTC peers bind a UDP port in the range, such as \"50000-50100\", so a firewall
+can be opened for it. Unused when the peers share the WebRTC port (web-port).
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+fn print_config_ice_usage() {
+    println!(
+        "
+\"mrial_server config ice\" requires 2 to 4 arguments.
+
+Usage \"mrial_server config ice add [url] [username] [credential]\",
+\"mrial_server config ice rm [url]\" or \"mrial_server config ice host-only [on|off]\"
+
+URLs are STUN or TURN servers, such as \"stun:stun.example.com:3478\" or
+\"turn:turn.example.com:3478\", TURN servers require a username and credential.
+With no servers, or host-only on, peers only gather host candidates.
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+    );
+}
+
+fn handle_config_ice_cli(args: &[String], config: &mut ServerConfig) -> bool {
+    match args {
+        [cmd, mode] if cmd == "host-only" && (mode == "on" || mode == "off") => {
+            config.ice_host_only = mode == "on";
+            true
+        }
+        [cmd, url, credentials @ ..] if cmd == "add" && credentials.len() != 1 => {
+            let is_turn = url.starts_with("turn:") || url.starts_with("turns:");
+            if !is_turn && !url.starts_with("stun:") && !url.starts_with("stuns:") {
+                println!(
+                    "Invalid URL \"{}\", it must start with stun: or turn:.",
+                    url
+                );
+                return false;
+            }
+
+            if is_turn && credentials.is_empty() {
+                println!("TURN servers require a username and credential.");
+                return false;
+            }
+
+            if config.ice_servers.iter().any(|server| server.url == *url) {
+                println!("ICE server already exists.");
+                return false;
+            }
+
+            config.ice_servers.push(IceServer {
+                url: url.clone(),
+                username: credentials.first().cloned(),
+                credential: credentials.get(1).cloned(),
+            });
+            true
+        }
+        [cmd, url] if cmd == "rm" => {
+            match config
+                .ice_servers
+                .iter()
+                .position(|server| server.url == *url)
+            {
+                Some(index) => {
+                    config.ice_servers.remove(index);
+                    true
+                }
+                None => {
+                    println!("ICE server not found.");
+                    false
+                }
+            }
+        }
+        _ => {
+            print_config_ice_usage();
+            false
+        }
+    }
+}
+
+fn handle_config_cli(args: &[String]) {
+    if args.is_empty() {
+        print_config_help();
+        return;
+    }
+
+    let cmd = &args[0];
+
+    let mut configs = ServerConfigs::new();
+    if let Err(e) = configs.load() {
+        println!("Error loading config: {}", e);
+        return;
+    }
+    let mut config = configs.get();
+
+    let updated = if cmd == "ls" {
+        print_config(&config);
+        false
+    } else if cmd == "bind" {
+        handle_config_bind_cli(&args[1..], &mut config)
+    } else if cmd == "port" || cmd == "web-port" || cmd == "http-port" {
+        handle_config_port_cli(&args[1..], &mut config, cmd)
+    } else if cmd == "http-tls-proxy" {
+        handle_config_http_tls_proxy_cli(&args[1..], &mut config)
+    } else if cmd == "control-group" {
+        handle_config_control_group_cli(&args[1..], &mut config)
+    } else if cmd == "keepalive" {
+        handle_config_keepalive_cli(&args[1..], &mut config)
+    } else if cmd == "retransmit-cache" {
+        handle_config_retransmit_cache_cli(&args[1..], &mut config)
+    } else if cmd == "name" {
+        handle_config_name_cli(&args[1..], &mut config)
+    } else if cmd == "discovery" {
+        handle_config_discovery_cli(&args[1..], &mut config)
+    } else if cmd == "relay" {
+        handle_config_relay_cli(&args[1..], &mut config)
+    } else if cmd == "web-port-range" {
+        handle_config_web_port_range_cli(&args[1..], &mut config)
+    } else if cmd == "ice" {
+        handle_config_ice_cli(&args[1..], &mut config)
+    } else if cmd == "--help" {
+        print_config_help();
+        false
+    } else {
+        println!(
+            "
+Invalid Option.
+
+Use `--help` for more information.
+"
+        );
+        false
+    };
+
+    if !updated {
+        return;
+    }
+
+    if let Err(e) = configs.set(config) {
+        println!("Error saving config: {}", e);
+        return;
+    }
+
+    println!("Config updated successfully, restart the server to apply it.");
+}
+
+fn print_config_help() {
+    println!(
+        "
+Usage: mrial_server config [options]
+
+Commands:
+
+    ls\t\tShow the bind addresses, ports, stream settings and host key
+    bind\t\tAdd or remove a bind address
+    port\t\tSet the port of bind addresses without one
+    web-port\tSet the UDP port WebRTC peers share, or \"off\"
+    web-port-range\tSet the UDP ports WebRTC peers pick from, \"min-max\" or \"off\"
+    http-port\tSet the TCP port the web player is served on, or \"off\"
+    http-tls-proxy\tLet web clients sign in behind a TLS reverse proxy, \"on\" or \"off\"
+    control-group\tGroup allowed to use `mrial_server ctl`, or \"off\"
+    keepalive\t\tSet the keepalive interval and timeout in milliseconds
+    retransmit-cache\tSet the retransmit cache budget in MB and max age in milliseconds
+    name\t\tSet the name advertised on the LAN, or \"off\" for the hostname
+    discovery\t\tAnswer players looking for servers on the LAN, \"on\" or \"off\"
+    relay\t\tRegister with a relay under a name for players behind NAT, or \"off\"
+    ice\t\tAdd or remove STUN and TURN servers, or gather host candidates only
+
+Flags:
+
+    --help\t\tShow this help message
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n"
+    );
+}
+
+/// Flags the server is started with, overriding the saved config
+pub const SERVER_FLAGS: [&str; 4] = ["--bind", "--port", "--web-port", "--http-port"];
+
+/// Applies the server flags to the config, the first `--bind` replaces the saved bind addresses.
+pub fn apply_server_args(args: &[String], config: &mut ServerConfig) -> Result<(), String> {
+    let mut bind = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) if SERVER_FLAGS.contains(&flag.as_str()) => value,
+            _ => {
+                return Err(format!(
+                    "Invalid server flag \"{}\", use `mrial_server --help` for more information.",
+                    flag
+                ))
+            }
+        };
+
+        if flag == "--bind" {
+            bind.push(value.clone());
+        } else if flag == "--port" {
+            config.port = parse_port(value).ok_or(format!("Invalid port \"{}\".", value))?;
+        } else if flag == "--http-port" {
+            config.http_port =
+                Some(parse_port(value).ok_or(format!("Invalid port \"{}\".", value))?);
+        } else {
+            config.web_port =
+                Some(parse_port(value).ok_or(format!("Invalid port \"{}\".", value))?);
+        }
+    }
+
+    for address in bind.iter() {
+        if let Err(e) = parse_bind_address(address, config.port) {
+            return Err(format!("Invalid address \"{}\": {}", address, e));
+        }
+    }
+
+    if !bind.is_empty() {
+        config.bind = bind;
+    }
+
+    Ok(())
+}
+
+fn handle_vault_create_cli(key_pairs: &mut KeyPairs) {
+    let passphrase = match std::env::var(VAULT_PASSPHRASE_ENV) {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase,
+        _ => {
+            println!(
+                "
+\"mrial_server vault create\" reads the passphrase from {VAULT_PASSPHRASE_ENV}.
+
+Usage \"{VAULT_PASSPHRASE_ENV}=[passphrase] mrial_server vault create\"
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial
+"
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = key_pairs.vault().create(&passphrase) {
+        println!("Error creating vault: {}", e);
+        return;
+    }
+
+    if let Err(e) = key_pairs.seal_all().and_then(|_| key_pairs.save()) {
+        println!("Error sealing keys: {}", e);
+        return;
+    }
+
+    println!(
+        "Vault created successfully, start the server with {} set to unlock it.",
+        VAULT_PASSPHRASE_ENV
+    );
+}
+
+fn handle_vault_cli(args: &[String]) {
+    if args.is_empty() {
+        print_vault_help();
+        return;
+    }
+
+    let cmd = &args[0];
+
+    let mut key_pairs = KeyPairs::new_server();
+    if let Err(e) = key_pairs.load() {
+        println!("Error loading keys: {}", e);
+        return;
+    }
+
+    if cmd == "create" {
+        handle_vault_create_cli(&mut key_pairs);
+    } else if cmd == "status" {
+        if !key_pairs.vault().is_enabled() {
+            println!("Vault: off, the host key is stored in plain text");
+        } else if let Err(e) = unlock_server_vault(&key_pairs) {
+            println!("Vault: on, failed to unlock: {}", e);
+        } else {
+            println!("Vault: on, unlocked with {}", VAULT_PASSPHRASE_ENV);
+        }
+    } else if cmd == "--help" {
+        print_vault_help();
+    } else {
+        println!(
+            "
+Invalid Option.
+
+Use `--help` for more information.
+"
+        );
+    }
+}
+
+fn print_vault_help() {
+    println!(
+        "
+Usage: mrial_server vault [options]
+
+Commands:
+
+    create\tCreate the vault and seal the host key with the passphrase in {VAULT_PASSPHRASE_ENV}
+    status\tShow whether the vault is enabled and unlocks with {VAULT_PASSPHRASE_ENV}
+
+The server reads the passphrase from {VAULT_PASSPHRASE_ENV} on start, it can not
+sign handshakes or answer discovery probes while the vault is locked.
+
+Flags:
+
+    --help\t\tShow this help message
+
+For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial\n"
+    );
+}
+
+fn print_help() {
+    println!(
+        "
+Usage: mrial_server [command] [options]
+
+Commands:
+
+    user\t\tManage authenticated users
+    access\t\tManage IP allow and deny rules
+    audit\t\tQuery the session audit log
+    config\t\tConfigure bind addresses and ports
+    ctl\t\t\tControl the running server
+    vault\t\tSeal the host key with a passphrase
+
+Server Flags:
+
+    --bind [address]\tBind address, repeat for several (replaces the config)
+    --port [port]\tPort of bind addresses without one
+    --web-port [port]\tUDP port WebRTC peers share
+    --http-port [port]\tTCP port the web player is served on
+
+Flags:
+
+    --help\t\tShow this help message
+
+    For more help on how to use Mrial CLI, head to https://github.com/mahitmehta/mrial"
+    );
+}
+
+pub fn handle_cli(args: &Vec<String>) {
+    let cmd = &args[1];
+
+    if cmd == "user" {
+        let user_args = &args[2..];
+        handle_user_cli(user_args);
+    } else if cmd == "access" {
+        let access_args = &args[2..];
+        handle_access_cli(access_args);
+    } else if cmd == "audit" {
+        let audit_args = &args[2..];
+        handle_audit_cli(audit_args);
+    } else if cmd == "config" {
+        let config_args = &args[2..];
+        handle_config_cli(config_args);
+    } else if cmd == "ctl" {
+        let ctl_args = &args[2..];
+        handle_ctl_cli(ctl_args);
+    } else if cmd == "vault" {
+        let vault_args = &args[2..];
+        handle_vault_cli(vault_args);
+    } else if cmd == "--help" {
+        print_help();
+    } else {
+        println!(
+            "
+Invalid Option.
+
+Use `--help` for more information."
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_relative_audit_time() {
+        let time = parse_audit_time("2h").unwrap();
+        let elapsed = Utc::now() - time;
+
+        assert!(elapsed >= Duration::try_hours(2).unwrap());
+        assert!(elapsed < Duration::try_hours(2).unwrap() + Duration::try_minutes(1).unwrap());
+    }
+
+    #[test]
+    fn rejects_multibyte_audit_time_unit() {
+        assert!(parse_audit_time("2é").is_none());
+        assert!(parse_audit_time("é").is_none());
+        assert!(parse_audit_time("").is_none());
+    }
+
+    #[test]
+    fn web_port_and_range_exclude_each_other() {
+        let web_port = |config: &mut ServerConfig, port: &str| {
+            handle_config_port_cli(&[port.to_string()], config, "web-port")
+        };
+        let range = |config: &mut ServerConfig, range: &str| {
+            handle_config_web_port_range_cli(&[range.to_string()], config)
+        };
+        let mut config = ServerConfig::default();
+
+        assert!(web_port(&mut config, "8555"));
+        assert!(!range(&mut config, "50000-50100"));
+        assert!(config.web_port_range.is_none());
+
+        assert!(web_port(&mut config, "off"));
+        assert!(range(&mut config, "50000-50100"));
+        assert!(!web_port(&mut config, "8555"));
+        assert!(config.web_port.is_none());
     }
 }
@@ -0,0 +1,192 @@
+use std::{
+    error::Error,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use ipnet::IpNet;
+use log::{debug, warn};
+use mrial_fs::{storage::StorageMultiType, AccessAction, AccessRule, AccessRules};
+
+#[derive(Debug)]
+pub enum AccessDenied {
+    /// A deny rule matched the address
+    Rule(AccessRule),
+    /// Allow rules exist for the scope but none of them matched the address
+    NotAllowed,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessDenied::Rule(rule) => write!(f, "Denied by Rule \"{}\"", rule),
+            AccessDenied::NotAllowed => write!(f, "Not Matched by Any Allow Rule"),
+        }
+    }
+}
+
+impl Error for AccessDenied {}
+
+/// Parses a CIDR, treating a bare address as a single host network.
+pub fn parse_cidr(cidr: &str) -> Result<IpNet, Box<dyn Error>> {
+    if let Ok(net) = cidr.parse::<IpNet>() {
+        return Ok(net);
+    }
+
+    Ok(IpNet::from(cidr.parse::<IpAddr>()?))
+}
+
+/// Evaluates the CIDR access rules for both app and web clients.
+///
+/// Rules are evaluated in order and the first rule matching the address decides.
+/// When no rule matches, the address is allowed unless the scope contains allow rules.
+pub struct AccessControl {
+    rules: Arc<RwLock<Vec<(IpNet, AccessRule)>>>,
+}
+
+impl AccessControl {
+    pub fn new() -> Self {
+        let access = Self {
+            rules: Arc::new(RwLock::new(Vec::new())),
+        };
+
+        if let Err(e) = access.reload() {
+            warn!("Failed to Load Access Rules: {}", e);
+        }
+
+        access
+    }
+
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let mut storage = AccessRules::new();
+        storage.load()?;
+
+        let mut rules = Vec::new();
+        for rule in storage.rules.get().unwrap_or_default() {
+            match parse_cidr(&rule.cidr) {
+                Ok(net) => rules.push((net, rule)),
+                Err(e) => warn!("Skipping Invalid Access Rule \"{}\": {}", rule, e),
+            }
+        }
+        debug!("Loaded {} Access Rules", rules.len());
+
+        if let Ok(mut current) = self.rules.write() {
+            *current = rules;
+        }
+
+        Ok(())
+    }
+
+    fn evaluate(&self, ip: IpAddr, user: Option<&str>) -> Result<(), AccessDenied> {
+        let ip = ip.to_canonical();
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(_) => return Ok(()),
+        };
+
+        let mut has_allow_rules = false;
+        for (net, rule) in rules.iter().filter(|(_, rule)| rule.user.as_deref() == user) {
+            if rule.action == AccessAction::Allow {
+                has_allow_rules = true;
+            }
+
+            if net.contains(&ip) {
+                return match rule.action {
+                    AccessAction::Allow => Ok(()),
+                    AccessAction::Deny => Err(AccessDenied::Rule(rule.clone())),
+                };
+            }
+        }
+
+        match has_allow_rules {
+            true => Err(AccessDenied::NotAllowed),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the rules that apply to every user,
+    /// used before any handshake state is created for the address.
+    #[inline]
+    pub fn check(&self, ip: IpAddr) -> Result<(), AccessDenied> {
+        self.evaluate(ip, None)
+    }
+
+    /// Checks the rules scoped to a user, once the user is known.
+    #[inline]
+    pub fn check_user(&self, ip: IpAddr, username: &str) -> Result<(), AccessDenied> {
+        self.evaluate(ip, Some(username))
+    }
+}
+
+impl Clone for AccessControl {
+    fn clone(&self) -> Self {
+        Self {
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(rules: &[(AccessAction, &str, Option<&str>)]) -> AccessControl {
+        let rules = rules
+            .iter()
+            .map(|(action, cidr, user)| {
+                let rule = AccessRule {
+                    action: *action,
+                    cidr: cidr.to_string(),
+                    user: user.map(str::to_string),
+                };
+                (parse_cidr(cidr).unwrap(), rule)
+            })
+            .collect();
+
+        AccessControl {
+            rules: Arc::new(RwLock::new(rules)),
+        }
+    }
+
+    #[test]
+    fn parse_cidr_accepts_bare_addresses() {
+        assert_eq!(parse_cidr("10.0.0.0/8").unwrap().prefix_len(), 8);
+        assert_eq!(parse_cidr("192.168.1.2").unwrap().prefix_len(), 32);
+        assert_eq!(parse_cidr("::1").unwrap().prefix_len(), 128);
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("host").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let access = access(&[
+            (AccessAction::Deny, "10.0.0.5", None),
+            (AccessAction::Allow, "10.0.0.0/8", None),
+        ]);
+
+        assert!(matches!(
+            access.check("10.0.0.5".parse().unwrap()),
+            Err(AccessDenied::Rule(_))
+        ));
+        assert!(access.check("10.1.2.3".parse().unwrap()).is_ok());
+        assert!(matches!(
+            access.check("192.168.1.2".parse().unwrap()),
+            Err(AccessDenied::NotAllowed)
+        ));
+        // IPv4-mapped IPv6 addresses match IPv4 rules
+        assert!(access.check("::ffff:10.1.2.3".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn user_rules_only_apply_to_the_user() {
+        let access = access(&[(AccessAction::Allow, "10.0.0.0/8", Some("alice"))]);
+        let ip = "192.168.1.2".parse().unwrap();
+        let allowed_ip = "10.0.0.1".parse().unwrap();
+
+        assert!(access.check(ip).is_ok());
+        assert!(access.check_user(ip, "bob").is_ok());
+        assert!(access.check_user(ip, "alice").is_err());
+        assert!(access.check_user(allowed_ip, "alice").is_ok());
+    }
+}
@@ -1,35 +1,127 @@
 use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
 use chacha20poly1305::{aead::KeyInit, ChaCha20Poly1305};
 use kanal::{AsyncReceiver, Sender};
-use log::{debug, error};
-use mrial_fs::{storage::StorageMultiType, Users};
+use log::{debug, error, info, warn};
+use mrial_fs::{storage::StorageMultiType, KeyPair, ServerConfig, User, Users};
 use rand::thread_rng;
 use rsa::{pkcs1::EncodeRsaPublicKey, RsaPrivateKey, RsaPublicKey};
-use std::{collections::HashMap, fmt, net::SocketAddr, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
-use tokio::{net::UdpSocket, runtime::Handle, sync::RwLock, task::JoinHandle};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{runtime::Handle, sync::RwLock, task::JoinHandle};
 
 use mrial_proto::{
     deploy::{Broadcaster, PacketDeployer},
+    auth::{
+        generate_challenge, key_fingerprint, sign_relay_registration, sign_session_key,
+        verify_challenge,
+    },
     packet::*,
-    ClientShakeAE, ClientStatePayload, JSONPayloadAE, JSONPayloadSE, JSONPayloadUE, ServerShookSE,
-    ServerShookUE, ServerStatePayload, SERVER_PING_TOLERANCE,
+    relay::{RelayMessage, RELAY_PUNCH_INTERVAL_MS, RELAY_PUNCH_TIMEOUT_MS},
+    ClientResponseSE, ClientShakeAE, ClientStatePayload, EAuthMethod, ESessionRole,
+    JSONPayloadAE, JSONPayloadSE, JSONPayloadUE, KeepaliveSettings, MonitorModesSE,
+    ServerChallengeSE, ServerShookSE, ServerShookUE, ServerStatePayload, SessionControlSE, SessionMember,
+    SessionStateSE,
 };
 
-#[cfg(target_os = "linux")]
 use crate::video::display::DisplayMeta;
 
-use super::{BroadcastTaskError, Client, PacketTypeVariant};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::discovery::load_host_key;
+
+use super::{
+    access::{AccessControl, AccessDenied},
+    lockout::AuthLockout,
+    relay::RelayedPlayers,
+    sockets::AppSockets,
+    retransmit::{RetransmitCache, RetransmitCacheSettings},
+    totp,
+    web::WebController,
+    BroadcastTaskError, Client, PacketTypeVariant, ServerMeta, StreamProfile,
+};
 
-const SERVER_DEFAULT_PORT: u16 = 8554;
 const RSA_PRIVATE_KEY_BIT_SIZE: usize = 2048;
 
+/// Control requests sent to the controller, fewer are sent when they do not fit a single packet
+const MAX_SESSION_REQUESTS: usize = 8;
+
+/// Packets sent to a client, shared with the broadcast task
+#[derive(Default)]
+struct AppClientStats {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+}
+
+impl AppClientStats {
+    fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of a connected client, listed over the control socket
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppClientInfo {
+    pub address: String,
+    pub username: Option<String>,
+    pub muted: bool,
+    pub profile: Option<StreamProfile>,
+    /// Seconds since the client completed the handshake
+    pub duration: u64,
+    /// Seconds since the last ping of the client
+    pub last_ping: u64,
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    /// Subpackets retransmitted from the retransmit cache
+    pub retransmit_hits: u64,
+    /// Subpackets requested for retransmission that were no longer cached
+    pub retransmit_misses: u64,
+    /// Bytes reserved by the retransmit cache
+    pub retransmit_cache_bytes: u64,
+    pub role: ESessionRole,
+    /// Spectator is waiting for the controller to grant it control
+    pub control_requested: bool,
+}
+
 pub struct AppClient {
     last_ping: SystemTime,
     src: SocketAddr,
     muted: bool,
     connected: bool,
+    connected_at: SystemTime,
+    /// Username sent in the Shake AE payload, set before the user is authenticated
+    username: Option<String>,
+    /// Stream profile requested by the client, it is only sent video once set
+    profile: Option<StreamProfile>,
+    /// Role sent in the Shake AE payload, the client is assigned a role once connected
+    requested_role: ESessionRole,
+    role: ESessionRole,
+    /// Time the spectator requested control, requests are listed to the controller in order
+    control_requested: Option<SystemTime>,
+    /// Input of a spectator is audited once per role, as it floods while the spectator moves the mouse
+    role_violation_audited: bool,
+    stats: Arc<AppClientStats>,
+    /// Allocated once the client is connected
+    retransmit_cache: Option<Arc<RetransmitCache>>,
     priv_key: Option<RsaPrivateKey>,
     sym_key: Arc<RwLock<Option<ChaCha20Poly1305>>>,
+    pending_auth: Option<PendingAuth>,
+}
+
+/// Outstanding auth challenge, along with the user and state it was issued for
+struct PendingAuth {
+    challenge: ServerChallengeSE,
+    username: String,
+    state: ClientStatePayload,
 }
 
 impl AppClient {
@@ -39,8 +131,18 @@ impl AppClient {
             priv_key: Some(priv_key),
             muted: false,
             connected: false,
+            connected_at: SystemTime::now(),
+            username: None,
+            profile: None,
+            requested_role: ESessionRole::default(),
+            role: ESessionRole::Spectator,
+            control_requested: None,
+            role_violation_audited: false,
+            stats: Arc::new(AppClientStats::default()),
+            retransmit_cache: None,
             last_ping: SystemTime::now(),
             sym_key: Arc::new(RwLock::new(None)),
+            pending_auth: None,
         }
     }
 
@@ -52,23 +154,88 @@ impl AppClient {
     pub fn is_connected(&self) -> bool {
         self.connected
     }
-}
 
-impl Client for AppClient {
-    fn is_alive(&self) -> bool {
-        let alive = self.last_ping.elapsed().unwrap().as_secs() < SERVER_PING_TOLERANCE;
+    fn set_role(&mut self, role: ESessionRole) {
+        self.role = role;
+        self.control_requested = None;
+        self.role_violation_audited = false;
+    }
+
+    fn is_controller(&self) -> bool {
+        self.connected && self.role == ESessionRole::Controller
+    }
+
+    /// The other clients are sent the new session state when a client affecting it leaves
+    fn affects_session(&self) -> bool {
+        self.is_controller() || self.control_requested.is_some()
+    }
+
+    fn session_member(&self) -> SessionMember {
+        SessionMember {
+            address: self.src.to_string(),
+            username: self.username.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Seconds since the client completed the handshake
+    fn session_duration(&self) -> u64 {
+        self.connected_at
+            .elapsed()
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
 
-        if !alive {
-            debug!("Client: {} is Dead", self.src);
+    fn info(&self) -> AppClientInfo {
+        AppClientInfo {
+            address: self.src.to_string(),
+            username: self.username.clone(),
+            muted: self.muted,
+            profile: self.profile,
+            duration: self.session_duration(),
+            last_ping: self
+                .last_ping
+                .elapsed()
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.stats.packets_sent.load(Ordering::Relaxed),
+            retransmit_hits: self
+                .retransmit_cache
+                .as_ref()
+                .map(|cache| cache.hits())
+                .unwrap_or_default(),
+            retransmit_misses: self
+                .retransmit_cache
+                .as_ref()
+                .map(|cache| cache.misses())
+                .unwrap_or_default(),
+            retransmit_cache_bytes: self
+                .retransmit_cache
+                .as_ref()
+                .map(|cache| cache.bytes() as u64)
+                .unwrap_or_default(),
+            role: self.role,
+            control_requested: self.control_requested.is_some(),
         }
+    }
+}
 
-        alive
+impl Client for AppClient {
+    fn is_alive(&self, timeout: Duration) -> bool {
+        self.last_ping
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed < timeout)
     }
 }
 
 #[derive(Debug)]
 pub enum AppConnectionError {
     InvalidCredentials,
+    AccessDenied(AccessDenied),
+    InvalidChallengeResponse,
+    InvalidTotpCode,
+    LockedOut,
+    ChallengeNotFound,
     ShakeAEDecryptionFailed,
     FailedToLoadUsers,
     ClientPrivateKeyNotFound,
@@ -81,6 +248,21 @@ impl fmt::Display for AppConnectionError {
             AppConnectionError::InvalidCredentials => {
                 write!(f, "User Not Found, Failed to Authenticate")
             }
+            AppConnectionError::AccessDenied(denied) => {
+                write!(f, "Access Denied for User: {}", denied)
+            }
+            AppConnectionError::InvalidChallengeResponse => {
+                write!(f, "Invalid Challenge Response, Failed to Authenticate")
+            }
+            AppConnectionError::InvalidTotpCode => {
+                write!(f, "Invalid or Replayed TOTP Code, Failed to Authenticate")
+            }
+            AppConnectionError::LockedOut => {
+                write!(f, "Too Many Failed Attempts, Locked Out for Now")
+            }
+            AppConnectionError::ChallengeNotFound => {
+                write!(f, "No Auth Challenge Issued to Client")
+            }
             AppConnectionError::ShakeAEDecryptionFailed => {
                 write!(f, "Failed to Decrypt Client Shake AE Payload")
             }
@@ -95,109 +277,203 @@ impl fmt::Display for AppConnectionError {
     }
 }
 
-type BroadcastPayload = (EPacketType, PacketTypeVariant, Vec<u8>);
-
-struct AppBroadcastTask {
-    receiver: AsyncReceiver<BroadcastPayload>,
-
-    audio_pcm_deployer: PacketDeployer,
-    audio_opus_deployer: PacketDeployer,
-    video_deployer: PacketDeployer,
-    audio_broadcaster: AppAudioBroadcaster,
-    video_broadcaster: AppVideoBroadcaster,
+impl AppConnectionError {
+    /// Whether the client failed to prove who it is, as opposed to the handshake failing
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            AppConnectionError::InvalidCredentials
+                | AppConnectionError::InvalidChallengeResponse
+                | AppConnectionError::InvalidTotpCode
+        )
+    }
 }
 
-const SUBPACKET_CACHE_LIFETIME: u64 = 4; // 4 seconds
+/// Frames are sent to the broadcast task unencrypted, it encrypts them with the key of each client.
+/// Frames with profiles are only sent to clients that requested one of them.
+type BroadcastPayload = (
+    EPacketType,
+    PacketTypeVariant,
+    Vec<u8>,
+    Option<Vec<StreamProfile>>,
+);
+
+/// Deployers of a single client, as frame ids and subpacket sizes
+/// depend on the frames encrypted for it.
+struct AppClientDeployers {
+    audio_pcm: PacketDeployer,
+    audio_opus: PacketDeployer,
+    video: PacketDeployer,
+}
 
-fn subpacket_key(frame_id: u8, real_packet_size: u32, remaining_packets: u16) -> u64 {
-    ((frame_id as u64) << 48) | ((real_packet_size as u64) << 16) | (remaining_packets as u64)
+impl AppClientDeployers {
+    fn new() -> Self {
+        Self {
+            audio_pcm: PacketDeployer::new(EPacketType::AudioPCM, false),
+            audio_opus: PacketDeployer::new(EPacketType::AudioOpus, false),
+            video: PacketDeployer::new(EPacketType::NAL, false),
+        }
+    }
 }
 
-struct AppVideoBroadcaster {
-    socket: Arc<UdpSocket>,
-    clients: Arc<RwLock<HashMap<String, AppClient>>>,
-    subpacket_cache: Arc<RwLock<HashMap<u64, (Vec<u8>, u64)>>>,
+/// Sends the subpackets of a frame to one client, caching video subpackets for retransmission.
+struct AppClientBroadcaster<'a> {
+    socket: &'a AppSockets,
+    src: SocketAddr,
+    stats: &'a AppClientStats,
+    retransmit_cache: Option<&'a RetransmitCache>,
+    failed: AtomicBool,
 }
 
-impl Broadcaster for AppVideoBroadcaster {
+impl Broadcaster for AppClientBroadcaster<'_> {
     async fn broadcast(&self, bytes: &[u8]) {
-        let clients = self.clients.read().await;
-
-        for client in clients.values() {
-            if let Err(e) = self.socket.send_to(bytes, client.src).await {
-                debug!("Failed to Broadcast Video to Client (Disconnecting): {}", e);
-
-                let src_str: String = client.src.to_string();
-                self.clients.write().await.remove(&src_str);
-            }
+        if self.failed.load(Ordering::Relaxed) {
+            return;
         }
 
-        // TODO: benchmark how long cache insertion takes, optimize if needed
-        let mut subpacket_cache = self.subpacket_cache.write().await;
-        let mut rnal = bytes.to_vec();
-       
-        rnal[0] &= 0b11100000; // clear the last 5 bits
-        rnal[0] |= EPacketType::RNAL as u8; // set the type to RNAL
+        if let Err(e) = self.socket.send_to(bytes, self.src).await {
+            debug!("Failed to Broadcast to Client {} (Disconnecting): {}", self.src, e);
+            self.failed.store(true, Ordering::Relaxed);
+            return;
+        }
+        self.stats.sent(bytes.len());
 
-        subpacket_cache.insert(
-            subpacket_key(
-                parse_frame_id(bytes),
-                parse_real_packet_size(bytes),
-                parse_packets_remaining(bytes),
-            ),
-            (rnal, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()),
-        );
+        if let Some(retransmit_cache) = self.retransmit_cache {
+            retransmit_cache.insert(bytes);
+        }
     }
 }
 
-struct AppAudioBroadcaster {
-    socket: Arc<UdpSocket>,
-    clients: Arc<RwLock<HashMap<String, AppClient>>>,
+/// Connected client as seen by the broadcast task, copied so the clients lock is not held
+struct ConnectedAppClient {
+    src: SocketAddr,
+    muted: bool,
+    profile: Option<StreamProfile>,
+    stats: Arc<AppClientStats>,
+    retransmit_cache: Option<Arc<RetransmitCache>>,
 }
 
-impl Broadcaster for AppAudioBroadcaster {
-    async fn broadcast(&self, bytes: &[u8]) {
-        let clients = self.clients.read().await;
+struct AppBroadcastTask {
+    receiver: AsyncReceiver<BroadcastPayload>,
 
-        for client in clients.values() {
-            if client.muted {
-                continue;
-            }
-            if let Err(e) = self.socket.send_to(bytes, client.src).await {
-                debug!("Failed to Broadcast Audio to Client (Disconnecting): {}", e);
+    socket: Arc<AppSockets>,
+    clients: Arc<RwLock<HashMap<String, AppClient>>>,
+    deployers: HashMap<SocketAddr, AppClientDeployers>,
+}
 
-                let src_str: String = client.src.to_string();
-                self.clients.write().await.remove(&src_str);
+impl AppBroadcastTask {
+    /// Connected clients along with their session key
+    async fn connected_clients(&self) -> Vec<(ConnectedAppClient, ChaCha20Poly1305)> {
+        let clients = self.clients.read().await;
+        let mut connected_clients = Vec::with_capacity(clients.len());
+
+        for client in clients.values().filter(|client| client.is_connected()) {
+            if let Some(sym_key) = client.sym_key.read().await.clone() {
+                connected_clients.push((
+                    ConnectedAppClient {
+                        src: client.src,
+                        muted: client.muted,
+                        profile: client.profile,
+                        stats: client.stats.clone(),
+                        retransmit_cache: client.retransmit_cache.clone(),
+                    },
+                    sym_key,
+                ));
             }
         }
+
+        connected_clients
     }
-}
 
-impl AppBroadcastTask {
     #[inline]
     async fn broadcast(&mut self, payload: BroadcastPayload) {
-        let (packet_type, packet_type_variant, bytes) = payload;
+        let (packet_type, packet_type_variant, bytes, profiles) = payload;
+        let is_audio = matches!(packet_type, EPacketType::AudioPCM | EPacketType::AudioOpus);
+
+        let clients = self.connected_clients().await;
+        self.deployers
+            .retain(|src, _| clients.iter().any(|(client, _)| client.src == *src));
+
+        let mut failed_clients = Vec::new();
 
-        match packet_type {
-            EPacketType::NAL => {
-                self.video_deployer
-                    .slice_and_send_variant(&bytes, packet_type_variant,  &self.video_broadcaster)
-                    .await;
+        for (client, sym_key) in clients {
+            let src = client.src;
+
+            if is_audio && client.muted {
+                continue;
             }
-            EPacketType::AudioPCM => {
-                self.audio_pcm_deployer
-                    .slice_and_send(&bytes, &self.audio_broadcaster)
-                    .await;
+
+            if let Some(profiles) = &profiles {
+                if !client
+                    .profile
+                    .is_some_and(|profile| profiles.contains(&profile))
+                {
+                    continue;
+                }
             }
-            EPacketType::AudioOpus => {
-                self.audio_opus_deployer
-                    .slice_and_send(&bytes, &self.audio_broadcaster)
-                    .await;
+
+            let encrypted_frame = match encrypt_frame(&sym_key, &bytes) {
+                Ok(encrypted_frame) => encrypted_frame,
+                Err(e) => {
+                    error!("Failed to Encrypt Frame for Client {}: {}", src, e);
+                    continue;
+                }
+            };
+
+            let broadcaster = AppClientBroadcaster {
+                socket: &self.socket,
+                src,
+                stats: &client.stats,
+                retransmit_cache: match packet_type {
+                    EPacketType::NAL => client.retransmit_cache.as_deref(),
+                    _ => None,
+                },
+                failed: AtomicBool::new(false),
+            };
+            let deployers = self
+                .deployers
+                .entry(src)
+                .or_insert_with(AppClientDeployers::new);
+
+            match packet_type {
+                EPacketType::NAL => {
+                    deployers
+                        .video
+                        .slice_and_send_variant(&encrypted_frame, packet_type_variant, &broadcaster)
+                        .await;
+                }
+                EPacketType::AudioPCM => {
+                    deployers
+                        .audio_pcm
+                        .slice_and_send(&encrypted_frame, &broadcaster)
+                        .await;
+                }
+                EPacketType::AudioOpus => {
+                    deployers
+                        .audio_opus
+                        .slice_and_send(&encrypted_frame, &broadcaster)
+                        .await;
+                }
+                _ => {
+                    error!("Unsupported Packet Type (Dropping): {:?}", packet_type);
+                    return;
+                }
             }
-            _ => {
-                error!("Unsupported Packet Type (Dropping): {:?}", packet_type);
+
+            if broadcaster.failed.load(Ordering::Relaxed) {
+                failed_clients.push(src);
             }
         }
+
+        if failed_clients.is_empty() {
+            return;
+        }
+
+        let mut clients = self.clients.write().await;
+        for src in failed_clients.iter() {
+            clients.remove(&src.to_string());
+            self.deployers.remove(src);
+        }
     }
 
     async fn broadcast_loop(&mut self) {
@@ -208,23 +484,16 @@ impl AppBroadcastTask {
 
     pub fn run(
         tokio_handle: Handle,
-        socket: Arc<UdpSocket>,
+        socket: Arc<AppSockets>,
         clients: Arc<RwLock<HashMap<String, AppClient>>>,
-        subpacket_cache: Arc<RwLock<HashMap<u64, (Vec<u8>, u64)>>>,
         receiver: AsyncReceiver<BroadcastPayload>,
     ) -> JoinHandle<()> {
         tokio_handle.spawn(async move {
             let mut thread = Self {
                 receiver,
-                audio_pcm_deployer: PacketDeployer::new(EPacketType::AudioPCM, false),
-                audio_opus_deployer: PacketDeployer::new(EPacketType::AudioOpus, false),
-                video_deployer: PacketDeployer::new(EPacketType::NAL, false),
-                video_broadcaster: AppVideoBroadcaster {
-                    socket: socket.clone(),
-                    clients: clients.clone(),
-                    subpacket_cache,
-                },
-                audio_broadcaster: AppAudioBroadcaster { socket, clients },
+                socket,
+                clients,
+                deployers: HashMap::new(),
             };
 
             thread.broadcast_loop().await;
@@ -233,11 +502,25 @@ impl AppBroadcastTask {
 }
 
 pub struct AppConnection {
-    socket: Arc<UdpSocket>,
+    socket: Arc<AppSockets>,
     clients: Arc<RwLock<HashMap<String, AppClient>>>,
     users: Users,
-
-    subpacket_cache: Arc<RwLock<HashMap<u64, (Vec<u8>, u64)>>>,
+    access: AccessControl,
+    audit: AuditLog,
+    lockout: AuthLockout,
+    keepalive: Arc<std::sync::RwLock<KeepaliveSettings>>,
+    retransmit_cache_settings: Arc<std::sync::RwLock<RetransmitCacheSettings>>,
+    /// Relay the server is registered with and the name it is registered under,
+    /// messages from any other address are ignored
+    relay: Arc<std::sync::RwLock<Option<(SocketAddr, String)>>>,
+    relayed: RelayedPlayers,
+    meta: Arc<RwLock<ServerMeta>>,
+    /// Changed whenever a client connects, disconnects or changes its profile
+    profiles_version: Arc<AtomicU64>,
+    /// App clients only join in control, or request it right away, while no web peer is
+    web_controller: WebController,
+    /// Signs the session key of every handshake, players refuse servers that do not
+    host_key: Option<Arc<KeyPair>>,
 
     broadcast_sender: Sender<BroadcastPayload>,
     broadcast_receiver: AsyncReceiver<BroadcastPayload>,
@@ -245,33 +528,137 @@ pub struct AppConnection {
 }
 
 impl AppConnection {
-    pub async fn new() -> Self {
-        let server_address = SocketAddr::from(([0, 0, 0, 0], SERVER_DEFAULT_PORT));
-        let socket = UdpSocket::bind(server_address).await.expect(&format!(
-            "Failed to Bind UDP Socket at Port:{}",
-            SERVER_DEFAULT_PORT
-        ));
-        let users = Users::new();
+    pub async fn new(
+        config: &ServerConfig,
+        data_dir: &Path,
+        access: AccessControl,
+        audit: AuditLog,
+        meta: Arc<RwLock<ServerMeta>>,
+        web_controller: WebController,
+    ) -> Self {
+        let socket = AppSockets::bind(config);
+        let users = Users::new_with_custom_dir(data_dir.to_path_buf());
 
         let (broadcast_sender, broadcast_receiver) = kanal::unbounded();
 
+        let host_key = match load_host_key(data_dir) {
+            Ok(host_key) => Some(Arc::new(host_key)),
+            Err(e) => {
+                error!("Failed to Load Host Key, Players Can Not Connect: {}", e);
+                None
+            }
+        };
+
         Self {
             broadcast_sender,
             broadcast_receiver: broadcast_receiver.clone_async(),
             broadcast_task: Arc::new(std::sync::RwLock::new(None)),
 
-            subpacket_cache: Arc::new(RwLock::new(HashMap::new())),
-
             clients: Arc::new(RwLock::new(HashMap::new())),
             socket: Arc::new(socket),
             users,
+            access,
+            audit,
+            lockout: AuthLockout::new(),
+            keepalive: Arc::new(std::sync::RwLock::new(KeepaliveSettings {
+                interval_ms: config.keepalive_interval_ms,
+                timeout_ms: config.keepalive_timeout_ms,
+            })),
+            retransmit_cache_settings: Arc::new(std::sync::RwLock::new(
+                RetransmitCacheSettings::from(config),
+            )),
+            relay: Arc::new(std::sync::RwLock::new(None)),
+            relayed: RelayedPlayers::new(),
+            meta,
+            profiles_version: Arc::new(AtomicU64::new(0)),
+            web_controller,
+            host_key,
+        }
+    }
+
+    /// Lockout of failed authentications, shared with web clients
+    pub fn lockout(&self) -> AuthLockout {
+        self.lockout.clone()
+    }
+
+    /// Fingerprint of the host key, None if it failed to load
+    pub fn host_key_fingerprint(&self) -> Option<String> {
+        let host_key = self.host_key.as_ref()?;
+        key_fingerprint(&host_key.public_key).ok()
+    }
+
+    pub fn keepalive(&self) -> KeepaliveSettings {
+        match self.keepalive.read() {
+            Ok(keepalive) => keepalive.clamped(),
+            Err(_) => KeepaliveSettings::default(),
+        }
+    }
+
+    /// Clients follow the new interval from their next handshake
+    pub fn set_keepalive(&self, keepalive: KeepaliveSettings) {
+        if let Ok(mut current) = self.keepalive.write() {
+            *current = keepalive;
+        }
+    }
+
+    fn retransmit_cache_settings(&self) -> RetransmitCacheSettings {
+        *self.retransmit_cache_settings.read().unwrap()
+    }
+
+    /// Clients connecting from now on get a cache with the new settings
+    pub fn set_retransmit_cache_settings(&self, settings: RetransmitCacheSettings) {
+        if let Ok(mut current) = self.retransmit_cache_settings.write() {
+            *current = settings;
         }
     }
 
     #[inline]
     pub async fn filter_clients(&self) {
+        let timeout = Duration::from_millis(self.keepalive().timeout_ms);
+
+        let mut session_changed = false;
+        let mut profiles_changed = false;
+
         let mut clients = self.clients.write().await;
-        clients.retain(|_, client| client.is_alive());
+        clients.retain(|_, client| {
+            let alive = client.is_alive(timeout);
+
+            if !alive && client.affects_session() {
+                session_changed = true;
+            }
+
+            if !alive && client.is_connected() {
+                profiles_changed = true;
+                warn!(
+                    "Client {} Unreachable, No Packets for {}ms",
+                    client.src,
+                    timeout.as_millis()
+                );
+                self.audit.record(
+                    Some(client.src),
+                    client.username.clone(),
+                    AuditEvent::IdleTimeout {
+                        duration: client.session_duration(),
+                    },
+                );
+            }
+
+            alive
+        });
+
+        self.socket
+            .retain_routes(|src| clients.contains_key(&src.to_string()));
+        self.relayed
+            .retain(|session| clients.contains_key(&session.to_string()));
+        drop(clients);
+
+        if profiles_changed {
+            self.profiles_changed();
+        }
+
+        if session_changed {
+            self.send_session_states().await;
+        }
     }
 
     #[inline]
@@ -298,6 +685,27 @@ impl AppConnection {
         Ok(self.socket.send_to(&buf, src).await?)
     }
 
+    /// Pings every connected client, so clients can tell the server is reachable on a static screen
+    pub async fn send_pings(&self) {
+        let mut buf = [0u8; HEADER];
+        write_header(EPacketType::Ping, 0, HEADER as u32, 0, &mut buf);
+
+        let clients: Vec<SocketAddr> = self
+            .clients
+            .read()
+            .await
+            .values()
+            .filter(|client| client.is_connected())
+            .map(|client| client.src)
+            .collect();
+
+        for src in clients {
+            if let Err(e) = self.socket.send_to(&buf, src).await {
+                debug!("Failed to Send Ping to Client {}: {}", src, e);
+            }
+        }
+    }
+
     #[inline]
     pub async fn received_ping(&self, src: SocketAddr) {
         let src_str: String = src.to_string();
@@ -318,200 +726,922 @@ impl AppConnection {
         }
     }
 
-    pub async fn remove_client(&self, src: SocketAddr) {
-        let src_str: String = src.to_string();
-        self.clients.write().await.remove(&src_str);
+    /// Sets the stream profile requested by the client, returning whether it changed
+    pub async fn set_profile(&self, src: SocketAddr, profile: StreamProfile) -> bool {
+        let changed = match self.clients.write().await.get_mut(&src.to_string()) {
+            Some(client) => client.profile.replace(profile) != Some(profile),
+            None => false,
+        };
+
+        if changed {
+            self.profiles_changed();
+        }
+
+        changed
     }
 
-    async fn get_client_priv_key(&self, src_str: &String) -> Option<RsaPrivateKey> {
+    fn profiles_changed(&self) {
+        self.profiles_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes whenever the profiles returned by `profiles` may have,
+    /// so they are only read again when it does
+    pub fn profiles_version(&self) -> u64 {
+        self.profiles_version.load(Ordering::Relaxed)
+    }
+
+    /// Distinct stream profiles requested by connected clients, in the order they connected
+    pub async fn profiles(&self) -> Vec<StreamProfile> {
         let clients = self.clients.read().await;
 
-        if let Some(client) = clients.get(src_str) {
-            return client.priv_key.clone();
+        let mut connected_clients: Vec<&AppClient> = clients
+            .values()
+            .filter(|client| client.is_connected())
+            .collect();
+        connected_clients.sort_by_key(|client| client.connected_at);
+
+        let mut profiles = Vec::new();
+        for profile in connected_clients.iter().filter_map(|client| client.profile) {
+            if !profiles.contains(&profile) {
+                profiles.push(profile);
+            }
         }
 
-        return None;
+        profiles
     }
 
-    pub async fn get_sym_key(&self) -> Option<ChaCha20Poly1305> {
-        if let Some(client) = self
-            .clients
-            .read()
-            .await
-            .values()
-            .find(|client| client.is_connected())
-        {
-            return client.sym_key.read().await.clone();
+    pub async fn remove_client(&self, src: SocketAddr) {
+        let src_str: String = src.to_string();
+        let mut session_changed = false;
+
+        if let Some(client) = self.clients.write().await.remove(&src_str) {
+            session_changed = client.affects_session();
+
+            if client.is_connected() {
+                self.profiles_changed();
+
+                let duration = client.session_duration();
+                self.audit.record(
+                    Some(src),
+                    client.username,
+                    AuditEvent::Disconnect { duration },
+                );
+            }
         }
 
-        None
+        self.socket.retain_routes(|client_src| *client_src != src);
+
+        if session_changed {
+            self.send_session_states().await;
+        }
     }
 
-    pub async fn drain_subpacket_cache(&self) {
-        let mut cache = self.subpacket_cache.write().await;
+    /// Connected clients, in the order they connected
+    pub async fn client_infos(&self) -> Vec<AppClientInfo> {
+        let clients = self.clients.read().await;
 
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        
-        #[cfg(feature = "stat")] 
-        {
-            let cache_size = cache.len();
-            debug!("Subpacket Cache Size: {}", cache_size);
+        let mut connected_clients: Vec<&AppClient> = clients
+            .values()
+            .filter(|client| client.is_connected())
+            .collect();
+        connected_clients.sort_by_key(|client| client.connected_at);
+
+        connected_clients
+            .iter()
+            .map(|client| client.info())
+            .collect()
+    }
+
+    /// Tells the client why it was disconnected by the server and removes it
+    pub async fn disconnect_client(&self, src: SocketAddr, reason: EDisconnectReason) -> bool {
+        if !self.clients.read().await.contains_key(&src.to_string()) {
+            return false;
         }
 
-        cache.retain(|_, (_, timestamp)| {
-            (now - *timestamp) < SUBPACKET_CACHE_LIFETIME
-        });
+        let mut buf = [0u8; HEADER];
+        let len = write_disconnect(reason, &mut buf);
+        if let Err(e) = self.socket.send_to(&buf[..len], src).await {
+            debug!("Failed to Send Disconnect to Client {}: {}", src, e);
+        }
 
-        #[cfg(feature = "stat")]
-        debug!("Drained {} Subpackets", cache_size - cache.len());
+        self.remove_client(src).await;
+        true
     }
 
-    pub async fn retransmit_frame(
-        &self,
-        src: SocketAddr,
-        frame_id: u8,
-        real_packet_size: u32,
-        subpacket_ids: Vec<u16>) {
-
-        let mut clients = self.clients.write().await;
+    /// Disconnects every client, including those still handshaking
+    pub async fn disconnect_clients(&self, reason: EDisconnectReason) {
+        let clients: Vec<SocketAddr> = self
+            .clients
+            .read()
+            .await
+            .values()
+            .map(|client| client.src)
+            .collect();
 
-        if let Some(client) = clients.get_mut(&src.to_string()) {
-            client.last_ping = SystemTime::now();
-
-            for subpacket_id in subpacket_ids {
-                if let Some((subpacket, _)) = self
-                    .subpacket_cache
-                    .read()
-                    .await
-                    .get(&subpacket_key(frame_id, real_packet_size, subpacket_id))
-                {
-                    if let Err(_) = self.socket.send_to(subpacket, client.src).await {
-                        self.remove_client(client.src).await;
-                    }
-                } else {
-                    error!("Cache not found for Frame: {} Size: {} Subpacket ID: {}", frame_id, real_packet_size, subpacket_id);
-                }
-            } 
+        for src in clients {
+            self.disconnect_client(src, reason).await;
         }
     }
 
-    pub async fn connect_client(
-        &mut self,
-        src: SocketAddr,
-        encypyted_payload: &[u8],
-        _headers: Option<Vec<u8>>,
-    ) -> Result<ClientStatePayload, AppConnectionError> {
-        let src_str = src.to_string();
+    /// Reloads the users, so removed users and keys are rejected from the next handshake
+    pub fn reload_users(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.users.load()
+    }
 
-        let priv_key = match self.get_client_priv_key(&src_str).await {
-            Some(priv_key) => priv_key,
-            None => {
-                return Err(AppConnectionError::ClientPrivateKeyNotFound);
-            }
+    /// Records an audit event for the client, along with the username it sent
+    pub async fn audit(&self, src: SocketAddr, event: AuditEvent) {
+        let username = match self.clients.read().await.get(&src.to_string()) {
+            Some(client) => client.username.clone(),
+            None => None,
         };
 
-        let payload = match ClientShakeAE::from_payload(encypyted_payload, priv_key) {
-            Ok(payload) => payload,
-            Err(e) => {
-                println!("Failed to Decrypt Client Shake AE Payload: {}", e);
-                return Err(AppConnectionError::ShakeAEDecryptionFailed);
-            }
+        self.audit.record(Some(src), username, event);
+    }
+
+    /// Session state of the client, only the controller is sent the control requests
+    fn session_state(
+        &self,
+        clients: &HashMap<String, AppClient>,
+        client: &AppClient,
+    ) -> SessionStateSE {
+        let controller = match clients.values().find(|client| client.is_controller()) {
+            Some(controller) => Some(controller.session_member()),
+            None => self.web_controller.read().ok().and_then(|web_controller| {
+                web_controller.as_ref().map(|peer| peer.session_member())
+            }),
         };
 
-        debug!("Client Shake AE by User: {:?}", payload.username);
-        match self.users.load() {
-            Ok(_) => {
-                if self
-                    .users
-                    .find_user_by_credentials(&payload.username, &payload.pass)
-                    .is_none()
-                {
-                    return Err(AppConnectionError::InvalidCredentials);
-                }
-            }
-            Err(_) => {
-                return Err(AppConnectionError::FailedToLoadUsers);
-            }
-        }
-        debug!("User Found, Authenticated");
+        let mut requests: Vec<&AppClient> = match client.is_controller() {
+            true => clients
+                .values()
+                .filter(|client| client.is_connected() && client.control_requested.is_some())
+                .collect(),
+            false => Vec::new(),
+        };
+        requests.sort_by_key(|client| client.control_requested);
+
+        let mut state = SessionStateSE {
+            role: client.role,
+            controller,
+            requests: requests
+                .iter()
+                .take(MAX_SESSION_REQUESTS)
+                .map(|client| client.session_member())
+                .collect(),
+        };
 
-        let mut clients = self.clients.write().await;
-        if let Some(client) = clients.get_mut(&src_str) {
-            let sym_key_vec = STANDARD_NO_PAD.decode(&payload.sym_key).unwrap();
-            let sym_key = ChaCha20Poly1305::new_from_slice(&sym_key_vec).unwrap();
+        while SessionStateSE::encrypted_len(&state) > PAYLOAD && state.requests.pop().is_some() {}
+        state
+    }
 
-            client.connected = true;
-            *client.sym_key.write().await = Some(sym_key.clone());
+    /// Sends every connected client its session state, after control changed
+    pub async fn send_session_states(&self) {
+        let mut packets = Vec::new();
+
+        let clients = self.clients.read().await;
+        for client in clients.values().filter(|client| client.is_connected()) {
+            let sym_key = client.sym_key.read().await.clone();
 
             let mut buf = [0u8; MTU];
+            let payload_len = match SessionStateSE::write_payload(
+                &mut buf[HEADER..],
+                sym_key,
+                &self.session_state(&clients, client),
+            ) {
+                Ok(len) => len,
+                Err(e) => {
+                    debug!("Failed to Write Session State for Client {}: {}", client.src, e);
+                    continue;
+                }
+            };
             write_header(
-                EPacketType::ShookSE,
-                0,
+                EPacketType::SessionState,
                 0,
+                (HEADER + payload_len) as u32,
                 0,
                 &mut buf,
             );
 
-            #[cfg(target_os = "linux")]
-            let mut widths = vec![0u16; 0];
-            #[cfg(target_os = "linux")]
-            let mut heights = vec![0u16; 0];
-
-            #[cfg(not(target_os = "linux"))]
-            let widths = vec![0u16; 0];
-            #[cfg(not(target_os = "linux"))]
-            let heights = vec![0u16; 0];
+            packets.push((client.src, buf[..HEADER + payload_len].to_vec()));
+        }
+        drop(clients);
 
-            // TODO: Windows and MacOS implementation needed
-            #[cfg(target_os = "linux")]
-            if let Ok((w, h)) = DisplayMeta::get_display_resolutions() {
-                widths = w;
-                heights = h;
+        for (src, packet) in packets {
+            if let Err(e) = self.socket.send_to(&packet, src).await {
+                debug!("Failed to Send Session State to Client {}: {}", src, e);
             }
+        }
+    }
 
-            let wrapped_sym_key = client.sym_key.read().await.clone();
-            let payload_len = match ServerShookSE::write_payload(
-                &mut buf[HEADER..],
-                wrapped_sym_key,
-                &ServerShookSE {
-                    server_state: ServerStatePayload {
-                        widths,
-                        heights,
-                        width: 0,
-                        height: 0,
-                        version: env!("CARGO_PKG_VERSION").to_string(),
-                    },
-                },
-            ) {
-                Ok(len) => len,
-                Err(_) => {
-                    return Err(AppConnectionError::Unexpected(
-                        "Failed to Write Server Shook SE Payload".to_string(),
-                    ));
-                }
-            };
-            debug!("Server Shook SE Payload Len: {}", payload_len);
-
-            if let Err(e) = self
-                .socket
-                .send_to(&buf[..HEADER + payload_len], &src)
-                .await
+    /// Makes the client the controller, the previous controller becomes a spectator
+    fn grant_control_locked(
+        &self,
+        clients: &mut HashMap<String, AppClient>,
+        src: SocketAddr,
+        by: &str,
+    ) -> bool {
+        if !clients
+            .get(&src.to_string())
+            .is_some_and(|client| client.is_connected())
+        {
+            return false;
+        }
+
+        for client in clients.values_mut().filter(|client| client.is_controller()) {
+            client.set_role(ESessionRole::Spectator);
+        }
+
+        let web_controller = self
+            .web_controller
+            .write()
+            .ok()
+            .and_then(|mut web_controller| web_controller.take());
+        if let Some(peer) = web_controller {
+            info!("Control Revoked from Web Peer {} by {}", peer.id, by);
+            self.audit.record(
+                Some(peer.src),
+                Some(peer.username),
+                AuditEvent::ControlRevoked { by: by.to_string() },
+            );
+        }
+
+        if let Some(client) = clients.get_mut(&src.to_string()) {
+            client.set_role(ESessionRole::Controller);
+
+            info!("Control Granted to Client {} by {}", src, by);
+            self.audit.record(
+                Some(src),
+                client.username.clone(),
+                AuditEvent::ControlGranted { by: by.to_string() },
+            );
+        }
+
+        true
+    }
+
+    /// Leaves the session without a controller
+    fn revoke_control_locked(&self, clients: &mut HashMap<String, AppClient>, by: &str) -> bool {
+        let mut revoked = false;
+
+        for client in clients.values_mut().filter(|client| client.is_controller()) {
+            client.set_role(ESessionRole::Spectator);
+            revoked = true;
+
+            info!("Control Revoked from Client {} by {}", client.src, by);
+            self.audit.record(
+                Some(client.src),
+                client.username.clone(),
+                AuditEvent::ControlRevoked { by: by.to_string() },
+            );
+        }
+
+        revoked
+    }
+
+    /// Grants control to an app client, as requested over the control socket
+    pub async fn grant_control(&self, src: SocketAddr) -> bool {
+        let granted = self.grant_control_locked(&mut *self.clients.write().await, src, "admin");
+
+        if granted {
+            self.send_session_states().await;
+        }
+        granted
+    }
+
+    /// Revokes control from the controller, as requested over the control socket
+    pub async fn revoke_control(&self) -> bool {
+        let revoked = self.revoke_control_locked(&mut *self.clients.write().await, "admin");
+
+        if revoked {
+            self.send_session_states().await;
+        }
+        revoked
+    }
+
+    /// Handles a session control message of a client
+    pub async fn session_control(&self, src: SocketAddr, payload: &[u8]) {
+        let mut sym_key = match self.get_sym_key(src).await {
+            Some(sym_key) => sym_key,
+            None => return,
+        };
+
+        let control = match SessionControlSE::from_payload(payload, &mut sym_key) {
+            Ok(control) => control,
+            Err(e) => {
+                debug!("Invalid Session Control from Client {}: {}", src, e);
+                return;
+            }
+        };
+
+        let mut clients = self.clients.write().await;
+        let is_controller = clients
+            .get(&src.to_string())
+            .is_some_and(|client| client.is_controller());
+
+        let changed = match control {
+            SessionControlSE::Request if is_controller => false,
+            SessionControlSE::Request => {
+                if self.has_web_controller()
+                    || clients.values().any(|client| client.is_controller())
+                {
+                    match clients.get_mut(&src.to_string()) {
+                        Some(client) if client.control_requested.is_none() => {
+                            info!("Client {} Requested Control", src);
+                            client.control_requested = Some(SystemTime::now());
+                            true
+                        }
+                        _ => false,
+                    }
+                } else {
+                    self.grant_control_locked(&mut clients, src, "request")
+                }
+            }
+            SessionControlSE::Grant { address } if is_controller => {
+                match address.parse::<SocketAddr>() {
+                    Ok(address) => self.grant_control_locked(&mut clients, address, "controller"),
+                    Err(_) => false,
+                }
+            }
+            SessionControlSE::Revoke if is_controller => {
+                self.revoke_control_locked(&mut clients, "controller")
+            }
+            control => {
+                warn!("Dropped {:?} from Client {}, It Is Not in Control", control, src);
+                false
+            }
+        };
+        drop(clients);
+
+        if changed {
+            self.send_session_states().await;
+        }
+    }
+
+    /// Whether input of the client is applied, only the controller's input is.
+    /// The first input of a spectator since its role changed is audited as a role violation.
+    pub async fn check_control(&self, src: SocketAddr) -> bool {
+        match self.clients.read().await.get(&src.to_string()) {
+            Some(client) if client.is_controller() => return true,
+            Some(client) if client.is_connected() && !client.role_violation_audited => {}
+            _ => return false,
+        }
+
+        if let Some(client) = self.clients.write().await.get_mut(&src.to_string()) {
+            client.role_violation_audited = true;
+
+            warn!("Dropped Input from Spectator {}", src);
+            self.audit.record(
+                Some(src),
+                client.username.clone(),
+                AuditEvent::RoleViolation {
+                    role: format!("{:?}", client.role),
+                },
+            );
+        }
+
+        false
+    }
+
+    fn has_web_controller(&self) -> bool {
+        self.web_controller
+            .read()
+            .is_ok_and(|web_controller| web_controller.is_some())
+    }
+
+    /// Whether an app client is in control of the session
+    pub async fn has_controller(&self) -> bool {
+        self.clients
+            .read()
+            .await
+            .values()
+            .any(|client| client.is_controller())
+    }
+
+    /// Whether the client is in control of the session
+    pub async fn is_controller(&self, src: SocketAddr) -> bool {
+        self.clients
+            .read()
+            .await
+            .get(&src.to_string())
+            .is_some_and(|client| client.is_controller())
+    }
+
+    async fn get_client_priv_key(&self, src_str: &String) -> Option<RsaPrivateKey> {
+        let clients = self.clients.read().await;
+
+        if let Some(client) = clients.get(src_str) {
+            return client.priv_key.clone();
+        }
+
+        return None;
+    }
+
+    /// Session key of the client, if it is connected
+    pub async fn get_sym_key(&self, src: SocketAddr) -> Option<ChaCha20Poly1305> {
+        match self.clients.read().await.get(&src.to_string()) {
+            Some(client) if client.is_connected() => client.sym_key.read().await.clone(),
+            _ => None,
+        }
+    }
+
+    /// Empties the frames of the retransmit caches that are too old to be retransmitted
+    pub async fn evict_retransmit_caches(&self) {
+        let clients = self.clients.read().await;
+
+        for client in clients.values() {
+            let cache = match &client.retransmit_cache {
+                Some(cache) => cache,
+                None => continue,
+            };
+
+            let _evicted = cache.evict_expired();
+
+            #[cfg(feature = "stat")]
+            debug!(
+                "Retransmit Cache of Client {}: {} Bytes, Evicted {} Bytes, {} Hits, {} Misses",
+                client.src,
+                cache.bytes(),
+                _evicted,
+                cache.hits(),
+                cache.misses()
+            );
+        }
+    }
+
+    pub async fn retransmit_frame(
+        &self,
+        src: SocketAddr,
+        frame_id: u8,
+        real_packet_size: u32,
+        subpacket_ids: Vec<u16>) {
+
+        let (stats, retransmit_cache) = match self.clients.write().await.get_mut(&src.to_string()) {
+            Some(client) if client.is_connected() => {
+                client.last_ping = SystemTime::now();
+                match &client.retransmit_cache {
+                    Some(retransmit_cache) => (client.stats.clone(), retransmit_cache.clone()),
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+
+        let mut buf = [0u8; MTU];
+        for subpacket_id in subpacket_ids {
+            let len = match retransmit_cache.get(frame_id, real_packet_size, subpacket_id, &mut buf) {
+                Some(len) => len,
+                None => {
+                    debug!("Cache not found for Frame: {} Size: {} Subpacket ID: {}", frame_id, real_packet_size, subpacket_id);
+                    continue;
+                }
+            };
+
+            if self.socket.send_to(&buf[..len], src).await.is_err() {
+                self.remove_client(src).await;
+                return;
+            }
+            stats.sent(len);
+        }
+    }
+
+    async fn send_shook_se(&self, src: SocketAddr) -> Result<(), AppConnectionError> {
+        let clients = self.clients.read().await;
+        let client = match clients.get(&src.to_string()) {
+            Some(client) => client,
+            None => {
+                return Err(AppConnectionError::Unexpected(
+                    "Client Not Found in Clients HashMap".to_string(),
+                ));
+            }
+        };
+
+        let mut buf = [0u8; MTU];
+        write_header(EPacketType::ShookSE, 0, 0, 0, &mut buf);
+
+        let meta = self.meta.read().await.clone();
+        let mut monitors = match DisplayMeta::get_monitors() {
+            Ok(monitors) => monitors,
+            Err(e) => {
+                debug!("Error listing monitors: {}", e);
+                Vec::new()
+            }
+        };
+
+        let (widths, heights, streamed) =
+            match DisplayMeta::find_monitor(&monitors, meta.monitor.as_deref()) {
+                Some(monitor) => (
+                    monitor.widths.clone(),
+                    monitor.heights.clone(),
+                    Some(monitor.name.clone()),
+                ),
+                None => (Vec::new(), Vec::new(), None),
+            };
+
+        // The resolutions of every monitor follow in packets of their own
+        let modes: Vec<MonitorModesSE> = monitors
+            .iter_mut()
+            .map(|monitor| MonitorModesSE {
+                streamed: streamed.as_ref() == Some(&monitor.name),
+                name: monitor.name.clone(),
+                widths: std::mem::take(&mut monitor.widths),
+                heights: std::mem::take(&mut monitor.heights),
+            })
+            .collect();
+
+        let mut shook = ServerShookSE {
+            server_state: ServerStatePayload {
+                widths,
+                heights,
+                width: meta.width as u16,
+                height: meta.height as u16,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                monitors,
+                monitor: meta.monitor,
+            },
+            keepalive: self.keepalive(),
+            session: self.session_state(&clients, client),
+        };
+
+        // Resolutions, which are sent again with the modes, go first, then requests and monitors
+        while ServerShookSE::encrypted_len(&shook) > PAYLOAD {
+            let state = &mut shook.server_state;
+            if state.widths.pop().is_some() {
+                state.heights.pop();
+            } else if shook.session.requests.pop().is_none() && state.monitors.pop().is_none() {
+                break;
+            }
+        }
+
+        let wrapped_sym_key = client.sym_key.read().await.clone();
+        let payload_len =
+            match ServerShookSE::write_payload(&mut buf[HEADER..], wrapped_sym_key.clone(), &shook)
             {
+                Ok(len) => len,
+                Err(_) => {
+                    return Err(AppConnectionError::Unexpected(
+                        "Failed to Write Server Shook SE Payload".to_string(),
+                    ));
+                }
+            };
+        debug!("Server Shook SE Payload Len: {}", payload_len);
+
+        if let Err(e) = self
+            .socket
+            .send_to(&buf[..HEADER + payload_len], src)
+            .await
+        {
+            return Err(AppConnectionError::Unexpected(e.to_string()));
+        }
+
+        for mut modes in modes {
+            while MonitorModesSE::encrypted_len(&modes) > PAYLOAD && modes.widths.pop().is_some() {
+                modes.heights.pop();
+            }
+
+            let mut buf = [0u8; MTU];
+            let payload_len = match MonitorModesSE::write_payload(
+                &mut buf[HEADER..],
+                wrapped_sym_key.clone(),
+                &modes,
+            ) {
+                Ok(len) => len,
+                Err(e) => {
+                    debug!("Failed to Write Modes of Monitor {}: {}", modes.name, e);
+                    continue;
+                }
+            };
+            write_header(
+                EPacketType::MonitorModes,
+                0,
+                (HEADER + payload_len) as u32,
+                0,
+                &mut buf,
+            );
+
+            if let Err(e) = self.socket.send_to(&buf[..HEADER + payload_len], src).await {
                 return Err(AppConnectionError::Unexpected(e.to_string()));
             }
+        }
+
+        // TODO: Send NAL Header
+        // let header_bytes = match headers.lock() {
+        //     Ok(headers) => headers.clone(),
+        //     Err(_) => None,
+        // };
 
-            // TODO: Send NAL Header
-            // let header_bytes = match headers.lock() {
-            //     Ok(headers) => headers.clone(),
-            //     Err(_) => None,
-            // };
+        Ok(())
+    }
+
+    async fn send_challenge(
+        &self,
+        src: SocketAddr,
+        challenge: &ServerChallengeSE,
+    ) -> Result<(), AppConnectionError> {
+        let sym_key = match self.clients.read().await.get(&src.to_string()) {
+            Some(client) => client.sym_key.read().await.clone(),
+            None => None,
+        };
+
+        let mut buf = [0u8; MTU];
+        let payload_len = match ServerChallengeSE::write_payload(&mut buf[HEADER..], sym_key, challenge)
+        {
+            Ok(len) => len,
+            Err(e) => return Err(AppConnectionError::Unexpected(e.to_string())),
+        };
+        write_header(
+            EPacketType::ShakeChallenge,
+            0,
+            (HEADER + payload_len) as u32,
+            0,
+            &mut buf,
+        );
 
-            return Ok(payload.state);
+        if let Err(e) = self
+            .socket
+            .send_to(&buf[..HEADER + payload_len], src)
+            .await
+        {
+            return Err(AppConnectionError::Unexpected(e.to_string()));
         }
+        debug!("Sent {:?} Challenge to Client: {}", challenge.method, src);
 
-        return Err(AppConnectionError::Unexpected(
-            "Client Not Found in Clients HashMap".to_string(),
-        ));
+        Ok(())
+    }
+
+    /// Issues the next auth challenge to the client, or marks it as connected
+    /// and completes the handshake when no challenges are left.
+    async fn next_auth_step(
+        &self,
+        src: SocketAddr,
+        challenge: Option<ServerChallengeSE>,
+        username: String,
+        state: ClientStatePayload,
+    ) -> Result<Option<ClientStatePayload>, AppConnectionError> {
+        let src_str = src.to_string();
+
+        if let Some(challenge) = challenge {
+            // Stored first, the response can arrive before sending returns
+            if let Some(client) = self.clients.write().await.get_mut(&src_str) {
+                client.pending_auth = Some(PendingAuth {
+                    challenge: challenge.clone(),
+                    username,
+                    state,
+                });
+            }
+
+            self.send_challenge(src, &challenge).await?;
+            return Ok(None);
+        }
+        self.lockout
+            .clear(self.observed_address(src).ip(), &username);
+
+        let mut clients = self.clients.write().await;
+        let has_controller = self.has_web_controller()
+            || clients
+                .values()
+                .any(|client| client.is_controller() && client.src != src);
+
+        match clients.get_mut(&src_str) {
+            Some(client) => {
+                client.connected = true;
+                client.connected_at = SystemTime::now();
+                client.set_role(match client.requested_role {
+                    ESessionRole::Controller if !has_controller => ESessionRole::Controller,
+                    _ => ESessionRole::Spectator,
+                });
+                client.retransmit_cache = Some(Arc::new(RetransmitCache::new(
+                    self.retransmit_cache_settings(),
+                )));
+            }
+            None => {
+                return Err(AppConnectionError::Unexpected(
+                    "Client Not Found in Clients HashMap".to_string(),
+                ));
+            }
+        }
+        drop(clients);
+        self.profiles_changed();
+
+        debug!("User Authenticated: {}", username);
+        self.audit.record(
+            Some(src),
+            Some(username),
+            AuditEvent::HandshakeSuccess {
+                width: state.width,
+                height: state.height,
+            },
+        );
+
+        self.send_shook_se(src).await?;
+        self.send_session_states().await;
+
+        Ok(Some(state))
+    }
+
+    fn totp_challenge(user: &User) -> Option<ServerChallengeSE> {
+        user.totp.as_ref().map(|_| ServerChallengeSE {
+            method: EAuthMethod::Totp,
+            challenge: String::new(),
+        })
+    }
+
+    /// Authenticates the Shake AE payload of a client. Returns `None` when the
+    /// client has been sent an auth challenge it must answer before it is connected.
+    pub async fn connect_client(
+        &mut self,
+        src: SocketAddr,
+        encypyted_payload: &[u8],
+        _headers: HashMap<StreamProfile, Vec<u8>>,
+    ) -> Result<Option<ClientStatePayload>, AppConnectionError> {
+        let src_str = src.to_string();
+
+        let priv_key = match self.get_client_priv_key(&src_str).await {
+            Some(priv_key) => priv_key,
+            None => {
+                return Err(AppConnectionError::ClientPrivateKeyNotFound);
+            }
+        };
+
+        let payload = match ClientShakeAE::from_payload(encypyted_payload, priv_key) {
+            Ok(payload) => payload,
+            Err(e) => {
+                println!("Failed to Decrypt Client Shake AE Payload: {}", e);
+                return Err(AppConnectionError::ShakeAEDecryptionFailed);
+            }
+        };
+
+        debug!("Client Shake AE by User: {:?}", payload.username);
+        let key_auth = payload.pass.is_empty();
+
+        if let Some(client) = self.clients.write().await.get_mut(&src_str) {
+            client.username = Some(payload.username.clone());
+            client.requested_role = payload.role;
+        }
+        self.audit.record(
+            Some(src),
+            Some(payload.username.clone()),
+            AuditEvent::HandshakeAttempt {
+                method: match key_auth {
+                    true => "public_key".to_string(),
+                    false => "password".to_string(),
+                },
+            },
+        );
+        let observed = self.observed_address(src);
+        if self.lockout.is_locked(observed.ip(), &payload.username) {
+            return Err(AppConnectionError::LockedOut);
+        }
+
+        let user = match self.users.load() {
+            Ok(_) => {
+                let user = match key_auth {
+                    true => self.users.find(payload.username.clone()),
+                    false => self
+                        .users
+                        .find_user_by_credentials(&payload.username, &payload.pass),
+                };
+
+                match user {
+                    Some(user) if !key_auth || !user.keys.is_empty() => user,
+                    _ => return Err(AppConnectionError::InvalidCredentials),
+                }
+            }
+            Err(_) => {
+                return Err(AppConnectionError::FailedToLoadUsers);
+            }
+        };
+
+        if let Err(denied) = self.access.check_user(observed.ip(), &user.username) {
+            return Err(AppConnectionError::AccessDenied(denied));
+        }
+
+        let sym_key_vec = match STANDARD_NO_PAD.decode(&payload.sym_key) {
+            Ok(sym_key_vec) => sym_key_vec,
+            Err(e) => return Err(AppConnectionError::Unexpected(e.to_string())),
+        };
+        let sym_key = match ChaCha20Poly1305::new_from_slice(&sym_key_vec) {
+            Ok(sym_key) => sym_key,
+            Err(e) => return Err(AppConnectionError::Unexpected(e.to_string())),
+        };
+
+        match self.clients.read().await.get(&src_str) {
+            Some(client) => *client.sym_key.write().await = Some(sym_key),
+            None => {
+                return Err(AppConnectionError::Unexpected(
+                    "Client Not Found in Clients HashMap".to_string(),
+                ));
+            }
+        }
+
+        let challenge = match key_auth {
+            true => Some(ServerChallengeSE {
+                method: EAuthMethod::PublicKey,
+                challenge: generate_challenge(),
+            }),
+            false => Self::totp_challenge(&user),
+        };
+
+        self.next_auth_step(src, challenge, payload.username, payload.state)
+            .await
+    }
+
+    /// Verifies the response of a client to the auth challenge it was last issued,
+    /// returning `None` if another challenge had to be issued after it.
+    pub async fn verify_challenge_response(
+        &mut self,
+        src: SocketAddr,
+        encypyted_payload: &[u8],
+    ) -> Result<Option<ClientStatePayload>, AppConnectionError> {
+        let src_str = src.to_string();
+
+        let (sym_key, pending_auth) = match self.clients.write().await.get_mut(&src_str) {
+            Some(client) => (client.sym_key.read().await.clone(), client.pending_auth.take()),
+            None => (None, None),
+        };
+
+        let (mut sym_key, pending_auth) = match (sym_key, pending_auth) {
+            (Some(sym_key), Some(pending_auth)) => (sym_key, pending_auth),
+            _ => return Err(AppConnectionError::ChallengeNotFound),
+        };
+
+        let response = match ClientResponseSE::from_payload(encypyted_payload, &mut sym_key) {
+            Ok(response) if response.method == pending_auth.challenge.method => response,
+            _ => return Err(AppConnectionError::InvalidChallengeResponse),
+        };
+
+        if self.users.load().is_err() {
+            return Err(AppConnectionError::FailedToLoadUsers);
+        }
+
+        let user = match self.users.find(pending_auth.username.clone()) {
+            Some(user) => user,
+            None => return Err(AppConnectionError::InvalidCredentials),
+        };
+
+        let challenge = match response.method {
+            EAuthMethod::PublicKey => {
+                if !user.keys.contains(&response.pub_key) {
+                    return Err(AppConnectionError::InvalidCredentials);
+                }
+
+                if verify_challenge(
+                    &response.pub_key,
+                    &pending_auth.challenge.challenge,
+                    &response.response,
+                )
+                .is_err()
+                {
+                    return Err(AppConnectionError::InvalidChallengeResponse);
+                }
+
+                Self::totp_challenge(&user)
+            }
+            EAuthMethod::Totp => {
+                let mut user_totp = match user.totp {
+                    Some(user_totp) => user_totp,
+                    None => return Err(AppConnectionError::ChallengeNotFound),
+                };
+
+                let step = match totp::verify_code(
+                    &user.username,
+                    &user_totp.secret,
+                    &response.response,
+                    user_totp.last_step,
+                ) {
+                    Some(step) => step,
+                    None => return Err(AppConnectionError::InvalidTotpCode),
+                };
+
+                user_totp.last_step = step;
+                if let Err(e) = self
+                    .users
+                    .set_totp(&user.username, Some(user_totp))
+                    .and_then(|_| self.users.save())
+                {
+                    return Err(AppConnectionError::Unexpected(e.to_string()));
+                }
+
+                None
+            }
+        };
+        debug!("Client: {} Passed {:?} Challenge", src, response.method);
+
+        self.next_auth_step(src, challenge, pending_auth.username, pending_auth.state)
+            .await
+    }
+
+    /// Counts a failed authentication towards locking out the address and user of the client
+    pub async fn auth_failed(&self, src: SocketAddr, error: &AppConnectionError) {
+        if !error.is_auth_failure() {
+            return;
+        }
+
+        let username = match self.clients.read().await.get(&src.to_string()) {
+            Some(client) => client.username.clone(),
+            None => None,
+        };
+        self.lockout
+            .record_failure(self.observed_address(src).ip(), username.as_deref());
     }
 
     pub async fn initialize_client(&self, src: SocketAddr) -> Result<(), std::io::Error> {
@@ -523,11 +1653,17 @@ impl AppConnection {
         let pub_key = RsaPublicKey::from(&priv_key);
         let pub_key_str = pub_key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF).unwrap();
 
-        self.clients
+        let previous = self
+            .clients
             .write()
             .await
             .insert(src_str.clone(), AppClient::new(src, priv_key));
 
+        // A client handshaking again is not connected until it authenticates
+        if previous.is_some_and(|client| client.is_connected()) {
+            self.profiles_changed();
+        }
+
         let mut buf = [0u8; MTU];
         write_header(
             EPacketType::ShookUE,
@@ -537,11 +1673,23 @@ impl AppConnection {
             &mut buf,
         );
 
+        // The player checks the signature against the host key it trusts for the server
+        let signature = self.host_key.as_ref().and_then(|host_key| {
+            sign_session_key(&host_key.secret_key, &pub_key_str)
+                .map_err(|e| error!("Failed to Sign Session Key: {}", e))
+                .ok()
+        });
+
         let mut amt = HEADER;
 
         amt += ServerShookUE::write_payload(
             &mut buf[HEADER..],
             &ServerShookUE {
+                host_key: signature
+                    .as_ref()
+                    .and(self.host_key.as_ref())
+                    .map(|host_key| host_key.public_key.clone()),
+                signature,
                 pub_key: pub_key_str,
             },
         );
@@ -562,47 +1710,36 @@ impl AppConnection {
                     Handle::current(),
                     self.socket.clone(),
                     self.clients.clone(),
-                    self.subpacket_cache.clone(),
                     self.broadcast_receiver.clone(),
                 ));
             }
         }
     }
 
+    /// Sends the frame to the broadcast task, which encrypts it with the session key
+    /// of each connected client, or only those that requested one of the profiles.
     #[inline]
     pub async fn broadcast_encrypted_frame(
         &self,
         packet_type: EPacketType,
         packet_type_variant: PacketTypeVariant,
         buf: &[u8],
+        profiles: Option<Vec<StreamProfile>>,
     ) -> Result<(), BroadcastTaskError> {
-        let sym_key = match self.get_sym_key().await {
-            Some(sym_key) => sym_key,
-            None => {
-                return Err(BroadcastTaskError::EncryptionFailed(
-                    "Symmetric Key Not Found".to_string(),
-                ));
+        if let Ok(task) = self.broadcast_task.read() {
+            if task.is_none() {
+                return Err(BroadcastTaskError::TaskNotRunning);
             }
-        };
-
-        match encrypt_frame(&sym_key, buf) {
-            Ok(encrypted_frame) => {
-                if let Ok(task) = self.broadcast_task.read() {
-                    if task.is_none() {
-                        return Err(BroadcastTaskError::TaskNotRunning);
-                    }
-                }
-
-                if let Err(e) = self.broadcast_sender.send((packet_type, packet_type_variant ,encrypted_frame)) {
-                    return Err(BroadcastTaskError::TransferFailed(e.to_string()));
-                }
+        }
 
-                return Ok(());
-            }
-            Err(e) => {
-                return Err(BroadcastTaskError::EncryptionFailed(e.to_string()));
-            }
+        if let Err(e) = self
+            .broadcast_sender
+            .send((packet_type, packet_type_variant, buf.to_owned(), profiles))
+        {
+            return Err(BroadcastTaskError::TransferFailed(e.to_string()));
         }
+
+        Ok(())
     }
 
     #[inline]
@@ -618,6 +1755,142 @@ impl AppConnection {
         }
     }
 
+    pub fn relay(&self) -> Option<SocketAddr> {
+        self.relay
+            .read()
+            .ok()
+            .and_then(|relay| relay.as_ref().map(|(address, _)| *address))
+    }
+
+    fn relay_name(&self) -> Option<String> {
+        self.relay
+            .read()
+            .ok()
+            .and_then(|relay| relay.as_ref().map(|(_, name)| name.clone()))
+    }
+
+    pub fn set_relay(&self, relay: Option<SocketAddr>, name: String) {
+        if let Ok(mut current) = self.relay.write() {
+            *current = relay.map(|relay| (relay, name));
+        }
+    }
+
+    /// Registration under the name, carrying the signature of the nonce when answering
+    /// a challenge of the relay. None without a host key, which relays require.
+    pub fn relay_registration(&self, name: String, nonce: Option<&str>) -> Option<RelayMessage> {
+        let host_key = self.host_key.as_ref()?;
+
+        let signature = match nonce {
+            Some(nonce) => match sign_relay_registration(&host_key.secret_key, nonce, &name) {
+                Ok(signature) => Some(signature),
+                Err(e) => {
+                    error!("Failed to Sign Relay Challenge: {}", e);
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        Some(RelayMessage::Register {
+            name,
+            host_key: host_key.public_key.clone(),
+            signature,
+        })
+    }
+
+    /// Sends a relay message from the app sockets, so the relay and players
+    /// punching through see the address clients connect to.
+    pub async fn send_relay_message(&self, message: &RelayMessage, target: SocketAddr) {
+        let mut buf = [0u8; MTU];
+        let len = message.write_packet(&mut buf);
+
+        if let Err(e) = self.socket.send_to(&buf[..len], target).await {
+            debug!("Failed to Send Relay Message to {}: {}", target, e);
+        }
+    }
+
+    /// Address access and lockout checks are run on, the address the relay observed
+    /// the player at for players relayed through it
+    pub fn observed_address(&self, src: SocketAddr) -> SocketAddr {
+        self.relayed.observed_address(src)
+    }
+
+    /// Punches through to a player introduced by the relay, and binds the server
+    /// to the relay session in case the player falls back to it.
+    fn punch(&self, token: u64, direct: SocketAddr, relayed: SocketAddr) {
+        let app = self.clone();
+
+        Handle::current().spawn(async move {
+            let punch = RelayMessage::Punch {
+                token,
+                server: true,
+            };
+
+            for _ in 0..RELAY_PUNCH_TIMEOUT_MS / RELAY_PUNCH_INTERVAL_MS {
+                app.send_relay_message(&punch, direct).await;
+                app.send_relay_message(&punch, relayed).await;
+                tokio::time::sleep(Duration::from_millis(RELAY_PUNCH_INTERVAL_MS)).await;
+            }
+        });
+    }
+
+    pub async fn relay_message(&self, src: SocketAddr, buf: &[u8]) {
+        let message = match RelayMessage::parse_packet(buf) {
+            Some(message) => message,
+            None => return,
+        };
+
+        // Players punching through only open the NAT, nothing is answered
+        if matches!(message, RelayMessage::Punch { .. }) {
+            return;
+        }
+
+        let relay = match self.relay() {
+            Some(relay) if relay == src => relay,
+            _ => {
+                debug!("Ignored Relay Message from {}", src);
+                return;
+            }
+        };
+
+        match message {
+            RelayMessage::Challenge { nonce } => {
+                let registration = self
+                    .relay_name()
+                    .and_then(|name| self.relay_registration(name, Some(&nonce)));
+
+                if let Some(registration) = registration {
+                    self.send_relay_message(&registration, relay).await;
+                }
+            }
+            RelayMessage::Registered { name } => {
+                debug!("Registered with Relay {} as \"{}\"", relay, name);
+            }
+            RelayMessage::Peer {
+                token,
+                direct,
+                relay_port,
+                ..
+            } => {
+                let relayed = SocketAddr::new(relay.ip(), relay_port);
+                if !self.relayed.introduce(relayed, direct) {
+                    warn!(
+                        "Relay Introduced Player {} Too Often, Not Punching Through",
+                        direct
+                    );
+                    return;
+                }
+
+                info!("Relay Introduced Player {}, Punching Through", direct);
+                self.punch(token, direct, relayed);
+            }
+            RelayMessage::Error { message } => {
+                warn!("Relay {} Error: {}", relay, message);
+            }
+            _ => {}
+        }
+    }
+
     #[inline]
     pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), std::io::Error> {
         self.socket.recv_from(buf).await
@@ -631,11 +1904,155 @@ impl Clone for AppConnection {
             broadcast_receiver: self.broadcast_receiver.clone(),
             broadcast_task: self.broadcast_task.clone(),
 
-            subpacket_cache: self.subpacket_cache.clone(),
 
             socket: self.socket.clone(),
             clients: self.clients.clone(),
             users: self.users.clone(),
+            access: self.access.clone(),
+            audit: self.audit.clone(),
+            lockout: self.lockout.clone(),
+            keepalive: self.keepalive.clone(),
+            retransmit_cache_settings: self.retransmit_cache_settings.clone(),
+            relay: self.relay.clone(),
+            relayed: self.relayed.clone(),
+            meta: self.meta.clone(),
+            profiles_version: self.profiles_version.clone(),
+            web_controller: self.web_controller.clone(),
+            host_key: self.host_key.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::UdpSocket, time::timeout};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    async fn test_connection(dir: &TestDir) -> AppConnection {
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+
+        AppConnection::new(
+            &config,
+            dir.path(),
+            AccessControl::new(),
+            AuditLog::new_with_custom_dir(dir.path().to_path_buf()),
+            Arc::new(RwLock::new(ServerMeta::default())),
+            WebController::default(),
+        )
+        .await
+    }
+
+    /// Connects a client receiving frames on a socket of its own, with its own session key
+    async fn connect_client(conn: &AppConnection, sym_key: &ChaCha20Poly1305) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = socket.local_addr().unwrap();
+
+        let priv_key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let mut client = AppClient::new(src, priv_key);
+        client.connected = true;
+        *client.sym_key.write().await = Some(sym_key.clone());
+        conn.clients.write().await.insert(src.to_string(), client);
+
+        socket
+    }
+
+    #[tokio::test]
+    async fn frames_are_encrypted_with_the_key_of_each_client() {
+        let dir = TestDir::new("app-broadcast");
+        let conn = test_connection(&dir).await;
+
+        let key_a = ChaCha20Poly1305::new(&[1u8; 32].into());
+        let key_b = ChaCha20Poly1305::new(&[2u8; 32].into());
+        let socket_a = connect_client(&conn, &key_a).await;
+        let socket_b = connect_client(&conn, &key_b).await;
+
+        conn.start_broadcast_async_task();
+        conn.broadcast_encrypted_frame(EPacketType::AudioPCM, 0, b"frame", None)
+            .await
+            .unwrap();
+
+        let clients = [(socket_a, &key_a, &key_b), (socket_b, &key_b, &key_a)];
+        for (socket, sym_key, other_key) in clients {
+            let mut buf = [0u8; MTU];
+            let len = timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let encrypted_frame = &buf[HEADER..len];
+
+            assert_eq!(
+                decrypt_frame(sym_key, encrypted_frame).as_deref(),
+                Some(&b"frame"[..])
+            );
+            assert!(decrypt_frame(other_key, encrypted_frame).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn connected_clients_are_pinged() {
+        let dir = TestDir::new("app-pings");
+        let conn = test_connection(&dir).await;
+        let socket = connect_client(&conn, &ChaCha20Poly1305::new(&[1u8; 32].into())).await;
+
+        conn.send_pings().await;
+
+        let mut buf = [0u8; MTU];
+        let len = timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, HEADER);
+        assert_eq!(parse_packet_type(&buf), EPacketType::Ping);
+    }
+
+    #[tokio::test]
+    async fn clients_that_stopped_pinging_are_dropped() {
+        let dir = TestDir::new("app-keepalive");
+        let conn = test_connection(&dir).await;
+        let keepalive = conn.keepalive();
+
+        let sym_key = ChaCha20Poly1305::new(&[1u8; 32].into());
+        let pinging = connect_client(&conn, &sym_key).await.local_addr().unwrap();
+        let silent = connect_client(&conn, &sym_key).await.local_addr().unwrap();
+
+        let timed_out = SystemTime::now() - Duration::from_millis(keepalive.timeout_ms + 1);
+        for client in conn.clients.write().await.values_mut() {
+            client.last_ping = timed_out;
+        }
+        conn.received_ping(pinging).await;
+
+        conn.filter_clients().await;
+
+        let clients = conn.clients.read().await;
+        assert!(clients.contains_key(&pinging.to_string()));
+        assert!(!clients.contains_key(&silent.to_string()));
+    }
+
+    #[tokio::test]
+    async fn challenge_response_without_a_pending_challenge_fails() {
+        let dir = TestDir::new("app-challenge");
+        let mut conn = test_connection(&dir).await;
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+
+        assert!(matches!(
+            conn.verify_challenge_response(src, &[0u8; 64]).await,
+            Err(AppConnectionError::ChallengeNotFound)
+        ));
+
+        // A client past the key exchange that was never sent a challenge
+        let priv_key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let client = AppClient::new(src, priv_key);
+        *client.sym_key.write().await = Some(ChaCha20Poly1305::new(&[7u8; 32].into()));
+        conn.clients.write().await.insert(src.to_string(), client);
+
+        assert!(matches!(
+            conn.verify_challenge_response(src, &[0u8; 64]).await,
+            Err(AppConnectionError::ChallengeNotFound)
+        ));
+    }
+}
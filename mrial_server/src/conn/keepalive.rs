@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use tokio::{task::JoinHandle, time::sleep};
+
+use super::ConnectionManager;
+
+/// Pings the app clients and drops those that stopped pinging,
+/// independent of the video and audio being sent.
+pub struct KeepaliveTask {
+    conn: ConnectionManager,
+}
+
+impl KeepaliveTask {
+    async fn keepalive_loop(&self) {
+        let app = self.conn.get_app();
+
+        loop {
+            let interval = app.keepalive().interval_ms;
+            sleep(Duration::from_millis(interval)).await;
+
+            app.send_pings().await;
+            app.filter_clients().await;
+        }
+    }
+
+    pub fn run(conn: ConnectionManager) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            KeepaliveTask { conn }.keepalive_loop().await;
+        })
+    }
+}
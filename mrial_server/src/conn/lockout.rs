@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Failed authentications allowed for a user from an address before the user
+/// is locked out there. Other addresses can still authenticate as the user,
+/// so failures of someone else can not lock the user out everywhere.
+const MAX_USER_AUTH_FAILURES: u32 = 5;
+
+/// Failed authentications allowed for an address before it is locked out,
+/// higher than for a user as several users may share the address behind a NAT
+const MAX_ADDRESS_AUTH_FAILURES: u32 = 20;
+
+/// Failures are forgotten this long after the last one, which also ends a lockout
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(300);
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+struct FailureCounter<K> {
+    failures: HashMap<K, Failures>,
+    limit: u32,
+}
+
+impl<K: Hash + Eq> FailureCounter<K> {
+    fn new(limit: u32) -> Self {
+        Self {
+            failures: HashMap::new(),
+            limit,
+        }
+    }
+
+    fn is_locked(&mut self, key: &K) -> bool {
+        self.failures
+            .retain(|_, failures| failures.last.elapsed() < AUTH_FAILURE_WINDOW);
+
+        self.failures
+            .get(key)
+            .is_some_and(|failures| failures.count >= self.limit)
+    }
+
+    fn record(&mut self, key: K) {
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        failures.count += 1;
+        failures.last = Instant::now();
+    }
+
+    fn clear(&mut self, key: &K) {
+        self.failures.remove(key);
+    }
+}
+
+struct AuthLockoutState {
+    /// Keyed by the address a user authenticated from along with its name
+    users: FailureCounter<(IpAddr, String)>,
+    addresses: FailureCounter<IpAddr>,
+}
+
+/// Locks out users and addresses after repeated failed authentications,
+/// so passwords and TOTP codes can not be guessed at the rate packets arrive.
+/// Clones share the failures.
+#[derive(Clone)]
+pub struct AuthLockout {
+    state: Arc<Mutex<AuthLockoutState>>,
+}
+
+impl AuthLockout {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(AuthLockoutState {
+                users: FailureCounter::new(MAX_USER_AUTH_FAILURES),
+                addresses: FailureCounter::new(MAX_ADDRESS_AUTH_FAILURES),
+            })),
+        }
+    }
+
+    /// Whether the address, or the user at the address, is locked out
+    pub fn is_locked(&self, ip: IpAddr, username: &str) -> bool {
+        match self.state.lock() {
+            Ok(mut state) => {
+                // Both are checked so the expired failures of each are pruned
+                let address_locked = state.addresses.is_locked(&ip);
+                state.users.is_locked(&(ip, username.to_string())) || address_locked
+            }
+            Err(_) => true,
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, username: Option<&str>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.addresses.record(ip);
+            if let Some(username) = username {
+                state.users.record((ip, username.to_string()));
+            }
+        }
+    }
+
+    pub fn clear(&self, ip: IpAddr, username: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.addresses.clear(&ip);
+            state.users.clear(&(ip, username.to_string()));
+        }
+    }
+}
+
+impl Default for AuthLockout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn locks_out_user_at_the_address_after_max_failures() {
+        let lockout = AuthLockout::new();
+
+        for _ in 0..MAX_USER_AUTH_FAILURES - 1 {
+            lockout.record_failure(ADDRESS, Some("user"));
+        }
+        assert!(!lockout.is_locked(ADDRESS, "user"));
+
+        lockout.record_failure(ADDRESS, Some("user"));
+        assert!(lockout.is_locked(ADDRESS, "user"));
+        assert!(!lockout.is_locked(ADDRESS, "other"));
+        assert!(!lockout.is_locked("10.0.0.1".parse().unwrap(), "user"));
+    }
+
+    #[test]
+    fn locks_out_address_after_max_failures() {
+        let lockout = AuthLockout::new();
+
+        for i in 0..MAX_ADDRESS_AUTH_FAILURES {
+            lockout.record_failure(ADDRESS, Some(&format!("user{}", i)));
+        }
+
+        assert!(lockout.is_locked(ADDRESS, "other"));
+        assert!(!lockout.is_locked("10.0.0.1".parse().unwrap(), "user0"));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let lockout = AuthLockout::new();
+
+        for _ in 0..MAX_USER_AUTH_FAILURES {
+            lockout.record_failure(ADDRESS, Some("user"));
+        }
+        lockout.clear(ADDRESS, "user");
+
+        assert!(!lockout.is_locked(ADDRESS, "user"));
+    }
+}
@@ -1,13 +1,28 @@
-use std::{fmt, net::SocketAddr, sync::Arc};
+use std::{fmt, net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
+use access::AccessControl;
 use app::AppConnection;
-use bytes::Bytes;
 use kanal::AsyncReceiver;
-use mrial_proto::{video::EColorSpace, EPacketType};
+use log::warn;
+use mrial_fs::{server_data_dir, ServerConfig};
+use mrial_proto::{
+    video::EColorSpace, ClientStatePayload, EDisconnectReason, EPacketType, KeepaliveSettings,
+};
+use retransmit::RetransmitCacheSettings;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use web::WebConnection;
+use web::{WebConnection, WebController, WebEvent, WebIceSettings};
 
+use crate::{audit::AuditLog, video::display::DisplayArea};
+
+pub mod access;
 pub mod app;
+pub mod keepalive;
+pub mod lockout;
+pub mod relay;
+pub mod retransmit;
+pub mod sockets;
+pub mod totp;
 pub mod web;
 
 pub type PacketTypeVariant = u8;
@@ -15,7 +30,6 @@ pub type PacketTypeVariant = u8;
 #[derive(Debug)]
 pub enum BroadcastTaskError {
     TransferFailed(String),
-    EncryptionFailed(String),
     TaskNotRunning,
 }
 
@@ -23,7 +37,6 @@ impl fmt::Display for BroadcastTaskError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             BroadcastTaskError::TaskNotRunning => write!(f, "Broadcast Task is not running"),
-            BroadcastTaskError::EncryptionFailed(msg) => write!(f, "Encryption Failed: {}", msg),
             BroadcastTaskError::TransferFailed(msg) => write!(f, "Transfer Failed: {}", msg),
         }
     }
@@ -32,39 +45,193 @@ impl fmt::Display for BroadcastTaskError {
 impl std::error::Error for BroadcastTaskError {}
 
 pub trait Client {
-    fn is_alive(&self) -> bool;
+    fn is_alive(&self, timeout: Duration) -> bool;
 }
 
+/// Area of the streamed monitor on the virtual desktop,
+/// along with the audio codec requested by the clients
 #[derive(Debug, Clone)]
 pub struct ServerMeta {
+    pub x: i32,
+    pub y: i32,
     pub width: usize,
     pub height: usize,
+    /// Name of the streamed monitor, `None` when the whole capture is streamed
+    pub monitor: Option<String>,
     pub opus: bool,
-    pub csp: EColorSpace
+}
+
+impl ServerMeta {
+    pub fn area(&self) -> DisplayArea {
+        DisplayArea {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
 }
 
 impl Default for ServerMeta {
     fn default() -> Self {
         Self {
+            x: 0,
+            y: 0,
             width: 0,
             height: 0,
+            monitor: None,
             opus: true,
-            csp: EColorSpace::default(),
+        }
+    }
+}
+
+/// Resolution and color space a client requested the video stream in,
+/// clients requesting the same profile share an encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StreamProfile {
+    pub width: usize,
+    pub height: usize,
+    pub csp: EColorSpace,
+}
+
+impl StreamProfile {
+    /// Profile the encoder is opened with, falls back to the dimensions of the
+    /// capture when none were requested and rounds them down to be even for YUV.
+    pub fn normalized(&self, capture_width: usize, capture_height: usize) -> Self {
+        let (width, height) = match self.width == 0 || self.height == 0 {
+            true => (capture_width, capture_height),
+            false => (self.width, self.height),
+        };
+
+        Self {
+            width: width & !1,
+            height: height & !1,
+            csp: self.csp,
+        }
+    }
+}
+
+impl From<&ClientStatePayload> for StreamProfile {
+    fn from(state: &ClientStatePayload) -> Self {
+        Self {
+            width: state.width as usize,
+            height: state.height as usize,
+            csp: state.csp,
         }
     }
 }
 pub struct ConnectionManager {
     web: WebConnection,
     app: AppConnection,
+    access: AccessControl,
+    audit: AuditLog,
+    config: Arc<std::sync::RwLock<ServerConfig>>,
+    /// Flags the server was started with, re-applied to the config when it is reloaded
+    server_args: Arc<Vec<String>>,
     meta: Arc<RwLock<ServerMeta>>,
 }
 
 impl ConnectionManager {
-    pub async fn new() -> Self {
+    pub async fn new(config: &ServerConfig) -> Self {
+        Self::new_with_custom_dir(config, &server_data_dir()).await
+    }
+
+    /// Connections keeping their users, host key and audit log in the directory
+    pub async fn new_with_custom_dir(config: &ServerConfig, data_dir: &Path) -> Self {
+        let access = AccessControl::new();
+        let audit = AuditLog::new_with_custom_dir(data_dir.to_path_buf()).with_writer();
+        let meta = Arc::new(RwLock::new(ServerMeta::default()));
+        let web_controller = WebController::default();
+
+        let app = AppConnection::new(
+            config,
+            data_dir,
+            access.clone(),
+            audit.clone(),
+            meta.clone(),
+            web_controller.clone(),
+        )
+        .await;
+
         Self {
-            web: WebConnection::new(),
-            app: AppConnection::new().await,
-            meta: Arc::new(RwLock::new(ServerMeta::default())),
+            web: WebConnection::new(
+                config,
+                data_dir,
+                access.clone(),
+                audit.clone(),
+                web_controller,
+                app.clone(),
+            ),
+            app,
+            access,
+            audit,
+            config: Arc::new(std::sync::RwLock::new(config.clone())),
+            server_args: Arc::new(Vec::new()),
+            meta,
+        }
+    }
+
+    pub fn with_server_args(mut self, args: &[String]) -> Self {
+        self.server_args = Arc::new(args.to_vec());
+        self
+    }
+
+    pub fn server_args(&self) -> &[String] {
+        &self.server_args
+    }
+
+    pub fn get_access(&self) -> AccessControl {
+        self.access.clone()
+    }
+
+    /// Config the server was started with, or last reloaded
+    pub fn get_config(&self) -> ServerConfig {
+        match self.config.read() {
+            Ok(config) => config.clone(),
+            Err(_) => ServerConfig::default(),
+        }
+    }
+
+    /// Replaces the config, except for the settings of the sockets
+    /// that are already bound, which apply on restart.
+    pub fn reload_config(&self, mut config: ServerConfig) {
+        for problem in config.validate() {
+            warn!("{}", problem);
+        }
+
+        if let Ok(mut current) = self.config.write() {
+            if config.bind != current.bind
+                || config.port != current.port
+                || config.web_port != current.web_port
+                || config.control_group != current.control_group
+                || config.discovery != current.discovery
+                || config.http_port != current.http_port
+            {
+                warn!("Socket Settings Changed, Restart the Server to Apply Them");
+            }
+
+            config.bind = current.bind.clone();
+            config.port = current.port;
+            config.web_port = current.web_port;
+            config.control_group = current.control_group.clone();
+            config.discovery = current.discovery;
+            config.http_port = current.http_port;
+
+            self.app.set_keepalive(KeepaliveSettings {
+                interval_ms: config.keepalive_interval_ms,
+                timeout_ms: config.keepalive_timeout_ms,
+            });
+            self.app
+                .set_retransmit_cache_settings(RetransmitCacheSettings::from(&config));
+            self.web.set_ice_settings(WebIceSettings::from(&config));
+            *current = config;
+        }
+    }
+
+    /// Syncs the audit log to disk
+    pub async fn flush_audit(&self) {
+        if let Err(e) = self.audit.flush().await {
+            warn!("Failed to Flush Audit Log: {}", e);
         }
     }
 
@@ -87,31 +254,20 @@ impl ConnectionManager {
         meta.clone()
     }
 
-    pub async fn set_meta(&self, meta: ServerMeta) -> bool {
-        let mut stream_restart_flag = false;
-
-        let mut current_meta = self.meta.write().await;
-
-        if current_meta.width != meta.width
-            || current_meta.height != meta.height
-            || current_meta.csp != meta.csp
-        {
-            stream_restart_flag = true;
-        }
-
-        current_meta.width = meta.width;
-        current_meta.height = meta.height;
-        current_meta.opus = meta.opus;
-        current_meta.csp = meta.csp;
+    pub async fn set_opus(&self, opus: bool) {
+        let mut meta = self.meta.write().await;
 
-        stream_restart_flag
+        meta.opus = opus;
     }
 
-    pub async fn set_dimensions(&self, width: usize, height: usize) {
+    pub async fn set_display(&self, area: DisplayArea, monitor: Option<String>) {
         let mut meta = self.meta.write().await;
 
-        meta.width = width;
-        meta.height = height;
+        meta.x = area.x;
+        meta.y = area.y;
+        meta.width = area.width;
+        meta.height = area.height;
+        meta.monitor = monitor;
     }
 
     #[inline]
@@ -125,7 +281,7 @@ impl ConnectionManager {
     }
 
     #[inline]
-    pub fn web_receiver(&self) -> AsyncReceiver<Bytes> {
+    pub fn web_receiver(&self) -> AsyncReceiver<WebEvent> {
         self.web.receiver()
     }
 
@@ -149,8 +305,17 @@ impl ConnectionManager {
         self.app.retransmit_frame(src, frame_id, real_packet_size, subpacket_ids).await
     }
     
-    pub async fn app_drain_subpacket_cache(&self) {
-        self.app.drain_subpacket_cache().await
+    pub async fn app_evict_retransmit_caches(&self) {
+        self.app.evict_retransmit_caches().await
+    }
+
+    pub fn app_profiles_version(&self) -> u64 {
+        self.app.profiles_version()
+    }
+
+    /// Address of an app client access is checked on, see `AppConnection::observed_address`
+    pub fn app_observed_address(&self, src: SocketAddr) -> SocketAddr {
+        self.app.observed_address(src)
     }
 
     #[inline]
@@ -159,8 +324,11 @@ impl ConnectionManager {
         packet_type: EPacketType,
         packet_type_variant: PacketTypeVariant,
         buf: &[u8],
+        profiles: Option<Vec<StreamProfile>>,
     ) -> Result<(), BroadcastTaskError> {
-        self.app.broadcast_encrypted_frame(packet_type, packet_type_variant, buf).await
+        self.app
+            .broadcast_encrypted_frame(packet_type, packet_type_variant, buf, profiles)
+            .await
     }
 
     #[inline]
@@ -179,6 +347,14 @@ impl ConnectionManager {
         };
     }
 
+    /// Tells every app and web client why it was disconnected, then drops them
+    pub async fn disconnect_clients(&self, reason: EDisconnectReason) {
+        tokio::join! {
+            self.web.disconnect_clients(reason),
+            self.app.disconnect_clients(reason),
+        };
+    }
+
     #[inline]
     pub async fn has_clients(&self) -> bool {
         let (has_web_clients, has_app_clients) = tokio::join! {
@@ -195,7 +371,33 @@ impl Clone for ConnectionManager {
         Self {
             web: self.web.clone(),
             app: self.app.clone(),
+            access: self.access.clone(),
+            audit: self.audit.clone(),
+            config: self.config.clone(),
+            server_args: self.server_args.clone(),
             meta: self.meta.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mrial_proto::video::EColorSpace;
+
+    use super::*;
+
+    #[test]
+    fn normalized_profile_is_even_and_falls_back_to_the_capture() {
+        let profile = |width, height| StreamProfile {
+            width,
+            height,
+            csp: EColorSpace::YUV420,
+        };
+
+        let hd = profile(1280, 720);
+        assert_eq!(hd.normalized(1920, 1080), hd);
+        assert_eq!(profile(1281, 721).normalized(1920, 1080), hd);
+        assert_eq!(profile(0, 0).normalized(1921, 1081), profile(1920, 1080));
+        assert_eq!(profile(1280, 0).normalized(1920, 1080), profile(1920, 1080));
+    }
+}
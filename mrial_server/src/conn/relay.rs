@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use mrial_fs::ServerConfig;
+use mrial_proto::relay::*;
+use tokio::{net::lookup_host, task::JoinHandle, time::sleep};
+
+use crate::discovery::server_name;
+
+use super::ConnectionManager;
+
+/// Times the server punches through to an address within the punch window,
+/// so a relay can not have the server send punches to an address at will
+const MAX_PUNCHES_PER_TARGET: u32 = 3;
+
+/// Punches are counted per target address for this long after the first one
+const PUNCH_WINDOW: Duration = Duration::from_secs(60);
+
+struct Punches {
+    count: u32,
+    first: Instant,
+}
+
+struct RelayedPlayer {
+    player: SocketAddr,
+    introduced: Instant,
+}
+
+#[derive(Default)]
+struct RelayedPlayersState {
+    /// Keyed by the address of the relay session, which is where datagrams of the player arrive from
+    sessions: HashMap<SocketAddr, RelayedPlayer>,
+    punches: HashMap<IpAddr, Punches>,
+}
+
+/// Players introduced by the relay, by the address of their relay session.
+/// All datagrams forwarded by the relay arrive from its address, so access and
+/// lockout checks are run on the address the relay observed the player at instead.
+/// Clones share the players.
+#[derive(Clone, Default)]
+pub struct RelayedPlayers {
+    state: Arc<Mutex<RelayedPlayersState>>,
+}
+
+impl RelayedPlayers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a player introduced by the relay, false if the player was punched
+    /// through to too many times already and should not be punched through to again
+    pub fn introduce(&self, session: SocketAddr, player: SocketAddr) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+
+        state.sessions.insert(
+            session,
+            RelayedPlayer {
+                player,
+                introduced: Instant::now(),
+            },
+        );
+
+        state
+            .punches
+            .retain(|_, punches| punches.first.elapsed() < PUNCH_WINDOW);
+        let punches = state.punches.entry(player.ip()).or_insert(Punches {
+            count: 0,
+            first: Instant::now(),
+        });
+        if punches.count >= MAX_PUNCHES_PER_TARGET {
+            return false;
+        }
+        punches.count += 1;
+
+        true
+    }
+
+    /// Address the relay observed the player at for datagrams from a relay session,
+    /// the address itself for anything else
+    pub fn observed_address(&self, src: SocketAddr) -> SocketAddr {
+        match self.state.lock() {
+            Ok(state) => state
+                .sessions
+                .get(&src)
+                .map_or(src, |session| session.player),
+            Err(_) => src,
+        }
+    }
+
+    /// Forgets the sessions of players no longer connected, once the relay would have closed them
+    pub fn retain(&self, mut connected: impl FnMut(&SocketAddr) -> bool) {
+        let timeout = Duration::from_millis(RELAY_SESSION_TIMEOUT_MS);
+
+        if let Ok(mut state) = self.state.lock() {
+            state.sessions.retain(|session, player| {
+                connected(session) || player.introduced.elapsed() < timeout
+            });
+        }
+    }
+}
+
+/// Name the server registers with the relay under, the advertised name if not set
+pub fn relay_name(config: &ServerConfig) -> String {
+    let mut name = config
+        .relay_name
+        .clone()
+        .unwrap_or_else(|| server_name(config));
+
+    while name.len() > RELAY_MAX_NAME {
+        name.pop();
+    }
+
+    name
+}
+
+/// Resolves a relay address, which may leave out the port or be a hostname
+async fn resolve_relay(relay: &str) -> Option<SocketAddr> {
+    if let Ok(mut addresses) = lookup_host(relay).await {
+        return addresses.next();
+    }
+
+    let host = relay.trim_start_matches('[').trim_end_matches(']');
+    lookup_host((host, RELAY_DEFAULT_PORT))
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+}
+
+/// Keeps the server registered with the configured relay, so players
+/// can reach it by name without port forwarding.
+pub struct RelayTask {
+    conn: ConnectionManager,
+}
+
+impl RelayTask {
+    async fn register_loop(&self) {
+        let app = self.conn.get_app();
+
+        loop {
+            let config = self.conn.get_config();
+
+            let relay = match &config.relay {
+                Some(relay) => match resolve_relay(relay).await {
+                    Some(address) => Some(address),
+                    None => {
+                        warn!("Failed to Resolve Relay \"{}\"", relay);
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            let name = relay_name(&config);
+            if let Some(address) = relay.filter(|address| Some(*address) != app.relay()) {
+                info!("Registering with Relay {} as \"{}\"", address, name);
+            }
+            app.set_relay(relay, name.clone());
+
+            // The relay challenges the first registration, which is signed with the host key
+            if let Some(relay) = relay {
+                match app.relay_registration(name, None) {
+                    Some(registration) => app.send_relay_message(&registration, relay).await,
+                    None => warn!("Registering with a Relay Requires the Host Key"),
+                }
+            }
+
+            sleep(Duration::from_millis(RELAY_REGISTER_INTERVAL_MS)).await;
+        }
+    }
+
+    pub fn run(conn: ConnectionManager) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            RelayTask { conn }.register_loop().await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relayed_players_are_seen_at_their_observed_address() {
+        let players = RelayedPlayers::new();
+        let session: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let player: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let other: SocketAddr = "198.51.100.1:40001".parse().unwrap();
+
+        assert!(players.introduce(session, player));
+
+        assert_eq!(players.observed_address(session), player);
+        assert_eq!(players.observed_address(other), other);
+    }
+
+    #[test]
+    fn punches_are_capped_per_target_address() {
+        let players = RelayedPlayers::new();
+        let session: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+
+        for port in 0..MAX_PUNCHES_PER_TARGET as u16 {
+            let player = SocketAddr::new("203.0.113.7".parse().unwrap(), 50000 + port);
+            assert!(players.introduce(session, player));
+        }
+
+        // Another port of the same address counts towards the same cap
+        assert!(!players.introduce(session, "203.0.113.7:60000".parse().unwrap()));
+        assert!(players.introduce(session, "203.0.113.8:50000".parse().unwrap()));
+    }
+
+    #[test]
+    fn sessions_of_connected_players_are_kept() {
+        let players = RelayedPlayers::new();
+        let session: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let player: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        players.introduce(session, player);
+
+        players.retain(|_| false);
+        assert_eq!(players.observed_address(session), player);
+
+        if let Ok(mut state) = players.state.lock() {
+            state.sessions.get_mut(&session).unwrap().introduced -=
+                Duration::from_millis(RELAY_SESSION_TIMEOUT_MS);
+        }
+        players.retain(|address| *address == session);
+        assert_eq!(players.observed_address(session), player);
+
+        players.retain(|_| false);
+        assert_eq!(players.observed_address(session), session);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use mrial_fs::ServerConfig;
+use mrial_proto::*;
+
+/// Frames kept per client, frame ids wrap at 256 so it must divide 256 evenly.
+/// At 60 FPS this covers just over 2 seconds.
+const FRAME_SLOTS: usize = 128;
+
+/// Budget and lifetime of the retransmit cache of each client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetransmitCacheSettings {
+    pub budget_bytes: usize,
+    pub max_age_ms: u64,
+}
+
+impl From<&ServerConfig> for RetransmitCacheSettings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            budget_bytes: config.retransmit_cache_bytes,
+            max_age_ms: config.retransmit_cache_age_ms,
+        }
+    }
+}
+
+/// Subpackets of a single frame, stored back to back in MTU sized cells
+/// in the order they were sent.
+struct FrameSlot {
+    frame_id: u8,
+    real_packet_size: u32,
+    sent_at: Option<Instant>,
+    /// Length of the subpacket in each cell, 0 if it was not cached
+    lens: Vec<u16>,
+    data: Vec<u8>,
+}
+
+impl FrameSlot {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frame_id: 0,
+            real_packet_size: 0,
+            sent_at: None,
+            lens: Vec::with_capacity(capacity / MTU),
+            data: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn holds(&self, frame_id: u8, real_packet_size: u32) -> bool {
+        self.sent_at.is_some()
+            && self.frame_id == frame_id
+            && self.real_packet_size == real_packet_size
+    }
+
+    fn is_expired(&self, max_age: Duration) -> bool {
+        self.sent_at
+            .is_some_and(|sent_at| sent_at.elapsed() >= max_age)
+    }
+
+    /// Empties the slot, releasing what it grew past its pre-allocated capacity.
+    /// Returns the bytes it held.
+    fn clear(&mut self, capacity: usize) -> usize {
+        let len = self.data.len();
+
+        self.sent_at = None;
+        self.lens.clear();
+        self.data.clear();
+        self.lens.shrink_to(capacity / MTU);
+        self.data.shrink_to(capacity);
+
+        len
+    }
+}
+
+/// Video subpackets sent to a client, kept for retransmission in a ring of frames
+/// indexed by frame id and subpacket. Each frame has its own lock, so retransmissions
+/// of older frames never wait on the frame being sent.
+pub struct RetransmitCache {
+    slots: Box<[Mutex<FrameSlot>]>,
+    /// Capacity each slot is pre-allocated with and shrinks back to
+    slot_capacity: usize,
+    bytes: AtomicUsize,
+    budget_bytes: usize,
+    max_age: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RetransmitCache {
+    pub fn new(settings: RetransmitCacheSettings) -> Self {
+        let slot_capacity = (settings.budget_bytes / FRAME_SLOTS / MTU) * MTU;
+
+        Self {
+            slots: (0..FRAME_SLOTS)
+                .map(|_| Mutex::new(FrameSlot::new(slot_capacity)))
+                .collect(),
+            slot_capacity,
+            bytes: AtomicUsize::new(0),
+            budget_bytes: settings.budget_bytes,
+            max_age: Duration::from_millis(settings.max_age_ms),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn slot_index(frame_id: u8) -> usize {
+        frame_id as usize % FRAME_SLOTS
+    }
+
+    /// Position of the subpacket in its frame, the deployer counts the remaining packets down to 0
+    #[inline]
+    fn subpacket_index(real_packet_size: u32, packets_remaining: u16) -> Option<usize> {
+        let subpackets = subpacket_count(real_packet_size) as usize;
+        let packets_remaining = packets_remaining as usize;
+
+        (packets_remaining < subpackets).then(|| subpackets - packets_remaining - 1)
+    }
+
+    /// Evicts the oldest frames, in ring order after the given slot, until the bytes fit the budget.
+    /// Returns the bytes that are available to the given slot.
+    fn make_room(&self, slot_index: usize, bytes: usize) -> usize {
+        for offset in 1..FRAME_SLOTS {
+            if self.bytes.load(Ordering::Relaxed) + bytes <= self.budget_bytes {
+                break;
+            }
+
+            let mut slot = self.slots[(slot_index + offset) % FRAME_SLOTS]
+                .lock()
+                .unwrap();
+            let freed = slot.clear(self.slot_capacity);
+            self.bytes.fetch_sub(freed, Ordering::Relaxed);
+        }
+
+        self.budget_bytes
+            .saturating_sub(self.bytes.load(Ordering::Relaxed))
+            .min(bytes)
+    }
+
+    /// Caches a video subpacket as it is sent, as an RNAL packet ready to be resent.
+    /// Subpackets of a frame larger than the budget are only cached up to the budget.
+    pub fn insert(&self, subpacket: &[u8]) {
+        let frame_id = parse_frame_id(subpacket);
+        let real_packet_size = parse_real_packet_size(subpacket);
+        let index =
+            match Self::subpacket_index(real_packet_size, parse_packets_remaining(subpacket)) {
+                Some(index) => index,
+                None => return,
+            };
+
+        let slot_index = Self::slot_index(frame_id);
+
+        let mut slot = self.slots[slot_index].lock().unwrap();
+
+        if !slot.holds(frame_id, real_packet_size) {
+            let freed = slot.clear(self.slot_capacity);
+            self.bytes.fetch_sub(freed, Ordering::Relaxed);
+            drop(slot);
+
+            // The other frames are locked while making room, never along with this one
+            let frame_bytes = subpacket_count(real_packet_size) as usize * MTU;
+            let cells = self.make_room(slot_index, frame_bytes) / MTU;
+
+            slot = self.slots[slot_index].lock().unwrap();
+            slot.frame_id = frame_id;
+            slot.real_packet_size = real_packet_size;
+            slot.sent_at = Some(Instant::now());
+            slot.lens.resize(cells, 0);
+            slot.data.resize(cells * MTU, 0);
+            self.bytes.fetch_add(cells * MTU, Ordering::Relaxed);
+        }
+
+        if index >= slot.lens.len() {
+            return;
+        }
+
+        let start = index * MTU;
+        let len = subpacket.len().min(MTU);
+
+        let cell = &mut slot.data[start..start + len];
+        cell.copy_from_slice(&subpacket[..len]);
+        cell[0] &= 0b11100000; // clear the last 5 bits
+        cell[0] |= EPacketType::RNAL as u8; // set the type to RNAL
+
+        slot.lens[index] = len as u16;
+    }
+
+    /// Copies a cached subpacket into the buffer, returning its length.
+    /// Subpackets of frames older than the max age are misses.
+    pub fn get(
+        &self,
+        frame_id: u8,
+        real_packet_size: u32,
+        packets_remaining: u16,
+        buf: &mut [u8; MTU],
+    ) -> Option<usize> {
+        let len = Self::subpacket_index(real_packet_size, packets_remaining).and_then(|index| {
+            let slot = self.slots[Self::slot_index(frame_id)].lock().unwrap();
+
+            if !slot.holds(frame_id, real_packet_size) || slot.is_expired(self.max_age) {
+                return None;
+            }
+
+            let len = *slot.lens.get(index)? as usize;
+            if len == 0 {
+                return None;
+            }
+
+            let start = index * MTU;
+            buf[..len].copy_from_slice(&slot.data[start..start + len]);
+
+            Some(len)
+        });
+
+        match len {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        len
+    }
+
+    /// Empties the frames older than the max age, returning the bytes released
+    pub fn evict_expired(&self) -> usize {
+        let mut evicted = 0;
+
+        for slot in self.slots.iter() {
+            let mut slot = slot.lock().unwrap();
+            if slot.is_expired(self.max_age) {
+                evicted += slot.clear(self.slot_capacity);
+            }
+        }
+
+        self.bytes.fetch_sub(evicted, Ordering::Relaxed);
+        evicted
+    }
+
+    /// Bytes reserved by the cached frames
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subpacket(frame_id: u8, real_packet_size: u32, packets_remaining: u16) -> [u8; MTU] {
+        let mut buf = [0u8; MTU];
+        write_header(
+            EPacketType::NAL,
+            packets_remaining,
+            real_packet_size,
+            frame_id,
+            &mut buf,
+        );
+        buf[HEADER] = frame_id;
+        buf
+    }
+
+    fn cache(budget_bytes: usize) -> RetransmitCache {
+        RetransmitCache::new(RetransmitCacheSettings {
+            budget_bytes,
+            max_age_ms: 60_000,
+        })
+    }
+
+    #[test]
+    fn subpacket_index_counts_down() {
+        let index = |remaining| RetransmitCache::subpacket_index(3 * PAYLOAD as u32, remaining);
+
+        assert_eq!(index(2), Some(0));
+        assert_eq!(index(1), Some(1));
+        assert_eq!(index(0), Some(2));
+        assert_eq!(index(3), None);
+    }
+
+    #[test]
+    fn cached_subpacket_is_resent_as_rnal() {
+        let cache = cache(FRAME_SLOTS * MTU);
+        cache.insert(&subpacket(7, PAYLOAD as u32, 0));
+
+        let mut buf = [0u8; MTU];
+        assert_eq!(cache.get(7, PAYLOAD as u32, 0, &mut buf), Some(MTU));
+        assert_eq!(parse_packet_type(&buf), EPacketType::RNAL);
+        assert_eq!(buf[HEADER], 7);
+
+        assert_eq!(cache.get(8, PAYLOAD as u32, 0, &mut buf), None);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    }
+
+    #[test]
+    fn make_room_evicts_oldest_frames() {
+        let cache = cache(2 * MTU);
+        let mut buf = [0u8; MTU];
+
+        for frame_id in 0..3 {
+            cache.insert(&subpacket(frame_id, PAYLOAD as u32, 0));
+        }
+
+        assert_eq!(cache.bytes(), 2 * MTU);
+        assert_eq!(cache.get(0, PAYLOAD as u32, 0, &mut buf), None);
+        assert!(cache.get(1, PAYLOAD as u32, 0, &mut buf).is_some());
+        assert!(cache.get(2, PAYLOAD as u32, 0, &mut buf).is_some());
+    }
+
+    #[test]
+    fn frame_ids_wrap_around_the_ring() {
+        let cache = cache(FRAME_SLOTS * MTU);
+        let mut buf = [0u8; MTU];
+
+        cache.insert(&subpacket(0, PAYLOAD as u32, 0));
+        cache.insert(&subpacket(FRAME_SLOTS as u8, PAYLOAD as u32, 0));
+
+        assert_eq!(cache.bytes(), MTU);
+        assert_eq!(cache.get(0, PAYLOAD as u32, 0, &mut buf), None);
+        assert!(cache
+            .get(FRAME_SLOTS as u8, PAYLOAD as u32, 0, &mut buf)
+            .is_some());
+        assert_eq!(buf[HEADER], FRAME_SLOTS as u8);
+    }
+}
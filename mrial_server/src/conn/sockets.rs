@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{AddrParseError, IpAddr, SocketAddr},
+    sync::RwLock,
+};
+
+use futures::future::select_all;
+use log::{debug, error};
+use mrial_fs::ServerConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Parses a bind address, which may leave out the port, such as
+/// "0.0.0.0", "[::]", "::1", "192.168.1.2:8554" or "[fe80::1]:8554".
+pub fn parse_bind_address(address: &str, port: u16) -> Result<SocketAddr, AddrParseError> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+
+    let ip = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Binds a UDP socket, the unspecified IPv6 address (`[::]`) also accepts IPv4 (dual-stack).
+pub fn bind_udp(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(!address.ip().is_unspecified())?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds a TCP listener, dual-stack like `bind_udp`
+pub fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(!address.ip().is_unspecified())?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// UDP sockets of every configured bind address. Packets are sent
+/// to a client from the socket its last packet was received on.
+pub struct AppSockets {
+    sockets: Vec<UdpSocket>,
+    routes: RwLock<HashMap<SocketAddr, usize>>,
+}
+
+impl AppSockets {
+    pub fn bind(config: &ServerConfig) -> Self {
+        let mut sockets = Vec::with_capacity(config.bind.len());
+
+        for address in config.bind.iter() {
+            let address = match parse_bind_address(address, config.port) {
+                Ok(address) => address,
+                Err(e) => {
+                    error!("Invalid Bind Address \"{}\": {}", address, e);
+                    continue;
+                }
+            };
+
+            match bind_udp(address) {
+                Ok(socket) => {
+                    debug!("Bound UDP Socket at {}", address);
+                    sockets.push(socket);
+                }
+                Err(e) => error!("Failed to Bind UDP Socket at {}: {}", address, e),
+            }
+        }
+
+        if sockets.is_empty() {
+            panic!("Failed to Bind UDP Socket at Any of: {}", config.bind.join(", "));
+        }
+
+        Self {
+            sockets,
+            routes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let readable = self.sockets.iter().map(|socket| Box::pin(socket.readable()));
+            let (ready, index, _) = select_all(readable).await;
+            ready?;
+
+            match self.sockets[index].try_recv_from(buf) {
+                Ok((len, src)) => {
+                    self.set_route(src, index);
+                    return Ok((len, src));
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let route = match self.routes.read() {
+            Ok(routes) => routes.get(&target).copied(),
+            Err(_) => None,
+        };
+        let index = route.unwrap_or_else(|| self.family_socket(target));
+
+        self.sockets[index].send_to(buf, target).await
+    }
+
+    /// First socket of the same address family as the target, used when no packet was received from it
+    fn family_socket(&self, target: SocketAddr) -> usize {
+        self.sockets
+            .iter()
+            .position(|socket| {
+                socket
+                    .local_addr()
+                    .is_ok_and(|address| address.is_ipv6() == target.is_ipv6())
+            })
+            .unwrap_or_default()
+    }
+
+    fn set_route(&self, src: SocketAddr, index: usize) {
+        if let Ok(routes) = self.routes.read() {
+            if routes.get(&src) == Some(&index) {
+                return;
+            }
+        }
+
+        if let Ok(mut routes) = self.routes.write() {
+            routes.insert(src, index);
+        }
+    }
+
+    /// Forgets the sockets of addresses that are no longer clients
+    pub fn retain_routes(&self, is_client: impl Fn(&SocketAddr) -> bool) {
+        if let Ok(mut routes) = self.routes.write() {
+            routes.retain(|src, _| is_client(src));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_address_port_is_optional() {
+        let parse = |address| parse_bind_address(address, 8554).unwrap().to_string();
+
+        assert_eq!(parse("0.0.0.0"), "0.0.0.0:8554");
+        assert_eq!(parse("192.168.1.2:9000"), "192.168.1.2:9000");
+        assert_eq!(parse("::1"), "[::1]:8554");
+        assert_eq!(parse("[::]"), "[::]:8554");
+        assert_eq!(parse("[fe80::1]:9000"), "[fe80::1]:9000");
+        assert!(parse_bind_address("localhost", 8554).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const TOTP_ISSUER: &str = "Mrial";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+const TOTP_SECRET_SIZE: usize = 20;
+
+/// Number of steps before and after the current step a code is still accepted for,
+/// to tolerate clock drift between the server and the authenticator.
+const TOTP_DRIFT_STEPS: u64 = 1;
+
+fn totp_from_secret(username: &str, secret: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| e.to_string())?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_DRIFT_STEPS as u8,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        username.to_string(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Generates a new Base32 encoded TOTP secret
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; TOTP_SECRET_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    Secret::Raw(secret.to_vec()).to_encoded().to_string()
+}
+
+/// The otpauth:// URI authenticator apps are enrolled with
+pub fn provisioning_uri(username: &str, secret: &str) -> Result<String, String> {
+    Ok(totp_from_secret(username, secret)?.get_url())
+}
+
+/// Verifies a code against the current time, within the drift window.
+/// Returns the step the code was generated for, which must be persisted as `last_step`
+/// so the same code can not be replayed. Codes for steps at or before `last_step` are rejected.
+pub fn verify_code(username: &str, secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    verify_code_at(username, secret, code, last_step, now)
+}
+
+/// Codes are compared in constant time, so the time taken does not leak matching digits
+fn verify_code_at(
+    username: &str,
+    secret: &str,
+    code: &str,
+    last_step: u64,
+    now: u64,
+) -> Option<u64> {
+    let totp = totp_from_secret(username, secret).ok()?;
+    let code = code.trim().as_bytes();
+    let current_step = now / TOTP_STEP;
+
+    let first_step = current_step.saturating_sub(TOTP_DRIFT_STEPS);
+    (first_step..=current_step + TOTP_DRIFT_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| bool::from(totp.generate(step * TOTP_STEP).as_bytes().ct_eq(code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn code_at(secret: &str, step: u64) -> String {
+        totp_from_secret("user", secret)
+            .unwrap()
+            .generate(step * TOTP_STEP)
+    }
+
+    #[test]
+    fn codes_within_drift_are_accepted() {
+        let secret = generate_secret();
+        let step = NOW / TOTP_STEP;
+
+        for drift_step in [step - 1, step, step + 1] {
+            let code = code_at(&secret, drift_step);
+            assert_eq!(
+                verify_code_at("user", &secret, &code, 0, NOW),
+                Some(drift_step)
+            );
+        }
+
+        let code = code_at(&secret, step + 2);
+        assert_eq!(verify_code_at("user", &secret, &code, 0, NOW), None);
+    }
+
+    #[test]
+    fn used_codes_are_rejected() {
+        let secret = generate_secret();
+        let step = NOW / TOTP_STEP;
+        let code = code_at(&secret, step);
+
+        assert_eq!(verify_code_at("user", &secret, &code, step, NOW), None);
+        assert_eq!(verify_code_at("user", &secret, "000000x", 0, NOW), None);
+        assert_eq!(verify_code_at("user", "not base32!", &code, 0, NOW), None);
+    }
+}
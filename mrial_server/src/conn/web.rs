@@ -1,115 +1,412 @@
-use std::sync::{self, Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        self,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use kanal::{bounded_async, unbounded, AsyncReceiver, AsyncSender, Sender};
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use mrial_fs::{hash_password, storage::StorageMultiType, PortRange, ServerConfig, User, Users};
+use serde::{Deserialize, Serialize};
 
 use mrial_proto::{
-    deploy::{Broadcaster, PacketDeployer}, EPacketType
+    parse_packet_type, write_disconnect, EDisconnectReason, EPacketType, ESessionRole,
+    SessionMember, HEADER,
 };
 use tokio::{runtime::Handle, sync::RwLock, task::JoinHandle};
 use webrtc::{
-    api::APIBuilder,
-    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
-    ice_transport::ice_server::RTCIceServer,
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS},
+        setting_engine::SettingEngine,
+        APIBuilder,
+    },
+    data_channel::{
+        data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage,
+        RTCDataChannel,
+    },
+    ice::{
+        udp_mux::{UDPMux, UDPMuxDefault, UDPMuxParams},
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_server::RTCIceServer,
+    },
+    interceptor::registry::Registry,
+    media::Sample,
     peer_connection::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+    rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, rtp_sender::RTCRtpSender},
+    stats::StatsReportType,
+    track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+};
+
+use crate::{
+    audio::ENCODE_FRAME_SIZE,
+    audit::{AuditEvent, AuditLog},
 };
 
-use super::BroadcastTaskError;
+use super::{
+    access::{AccessControl, AccessDenied},
+    app::AppConnection,
+    lockout::AuthLockout,
+    sockets::{bind_udp, parse_bind_address},
+    totp, BroadcastTaskError,
+};
 
 #[derive(Clone)]
 struct WebClient {
+    /// User the peer authenticated as before it was created
+    user: User,
+    /// Address the client signaled from
+    src: SocketAddr,
+    connected_at: Instant,
     peer_connection: Arc<RTCPeerConnection>,
-    data_channel: Arc<RTCDataChannel>,
+    control_channel: Arc<RTCDataChannel>,
+    media_channel: Arc<RTCDataChannel>,
+    video_track: Arc<TrackLocalStaticSample>,
+    audio_track: Arc<TrackLocalStaticSample>,
+    /// Set by the client over the data channel, shared with its message handler
+    muted: Arc<AtomicBool>,
+    /// Input the peer sends while not in control is audited once, as it floods while the mouse moves.
+    /// Reset when the peer is given control.
+    role_violation_audited: bool,
+}
+
+impl WebClient {
+    #[inline]
+    fn session_duration(&self) -> u64 {
+        self.connected_at.elapsed().as_secs()
+    }
+
+    /// Tells the client why it was disconnected by the server, then closes its connection
+    async fn disconnect(&self, reason: EDisconnectReason) {
+        let mut buf = [0u8; HEADER];
+        let len = write_disconnect(reason, &mut buf);
+        let disconnect = Bytes::copy_from_slice(&buf[..len]);
+        if let Err(e) = self.control_channel.send(&disconnect).await {
+            debug!("Failed to Send Disconnect to Web Client: {}", e);
+        }
+
+        let _ = self.control_channel.close().await;
+        let _ = self.media_channel.close().await;
+        let _ = self.peer_connection.close().await;
+    }
 }
 
 type BroadcastPayload = (EPacketType, Vec<u8>);
 
+/// Message of a web client, with the ID of its peer and the label of the data channel
+/// it was received on
+pub type WebEvent = (u64, &'static str, Bytes);
+
+/// Negotiated data channel for input and control packets, ordered and reliable
+pub const CONTROL_CHANNEL: &str = "control";
+const CONTROL_CHANNEL_ID: u16 = 0;
+
+/// Negotiated data channel for media packets that are not sent over the tracks, unordered
+/// and without retransmissions so a lost packet never holds up the ones after it
+pub const MEDIA_CHANNEL: &str = "media";
+const MEDIA_CHANNEL_ID: u16 = 1;
+
+/// Web clients by the ID of their peer connection, assigned when it is created
+type WebClients = Arc<RwLock<HashMap<u64, WebClient>>>;
+
+/// Web peer in control of the session
+#[derive(Clone, Debug)]
+pub struct WebControllerPeer {
+    pub id: u64,
+    /// Address the peer signaled from
+    pub src: SocketAddr,
+    pub username: String,
+}
+
+impl WebControllerPeer {
+    pub fn session_member(&self) -> SessionMember {
+        SessionMember {
+            address: self.src.to_string(),
+            username: self.username.clone(),
+        }
+    }
+}
+
+/// Shared with the app connection, so a single app client or web peer is in control
+/// of the session. Every other web peer is a spectator.
+pub type WebController = Arc<sync::RwLock<Option<WebControllerPeer>>>;
+
+/// Gives up control if the removed peer was in control, returning whether it was
+fn release_control(controller: &WebController, id: u64) -> bool {
+    match controller.write() {
+        Ok(mut controller) if controller.as_ref().is_some_and(|peer| peer.id == id) => {
+            *controller = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// State a web client sends in a `ClientState` packet over the data channel,
+/// the other fields of the app's `ClientStatePayload` are ignored.
+#[derive(Deserialize, Debug)]
+struct WebClientState {
+    muted: bool,
+}
+
+/// Snapshot of a web client, listed over the control socket
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebClientInfo {
+    pub id: u64,
+    pub address: Option<String>,
+    pub username: String,
+    pub role: ESessionRole,
+    /// Seconds since the data channel of the client opened
+    pub duration: u64,
+    pub state: String,
+    pub bytes_sent: u64,
+    pub messages_sent: u64,
+    pub rtp_bytes_sent: u64,
+    pub rtp_packets_sent: u64,
+    pub nack_count: u64,
+    pub pli_count: u64,
+}
+
+#[derive(Debug)]
+pub enum WebAuthError {
+    InvalidCredentials,
+    AccessDenied(AccessDenied),
+    /// The user has TOTP enabled and no code was sent
+    TotpRequired,
+    InvalidTotpCode,
+    LockedOut,
+    FailedToLoadUsers,
+    Unexpected(String),
+}
+
+impl WebAuthError {
+    /// Whether the error counts towards locking out the address and user
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            WebAuthError::InvalidCredentials | WebAuthError::InvalidTotpCode
+        )
+    }
+}
+
+impl fmt::Display for WebAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebAuthError::InvalidCredentials => {
+                write!(f, "User Not Found, Failed to Authenticate")
+            }
+            WebAuthError::AccessDenied(denied) => write!(f, "Access Denied for User: {}", denied),
+            WebAuthError::TotpRequired => write!(f, "TOTP Code Required"),
+            WebAuthError::InvalidTotpCode => {
+                write!(f, "Invalid or Replayed TOTP Code, Failed to Authenticate")
+            }
+            WebAuthError::LockedOut => {
+                write!(f, "Too Many Failed Attempts, Locked Out for Now")
+            }
+            WebAuthError::FailedToLoadUsers => {
+                write!(f, "Failed to load Users, Failed to Authenticate")
+            }
+            WebAuthError::Unexpected(e) => write!(f, "Unexpected Error: {}", e),
+        }
+    }
+}
+
 pub struct WebConnection {
-    // TODO: Change this to a hashmap with the key as some client ID
-    clients: Arc<RwLock<Vec<WebClient>>>,
+    clients: WebClients,
+    next_peer_id: Arc<AtomicU64>,
+    controller: WebController,
+    /// Told about control changes, so app clients know who is in control
+    app: AppConnection,
 
     broadcast_sender: Sender<BroadcastPayload>,
     broadcast_receiver: AsyncReceiver<BroadcastPayload>,
     broadcast_task: Arc<sync::RwLock<Option<JoinHandle<()>>>>,
 
-    input_sender: AsyncSender<Bytes>,
-    input_receiver: AsyncReceiver<Bytes>,
-}
+    input_sender: AsyncSender<WebEvent>,
+    input_receiver: AsyncReceiver<WebEvent>,
+
+    access: AccessControl,
+    /// Socket all peers share when a WebRTC port is configured
+    udp_mux: Option<Arc<dyn UDPMux + Send + Sync>>,
 
-const STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+    keyframe: KeyframeRequest,
+    ice_settings: Arc<sync::RwLock<WebIceSettings>>,
 
-struct WebVideoBroadcaster {
-    clients: Arc<RwLock<Vec<WebClient>>>,
+    users: Arc<sync::Mutex<Users>>,
+    audit: AuditLog,
+    /// Shared with app clients, so a password can not be guessed over both
+    lockout: AuthLockout,
 }
 
-impl Broadcaster for WebVideoBroadcaster {
-    async fn broadcast(&self, bytes: &[u8]) {
-        let clients = self.clients.read().await;
+/// ICE settings peers are created with, reloaded with the config
+#[derive(Clone)]
+pub struct WebIceSettings {
+    pub ice_servers: Vec<RTCIceServer>,
+    pub port_range: Option<PortRange>,
+    /// Only host candidates are gathered, regardless of the ICE servers
+    pub host_only: bool,
+}
 
-        for client in clients.iter() {
-            let bytes = Bytes::from(bytes.to_owned());
-            if let Err(e) = client.data_channel.send(&bytes).await {
-                error!("Failed to Broadcast Audio to Client (Disconnecting): {e}");
+impl From<&ServerConfig> for WebIceSettings {
+    fn from(config: &ServerConfig) -> Self {
+        // Without ICE servers, peers only gather host candidates
+        let ice_servers = match config.ice_host_only {
+            true => Vec::new(),
+            false => config
+                .ice_servers
+                .iter()
+                .map(|server| RTCIceServer {
+                    urls: vec![server.url.clone()],
+                    username: server.username.clone().unwrap_or_default(),
+                    credential: server.credential.clone().unwrap_or_default(),
+                })
+                .collect(),
+        };
 
-                let _ = client.data_channel.close().await;
-                let _ = client.peer_connection.close().await;
-            }
+        Self {
+            ice_servers,
+            port_range: config.web_port_range,
+            host_only: config.ice_host_only,
         }
     }
 }
 
-struct WebAudioBroadcaster {
-    clients: Arc<RwLock<Vec<WebClient>>>,
+/// The web stream is encoded with the high profile, browsers match it regardless of level
+const VIDEO_TRACK_FMTP: &str =
+    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032";
+
+/// Opus as browsers offer it, with in-band FEC
+const AUDIO_TRACK_FMTP: &str = "minptime=10;useinbandfec=1";
+
+/// Duration of the first sample of the stream, the rest last until the next sample
+const DEFAULT_SAMPLE_DURATION: Duration = Duration::from_micros(16667);
+
+/// Each Opus packet encodes a full frame of 48 kHz audio
+const AUDIO_SAMPLE_DURATION: Duration =
+    Duration::from_micros(ENCODE_FRAME_SIZE as u64 * 1_000_000 / 48000);
+
+/// Shortest time between key frames requested by web peers
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Key frames requested by web peers when they join and over RTCP, limited
+/// so a lossy peer sending a PLI per lost frame can not keep reopening the encoder.
+#[derive(Clone)]
+struct KeyframeRequest {
+    pending: Arc<AtomicBool>,
+    last_taken: Arc<sync::Mutex<Option<Instant>>>,
 }
 
-impl Broadcaster for WebAudioBroadcaster {
-    async fn broadcast(&self, bytes: &[u8]) {
-        let clients = self.clients.read().await;
+impl KeyframeRequest {
+    fn new() -> Self {
+        Self {
+            pending: Arc::new(AtomicBool::new(false)),
+            last_taken: Arc::new(sync::Mutex::new(None)),
+        }
+    }
 
-        for client in clients.iter() {
-            // if client.muted {
-            //     continue;
-            // }
+    #[inline]
+    fn request(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+    }
 
-            let bytes = Bytes::from(bytes.to_owned());
-            if let Err(e) = client.data_channel.send(&bytes).await {
-                error!("Failed to Broadcast Audio to Client (Disconnecting): {e}");
+    /// Whether a key frame should be sent now, requests within the interval
+    /// of the last key frame stay pending until it passes.
+    fn take(&self) -> bool {
+        if !self.pending.load(Ordering::Relaxed) {
+            return false;
+        }
 
-                let _ = client.data_channel.close().await;
-                let _ = client.peer_connection.close().await;
-            }
+        let mut last_taken = self.last_taken.lock().unwrap();
+        if last_taken.is_some_and(|taken| taken.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+            return false;
         }
+
+        *last_taken = Some(Instant::now());
+        self.pending.store(false, Ordering::Relaxed);
+
+        true
     }
 }
 
 struct WebBroadcastTask {
     receiver: AsyncReceiver<BroadcastPayload>,
+    clients: WebClients,
 
-    video_deployer: PacketDeployer,
-    audio_pcm_deployer: PacketDeployer,
-    video_broadcaster: WebVideoBroadcaster,
-    audio_broadcaster: WebAudioBroadcaster,
+    last_sample: Option<Instant>,
 }
 
 impl WebBroadcastTask {
+    /// Writes the NALs of a frame to the video track of every client,
+    /// which packetizes them into RTP for the browser's decoder.
+    async fn write_video_sample(&mut self, buf: Vec<u8>) {
+        let duration = self
+            .last_sample
+            .map_or(DEFAULT_SAMPLE_DURATION, |last_sample| last_sample.elapsed());
+        self.last_sample = Some(Instant::now());
+
+        let sample = Sample {
+            data: Bytes::from(buf),
+            duration,
+            ..Default::default()
+        };
+
+        let clients = self.clients.read().await;
+
+        for client in clients.values() {
+            if let Err(e) = client.video_track.write_sample(&sample).await {
+                debug!("Failed to Write Video Sample to Web Client: {e}");
+            }
+        }
+    }
+
+    /// Writes an Opus packet to the audio track of every client that is not muted
+    async fn write_audio_sample(&self, buf: Vec<u8>) {
+        let sample = Sample {
+            data: Bytes::from(buf),
+            duration: AUDIO_SAMPLE_DURATION,
+            ..Default::default()
+        };
+
+        let clients = self.clients.read().await;
+
+        for client in clients.values() {
+            if client.muted.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            if let Err(e) = client.audio_track.write_sample(&sample).await {
+                debug!("Failed to Write Audio Sample to Web Client: {e}");
+            }
+        }
+    }
+
     #[inline]
     async fn broadcast(&mut self, payload: BroadcastPayload) {
         let (packet_type, buf) = payload;
 
         match packet_type {
             EPacketType::NAL => {
-                self.video_deployer
-                    .slice_and_send(&buf, &self.video_broadcaster)
-                    .await;
+                self.write_video_sample(buf).await;
             }
-            EPacketType::AudioPCM => {
-                self.audio_pcm_deployer
-                    .slice_and_send(&buf, &self.audio_broadcaster)
-                    .await;
+            EPacketType::AudioOpus => {
+                self.write_audio_sample(buf).await;
             }
             _ => {
                 error!("Unsupported Packet Type (Dropping): {:?}", packet_type);
@@ -125,17 +422,13 @@ impl WebBroadcastTask {
 
     pub fn run(
         tokio_handle: Handle,
-        clients: Arc<RwLock<Vec<WebClient>>>,
+        clients: WebClients,
         receiver: AsyncReceiver<BroadcastPayload>,
     ) -> JoinHandle<()> {
         tokio_handle.spawn(async move {
             let mut thread = Self {
-                audio_pcm_deployer: PacketDeployer::new(EPacketType::AudioPCM, false),
-                video_deployer: PacketDeployer::new(EPacketType::NAL, false),
-                video_broadcaster: WebVideoBroadcaster {
-                    clients: clients.clone(),
-                },
-                audio_broadcaster: WebAudioBroadcaster { clients },
+                last_sample: None,
+                clients,
                 receiver,
             };
 
@@ -146,19 +439,313 @@ impl WebBroadcastTask {
 
 const MAX_INPUT_BUFFER_SIZE: usize = 100;
 
+/// Address of the remote candidate in the nominated ICE candidate pair,
+/// the address the peer's media is actually exchanged with.
+async fn remote_address(peer_connection: &RTCPeerConnection) -> Option<IpAddr> {
+    let stats = peer_connection.get_stats().await;
+
+    let remote_candidate_id = stats.reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => {
+            Some(pair.remote_candidate_id.clone())
+        }
+        _ => None,
+    })?;
+
+    match stats.reports.get(&remote_candidate_id) {
+        Some(StatsReportType::RemoteCandidate(candidate)) => candidate.ip.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Reads the RTCP the peer sends about the video track, requesting a key frame
+/// when it lost one. NACKs are answered by the interceptors from their own buffer.
+async fn read_video_rtcp(rtp_sender: Arc<RTCRtpSender>, keyframe: KeyframeRequest) {
+    while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+        for packet in packets.iter() {
+            let packet = packet.as_any();
+
+            if packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>() {
+                debug!("Web Client Requested Key Frame");
+                keyframe.request();
+            }
+        }
+    }
+}
+
+/// Reads the RTCP the peer sends about the audio track, which has to be read
+/// for the interceptors to process it.
+async fn read_audio_rtcp(rtp_sender: Arc<RTCRtpSender>) {
+    while rtp_sender.read_rtcp().await.is_ok() {}
+}
+
+/// Records the end of the session of a web client that was removed from the clients
+fn audit_disconnect(audit: &AuditLog, client: &WebClient) {
+    audit.record(
+        Some(client.src),
+        Some(client.user.username.clone()),
+        AuditEvent::Disconnect {
+            duration: client.session_duration(),
+        },
+    );
+}
+
+/// Binds the socket WebRTC peers are multiplexed on, at the first bind address of the server
+fn bind_udp_mux(config: &ServerConfig, port: u16) -> Option<Arc<dyn UDPMux + Send + Sync>> {
+    let address = match config.bind.first() {
+        Some(address) => parse_bind_address(address, port).ok()?,
+        None => return None,
+    };
+    let address = SocketAddr::new(address.ip(), port);
+
+    match bind_udp(address) {
+        Ok(socket) => {
+            debug!("Bound WebRTC UDP Socket at {}", address);
+            Some(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+        }
+        Err(e) => {
+            error!("Failed to Bind WebRTC UDP Socket at {}: {}", address, e);
+            None
+        }
+    }
+}
+
 impl WebConnection {
-    pub fn new() -> Self {
+    pub fn new(
+        config: &ServerConfig,
+        data_dir: &Path,
+        access: AccessControl,
+        audit: AuditLog,
+        controller: WebController,
+        app: AppConnection,
+    ) -> Self {
         let (broadcast_sender, broadcast_receiver) = unbounded::<BroadcastPayload>();
-        let (input_sender, input_receiver) = bounded_async::<Bytes>(MAX_INPUT_BUFFER_SIZE);
+        let (input_sender, input_receiver) = bounded_async::<WebEvent>(MAX_INPUT_BUFFER_SIZE);
 
         Self {
             broadcast_task: Arc::new(sync::RwLock::new(None)),
             broadcast_sender,
             broadcast_receiver: broadcast_receiver.as_async().clone(),
-            clients: Arc::new(RwLock::new(vec![])),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            next_peer_id: Arc::new(AtomicU64::new(1)),
+            controller,
             input_sender,
             input_receiver,
+            access,
+            udp_mux: config.web_port.and_then(|port| bind_udp_mux(config, port)),
+            keyframe: KeyframeRequest::new(),
+            ice_settings: Arc::new(sync::RwLock::new(WebIceSettings::from(config))),
+            users: Arc::new(sync::Mutex::new(Users::new_with_custom_dir(
+                data_dir.to_path_buf(),
+            ))),
+            audit,
+            lockout: app.lockout(),
+            app,
+        }
+    }
+
+    /// Authenticates a web client with the password of a user, followed by
+    /// a TOTP code if the user has it enabled. Attempts are audited under the
+    /// address the client signaled from, and repeated failures lock it out.
+    pub async fn authenticate(
+        &self,
+        src: SocketAddr,
+        username: String,
+        pass: String,
+        totp_code: Option<String>,
+    ) -> Result<User, WebAuthError> {
+        self.audit.record(
+            Some(src),
+            Some(username.clone()),
+            AuditEvent::HandshakeAttempt {
+                method: "web_password".to_string(),
+            },
+        );
+
+        // The users are loaded from disk and the password hashed, off the runtime
+        let web = self.clone();
+        let name = username.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            if web.lockout.is_locked(src.ip(), &name) {
+                return Err(WebAuthError::LockedOut);
+            }
+            web.verify_credentials(src, &name, &pass, totp_code.as_deref())
+        })
+        .await
+        .unwrap_or_else(|e| Err(WebAuthError::Unexpected(e.to_string())));
+
+        match &result {
+            Ok(_) => self.lockout.clear(src.ip(), &username),
+            Err(e) => {
+                if e.is_auth_failure() {
+                    self.lockout.record_failure(src.ip(), Some(&username));
+                }
+
+                self.audit.record(
+                    Some(src),
+                    Some(username),
+                    AuditEvent::HandshakeFailure {
+                        reason: e.to_string(),
+                    },
+                );
+            }
+        }
+
+        result
+    }
+
+    /// Records an audit event for a web client, under the address it signaled from
+    pub fn audit(&self, src: SocketAddr, username: &str, event: AuditEvent) {
+        self.audit
+            .record(Some(src), Some(username.to_string()), event);
+    }
+
+    fn role(&self, id: u64) -> ESessionRole {
+        match self.controller.read() {
+            Ok(controller) if controller.as_ref().is_some_and(|peer| peer.id == id) => {
+                ESessionRole::Controller
+            }
+            _ => ESessionRole::Spectator,
+        }
+    }
+
+    /// Whether input of the peer is applied, only the controller's input is.
+    /// The first input of a spectator since it was last given control is audited
+    /// as a role violation, under its user and the address it signaled from.
+    pub async fn check_control(&self, id: u64) -> bool {
+        if self.role(id) == ESessionRole::Controller {
+            return true;
+        }
+
+        if self
+            .clients
+            .read()
+            .await
+            .get(&id)
+            .is_none_or(|client| client.role_violation_audited)
+        {
+            return false;
+        }
+
+        if let Some(client) = self.clients.write().await.get_mut(&id) {
+            client.role_violation_audited = true;
+
+            warn!("Dropped Input from Web Peer {}, It Is Not in Control", id);
+            self.audit.record(
+                Some(client.src),
+                Some(client.user.username.clone()),
+                AuditEvent::RoleViolation {
+                    role: format!("{:?}", ESessionRole::Spectator),
+                },
+            );
+        }
+
+        false
+    }
+
+    /// Gives control to the peer, taking it from the app client in control
+    pub async fn grant_control(&self, id: u64, by: &str) -> bool {
+        let peer = match self.clients.write().await.get_mut(&id) {
+            Some(client) => {
+                client.role_violation_audited = false;
+
+                info!("Control Granted to Web Peer {} by {}", id, by);
+                self.audit.record(
+                    Some(client.src),
+                    Some(client.user.username.clone()),
+                    AuditEvent::ControlGranted { by: by.to_string() },
+                );
+
+                WebControllerPeer {
+                    id,
+                    src: client.src,
+                    username: client.user.username.clone(),
+                }
+            }
+            None => return false,
+        };
+
+        if let Ok(mut controller) = self.controller.write() {
+            *controller = Some(peer);
         }
+
+        // App clients are sent the new session state whether or not one of them was in control
+        if !self.app.revoke_control().await {
+            self.app.send_session_states().await;
+        }
+        true
+    }
+
+    /// Takes control from the peer in control, if any
+    pub async fn revoke_control(&self, by: &str) -> bool {
+        let peer = match self.controller.write() {
+            Ok(mut controller) => controller.take(),
+            Err(_) => None,
+        };
+
+        let Some(peer) = peer else {
+            return false;
+        };
+
+        info!("Control Revoked from Web Peer {} by {}", peer.id, by);
+        self.audit.record(
+            Some(peer.src),
+            Some(peer.username),
+            AuditEvent::ControlRevoked { by: by.to_string() },
+        );
+
+        self.app.send_session_states().await;
+        true
+    }
+
+    fn verify_credentials(
+        &self,
+        src: SocketAddr,
+        username: &str,
+        pass: &str,
+        totp_code: Option<&str>,
+    ) -> Result<User, WebAuthError> {
+        let mut users = self.users.lock().unwrap();
+        if users.load().is_err() {
+            return Err(WebAuthError::FailedToLoadUsers);
+        }
+
+        let user = users
+            .find_user_by_credentials(&username.to_string(), &hash_password(pass))
+            .ok_or(WebAuthError::InvalidCredentials)?;
+
+        if let Err(denied) = self.access.check_user(src.ip(), &user.username) {
+            return Err(WebAuthError::AccessDenied(denied));
+        }
+
+        let mut user_totp = match user.totp.clone() {
+            Some(user_totp) => user_totp,
+            None => return Ok(user),
+        };
+
+        let code = totp_code.ok_or(WebAuthError::TotpRequired)?;
+        let step = totp::verify_code(&user.username, &user_totp.secret, code, user_totp.last_step)
+            .ok_or(WebAuthError::InvalidTotpCode)?;
+
+        user_totp.last_step = step;
+        users
+            .set_totp(&user.username, Some(user_totp))
+            .and_then(|_| users.save())
+            .map_err(|e| WebAuthError::Unexpected(e.to_string()))?;
+
+        Ok(user)
+    }
+
+    /// Applies to peers created from now on, connected peers keep their candidates
+    pub fn set_ice_settings(&self, settings: WebIceSettings) {
+        if let Ok(mut current) = self.ice_settings.write() {
+            *current = settings;
+        }
+    }
+
+    /// Whether a web client is waiting on a key frame, which is then considered sent
+    #[inline]
+    pub fn take_keyframe_request(&self) -> bool {
+        self.keyframe.take()
     }
 
     /// This function starts the broadcast spawns tokio async task
@@ -177,7 +764,7 @@ impl WebConnection {
     }
 
     #[inline]
-    pub fn receiver(&self) -> AsyncReceiver<Bytes> {
+    pub fn receiver(&self) -> AsyncReceiver<WebEvent> {
         self.input_receiver.clone()
     }
 
@@ -202,126 +789,427 @@ impl WebConnection {
         Ok(())
     }
 
-    pub async fn initialize_client(
-        self,
-        desc_data: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let api = APIBuilder::new().build();
+    /// Creates the peer connection of a web client authenticated as the user from its offer,
+    /// and returns it with the answer. Local ICE candidates are sent on the candidate sender
+    /// as they are gathered (trickle ICE), the remote ones are added to the peer connection
+    /// by the caller. The client is added once its data channel opens, in control of the
+    /// session if it requested control and no other client is in control.
+    pub async fn connect_peer(
+        &self,
+        user: User,
+        src: SocketAddr,
+        role: ESessionRole,
+        offer: RTCSessionDescription,
+        candidate_sender: AsyncSender<RTCIceCandidateInit>,
+    ) -> Result<
+        (Arc<RTCPeerConnection>, RTCSessionDescription),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let ice_settings = self.ice_settings.read().unwrap().clone();
+
+        let mut setting_engine = SettingEngine::default();
+        if let Some(udp_mux) = &self.udp_mux {
+            setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux.clone()));
+        } else if let Some(range) = ice_settings.port_range {
+            let ephemeral = EphemeralUDP::new(range.min, range.max)?;
+            setting_engine.set_udp_network(UDPNetwork::Ephemeral(ephemeral));
+        }
+
+        // ICE lite agents only gather host candidates, the browser runs the checks
+        if ice_settings.host_only {
+            setting_engine.set_lite(true);
+        }
+
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+
+        // Answers NACKs from the packets it sent and sends sender reports
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
 
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec![STUN_SERVER.to_string()],
-                ..Default::default()
-            }],
+            ice_servers: ice_settings.ice_servers,
             ..Default::default()
         };
 
         let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
+        let video_track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: VIDEO_TRACK_FMTP.to_owned(),
+                rtcp_feedback: vec![],
+            },
+            "video".to_owned(),
+            "mrial".to_owned(),
+        ));
+
+        let rtp_sender = peer_connection
+            .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        tokio::spawn(read_video_rtcp(rtp_sender, self.keyframe.clone()));
+
+        let audio_track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                sdp_fmtp_line: AUDIO_TRACK_FMTP.to_owned(),
+                rtcp_feedback: vec![],
+            },
+            "audio".to_owned(),
+            "mrial".to_owned(),
+        ));
+
+        let rtp_sender = peer_connection
+            .add_track(Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        tokio::spawn(read_audio_rtcp(rtp_sender));
+
+        let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        let muted = Arc::new(AtomicBool::new(false));
+        // Messages are only forwarded once the client passed the access check and was added
+        let admitted = Arc::new(AtomicBool::new(false));
+
+        let peer_connection_clone = peer_connection.clone();
+        let clients = self.clients.clone();
+        let audit = self.audit.clone();
+        let controller = self.controller.clone();
+        let app = self.app.clone();
         peer_connection.on_peer_connection_state_change(Box::new(
             move |s: RTCPeerConnectionState| {
-                println!("Peer Connection State has changed: {s}");
-
-                if s == RTCPeerConnectionState::Failed {
-                    // Wait until PeerConnection has had no network activity for 30 seconds or another failure. It may be reconnected using an ICE Restart.
-                    // Use webrtc.PeerConnectionStateDisconnected if you are interested in detecting faster timeout.
-                    // Note that the PeerConnection may come back from PeerConnectionStateDisconnected.
-                    println!("Peer Connection has gone to failed exiting");
-                    // let _ = done_tx.try_send(());
-                }
+                debug!("Peer {id} Connection State Changed: {s}");
+
+                // Failed peers are not restarted, the client signals a new offer instead
+                let peer_connection = peer_connection_clone.clone();
+                let clients = clients.clone();
+                let audit = audit.clone();
+                let controller = controller.clone();
+                let app = app.clone();
+                Box::pin(async move {
+                    if s == RTCPeerConnectionState::Failed {
+                        let _ = peer_connection.close().await;
+                    }
 
-                Box::pin(async {})
+                    if matches!(
+                        s,
+                        RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                    ) {
+                        if let Some(client) = clients.write().await.remove(&id) {
+                            audit_disconnect(&audit, &client);
+                        }
+                        if release_control(&controller, id) {
+                            app.send_session_states().await;
+                        }
+                    }
+                })
             },
         ));
 
+        peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let candidate_sender = candidate_sender.clone();
+
+            Box::pin(async move {
+                // Gathering is complete once there are no more candidates
+                let Some(candidate) = candidate else {
+                    return;
+                };
+
+                match candidate.to_json() {
+                    Ok(candidate) => {
+                        let _ = candidate_sender.send(candidate).await;
+                    }
+                    Err(e) => debug!("Failed to Serialize ICE Candidate: {e}"),
+                }
+            })
+        }));
+
+        let control_channel = peer_connection
+            .create_data_channel(
+                CONTROL_CHANNEL,
+                Some(RTCDataChannelInit {
+                    negotiated: Some(CONTROL_CHANNEL_ID),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        let media_channel = peer_connection
+            .create_data_channel(
+                MEDIA_CHANNEL,
+                Some(RTCDataChannelInit {
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    negotiated: Some(MEDIA_CHANNEL_ID),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
         let peer_connection_clone = peer_connection.clone();
+        let control_channel_clone = control_channel.clone();
+        let media_channel_clone = media_channel.clone();
         let clients = self.clients.clone();
-        let input_sender = self.input_sender.clone();
-        // Register data channel creation handling
-        peer_connection.on_data_channel(Box::new(move |data_channel: Arc<RTCDataChannel>| {
-            let dc_label = data_channel.label().to_owned();
-            let dc_id = data_channel.id();
-            debug!("New DataChannel {dc_label} {dc_id}");
-
-            let peer_connection_clone = peer_connection_clone.clone();
-            let clients_clone = clients.clone();
-            let input_sender = input_sender.clone();
+        let access = self.access.clone();
+        let keyframe = self.keyframe.clone();
+        let controller = self.controller.clone();
+        let app = self.app.clone();
+        let muted_clone = muted.clone();
+        let admitted_clone = admitted.clone();
+        // The client is added once it can be sent control packets
+        control_channel.on_open(Box::new(move || {
+            debug!("Web Peer {id} Control Channel Open");
 
-            // Register channel opening handling
             Box::pin(async move {
-                let data_channel_clone = Arc::clone(&data_channel);
-
-                let dc_label2 = dc_label.clone();
-                let dc_id2 = dc_id;
-                data_channel.on_close(Box::new(move || {
-                    debug!("Data channel closed");
-                    Box::pin(async {})
-                }));
-
-                data_channel.on_open(Box::new(move || {
-                    debug!("Data channel '{dc_label2}'-'{dc_id2}' open.");
-
-                    Box::pin(async move {
-                        let mut clients = clients_clone.write().await;
-
-                        clients.push(WebClient {
-                            peer_connection: peer_connection_clone,
-                            data_channel: data_channel_clone,
-                        });
-                    })
-                }));
-
-                data_channel.on_message(Box::new(move |event: DataChannelMessage| {
-                    let input_sender = input_sender.clone();
-
-                    Box::pin(async move {
-                        if let Err(e) = input_sender.send(event.data).await {
-                            error!("Failed to send event to input channel: {e}");
+                // Fails closed when the remote address can not be determined
+                let denied = match remote_address(&peer_connection_clone).await {
+                    Some(ip) => access
+                        .check(ip)
+                        .err()
+                        .map(|denied| (ip.to_string(), denied)),
+                    None => Some(("Unknown Address".to_string(), AccessDenied::NotAllowed)),
+                };
+
+                if let Some((address, denied)) = denied {
+                    warn!("Rejected Web Client {}: {}", address, denied);
+
+                    let _ = peer_connection_clone.close().await;
+                    return;
+                }
+
+                let peer = WebControllerPeer {
+                    id,
+                    src,
+                    username: user.username.clone(),
+                };
+
+                clients.write().await.insert(
+                    id,
+                    WebClient {
+                        user,
+                        src,
+                        connected_at: Instant::now(),
+                        peer_connection: peer_connection_clone,
+                        control_channel: control_channel_clone,
+                        media_channel: media_channel_clone,
+                        video_track,
+                        audio_track,
+                        muted: muted_clone,
+                        role_violation_audited: false,
+                    },
+                );
+                admitted_clone.store(true, Ordering::Relaxed);
+
+                if role == ESessionRole::Controller && !app.has_controller().await {
+                    let took_control = match controller.write() {
+                        Ok(mut controller) if controller.is_none() => {
+                            *controller = Some(peer);
+                            true
                         }
-                    })
-                }));
+                        _ => false,
+                    };
+
+                    if took_control {
+                        info!("Web Peer {id} Joined in Control");
+                        app.send_session_states().await;
+                    }
+                }
+
+                // The stream is joined mid GOP, so it can not be decoded until the next key frame
+                keyframe.request();
+            })
+        }));
+
+        let input_sender = self.input_sender.clone();
+        let admitted_clone = admitted.clone();
+        control_channel.on_message(Box::new(move |message: DataChannelMessage| {
+            if !admitted_clone.load(Ordering::Relaxed) {
+                debug!("Dropped Message from Web Peer {id}, Not Admitted");
+                return Box::pin(async {});
+            }
+
+            // Muting only concerns the tracks of this client, so it is not forwarded
+            if message.data.len() > HEADER
+                && parse_packet_type(&message.data) == EPacketType::ClientState
+            {
+                match serde_json::from_slice::<WebClientState>(&message.data[HEADER..]) {
+                    Ok(state) => muted.store(state.muted, Ordering::Relaxed),
+                    Err(e) => debug!("Invalid Web Client State: {e}"),
+                }
+
+                return Box::pin(async {});
+            }
+
+            let input_sender = input_sender.clone();
+            Box::pin(async move {
+                if let Err(e) = input_sender.send((id, CONTROL_CHANNEL, message.data)).await {
+                    error!("Failed to send event to input channel: {e}");
+                }
             })
         }));
-        let offer = serde_json::from_str::<RTCSessionDescription>(&desc_data)?;
 
-        // Set the remote SessionDescription
+        let input_sender = self.input_sender.clone();
+        media_channel.on_message(Box::new(move |message: DataChannelMessage| {
+            if !admitted.load(Ordering::Relaxed) {
+                return Box::pin(async {});
+            }
+
+            // Best-effort like the channel, a full queue drops the message instead of waiting
+            match input_sender.try_send((id, MEDIA_CHANNEL, message.data)) {
+                Ok(true) => {}
+                Ok(false) => debug!("Dropped Media Message from Web Peer {id}, Queue Full"),
+                Err(e) => error!("Failed to send event to input channel: {e}"),
+            }
+            Box::pin(async {})
+        }));
+
+        if let Err(e) = Self::answer_offer(&peer_connection, offer).await {
+            let _ = peer_connection.close().await;
+            return Err(e.into());
+        }
+
+        let answer = peer_connection
+            .local_description()
+            .await
+            .ok_or("Failed to Create Local Description")?;
+
+        Ok((peer_connection, answer))
+    }
+
+    /// Sets the offer and answer of the peer connection, which starts gathering candidates
+    async fn answer_offer(
+        peer_connection: &RTCPeerConnection,
+        offer: RTCSessionDescription,
+    ) -> Result<(), webrtc::Error> {
         peer_connection.set_remote_description(offer).await?;
 
-        // Create an answer
         let answer = peer_connection.create_answer(None).await?;
+        peer_connection.set_local_description(answer).await?;
+
+        Ok(())
+    }
 
-        // Create channel that is blocked until ICE Gathering is complete
-        let mut gather_complete = peer_connection.gathering_complete_promise().await;
+    pub async fn client_infos(&self) -> Vec<WebClientInfo> {
+        let clients = self.clients.read().await.clone();
+        let mut infos = Vec::with_capacity(clients.len());
 
-        // Sets the LocalDescription, and starts our UDP listeners
-        peer_connection.set_local_description(answer).await?;
+        for (id, client) in clients.iter() {
+            let stats = client.peer_connection.get_stats().await;
 
-        // Block until ICE Gathering is complete, disabling trickle ICE
-        // we do this because we only can exchange one signaling message
-        // in a production application you should exchange ICE Candidates via OnICECandidate
-        let _ = gather_complete.recv().await;
+            let mut info = WebClientInfo {
+                id: *id,
+                address: remote_address(&client.peer_connection)
+                    .await
+                    .map(|ip| ip.to_string()),
+                username: client.user.username.clone(),
+                role: self.role(*id),
+                duration: client.session_duration(),
+                state: client.peer_connection.connection_state().to_string(),
+                bytes_sent: 0,
+                messages_sent: 0,
+                rtp_bytes_sent: 0,
+                rtp_packets_sent: 0,
+                nack_count: 0,
+                pli_count: 0,
+            };
 
-        // Output the answer in base64 so we can paste it in browser
-        if let Some(local_desc) = peer_connection.local_description().await {
-            let json_str = serde_json::to_string(&local_desc)?;
-            println!("{json_str}");
-        } else {
-            println!("generate local_description failed!");
+            for report in stats.reports.values() {
+                match report {
+                    StatsReportType::DataChannel(stats) => {
+                        info.bytes_sent += stats.bytes_sent as u64;
+                        info.messages_sent += stats.messages_sent as u64;
+                    }
+                    StatsReportType::OutboundRTP(stats) => {
+                        info.rtp_bytes_sent += stats.bytes_sent;
+                        info.rtp_packets_sent += stats.packets_sent;
+                        info.nack_count += stats.nack_count;
+                        info.pli_count += stats.pli_count.unwrap_or(0);
+                    }
+                    _ => {}
+                }
+            }
+
+            infos.push(info);
         }
 
-        Ok(())
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Disconnects the web clients at the address
+    pub async fn disconnect_client(&self, address: IpAddr, reason: EDisconnectReason) -> bool {
+        let clients = self.clients.read().await.clone();
+        let mut disconnected = false;
+
+        for client in clients.values() {
+            if remote_address(&client.peer_connection).await != Some(address) {
+                continue;
+            }
+
+            client.disconnect(reason).await;
+            disconnected = true;
+        }
+
+        if disconnected {
+            self.filter_clients().await;
+        }
+
+        disconnected
+    }
+
+    pub async fn disconnect_clients(&self, reason: EDisconnectReason) {
+        let clients: Vec<WebClient> = self
+            .clients
+            .write()
+            .await
+            .drain()
+            .map(|(_, client)| client)
+            .collect();
+
+        for client in clients.iter() {
+            audit_disconnect(&self.audit, client);
+            client.disconnect(reason).await;
+        }
+
+        let released = self
+            .controller
+            .write()
+            .is_ok_and(|mut controller| controller.take().is_some());
+        if released {
+            self.app.send_session_states().await;
+        }
     }
 
     #[inline]
     pub async fn filter_clients(&self) {
-        self.clients.write().await.retain(|client| {
-            client.peer_connection.connection_state() == RTCPeerConnectionState::Connected
+        let mut released = false;
+
+        self.clients.write().await.retain(|id, client| {
+            let connected =
+                client.peer_connection.connection_state() == RTCPeerConnectionState::Connected;
+
+            if !connected {
+                audit_disconnect(&self.audit, client);
+                released |= release_control(&self.controller, *id);
+            }
+
+            connected
         });
+
+        if released {
+            self.app.send_session_states().await;
+        }
     }
 
     #[inline]
     pub async fn has_clients(&self) -> bool {
-        self.clients.read().await.iter().any(|client| {
+        self.clients.read().await.values().any(|client| {
             client.peer_connection.connection_state() == RTCPeerConnectionState::Connected
         })
     }
@@ -331,11 +1219,424 @@ impl Clone for WebConnection {
     fn clone(&self) -> Self {
         Self {
             clients: self.clients.clone(),
+            next_peer_id: self.next_peer_id.clone(),
+            controller: self.controller.clone(),
+            app: self.app.clone(),
             broadcast_sender: self.broadcast_sender.clone(),
             broadcast_receiver: self.broadcast_receiver.clone(),
             broadcast_task: self.broadcast_task.clone(),
             input_sender: self.input_sender.clone(),
             input_receiver: self.input_receiver.clone(),
+            access: self.access.clone(),
+            udp_mux: self.udp_mux.clone(),
+            keyframe: self.keyframe.clone(),
+            ice_settings: self.ice_settings.clone(),
+            users: self.users.clone(),
+            audit: self.audit.clone(),
+            lockout: self.lockout.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mrial_proto::write_header;
+    use serde_json::json;
+    use tokio::time::timeout;
+    use webrtc::{
+        api::media_engine::MediaEngine,
+        data_channel::data_channel_state::RTCDataChannelState,
+        rtp_transceiver::{
+            rtp_codec::RTPCodecType, rtp_transceiver_direction::RTCRtpTransceiverDirection,
+            RTCRtpTransceiverInit,
+        },
+        track::track_remote::TrackRemote,
+    };
+
+    use super::*;
+    use crate::{conn::ServerMeta, test_dir::TestDir};
+
+    async fn test_connection(dir: &TestDir) -> WebConnection {
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        let access = AccessControl::new();
+        let audit = AuditLog::new_with_custom_dir(dir.path().to_path_buf()).with_writer();
+        let controller = WebController::default();
+
+        let app = AppConnection::new(
+            &config,
+            dir.path(),
+            access.clone(),
+            audit.clone(),
+            Arc::new(RwLock::new(ServerMeta::default())),
+            controller.clone(),
+        )
+        .await;
+
+        WebConnection::new(&config, dir.path(), access, audit, controller, app)
+    }
+
+    fn test_user() -> User {
+        User {
+            username: "alice".to_string(),
+            pass: String::new(),
+            keys: Vec::new(),
+            totp: None,
+        }
+    }
+
+    /// Peer of a browser receiving the tracks, with the negotiated data channels
+    struct Browser {
+        peer_connection: Arc<RTCPeerConnection>,
+        control_channel: Arc<RTCDataChannel>,
+        media_channel: Arc<RTCDataChannel>,
+    }
+
+    impl Browser {
+        async fn new() -> Self {
+            let mut media_engine = MediaEngine::default();
+            media_engine.register_default_codecs().unwrap();
+            let api = APIBuilder::new().with_media_engine(media_engine).build();
+
+            let peer_connection = Arc::new(
+                api.new_peer_connection(RTCConfiguration::default())
+                    .await
+                    .unwrap(),
+            );
+
+            for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+                peer_connection
+                    .add_transceiver_from_kind(
+                        kind,
+                        Some(RTCRtpTransceiverInit {
+                            direction: RTCRtpTransceiverDirection::Recvonly,
+                            send_encodings: Vec::new(),
+                        }),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let control_channel = peer_connection
+                .create_data_channel(
+                    CONTROL_CHANNEL,
+                    Some(RTCDataChannelInit {
+                        negotiated: Some(CONTROL_CHANNEL_ID),
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap();
+            let media_channel = peer_connection
+                .create_data_channel(
+                    MEDIA_CHANNEL,
+                    Some(RTCDataChannelInit {
+                        ordered: Some(false),
+                        max_retransmits: Some(0),
+                        negotiated: Some(MEDIA_CHANNEL_ID),
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .unwrap();
+
+            Self {
+                peer_connection,
+                control_channel,
+                media_channel,
+            }
+        }
+
+        /// Offer with the candidates gathered up front
+        async fn offer(&self) -> RTCSessionDescription {
+            let description = self.peer_connection.create_offer(None).await.unwrap();
+            let mut gathered = self.peer_connection.gathering_complete_promise().await;
+            self.peer_connection
+                .set_local_description(description)
+                .await
+                .unwrap();
+            let _ = gathered.recv().await;
+
+            self.peer_connection.local_description().await.unwrap()
+        }
+
+        /// Connects to the server as the user, returning the answer once the server added the client
+        async fn connect(&self, web: &WebConnection, role: ESessionRole) -> RTCSessionDescription {
+            let (candidate_sender, candidate_receiver) = bounded_async(16);
+            let offer = self.offer().await;
+
+            let (_, answer) = web
+                .connect_peer(
+                    test_user(),
+                    "127.0.0.1:50000".parse().unwrap(),
+                    role,
+                    offer,
+                    candidate_sender,
+                )
+                .await
+                .unwrap();
+
+            let peer_connection = self.peer_connection.clone();
+            tokio::spawn(async move {
+                while let Ok(candidate) = candidate_receiver.recv().await {
+                    let _ = peer_connection.add_ice_candidate(candidate).await;
+                }
+            });
+            self.peer_connection
+                .set_remote_description(answer.clone())
+                .await
+                .unwrap();
+
+            let added = web.clients.read().await.len() + 1;
+            timeout(Duration::from_secs(10), async {
+                while web.clients.read().await.len() < added
+                    || self.media_channel.ready_state() != RTCDataChannelState::Open
+                {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+            .await
+            .unwrap();
+
+            answer
+        }
+
+        /// Mutes or unmutes the audio track, the way the browser player does
+        async fn set_muted(&self, muted: bool) {
+            let payload = json!({ "muted": muted }).to_string();
+            let mut buf = vec![0u8; HEADER + payload.len()];
+            buf[HEADER..].copy_from_slice(payload.as_bytes());
+            write_header(EPacketType::ClientState, 0, buf.len() as u32, 0, &mut buf);
+
+            self.control_channel.send(&Bytes::from(buf)).await.unwrap();
+        }
+
+        /// Waits for the first RTP packet of the track of the kind
+        async fn first_packet(&self, kind: RTPCodecType) -> webrtc::rtp::packet::Packet {
+            let (track_sender, track_receiver) = bounded_async::<Arc<TrackRemote>>(2);
+            self.peer_connection.on_track(Box::new(move |track, _, _| {
+                let track_sender = track_sender.clone();
+                Box::pin(async move {
+                    let _ = track_sender.send(track).await;
+                })
+            }));
+
+            timeout(Duration::from_secs(10), async {
+                loop {
+                    let track = track_receiver.recv().await.unwrap();
+                    if track.kind() == kind {
+                        return track.read_rtp().await.unwrap().0;
+                    }
+                }
+            })
+            .await
+            .unwrap()
         }
     }
+
+    #[test]
+    fn keyframe_requests_are_limited_to_one_per_interval() {
+        let keyframe = KeyframeRequest::new();
+        assert!(!keyframe.take());
+
+        keyframe.request();
+        assert!(keyframe.take());
+        assert!(!keyframe.take());
+
+        // Requested again right after, it stays pending until the interval passes
+        keyframe.request();
+        assert!(!keyframe.take());
+        *keyframe.last_taken.lock().unwrap() = Some(Instant::now() - KEYFRAME_REQUEST_INTERVAL);
+        assert!(keyframe.take());
+    }
+
+    #[tokio::test]
+    async fn video_is_sent_over_an_h264_track() {
+        let dir = TestDir::new("web-video");
+        let web = test_connection(&dir).await;
+        web.start_broadcast_async_task();
+
+        let browser = Browser::new().await;
+        let answer = browser.connect(&web, ESessionRole::Spectator).await;
+        assert!(answer.sdp.contains("H264/90000"), "{}", answer.sdp);
+        assert!(
+            answer.sdp.contains("profile-level-id=640032"),
+            "{}",
+            answer.sdp
+        );
+
+        // Joining mid GOP, the peer waits on a key frame
+        assert!(web.take_keyframe_request());
+
+        let packet = browser.first_packet(RTPCodecType::Video);
+        let frame = [0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00];
+        let sender = async {
+            loop {
+                web.broadcast_frame(EPacketType::NAL, &frame).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let packet = tokio::select! {
+            packet = packet => packet,
+            _ = sender => unreachable!(),
+        };
+        assert_eq!(packet.payload.as_ref(), &frame[4..]);
+
+        browser.peer_connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn audio_is_sent_over_an_opus_track_to_peers_not_muted() {
+        let dir = TestDir::new("web-audio");
+        let web = test_connection(&dir).await;
+        web.start_broadcast_async_task();
+
+        let muted = Browser::new().await;
+        let answer = muted.connect(&web, ESessionRole::Spectator).await;
+        assert!(answer.sdp.contains("opus/48000/2"), "{}", answer.sdp);
+
+        muted.set_muted(true).await;
+        timeout(Duration::from_secs(5), async {
+            while !web
+                .clients
+                .read()
+                .await
+                .values()
+                .any(|client| client.muted.load(Ordering::Relaxed))
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let listening = Browser::new().await;
+        listening.connect(&web, ESessionRole::Spectator).await;
+
+        let packet = listening.first_packet(RTPCodecType::Audio);
+        let opus = [0xfc, 0xff, 0xfe];
+        let sender = async {
+            loop {
+                web.broadcast_frame(EPacketType::AudioOpus, &opus).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let packet = tokio::select! {
+            packet = packet => packet,
+            _ = sender => unreachable!(),
+        };
+        assert_eq!(packet.payload.as_ref(), &opus);
+
+        // Only audio was sent, so no RTP at all went to the muted peer
+        let infos = web.client_infos().await;
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[0].rtp_packets_sent, 0);
+        assert!(infos[1].rtp_packets_sent > 0);
+
+        muted.peer_connection.close().await.unwrap();
+        listening.peer_connection.close().await.unwrap();
+    }
+
+    #[test]
+    fn control_is_only_released_by_the_peer_in_control() {
+        let controller = WebController::default();
+        *controller.write().unwrap() = Some(WebControllerPeer {
+            id: 1,
+            src: "127.0.0.1:50000".parse().unwrap(),
+            username: "alice".to_string(),
+        });
+
+        assert!(!release_control(&controller, 2));
+        assert!(controller.read().unwrap().is_some());
+
+        assert!(release_control(&controller, 1));
+        assert!(controller.read().unwrap().is_none());
+        assert!(!release_control(&controller, 1));
+    }
+
+    #[tokio::test]
+    async fn input_of_spectators_is_dropped_and_audited_once_per_grant() {
+        let dir = TestDir::new("web-control");
+        let web = test_connection(&dir).await;
+
+        let controller = Browser::new().await;
+        controller.connect(&web, ESessionRole::Controller).await;
+        let spectator = Browser::new().await;
+        spectator.connect(&web, ESessionRole::Controller).await;
+
+        // The second peer requesting control joins as a spectator
+        assert!(web.check_control(1).await);
+        assert!(!web.check_control(2).await);
+        assert!(!web.check_control(2).await);
+
+        assert!(web.grant_control(2, "admin").await);
+        assert!(!web.check_control(1).await);
+        assert!(web.check_control(2).await);
+
+        assert!(web.revoke_control("admin").await);
+        assert!(!web.check_control(2).await);
+
+        // Once for the spectator before it was given control and again after, once for the other peer
+        web.audit.flush().await.unwrap();
+        let records = AuditLog::new_with_custom_dir(dir.path().to_path_buf())
+            .read()
+            .unwrap();
+        let violations = records
+            .iter()
+            .filter(|record| record.event.name() == "role_violation")
+            .count();
+        assert_eq!(violations, 3);
+
+        controller.peer_connection.close().await.unwrap();
+        spectator.peer_connection.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_are_forwarded_with_the_label_of_their_channel() {
+        let dir = TestDir::new("web-channels");
+        let web = test_connection(&dir).await;
+        let receiver = web.receiver();
+
+        let browser = Browser::new().await;
+        browser.connect(&web, ESessionRole::Spectator).await;
+
+        let clients = web.clients.read().await;
+        let (id, client) = clients.iter().next().unwrap();
+        let id = *id;
+        assert!(client.control_channel.ordered());
+        assert_eq!(client.control_channel.max_retransmits(), None);
+        assert!(!client.media_channel.ordered());
+        assert_eq!(client.media_channel.max_retransmits(), Some(0));
+        drop(clients);
+
+        let mut input = [0u8; HEADER + mrial_proto::input::PAYLOAD];
+        write_header(
+            EPacketType::InputState,
+            0,
+            input.len() as u32,
+            0,
+            &mut input,
+        );
+        let input = Bytes::copy_from_slice(&input);
+
+        // Muting is handled by the connection, so the input after it is the first message forwarded
+        browser.set_muted(true).await;
+        browser.control_channel.send(&input).await.unwrap();
+        let event = timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event, (id, CONTROL_CHANNEL, input.clone()));
+
+        browser.media_channel.send(&input).await.unwrap();
+        let event = timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event, (id, MEDIA_CHANNEL, input));
+
+        browser.peer_connection.close().await.unwrap();
+    }
 }
@@ -0,0 +1,137 @@
+#[cfg(unix)]
+pub mod server;
+
+use std::{fmt, path::PathBuf};
+
+use mrial_fs::server_data_dir;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::conn::{app::AppClientInfo, web::WebClientInfo};
+
+const CONTROL_SOCKET_FILE: &str = "control.sock";
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+pub const METHOD_CLIENTS_LIST: &str = "clients.list";
+pub const METHOD_CLIENTS_KICK: &str = "clients.kick";
+pub const METHOD_STREAM_KEYFRAME: &str = "stream.keyframe";
+pub const METHOD_STREAM_ENCODER: &str = "stream.encoder";
+pub const METHOD_USERS_RELOAD: &str = "users.reload";
+pub const METHOD_SESSION_GRANT: &str = "session.grant";
+pub const METHOD_SESSION_REVOKE: &str = "session.revoke";
+
+pub const ERROR_PARSE: i64 = -32700;
+pub const ERROR_METHOD_NOT_FOUND: i64 = -32601;
+pub const ERROR_INVALID_PARAMS: i64 = -32602;
+pub const ERROR_SERVER: i64 = -32000;
+pub const ERROR_CLIENT_NOT_FOUND: i64 = -32001;
+
+/// Unix-domain socket the server listens for control requests on
+pub fn control_socket_path() -> PathBuf {
+    server_data_dir().join(CONTROL_SOCKET_FILE)
+}
+
+/// JSON-RPC 2.0 request, sent as a single line over the control socket
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl RpcRequest {
+    pub fn new(id: u64, method: &str, params: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id: Value::from(id),
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    pub fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Result of `clients.list`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientsList {
+    pub app: Vec<AppClientInfo>,
+    pub web: Vec<WebClientInfo>,
+}
+
+/// Params of `clients.kick`, either an app client "ip:port" or an IP,
+/// which kicks every app and web client at the address.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KickParams {
+    pub address: String,
+}
+
+/// Params of `session.grant`, the "ip:port" of the app client given control
+/// or the ID of the web peer given control
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrantParams {
+    pub address: String,
+}
+
+/// Params of `stream.encoder`, settings left out are kept
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct EncoderParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u8>,
+}
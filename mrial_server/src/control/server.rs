@@ -0,0 +1,478 @@
+use std::{
+    fs::{self, Permissions},
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::PermissionsExt,
+    sync::Arc,
+};
+
+use kanal::AsyncSender;
+use log::{debug, error, warn};
+use mrial_proto::EDisconnectReason;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+    task::JoinHandle,
+};
+
+use crate::{
+    conn::ConnectionManager,
+    video::{
+        simulcast::{EncoderSettings, ENCODER_PRESETS, MAX_ENCODER_CRF},
+        VideoServerAction,
+    },
+};
+
+use super::*;
+
+/// Serves the JSON-RPC control API to root and, if configured, the members of the control group.
+pub struct ControlServer {
+    conn: ConnectionManager,
+    video_server_ch_sender: AsyncSender<VideoServerAction>,
+    encoder_settings: Arc<Mutex<EncoderSettings>>,
+    control_gid: Option<Gid>,
+}
+
+impl ControlServer {
+    fn new(
+        conn: ConnectionManager,
+        video_server_ch_sender: AsyncSender<VideoServerAction>,
+    ) -> Self {
+        let control_gid = conn
+            .get_config()
+            .control_group
+            .and_then(|group| match Group::from_name(&group) {
+                Ok(Some(group)) => Some(group.gid),
+                _ => {
+                    warn!(
+                        "Control Group \"{}\" Not Found, Only Root May Connect",
+                        group
+                    );
+                    None
+                }
+            });
+
+        Self {
+            conn,
+            video_server_ch_sender,
+            encoder_settings: Arc::new(Mutex::new(EncoderSettings::default())),
+            control_gid,
+        }
+    }
+
+    fn bind(&self) -> io::Result<UnixListener> {
+        let path = control_socket_path();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Left behind by a server that did not shut down cleanly
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+
+        let mode = match self.control_gid {
+            Some(gid) => {
+                unistd::chown(&path, None, Some(gid))?;
+                0o660
+            }
+            None => 0o600,
+        };
+        fs::set_permissions(&path, Permissions::from_mode(mode))?;
+
+        debug!("Bound Control Socket at {:?}", path);
+
+        Ok(listener)
+    }
+
+    /// Checks the credentials of the peer, the socket permissions alone
+    /// are not trusted in case the data directory is shared.
+    fn is_authorized(&self, stream: &UnixStream) -> bool {
+        let cred = match stream.peer_cred() {
+            Ok(cred) => cred,
+            Err(e) => {
+                debug!("Failed to Read Control Peer Credentials: {}", e);
+                return false;
+            }
+        };
+
+        let uid = Uid::from_raw(cred.uid());
+        if uid.is_root() || uid == unistd::geteuid() {
+            return true;
+        }
+
+        let gid = match self.control_gid {
+            Some(gid) => gid,
+            None => return false,
+        };
+
+        if Gid::from_raw(cred.gid()) == gid {
+            return true;
+        }
+
+        let user = match User::from_uid(uid) {
+            Ok(Some(user)) => user,
+            _ => return false,
+        };
+
+        match Group::from_gid(gid) {
+            Ok(Some(group)) => user.gid == gid || group.mem.contains(&user.name),
+            _ => false,
+        }
+    }
+
+    async fn list_clients(&self) -> Result<Value, RpcError> {
+        let (app, web) = (self.conn.get_app(), self.conn.get_web());
+        let (app, web) = tokio::join!(app.client_infos(), web.client_infos());
+
+        serde_json::to_value(ClientsList { app, web })
+            .map_err(|e| RpcError::new(ERROR_SERVER, e.to_string()))
+    }
+
+    async fn kick_client(&self, params: Value) -> Result<Value, RpcError> {
+        let params: KickParams = serde_json::from_value(params)
+            .map_err(|e| RpcError::new(ERROR_INVALID_PARAMS, e.to_string()))?;
+
+        let app = self.conn.get_app();
+
+        let kicked = if let Ok(src) = params.address.parse::<SocketAddr>() {
+            app.disconnect_client(src, EDisconnectReason::Kicked).await
+        } else if let Ok(ip) = params.address.parse::<IpAddr>() {
+            let mut kicked = false;
+            for client in app.client_infos().await {
+                let src = match client.address.parse::<SocketAddr>() {
+                    Ok(src) => src,
+                    Err(_) => continue,
+                };
+
+                if src.ip().to_canonical() == ip.to_canonical() {
+                    kicked |= app.disconnect_client(src, EDisconnectReason::Kicked).await;
+                }
+            }
+
+            self.conn
+                .get_web()
+                .disconnect_client(ip, EDisconnectReason::Kicked)
+                .await
+                || kicked
+        } else {
+            return Err(RpcError::new(
+                ERROR_INVALID_PARAMS,
+                format!("Invalid address \"{}\"", params.address),
+            ));
+        };
+
+        if !kicked {
+            return Err(RpcError::new(ERROR_CLIENT_NOT_FOUND, "Client not found"));
+        }
+
+        Ok(Value::Bool(true))
+    }
+
+    async fn grant_control(&self, params: Value) -> Result<Value, RpcError> {
+        let params: GrantParams = serde_json::from_value(params)
+            .map_err(|e| RpcError::new(ERROR_INVALID_PARAMS, e.to_string()))?;
+
+        let granted = if let Ok(peer_id) = params.address.parse::<u64>() {
+            self.conn.get_web().grant_control(peer_id, "admin").await
+        } else if let Ok(src) = params.address.parse::<SocketAddr>() {
+            self.conn.get_app().grant_control(src).await
+        } else {
+            return Err(RpcError::new(
+                ERROR_INVALID_PARAMS,
+                format!("Invalid address \"{}\"", params.address),
+            ));
+        };
+
+        if !granted {
+            return Err(RpcError::new(ERROR_CLIENT_NOT_FOUND, "Client not found"));
+        }
+
+        Ok(Value::Bool(true))
+    }
+
+    async fn send_video_action(&self, action: VideoServerAction) -> Result<(), RpcError> {
+        self.video_server_ch_sender
+            .send(action)
+            .await
+            .map_err(|e| RpcError::new(ERROR_SERVER, e.to_string()))
+    }
+
+    async fn update_encoder(&self, params: Value) -> Result<Value, RpcError> {
+        let params: EncoderParams = serde_json::from_value(params)
+            .map_err(|e| RpcError::new(ERROR_INVALID_PARAMS, e.to_string()))?;
+
+        if let Some(preset) = &params.preset {
+            if !ENCODER_PRESETS.contains(&preset.as_str()) {
+                return Err(RpcError::new(
+                    ERROR_INVALID_PARAMS,
+                    format!(
+                        "Invalid preset \"{}\", use one of: {}",
+                        preset,
+                        ENCODER_PRESETS.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        if let Some(crf) = params.crf {
+            if crf > MAX_ENCODER_CRF {
+                return Err(RpcError::new(
+                    ERROR_INVALID_PARAMS,
+                    format!("Invalid CRF {}, must be at most {}", crf, MAX_ENCODER_CRF),
+                ));
+            }
+        }
+
+        let mut settings = self.encoder_settings.lock().await;
+        let mut updated = settings.clone();
+
+        if let Some(preset) = params.preset {
+            updated.preset = preset;
+        }
+        if let Some(crf) = params.crf {
+            updated.crf = crf;
+        }
+
+        if updated != *settings {
+            self.send_video_action(VideoServerAction::EncoderUpdate(updated.clone()))
+                .await?;
+            *settings = updated;
+        }
+
+        serde_json::to_value(&*settings).map_err(|e| RpcError::new(ERROR_SERVER, e.to_string()))
+    }
+
+    fn reload_users(&self) -> Result<Value, RpcError> {
+        self.conn
+            .get_app()
+            .reload_users()
+            .map_err(|e| RpcError::new(ERROR_SERVER, format!("Failed to reload users: {}", e)))?;
+        self.conn.get_access().reload().map_err(|e| {
+            RpcError::new(
+                ERROR_SERVER,
+                format!("Failed to reload access rules: {}", e),
+            )
+        })?;
+
+        Ok(Value::Bool(true))
+    }
+
+    async fn handle_request(&self, request: RpcRequest) -> RpcResponse {
+        debug!("Control Request: {}", request.method);
+
+        let result = match request.method.as_str() {
+            METHOD_CLIENTS_LIST => self.list_clients().await,
+            METHOD_CLIENTS_KICK => self.kick_client(request.params).await,
+            METHOD_STREAM_KEYFRAME => self
+                .send_video_action(VideoServerAction::ForceKeyframe)
+                .await
+                .map(|_| Value::Bool(true)),
+            METHOD_STREAM_ENCODER => self.update_encoder(request.params).await,
+            METHOD_USERS_RELOAD => self.reload_users(),
+            METHOD_SESSION_GRANT => self.grant_control(request.params).await,
+            METHOD_SESSION_REVOKE => {
+                let app_revoked = self.conn.get_app().revoke_control().await;
+                let web_revoked = self.conn.get_web().revoke_control("admin").await;
+
+                Ok(Value::Bool(app_revoked || web_revoked))
+            }
+            method => Err(RpcError::new(
+                ERROR_METHOD_NOT_FOUND,
+                format!("Method \"{}\" not found", method),
+            )),
+        };
+
+        match result {
+            Ok(result) => RpcResponse::result(request.id, result),
+            Err(error) => RpcResponse::error(request.id, error),
+        }
+    }
+
+    async fn handle_stream(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<RpcRequest>(&line) {
+                Ok(request) => self.handle_request(request).await,
+                Err(e) => {
+                    RpcResponse::error(Value::Null, RpcError::new(ERROR_PARSE, e.to_string()))
+                }
+            };
+
+            let mut buf = serde_json::to_vec(&response)?;
+            buf.push(b'\n');
+            writer.write_all(&buf).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn accept_loop(self: Arc<Self>, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to Accept Control Connection: {}", e);
+                    continue;
+                }
+            };
+
+            if !self.is_authorized(&stream) {
+                warn!("Rejected Control Connection from Unauthorized User");
+                continue;
+            }
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_stream(stream).await {
+                    debug!("Control Connection Closed: {}", e);
+                }
+            });
+        }
+    }
+
+    pub fn run(
+        conn: ConnectionManager,
+        video_server_ch_sender: AsyncSender<VideoServerAction>,
+    ) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            let server = ControlServer::new(conn, video_server_ch_sender);
+
+            match server.bind() {
+                Ok(listener) => Arc::new(server).accept_loop(listener).await,
+                Err(e) => error!("Failed to Bind Control Socket: {}", e),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kanal::AsyncReceiver;
+    use mrial_fs::ServerConfig;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    async fn test_server(dir: &TestDir) -> (ControlServer, AsyncReceiver<VideoServerAction>) {
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        let conn = ConnectionManager::new_with_custom_dir(&config, dir.path()).await;
+        let (sender, receiver) = kanal::unbounded_async();
+
+        (ControlServer::new(conn, sender), receiver)
+    }
+
+    async fn request(server: &ControlServer, method: &str, params: Value) -> RpcResponse {
+        server
+            .handle_request(RpcRequest::new(1, method, params))
+            .await
+    }
+
+    fn error_code(response: &RpcResponse) -> Option<i64> {
+        response.error.as_ref().map(|error| error.code)
+    }
+
+    #[tokio::test]
+    async fn encoder_settings_are_validated_and_sent_to_the_video_server() {
+        let dir = TestDir::new("control-encoder");
+        let (server, receiver) = test_server(&dir).await;
+
+        let response = request(
+            &server,
+            METHOD_STREAM_ENCODER,
+            json!({ "preset": "slowest" }),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some(ERROR_INVALID_PARAMS));
+        let response = request(&server, METHOD_STREAM_ENCODER, json!({ "crf": 52 })).await;
+        assert_eq!(error_code(&response), Some(ERROR_INVALID_PARAMS));
+        assert!(receiver.is_empty());
+
+        let response = request(&server, METHOD_STREAM_ENCODER, json!({ "crf": 30 })).await;
+        let expected = EncoderSettings {
+            crf: 30,
+            ..Default::default()
+        };
+        assert_eq!(
+            response.result,
+            Some(serde_json::to_value(&expected).unwrap())
+        );
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            Some(VideoServerAction::EncoderUpdate(expected))
+        );
+
+        // Unchanged settings are not sent again
+        request(&server, METHOD_STREAM_ENCODER, json!({ "crf": 30 })).await;
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_and_clients_are_errors() {
+        let dir = TestDir::new("control-errors");
+        let (server, _receiver) = test_server(&dir).await;
+
+        let response = request(&server, "clients.ban", Value::Null).await;
+        assert_eq!(error_code(&response), Some(ERROR_METHOD_NOT_FOUND));
+
+        let kick = |address: &str| json!({ "address": address });
+        let response = request(&server, METHOD_CLIENTS_KICK, kick("not an address")).await;
+        assert_eq!(error_code(&response), Some(ERROR_INVALID_PARAMS));
+        let response = request(&server, METHOD_CLIENTS_KICK, kick("127.0.0.1:50000")).await;
+        assert_eq!(error_code(&response), Some(ERROR_CLIENT_NOT_FOUND));
+        let response = request(&server, METHOD_CLIENTS_KICK, Value::Null).await;
+        assert_eq!(error_code(&response), Some(ERROR_INVALID_PARAMS));
+
+        let response = request(&server, METHOD_CLIENTS_LIST, Value::Null).await;
+        assert_eq!(response.result, Some(json!({ "app": [], "web": [] })));
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_line_by_line() {
+        let dir = TestDir::new("control-stream");
+        let (server, _receiver) = test_server(&dir).await;
+        let (client, stream) = UnixStream::pair().unwrap();
+
+        let task = tokio::spawn(async move { server.handle_stream(stream).await });
+
+        let (reader, mut writer) = client.into_split();
+        let request = serde_json::to_string(&RpcRequest::new(7, METHOD_CLIENTS_LIST, Value::Null));
+        writer
+            .write_all(format!("not json\n\n{}\n", request.unwrap()).as_bytes())
+            .await
+            .unwrap();
+        drop(writer);
+
+        let mut lines = BufReader::new(reader).lines();
+        let parse_error: RpcResponse =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(error_code(&parse_error), Some(ERROR_PARSE));
+        assert_eq!(parse_error.id, Value::Null);
+
+        let listed: RpcResponse =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(listed.id, json!(7));
+        assert!(listed.result.is_some());
+
+        assert!(lines.next_line().await.unwrap().is_none());
+        task.await.unwrap().unwrap();
+    }
+}
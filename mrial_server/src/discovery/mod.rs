@@ -0,0 +1,167 @@
+use std::{env, error::Error, net::SocketAddr, path::Path};
+
+use log::{debug, error, info, warn};
+use mrial_fs::{
+    storage::StorageMultiType, vault::VaultError, KeyPair, KeyPairs, ServerConfig, HOST_KEY_PAIR,
+};
+use mrial_proto::{auth, discovery::*};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::conn::{
+    sockets::{bind_udp, parse_bind_address},
+    ConnectionManager,
+};
+
+/// Environment variable the passphrase of the server vault is read from
+pub const VAULT_PASSPHRASE_ENV: &str = "MRIAL_VAULT_PASSPHRASE";
+
+/// Unlocks the server vault with the passphrase from the environment, if it is enabled
+pub fn unlock_server_vault(key_pairs: &KeyPairs) -> Result<(), VaultError> {
+    let vault = key_pairs.vault();
+    if !vault.is_enabled() || vault.is_unlocked() {
+        return Ok(());
+    }
+
+    match env::var(VAULT_PASSPHRASE_ENV) {
+        Ok(passphrase) => vault.unlock(&passphrase),
+        Err(_) => Err(VaultError::Locked),
+    }
+}
+
+/// Loads the host key of the server, generating and saving one on first start.
+/// The secret key is sealed once the server vault is created, which is then
+/// unlocked with the passphrase in `MRIAL_VAULT_PASSPHRASE`.
+pub fn load_host_key(data_dir: &Path) -> Result<KeyPair, Box<dyn Error>> {
+    let mut key_pairs = KeyPairs::new_server_with_custom_dir(data_dir.to_path_buf());
+    key_pairs.load()?;
+    unlock_server_vault(&key_pairs)?;
+
+    if let Some(key_pair) = key_pairs.find(HOST_KEY_PAIR.to_string()) {
+        return Ok(key_pairs.unseal(key_pair)?);
+    }
+
+    let (secret_key, public_key) = auth::generate_key_pair();
+    let key_pair = KeyPair {
+        name: HOST_KEY_PAIR.to_string(),
+        public_key,
+        secret_key,
+        sealed: None,
+    };
+
+    key_pairs.add(key_pair.clone())?;
+    key_pairs.save()?;
+    info!("Generated New Host Key");
+
+    Ok(key_pair)
+}
+
+/// Name advertised to players, the configured one or the hostname
+pub fn server_name(config: &ServerConfig) -> String {
+    if let Some(name) = &config.name {
+        return name.clone();
+    }
+
+    #[cfg(unix)]
+    let hostname = nix::unistd::gethostname()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok());
+    #[cfg(not(unix))]
+    let hostname = std::env::var("COMPUTERNAME").ok();
+
+    hostname.unwrap_or_else(|| "Mrial Server".to_string())
+}
+
+/// Port players connect to, the one of the first bind address
+fn app_port(config: &ServerConfig) -> u16 {
+    config
+        .bind
+        .iter()
+        .find_map(|address| parse_bind_address(address, config.port).ok())
+        .map_or(config.port, |address| address.port())
+}
+
+/// Answers the discovery probes players broadcast on the LAN with a beacon
+/// carrying the name, version, port and host key fingerprint of the server.
+pub struct DiscoveryTask {
+    conn: ConnectionManager,
+    fingerprint: String,
+}
+
+impl DiscoveryTask {
+    fn beacon(&self) -> Option<Vec<u8>> {
+        let config = self.conn.get_config();
+
+        DiscoveryBeacon::new(
+            &server_name(&config),
+            env!("CARGO_PKG_VERSION"),
+            app_port(&config),
+            self.fingerprint.clone(),
+        )
+        .to_bytes()
+    }
+
+    async fn discovery_loop(&self, socket: UdpSocket) {
+        let access = self.conn.get_access();
+        let mut buf = [0u8; DISCOVERY_PROBE_SIZE];
+
+        loop {
+            let (len, src) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Failed to Receive Discovery Probe: {}", e);
+                    continue;
+                }
+            };
+
+            if !is_discovery_probe(&buf[..len]) {
+                continue;
+            }
+
+            if let Err(e) = access.check(src.ip()) {
+                debug!("Ignored Discovery Probe from {}: {}", src, e);
+                continue;
+            }
+
+            let beacon = match self.beacon() {
+                Some(beacon) => beacon,
+                None => {
+                    warn!("Discovery Beacon Exceeds {} Bytes", DISCOVERY_PROBE_SIZE);
+                    continue;
+                }
+            };
+
+            if let Err(e) = socket.send_to(&beacon, src).await {
+                debug!("Failed to Send Discovery Beacon to {}: {}", src, e);
+            }
+        }
+    }
+
+    /// Answers probes sent to the address, usually `DISCOVERY_PORT` on every interface
+    pub fn run(conn: ConnectionManager, address: SocketAddr) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            let fingerprint = match conn.get_app().host_key_fingerprint() {
+                Some(fingerprint) => fingerprint,
+                None => {
+                    error!("Discovery Requires the Host Key");
+                    return;
+                }
+            };
+
+            let socket = match bind_udp(address) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Failed to Bind Discovery Socket: {}", e);
+                    return;
+                }
+            };
+
+            info!("Answering Discovery Probes on {}", address);
+
+            DiscoveryTask { conn, fingerprint }
+                .discovery_loop(socket)
+                .await;
+        })
+    }
+}
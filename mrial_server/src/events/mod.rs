@@ -16,8 +16,10 @@ use tokio::{sync::Mutex, task::JoinHandle};
 #[cfg(target_os = "linux")]
 use std::time::Duration;
 
-use crate::{conn::ServerMeta, video::VideoServerAction};
+use crate::conn::web::CONTROL_CHANNEL;
+use crate::video::{display::DisplayArea, simulcast::EncoderHeaders};
 use crate::{audio::AudioServerAction, conn::ConnectionManager};
+use crate::{audit::AuditEvent, conn::StreamProfile, video::VideoServerAction};
 
 pub enum InputThreadAction {
     Restart,
@@ -80,8 +82,8 @@ impl InputThread {
                     }
                     self.session_restart_in_progress = false;
                 }
-                InputThreadAction::Input((buf, width, height)) => {
-                    self.input(&buf, width, height);
+                InputThreadAction::Input((buf, area)) => {
+                    self.input(&buf, area);
                 }
             }
         }
@@ -237,7 +239,8 @@ impl InputThread {
         Ok(())
     }
 
-    fn input(&mut self, buf: &[u8], width: usize, height: usize) {
+    /// Applies input of a client, positions are relative to the streamed area
+    fn input(&mut self, buf: &[u8], area: DisplayArea) {
         // TODO: Scroll only works on linux
         if scroll_requested(&buf) {
             let x_delta = i16::from_be_bytes(buf[14..16].try_into().unwrap());
@@ -249,7 +252,8 @@ impl InputThread {
         }
 
         if click_requested(buf) {
-            let (x, y, right) = parse_click(buf, width, height);
+            let (x, y, right) = parse_click(buf, area.width, area.height);
+            let (x, y) = area.to_desktop(x, y);
 
             match self.enigo.move_mouse(x, y, enigo::Coordinate::Abs) {
                 Ok(_) => {
@@ -279,7 +283,8 @@ impl InputThread {
         }
 
         if mouse_move_requested(buf) {
-            let (x, y, pressed) = parse_mouse_move(buf, width as f32, height as f32);
+            let (x, y, pressed) = parse_mouse_move(buf, area.width as f32, area.height as f32);
+            let (x, y) = area.to_desktop(x, y);
 
             if let Err(e) = self
                 .enigo
@@ -320,7 +325,7 @@ pub enum EventsTaskAction {
     RestartInputTread,
 }
 
-type InputPayload = (Bytes, usize, usize);
+type InputPayload = (Bytes, DisplayArea);
 
 pub struct EventsTask {
     input_thread_handle: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
@@ -328,7 +333,7 @@ pub struct EventsTask {
     input_sender: AsyncSender<InputThreadAction>,
 
     conn: ConnectionManager,
-    headers: Arc<Mutex<Option<Vec<u8>>>>,
+    headers: EncoderHeaders,
     video_server_ch_sender: AsyncSender<VideoServerAction>,
 
     // TODO: Consider adding actions for configuring audio
@@ -341,7 +346,7 @@ const MAX_INPUT_QUEUE: usize = 100;
 impl EventsTask {
     pub fn new(
         conn: ConnectionManager,
-        headers: Arc<Mutex<Option<Vec<u8>>>>,
+        headers: EncoderHeaders,
         video_server_ch_sender: AsyncSender<VideoServerAction>,
         audio_server_ch_sender: AsyncSender<AudioServerAction>,
     ) -> Result<Self, NewConError> {
@@ -359,15 +364,25 @@ impl EventsTask {
         })
     }
 
-    async fn handle_web_event(&mut self, buf: Bytes) {
+    async fn handle_web_event(&mut self, peer_id: u64, label: &str, buf: Bytes) {
+        if label != CONTROL_CHANNEL {
+            // Nothing is sent over the media channel by web clients yet
+            debug!("Dropped Message from Web Peer {peer_id} on the {label} Channel");
+            return;
+        }
+
         let packet_type = parse_packet_type(&buf);
 
         match packet_type {
             EPacketType::InputState => {
+                if !self.conn.get_web().check_control(peer_id).await {
+                    return;
+                }
+
                 let meta = self.conn.get_meta().await;
 
                 let input_buf = buf.slice(HEADER..);
-                let input = InputThreadAction::Input((input_buf, meta.width, meta.height));
+                let input = InputThreadAction::Input((input_buf, meta.area()));
 
                 if let Err(e) = self.input_sender.send(input).await {
                     warn!("Error sending input payload to input thread: {}", e);
@@ -379,9 +394,75 @@ impl EventsTask {
         }
     }
 
+    async fn client_connected(&mut self, src: SocketAddr, meta: ClientStatePayload) {
+        debug!("Client Meta: {:?}", meta);
+
+        let app = self.conn.get_app();
+        let profile = StreamProfile::from(&meta);
+
+        app.mute_client(src, meta.muted).await;
+        app.set_profile(src, profile).await;
+        self.conn.set_opus(meta.opus).await;
+
+        self.select_monitor(src, meta.monitor).await;
+        self.send_config_update(profile).await;
+    }
+
+    /// The monitor is shared by every client, so only the controller selects it
+    async fn select_monitor(&self, src: SocketAddr, monitor: Option<String>) {
+        let Some(monitor) = monitor else {
+            return;
+        };
+
+        if !self.conn.get_app().is_controller(src).await {
+            debug!("Ignored Monitor Selection from Spectator {}", src);
+            return;
+        }
+
+        if let Err(e) = self
+            .video_server_ch_sender
+            .send(VideoServerAction::SelectMonitor(monitor))
+            .await
+        {
+            warn!("Error sending select monitor action to video server: {}", e);
+        }
+    }
+
+    async fn send_config_update(&self, profile: StreamProfile) {
+        if let Err(e) = self
+            .video_server_ch_sender
+            .send(VideoServerAction::ConfigUpdate(profile))
+            .await
+        {
+            warn!(
+                "Error sending {:?} action to video server: {}",
+                VideoServerAction::ConfigUpdate(profile),
+                e
+            );
+        }
+    }
+
     async fn handle_app_event(&mut self, buf: &[u8], src: SocketAddr, size: usize) {
         let packet_type = parse_packet_type(&buf);
 
+        let observed = self.conn.app_observed_address(src);
+        if let Err(denied) = self.conn.get_access().check(observed.ip()) {
+            // Only handshake attempts are logged to avoid flooding the log
+            if packet_type == EPacketType::ShakeUE {
+                warn!("Rejected Client {}: {}", src, denied);
+                self.conn
+                    .get_app()
+                    .audit(
+                        src,
+                        AuditEvent::HandshakeFailure {
+                            reason: denied.to_string(),
+                        },
+                    )
+                    .await;
+            }
+            return;
+        }
+
         match packet_type {
             EPacketType::Retransmit => {
                 let (frame_id, real_packet_size, subpacket_ids) = parse_retransmit_body(&buf[HEADER..size]);
@@ -392,36 +473,51 @@ impl EventsTask {
 
                 let headers = self.headers.lock().await.clone();
                 let meta = match app.connect_client(src, &buf[HEADER..size], headers).await {
-                    Ok(meta) => meta,
+                    Ok(Some(meta)) => meta,
+                    Ok(None) => {
+                        debug!("Awaiting Auth Challenge Response from: {}", src);
+                        return;
+                    }
                     Err(e) => {
                         warn!("Error connecting client: {}", e);
+                        app.auth_failed(src, &e).await;
+                        app.audit(
+                            src,
+                            AuditEvent::HandshakeFailure {
+                                reason: e.to_string(),
+                            },
+                        )
+                        .await;
                         return;
                     }
                 };
 
-                debug!("Client Meta: {:?}", meta);
+                self.client_connected(src, meta).await;
+            }
+            EPacketType::ShakeResponse => {
+                let mut app = self.conn.get_app();
 
-                app.mute_client(src, meta.muted).await;
-                self.conn
-                    .set_meta(ServerMeta {
-                        width: meta.width as usize,
-                        height: meta.height as usize,
-                        opus: meta.opus,
-                        csp: meta.csp
-                    })
-                    .await;
+                let meta = match app.verify_challenge_response(src, &buf[HEADER..size]).await {
+                    Ok(Some(meta)) => meta,
+                    Ok(None) => {
+                        debug!("Awaiting Auth Challenge Response from: {}", src);
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Error connecting client: {}", e);
+                        app.auth_failed(src, &e).await;
+                        app.audit(
+                            src,
+                            AuditEvent::HandshakeFailure {
+                                reason: e.to_string(),
+                            },
+                        )
+                        .await;
+                        return;
+                    }
+                };
 
-                if let Err(e) = self
-                    .video_server_ch_sender
-                    .send(VideoServerAction::ConfigUpdate)
-                    .await
-                {
-                    warn!(
-                        "Error sending {:?} action to video server: {}",
-                        VideoServerAction::ConfigUpdate,
-                        e
-                    );
-                }
+                self.client_connected(src, meta).await;
             }
             EPacketType::ShakeUE => {
                 let app = self.conn.get_app();
@@ -429,7 +525,7 @@ impl EventsTask {
             }
             EPacketType::ClientState => {
                 let app = self.conn.get_app();
-                let sym_key = app.get_sym_key().await;
+                let sym_key = app.get_sym_key(src).await;
 
                 if sym_key.is_none() {
                     return;
@@ -444,32 +540,27 @@ impl EventsTask {
                 };
 
                 debug!("Client State: {:?}", meta);
+                app.audit(
+                    src,
+                    AuditEvent::ConfigChange {
+                        width: meta.width,
+                        height: meta.height,
+                        muted: meta.muted,
+                        opus: meta.opus,
+                    },
+                )
+                .await;
 
                 app.mute_client(src, meta.muted).await;
-                let needs_restart = self.conn
-                    .set_meta(ServerMeta {
-                        width: meta.width as usize,
-                        height: meta.height as usize,
-                        opus: meta.opus,
-                        csp: meta.csp
-                    })
-                    .await;
+                self.conn.set_opus(meta.opus).await;
+                self.select_monitor(src, meta.monitor.clone()).await;
 
-                if !needs_restart {
+                let profile = StreamProfile::from(&meta);
+                if !app.set_profile(src, profile).await {
                     return;
                 }
 
-                if let Err(e) = self
-                    .video_server_ch_sender
-                    .send(VideoServerAction::ConfigUpdate)
-                    .await
-                {
-                    warn!(
-                        "Error sending {:?} action to video server: {}",
-                        VideoServerAction::ConfigUpdate,
-                        e
-                    );
-                }
+                self.send_config_update(profile).await;
             }
             EPacketType::Alive => {
                 if let Err(e) = self.conn.get_app().send_alive(src).await {
@@ -495,12 +586,25 @@ impl EventsTask {
                     warn!("Error sending inactive action to video server: {}", e);
                 }
             }
+            EPacketType::Relay => {
+                self.conn.get_app().relay_message(src, &buf[..size]).await;
+            }
+            EPacketType::SessionControl => {
+                self.conn
+                    .get_app()
+                    .session_control(src, &buf[HEADER..size])
+                    .await;
+            }
             EPacketType::InputState => {
+                if !self.conn.get_app().check_control(src).await {
+                    return;
+                }
+
                 let meta = self.conn.get_meta().await;
 
                 let bytes = Bytes::copy_from_slice(&buf[HEADER..size]);
 
-                let input = InputThreadAction::Input((bytes, meta.width, meta.height));
+                let input = InputThreadAction::Input((bytes, meta.area()));
                 if let Err(e) = self.input_sender.send(input).await {
                     warn!("Error sending input payload to input thread: {}", e);
                 }
@@ -553,8 +657,8 @@ impl EventsTask {
                 }
                 web_ret = web_receiver.recv() => {
                     match web_ret {
-                        Ok(bytes) => {
-                            self.handle_web_event(bytes).await;
+                        Ok((peer_id, label, bytes)) => {
+                            self.handle_web_event(peer_id, label, bytes).await;
                         }
                         _ => {}
                     }
@@ -584,7 +688,7 @@ impl EventsTask {
 
     pub fn run(
         conn: ConnectionManager,
-        headers: Arc<Mutex<Option<Vec<u8>>>>,
+        headers: EncoderHeaders,
         event_ch_receiver: AsyncReceiver<EventsTaskAction>,
         video_server_ch_sender: AsyncSender<VideoServerAction>,
         audio_server_ch_sender: AsyncSender<AudioServerAction>,
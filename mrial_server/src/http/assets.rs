@@ -0,0 +1,88 @@
+/// File of the browser player, compiled into the binary
+pub struct Asset {
+    pub content_type: &'static str,
+    pub body: &'static [u8],
+}
+
+const INDEX: Asset = Asset {
+    content_type: "text/html; charset=utf-8",
+    body: include_bytes!("player/index.html"),
+};
+
+const SCRIPT: Asset = Asset {
+    content_type: "text/javascript; charset=utf-8",
+    body: include_bytes!("player/player.js"),
+};
+
+const STYLE: Asset = Asset {
+    content_type: "text/css; charset=utf-8",
+    body: include_bytes!("player/player.css"),
+};
+
+/// Asset served at the path, the player's page at the root
+pub fn find(path: &str) -> Option<&'static Asset> {
+    match path {
+        "/" | "/index.html" => Some(&INDEX),
+        "/player.js" => Some(&SCRIPT),
+        "/player.css" => Some(&STYLE),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mrial_proto::{input, EDisconnectReason, EPacketType, HEADER};
+
+    use super::*;
+    use crate::{
+        conn::web::{CONTROL_CHANNEL, MEDIA_CHANNEL},
+        http::SIGNALING_PATH,
+    };
+
+    #[test]
+    fn page_loads_the_served_assets() {
+        let page = std::str::from_utf8(INDEX.body).unwrap();
+
+        for path in ["/player.js", "/player.css"] {
+            assert!(page.contains(&format!("\"{}\"", path)), "{}", path);
+            assert!(find(path).is_some(), "{}", path);
+        }
+        assert!(find("/index.html").is_some());
+        assert!(find("/player").is_none());
+    }
+
+    #[test]
+    fn player_speaks_the_protocol() {
+        let script = std::str::from_utf8(SCRIPT.body).unwrap();
+
+        let constants = [
+            format!("SIGNALING_PATH = \"{}\"", SIGNALING_PATH),
+            format!("label: \"{}\", id: 0", CONTROL_CHANNEL),
+            format!("label: \"{}\", id: 1", MEDIA_CHANNEL),
+            format!("HEADER = {}", HEADER),
+            format!("INPUT_STATE: {}", EPacketType::InputState as u8),
+            format!("CLIENT_STATE: {}", EPacketType::ClientState as u8),
+            format!("DISCONNECT: {}", EPacketType::Disconnect as u8),
+            format!(
+                "{}: \"The server shut down.\"",
+                EDisconnectReason::Shutdown as u8
+            ),
+            format!("{}: \"Disconnected by", EDisconnectReason::Kicked as u8),
+            format!("INPUT_PAYLOAD = {}", input::PAYLOAD),
+            format!(
+                "KeyEvent = {{ NONE: {}, PRESS: {}, RELEASE: {} }}",
+                input::KeyEvent::None as u8,
+                input::KeyEvent::Press as u8,
+                input::KeyEvent::Release as u8
+            ),
+            format!("ArrowDown: {}", input::Key::DownArrow as u8),
+            format!("Backspace: {}", input::Key::Backspace as u8),
+            format!("Enter: {}", input::Key::Return as u8),
+            format!("\" \": {}", input::Key::Space as u8),
+        ];
+
+        for constant in constants.iter() {
+            assert!(script.contains(constant.as_str()), "{}", constant);
+        }
+    }
+}
@@ -0,0 +1,439 @@
+pub mod assets;
+pub mod signaling;
+pub mod websocket;
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{debug, error, info, warn};
+use mrial_fs::ServerConfig;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::Semaphore,
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::conn::{
+    sockets::{bind_tcp, parse_bind_address},
+    ConnectionManager,
+};
+
+use signaling::SignalingSession;
+use websocket::{WebSocketReader, WebSocketWriter};
+
+/// Path web clients open the signaling WebSocket on
+pub const SIGNALING_PATH: &str = "/signal";
+
+/// Largest request line and headers accepted
+const MAX_REQUEST_HEAD: u64 = 8 * 1024;
+
+/// Time a client has to send its request line and headers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Signaling sessions open at once, further upgrades are refused until one ends
+const MAX_SIGNALING_SESSIONS: usize = 32;
+
+/// Request line and headers of an HTTP/1.1 request, requests with a body are not supported
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    /// Reads the request head, `None` if the client closed the connection
+    /// or sent more than the head limit.
+    async fn read(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Option<Self>> {
+        let mut reader = reader.take(MAX_REQUEST_HEAD);
+
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        // The query string is not used by any route
+        let mut parts = line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (
+                method.to_string(),
+                path.split('?').next().unwrap_or(path).to_string(),
+            ),
+            _ => return Ok(None),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+
+        Ok(Some(Self {
+            method,
+            path,
+            headers,
+        }))
+    }
+
+    /// Value of a header, by its case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the request came from a page of this server or not from a browser,
+    /// so other sites can not signal with the credentials their users type in
+    fn is_same_origin(&self) -> bool {
+        let origin = match self.header("origin") {
+            Some(origin) => origin,
+            None => return true,
+        };
+
+        let Some(host) = self.header("host") else {
+            return false;
+        };
+
+        origin
+            .strip_prefix("http://")
+            .or_else(|| origin.strip_prefix("https://"))
+            .is_some_and(|origin| origin.eq_ignore_ascii_case(host))
+    }
+
+    fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Only the player's own assets and signaling are allowed, and it may not be framed
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; connect-src 'self' ws: wss:; frame-ancestors 'none'";
+
+/// Writes a complete response and asks the client to close the connection
+async fn respond<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nContent-Security-Policy: {}\r\nX-Content-Type-Options: nosniff\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        CONTENT_SECURITY_POLICY
+    );
+
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.shutdown().await
+}
+
+/// Whether web clients may sign in over the listener. Their passwords are sent in
+/// plain text unless a reverse proxy terminates TLS in front of it, which can only
+/// be assumed on loopback or when the config says so.
+fn is_sign_in_allowed(config: &ServerConfig, address: SocketAddr) -> bool {
+    address.ip().is_loopback() || config.http_tls_proxy
+}
+
+/// Listens for web clients over HTTP, serving the browser player, which upgrades to
+/// a WebSocket to signal its WebRTC peer connection. TLS is left to a reverse proxy
+/// in front of the listener.
+pub struct HttpServer {
+    conn: ConnectionManager,
+    /// Address of the listener
+    address: SocketAddr,
+    signaling_sessions: Arc<Semaphore>,
+}
+
+impl HttpServer {
+    async fn handle_stream(&self, stream: TcpStream, src: SocketAddr) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let request = match timeout(REQUEST_TIMEOUT, HttpRequest::read(&mut reader)).await {
+            Ok(request) => request?,
+            Err(_) => return Ok(()),
+        };
+
+        let request = match request {
+            Some(request) => request,
+            None => return respond(&mut writer, "400 Bad Request", "text/plain", b"").await,
+        };
+
+        if request.method != "GET" {
+            return respond(&mut writer, "405 Method Not Allowed", "text/plain", b"").await;
+        }
+
+        if request.path != SIGNALING_PATH {
+            return match assets::find(&request.path) {
+                Some(asset) => respond(&mut writer, "200 OK", asset.content_type, asset.body).await,
+                None => respond(&mut writer, "404 Not Found", "text/plain", b"Not Found").await,
+            };
+        }
+
+        let key = match request.header("sec-websocket-key") {
+            Some(key) if request.is_websocket_upgrade() => key,
+            _ => {
+                return respond(
+                    &mut writer,
+                    "426 Upgrade Required",
+                    "text/plain",
+                    b"Signaling requires a WebSocket",
+                )
+                .await
+            }
+        };
+
+        if !request.is_same_origin() {
+            debug!("Refused Signaling from {}, Cross-Origin Request", src);
+            return respond(&mut writer, "403 Forbidden", "text/plain", b"").await;
+        }
+
+        // Refused before the upgrade, so the player never sends the password
+        if !is_sign_in_allowed(&self.conn.get_config(), self.address) {
+            debug!(
+                "Refused Signaling from {}, No TLS in Front of the Listener",
+                src
+            );
+            return respond(
+                &mut writer,
+                "403 Forbidden",
+                "text/plain",
+                b"Signing In Requires TLS",
+            )
+            .await;
+        }
+
+        let _permit = match self.signaling_sessions.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("Refused Signaling from {}, Too Many Sessions", src);
+                return respond(&mut writer, "503 Service Unavailable", "text/plain", b"").await;
+            }
+        };
+
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            websocket::accept_key(key)
+        );
+        writer.write_all(head.as_bytes()).await?;
+
+        SignalingSession::new(self.conn.clone(), src)
+            .run(WebSocketReader::new(reader), WebSocketWriter::new(writer))
+            .await;
+
+        Ok(())
+    }
+
+    async fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        let access = self.conn.get_access();
+
+        loop {
+            let (stream, src) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("Failed to Accept HTTP Connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(denied) = access.check(src.ip()) {
+                debug!("Rejected HTTP Connection from {}: {}", src, denied);
+                continue;
+            }
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_stream(stream, src).await {
+                    debug!("HTTP Connection from {} Closed: {}", src, e);
+                }
+            });
+        }
+    }
+
+    /// Address of the listener, the first bind address at the HTTP port
+    fn address(config: &ServerConfig, port: u16) -> Option<SocketAddr> {
+        let address = parse_bind_address(config.bind.first()?, port).ok()?;
+        Some(SocketAddr::new(address.ip(), port))
+    }
+
+    pub fn run(conn: ConnectionManager) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            let config = conn.get_config();
+            let address = match config
+                .http_port
+                .and_then(|port| HttpServer::address(&config, port))
+            {
+                Some(address) => address,
+                None => {
+                    error!("No Bind Address for the HTTP Listener");
+                    return;
+                }
+            };
+
+            let listener = match bind_tcp(address) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to Bind HTTP Listener at {}: {}", address, e);
+                    return;
+                }
+            };
+
+            info!("Serving Web Player at http://{}", address);
+            if !is_sign_in_allowed(&config, address) {
+                warn!(
+                    "Web Clients Can Not Sign In at {}, Passwords Would Be Sent in Plain Text. Bind It to Loopback Behind a TLS Reverse Proxy, or Turn On http-tls-proxy If One Is in Front of It",
+                    address
+                );
+            }
+            debug!(
+                "Signaling Web Clients at ws://{}{}",
+                address, SIGNALING_PATH
+            );
+
+            let server = HttpServer {
+                conn,
+                address,
+                signaling_sessions: Arc::new(Semaphore::new(MAX_SIGNALING_SESSIONS)),
+            };
+            Arc::new(server).accept_loop(listener).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// Sends the request to the server and returns the lines of the head of its response
+    async fn send(server: Arc<HttpServer>, request: &str) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, src) = listener.accept().await.unwrap();
+        tokio::spawn(async move { server.handle_stream(stream, src).await });
+
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+
+        String::from_utf8(response)
+            .unwrap()
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// Sends a signaling upgrade to the server and returns the status line of its response
+    async fn upgrade(server: Arc<HttpServer>) -> String {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: mrial\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            SIGNALING_PATH
+        );
+
+        send(server, &request).await.remove(0)
+    }
+
+    async fn test_server(dir: &TestDir, address: &str) -> Arc<HttpServer> {
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+
+        Arc::new(HttpServer {
+            conn: ConnectionManager::new_with_custom_dir(&config, dir.path()).await,
+            address: address.parse().unwrap(),
+            signaling_sessions: Arc::new(Semaphore::new(MAX_SIGNALING_SESSIONS)),
+        })
+    }
+
+    #[test]
+    fn sign_in_requires_loopback_or_a_tls_proxy() {
+        let mut config = ServerConfig::default();
+
+        assert!(is_sign_in_allowed(
+            &config,
+            "127.0.0.1:8080".parse().unwrap()
+        ));
+        assert!(is_sign_in_allowed(&config, "[::1]:8080".parse().unwrap()));
+        assert!(!is_sign_in_allowed(
+            &config,
+            "0.0.0.0:8080".parse().unwrap()
+        ));
+        assert!(!is_sign_in_allowed(
+            &config,
+            "192.0.2.1:8080".parse().unwrap()
+        ));
+
+        config.http_tls_proxy = true;
+        assert!(is_sign_in_allowed(&config, "0.0.0.0:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn signaling_is_refused_without_tls_in_front_of_the_listener() {
+        let dir = TestDir::new("http-signaling");
+
+        let server = test_server(&dir, "127.0.0.1:8080").await;
+        assert_eq!(upgrade(server).await, "HTTP/1.1 101 Switching Protocols");
+
+        let server = test_server(&dir, "0.0.0.0:8080").await;
+        assert_eq!(upgrade(server.clone()).await, "HTTP/1.1 403 Forbidden");
+
+        let mut config = server.conn.get_config();
+        config.http_tls_proxy = true;
+        server.conn.reload_config(config);
+        assert_eq!(upgrade(server).await, "HTTP/1.1 101 Switching Protocols");
+    }
+
+    #[tokio::test]
+    async fn player_is_served_with_its_content_type_and_policy() {
+        let dir = TestDir::new("http-player");
+        let server = test_server(&dir, "127.0.0.1:8080").await;
+
+        let head = send(server.clone(), "GET / HTTP/1.1\r\nHost: mrial\r\n\r\n").await;
+        assert_eq!(head[0], "HTTP/1.1 200 OK");
+        assert!(head.contains(&"Content-Type: text/html; charset=utf-8".to_string()));
+        assert!(head.contains(&format!(
+            "Content-Security-Policy: {}",
+            CONTENT_SECURITY_POLICY
+        )));
+
+        let head = send(
+            server.clone(),
+            "GET /player.js?v=1 HTTP/1.1\r\nHost: mrial\r\n\r\n",
+        )
+        .await;
+        assert_eq!(head[0], "HTTP/1.1 200 OK");
+        assert!(head.contains(&"Content-Type: text/javascript; charset=utf-8".to_string()));
+
+        let head = send(server.clone(), "GET /../Cargo.toml HTTP/1.1\r\n\r\n").await;
+        assert_eq!(head[0], "HTTP/1.1 404 Not Found");
+
+        let head = send(server, "POST / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(head[0], "HTTP/1.1 405 Method Not Allowed");
+    }
+}
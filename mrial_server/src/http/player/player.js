@@ -0,0 +1,414 @@
+"use strict";
+
+// Browser player of mrial_server: signals a WebRTC peer over the WebSocket on the
+// listener it is served from, plays the video and audio tracks and sends input over
+// the control channel in the InputState layout of mrial_proto::input.
+(() => {
+  const SIGNALING_PATH = "/signal";
+
+  // Data channels negotiated with the server under fixed IDs, input and control
+  // packets are reliable while media packets are never retransmitted
+  const CONTROL_CHANNEL = { label: "control", id: 0 };
+  const MEDIA_CHANNEL = { label: "media", id: 1, ordered: false, maxRetransmits: 0 };
+
+  // mrial_proto::packet
+  const HEADER = 8;
+  const PACKET_TYPE_MASK = 0b00011111;
+  const PacketType = {
+    INPUT_STATE: 7,
+    CLIENT_STATE: 8,
+    DISCONNECT: 10,
+  };
+  const DisconnectReason = {
+    1: "The server shut down.",
+    2: "Disconnected by an administrator.",
+  };
+
+  // mrial_proto::input
+  const INPUT_PAYLOAD = 24;
+  const KeyEvent = { NONE: 0, PRESS: 1, RELEASE: 2 };
+  const Key = {
+    ArrowDown: 1,
+    ArrowUp: 2,
+    ArrowLeft: 3,
+    ArrowRight: 4,
+    Backspace: 8,
+    Tab: 9,
+    Enter: 10,
+    " ": 32,
+  };
+
+  // Command is sent as Control on macOS, as the app player does
+  const IS_MAC = /Mac/.test(navigator.platform);
+  const MODIFIERS = {
+    Control: 0,
+    Shift: 1,
+    Alt: 2,
+    Meta: IS_MAC ? 0 : 3,
+  };
+
+  // Pixels of wheel movement sent as one scroll step
+  const WHEEL_STEP = 100;
+  const WHEEL_LINE = 33;
+  const WHEEL_PAGE = 800;
+
+  const $ = (id) => document.getElementById(id);
+  const login = $("login");
+  const status = $("status");
+  const totpInput = $("totp");
+  const stream = $("stream");
+  const video = $("video");
+  const muteButton = $("mute");
+
+  let session = null;
+
+  const setStatus = (message) => {
+    status.textContent = message || "";
+  };
+
+  const writeHeader = (view, packetType, size) => {
+    view.setUint8(0, packetType);
+    view.setUint16(1, 0);
+    view.setUint32(3, size);
+    view.setUint8(7, 0);
+  };
+
+  const inputPacket = () => {
+    const view = new DataView(new ArrayBuffer(HEADER + INPUT_PAYLOAD));
+    writeHeader(view, PacketType.INPUT_STATE, INPUT_PAYLOAD);
+    return view;
+  };
+
+  const clientStatePacket = (state) => {
+    const json = new TextEncoder().encode(JSON.stringify(state));
+    const buf = new Uint8Array(HEADER + json.length);
+    writeHeader(new DataView(buf.buffer), PacketType.CLIENT_STATE, json.length);
+    buf.set(json, HEADER);
+    return buf;
+  };
+
+  // Position relative to the displayed frame, in ten-thousandths offset by one
+  // as mrial_proto::input writes it, or null outside of the frame.
+  const framePosition = (event) => {
+    if (!video.videoWidth || !video.videoHeight) {
+      return null;
+    }
+
+    const rect = video.getBoundingClientRect();
+    const scale = Math.min(rect.width / video.videoWidth, rect.height / video.videoHeight);
+    const width = video.videoWidth * scale;
+    const height = video.videoHeight * scale;
+    const x = event.clientX - rect.left - (rect.width - width) / 2;
+    const y = event.clientY - rect.top - (rect.height - height) / 2;
+
+    if (x < 0 || y < 0 || x > width || y > height) {
+      return null;
+    }
+
+    return {
+      x: Math.round((x / width) * 10000) + 1,
+      y: Math.round((y / height) * 10000) + 1,
+    };
+  };
+
+  const keyCode = (key) => {
+    if (key in Key) {
+      return Key[key];
+    }
+
+    // Printable ASCII, other keys are not supported by the server
+    if (key.length === 1) {
+      const code = key.charCodeAt(0);
+      if (code >= 33 && code <= 126) {
+        return code;
+      }
+    }
+
+    return 0;
+  };
+
+  class Session {
+    constructor(credentials) {
+      this.credentials = credentials;
+      this.answered = false;
+      this.pendingCandidates = [];
+      this.closed = false;
+      this.muted = false;
+      this.pendingMove = null;
+      this.wheel = { x: 0, y: 0 };
+      video.muted = false;
+
+      this.pc = new RTCPeerConnection();
+      this.pc.addTransceiver("video", { direction: "recvonly" });
+      this.pc.addTransceiver("audio", { direction: "recvonly" });
+      const { label: control, ...controlInit } = CONTROL_CHANNEL;
+      const { label: media, ...mediaInit } = MEDIA_CHANNEL;
+      this.channel = this.pc.createDataChannel(control, { negotiated: true, ...controlInit });
+      this.channel.binaryType = "arraybuffer";
+      this.mediaChannel = this.pc.createDataChannel(media, { negotiated: true, ...mediaInit });
+      this.mediaChannel.binaryType = "arraybuffer";
+
+      this.pc.ontrack = (event) => {
+        if (video.srcObject !== event.streams[0]) {
+          video.srcObject = event.streams[0];
+        }
+      };
+      this.pc.onicecandidate = (event) => {
+        if (event.candidate) {
+          this.sendCandidate(event.candidate.toJSON());
+        }
+      };
+      this.pc.onconnectionstatechange = () => {
+        const state = this.pc.connectionState;
+        if (state === "failed" || state === "closed") {
+          this.close("The connection to the server was lost.");
+        }
+      };
+
+      this.channel.onopen = () => {
+        login.hidden = true;
+        stream.hidden = false;
+        video.focus();
+        this.sendState();
+      };
+      this.channel.onmessage = (event) => this.handleMessage(new Uint8Array(event.data));
+
+      this.ws = new WebSocket(
+        (location.protocol === "https:" ? "wss://" : "ws://") + location.host + SIGNALING_PATH,
+      );
+      this.ws.onopen = () => this.offer();
+      this.ws.onmessage = (event) => this.handleSignal(JSON.parse(event.data));
+      this.ws.onclose = () => {
+        if (!this.answered) {
+          this.close("Failed to reach the server.");
+        }
+      };
+    }
+
+    signal(message) {
+      if (this.ws.readyState === WebSocket.OPEN) {
+        this.ws.send(JSON.stringify(message));
+      }
+    }
+
+    async offer() {
+      if (!this.pc.localDescription) {
+        await this.pc.setLocalDescription(await this.pc.createOffer());
+      }
+
+      const { username, pass, totp } = this.credentials;
+      this.signal({ type: "offer", username, pass, totp, sdp: this.pc.localDescription });
+    }
+
+    // Candidates are only accepted once the offer was answered
+    sendCandidate(candidate) {
+      if (this.answered) {
+        this.signal({ type: "candidate", candidate });
+      } else {
+        this.pendingCandidates.push(candidate);
+      }
+    }
+
+    async handleSignal(message) {
+      switch (message.type) {
+        case "answer":
+          await this.pc.setRemoteDescription(message.sdp);
+          this.answered = true;
+          this.pendingCandidates.forEach((candidate) => this.sendCandidate(candidate));
+          this.pendingCandidates = [];
+          setStatus("Connecting...");
+          break;
+        case "candidate":
+          await this.pc.addIceCandidate(message.candidate);
+          break;
+        case "totp_required":
+          totpInput.hidden = false;
+          totpInput.focus();
+          this.close("Enter your TOTP code.");
+          break;
+        case "error":
+          this.close(message.message);
+          break;
+      }
+    }
+
+    handleMessage(buf) {
+      if ((buf[0] & PACKET_TYPE_MASK) === PacketType.DISCONNECT) {
+        this.close(DisconnectReason[buf[0] >> 5] || "Disconnected by the server.");
+      }
+    }
+
+    send(buf) {
+      if (this.channel.readyState === "open") {
+        this.channel.send(buf);
+      }
+    }
+
+    sendState() {
+      this.send(clientStatePacket({ muted: this.muted }));
+      muteButton.textContent = this.muted ? "Unmute" : "Mute";
+    }
+
+    toggleMute() {
+      this.muted = !this.muted;
+      video.muted = this.muted;
+      this.sendState();
+    }
+
+    sendKey(event, keyEvent) {
+      const view = inputPacket();
+      let handled = false;
+
+      if (event.key in MODIFIERS) {
+        view.setUint8(HEADER + MODIFIERS[event.key], keyEvent);
+        handled = true;
+      } else {
+        const code = keyCode(event.key);
+        if (code) {
+          view.setUint8(HEADER + (keyEvent === KeyEvent.PRESS ? 8 : 9), code);
+          handled = true;
+        }
+      }
+
+      if (handled) {
+        event.preventDefault();
+        this.send(view.buffer);
+      }
+    }
+
+    sendClick(event, right) {
+      const position = framePosition(event);
+      if (!position) {
+        return;
+      }
+
+      const view = inputPacket();
+      view.setUint16(HEADER + 4, position.x | (right ? 0x8000 : 0));
+      view.setUint16(HEADER + 6, position.y);
+      this.send(view.buffer);
+    }
+
+    // Moves are coalesced to one per animation frame
+    queueMove(event) {
+      const position = framePosition(event);
+      if (!position) {
+        return;
+      }
+
+      if (!this.pendingMove) {
+        requestAnimationFrame(() => this.flushMove());
+      }
+      this.pendingMove = { ...position, pressed: (event.buttons & 1) === 1 };
+    }
+
+    flushMove() {
+      const move = this.pendingMove;
+      this.pendingMove = null;
+      if (!move) {
+        return;
+      }
+
+      const view = inputPacket();
+      view.setUint16(HEADER + 10, move.x);
+      view.setUint16(HEADER + 12, move.y);
+      view.setUint8(HEADER + 14, move.pressed ? 1 : 0);
+      this.send(view.buffer);
+    }
+
+    // Wheel deltas are accumulated into steps, positive steps scroll up as on the app player
+    sendWheel(event) {
+      const scale =
+        event.deltaMode === WheelEvent.DOM_DELTA_LINE
+          ? WHEEL_LINE
+          : event.deltaMode === WheelEvent.DOM_DELTA_PAGE
+            ? WHEEL_PAGE
+            : 1;
+
+      this.wheel.x -= event.deltaX * scale;
+      this.wheel.y -= event.deltaY * scale;
+
+      const x = Math.trunc(this.wheel.x / WHEEL_STEP);
+      const y = Math.trunc(this.wheel.y / WHEEL_STEP);
+      if (!x && !y) {
+        return;
+      }
+
+      this.wheel.x -= x * WHEEL_STEP;
+      this.wheel.y -= y * WHEEL_STEP;
+
+      const view = inputPacket();
+      view.setInt16(HEADER + 14, x);
+      view.setInt16(HEADER + 16, y);
+      this.send(view.buffer);
+    }
+
+    close(message) {
+      if (this.closed) {
+        return;
+      }
+      this.closed = true;
+
+      this.ws.close();
+      this.pc.close();
+      video.srcObject = null;
+
+      stream.hidden = true;
+      login.hidden = false;
+      $("connect").disabled = false;
+      setStatus(message);
+
+      if (session === this) {
+        session = null;
+      }
+    }
+  }
+
+  login.addEventListener("submit", (event) => {
+    event.preventDefault();
+
+    if (session) {
+      session.close();
+    }
+
+    const credentials = {
+      username: $("username").value,
+      pass: $("pass").value,
+      totp: totpInput.hidden || !totpInput.value ? null : totpInput.value,
+    };
+    totpInput.value = "";
+
+    $("connect").disabled = true;
+    setStatus("Authenticating...");
+    session = new Session(credentials);
+  });
+
+  video.addEventListener("keydown", (event) => session && session.sendKey(event, KeyEvent.PRESS));
+  video.addEventListener("keyup", (event) => session && session.sendKey(event, KeyEvent.RELEASE));
+  video.addEventListener("mousemove", (event) => session && session.queueMove(event));
+  video.addEventListener("click", (event) => {
+    video.focus();
+    if (session) {
+      session.sendClick(event, false);
+    }
+  });
+  video.addEventListener("contextmenu", (event) => {
+    event.preventDefault();
+    if (session) {
+      session.sendClick(event, true);
+    }
+  });
+  video.addEventListener(
+    "wheel",
+    (event) => {
+      event.preventDefault();
+      if (session) {
+        session.sendWheel(event);
+      }
+    },
+    { passive: false },
+  );
+
+  muteButton.addEventListener("click", () => session && session.toggleMute());
+  $("fullscreen").addEventListener("click", () => stream.requestFullscreen());
+  $("disconnect").addEventListener("click", () => session && session.close());
+})();
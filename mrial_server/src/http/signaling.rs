@@ -0,0 +1,458 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use kanal::{bounded_async, unbounded_async, AsyncSender};
+use log::{debug, info, warn};
+use mrial_proto::ESessionRole;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{sleep, sleep_until, Instant},
+};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::{
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+};
+
+use crate::{
+    audit::AuditEvent,
+    conn::{web::WebAuthError, ConnectionManager},
+};
+
+use super::websocket::{self, Message, WebSocketReader, WebSocketWriter, CLOSE_NORMAL};
+
+/// Failed offers after which the WebSocket is closed
+const MAX_FAILED_OFFERS: u32 = 3;
+/// Delay before answering a failed offer, multiplied by the failures so far.
+/// Failures also count towards the lockout of the address, across sessions.
+const FAILED_OFFER_BACKOFF: Duration = Duration::from_secs(1);
+/// Time a client has from the upgrade until its offer is answered
+const SIGNALING_OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time a client may stay silent before the WebSocket is closed
+const SIGNALING_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Messages a web client sends over the signaling WebSocket, as JSON text messages.
+/// The offer carries the credentials, candidates trickle in after it.
+/// Peers requesting control join as spectators when another client is in control.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalRequest {
+    Offer {
+        username: String,
+        pass: String,
+        #[serde(default)]
+        totp: Option<String>,
+        #[serde(default)]
+        role: ESessionRole,
+        sdp: Box<RTCSessionDescription>,
+    },
+    Candidate {
+        candidate: RTCIceCandidateInit,
+    },
+}
+
+/// Messages the server sends over the signaling WebSocket
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalResponse {
+    Answer {
+        sdp: Box<RTCSessionDescription>,
+    },
+    Candidate {
+        candidate: RTCIceCandidateInit,
+    },
+    /// The user has TOTP enabled, the offer has to be sent again with a code
+    TotpRequired,
+    Error {
+        message: String,
+    },
+}
+
+impl SignalResponse {
+    fn error(message: &str) -> Self {
+        SignalResponse::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Signals the peer connection of a single web client, from its offer until the
+/// WebSocket closes. Peers that did not connect by then are closed with it.
+pub struct SignalingSession {
+    conn: ConnectionManager,
+    src: SocketAddr,
+    peer_connection: Option<Arc<RTCPeerConnection>>,
+    failed_offers: u32,
+}
+
+impl SignalingSession {
+    pub fn new(conn: ConnectionManager, src: SocketAddr) -> Self {
+        Self {
+            conn,
+            src,
+            peer_connection: None,
+            failed_offers: 0,
+        }
+    }
+
+    /// Counts a failed offer and holds back its response
+    async fn fail_offer(&mut self, message: &str) -> SignalResponse {
+        self.failed_offers += 1;
+        sleep(FAILED_OFFER_BACKOFF * self.failed_offers).await;
+
+        SignalResponse::error(message)
+    }
+
+    async fn handle_offer(
+        &mut self,
+        username: String,
+        pass: String,
+        totp: Option<String>,
+        role: ESessionRole,
+        offer: RTCSessionDescription,
+        candidate_sender: &AsyncSender<RTCIceCandidateInit>,
+    ) -> SignalResponse {
+        if self.peer_connection.is_some() {
+            return SignalResponse::error("Already Offered");
+        }
+
+        let web = self.conn.get_web();
+
+        // Peers are only created for authenticated users
+        let user = match web
+            .authenticate(self.src, username.clone(), pass, totp)
+            .await
+        {
+            Ok(user) => user,
+            Err(WebAuthError::TotpRequired) => return SignalResponse::TotpRequired,
+            Err(WebAuthError::LockedOut) => {
+                warn!("Rejected Web Client {}: Locked Out", self.src);
+                self.failed_offers = MAX_FAILED_OFFERS;
+                return SignalResponse::error("Too Many Failed Attempts");
+            }
+            Err(e) => {
+                warn!("Rejected Web Client {}: {}", self.src, e);
+                return self.fail_offer("Authentication Failed").await;
+            }
+        };
+
+        let connected = web
+            .connect_peer(user, self.src, role, offer, candidate_sender.clone())
+            .await;
+        let (peer_connection, answer) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                warn!("Failed to Connect Web Client {}: {}", self.src, e);
+                return self.fail_offer("Invalid Offer").await;
+            }
+        };
+
+        let meta = self.conn.get_meta().await;
+        web.audit(
+            self.src,
+            &username,
+            AuditEvent::HandshakeSuccess {
+                width: meta.width as u16,
+                height: meta.height as u16,
+            },
+        );
+        info!("Web Client {} Authenticated as {}", self.src, username);
+
+        self.peer_connection = Some(peer_connection);
+        SignalResponse::Answer {
+            sdp: Box::new(answer),
+        }
+    }
+
+    async fn handle_request(
+        &mut self,
+        text: &str,
+        candidate_sender: &AsyncSender<RTCIceCandidateInit>,
+    ) -> Option<SignalResponse> {
+        let request = match serde_json::from_str::<SignalRequest>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Invalid Signal from {}: {}", self.src, e);
+                return Some(SignalResponse::error("Invalid Message"));
+            }
+        };
+
+        match request {
+            SignalRequest::Offer {
+                username,
+                pass,
+                totp,
+                role,
+                sdp,
+            } => Some(
+                self.handle_offer(username, pass, totp, role, *sdp, candidate_sender)
+                    .await,
+            ),
+            SignalRequest::Candidate { candidate } => {
+                let peer_connection = match &self.peer_connection {
+                    Some(peer_connection) => peer_connection,
+                    None => return Some(SignalResponse::error("No Offer")),
+                };
+
+                if let Err(e) = peer_connection.add_ice_candidate(candidate).await {
+                    debug!("Invalid ICE Candidate from {}: {}", self.src, e);
+                }
+
+                None
+            }
+        }
+    }
+
+    pub async fn run<R, W>(mut self, mut reader: WebSocketReader<R>, mut writer: WebSocketWriter<W>)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        // Messages are read in their own task, reads can not be cancelled mid frame
+        let (message_sender, message_receiver) = bounded_async::<io::Result<Message>>(8);
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let message = reader.read_message().await;
+
+                let closed = !matches!(message, Ok(Message::Text(_) | Message::Ping(_)));
+                if message_sender.send(message).await.is_err() || closed {
+                    break;
+                }
+            }
+        });
+        let mut close_code = CLOSE_NORMAL;
+
+        let (candidate_sender, candidate_receiver) = unbounded_async::<RTCIceCandidateInit>();
+
+        // Idle sockets would otherwise hold the signaling permits forever
+        let offer_deadline = Instant::now() + SIGNALING_OFFER_TIMEOUT;
+        let mut idle_deadline = Instant::now() + SIGNALING_IDLE_TIMEOUT;
+
+        loop {
+            let deadline = match self.peer_connection {
+                Some(_) => idle_deadline,
+                None => idle_deadline.min(offer_deadline),
+            };
+
+            let response = tokio::select! {
+                message = message_receiver.recv() => match message {
+                    Ok(Ok(Message::Text(text))) => {
+                        idle_deadline = Instant::now() + SIGNALING_IDLE_TIMEOUT;
+                        self.handle_request(&text, &candidate_sender).await
+                    }
+                    Ok(Ok(Message::Ping(payload))) => {
+                        if writer.send_pong(&payload).await.is_err() {
+                            break;
+                        }
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        debug!("Failed to Read Signal: {}", e);
+                        close_code = websocket::close_code(&e);
+                        break;
+                    }
+                    Ok(Ok(Message::Close)) | Err(_) => break,
+                },
+                Ok(candidate) = candidate_receiver.recv() => {
+                    Some(SignalResponse::Candidate { candidate })
+                }
+                _ = sleep_until(deadline) => {
+                    debug!("Signaling of Web Client {} Timed Out", self.src);
+                    break;
+                }
+            };
+
+            let Some(response) = response else {
+                continue;
+            };
+
+            let sent = match serde_json::to_string(&response) {
+                Ok(text) => writer.send_text(&text).await.is_ok(),
+                Err(_) => true,
+            };
+
+            if !sent || self.failed_offers >= MAX_FAILED_OFFERS {
+                break;
+            }
+        }
+
+        reader_task.abort();
+        let _ = writer.close(close_code).await;
+
+        if let Some(peer_connection) = self.peer_connection {
+            if peer_connection.connection_state() != RTCPeerConnectionState::Connected {
+                debug!(
+                    "Closing Web Client {}, Signaling Ended Before It Connected",
+                    self.src
+                );
+                let _ = peer_connection.close().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mrial_fs::{storage::StorageMultiType, ServerConfig, User, Users};
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+        time::timeout,
+    };
+    use webrtc::{
+        api::{media_engine::MediaEngine, APIBuilder},
+        peer_connection::configuration::RTCConfiguration,
+    };
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// Writes a text message the way browsers do, masked
+    async fn send(writer: &mut WriteHalf<DuplexStream>, text: &str) {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x81];
+        match text.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        writer.write_all(&frame).await.unwrap();
+    }
+
+    /// Reads a message of the server, None once it closed the WebSocket
+    async fn recv(reader: &mut ReadHalf<DuplexStream>) -> Option<Value> {
+        let mut head = [0u8; 2];
+        reader.read_exact(&mut head).await.ok()?;
+
+        let len = match head[1] & 0x7f {
+            126 => reader.read_u16().await.ok()? as usize,
+            127 => reader.read_u64().await.ok()? as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await.ok()?;
+
+        match head[0] & 0x0f {
+            0x1 => serde_json::from_slice(&payload).ok(),
+            _ => None,
+        }
+    }
+
+    /// Starts a session for a client signaling from the address
+    fn start(conn: &ConnectionManager, src: &str) -> (DuplexStream, tokio::task::JoinHandle<()>) {
+        let (client, server) = duplex(64 * 1024);
+        let (reader, writer) = split(server);
+
+        let session = SignalingSession::new(conn.clone(), src.parse().unwrap());
+        let task =
+            tokio::spawn(session.run(WebSocketReader::new(reader), WebSocketWriter::new(writer)));
+
+        (client, task)
+    }
+
+    fn offer(username: &str, pass: &str, sdp: &RTCSessionDescription) -> String {
+        json!({
+            "type": "offer",
+            "username": username,
+            "pass": pass,
+            "role": "Spectator",
+            "sdp": sdp,
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn answers_offers_and_closes_after_failed_ones() {
+        let dir = TestDir::new("signaling");
+
+        let mut users = Users::new_with_custom_dir(dir.path().to_path_buf());
+        users.load().unwrap();
+        users
+            .add(User {
+                username: "alice".to_string(),
+                pass: "correct horse".to_string(),
+                keys: Vec::new(),
+                totp: None,
+            })
+            .unwrap();
+        users.save().unwrap();
+
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        let conn = ConnectionManager::new_with_custom_dir(&config, dir.path()).await;
+
+        // The offer of a browser, with its candidates gathered up front
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().unwrap();
+        let api = APIBuilder::new().with_media_engine(media_engine).build();
+        let offerer = api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        offerer.create_data_channel("input", None).await.unwrap();
+
+        let description = offerer.create_offer(None).await.unwrap();
+        let mut gathered = offerer.gathering_complete_promise().await;
+        offerer.set_local_description(description).await.unwrap();
+        let _ = gathered.recv().await;
+        let sdp = offerer.local_description().await.unwrap();
+
+        let (client, task) = start(&conn, "127.0.0.1:50000");
+        let (mut reader, mut writer) = split(client);
+        send(&mut writer, &offer("alice", "correct horse", &sdp)).await;
+
+        let answer = loop {
+            let response = timeout(Duration::from_secs(10), recv(&mut reader))
+                .await
+                .unwrap()
+                .unwrap();
+            if response["type"] != "candidate" {
+                break response;
+            }
+        };
+        assert_eq!(answer["type"], "answer", "{}", answer);
+
+        let answer = serde_json::from_value::<RTCSessionDescription>(answer["sdp"].clone());
+        offerer
+            .set_remote_description(answer.unwrap())
+            .await
+            .unwrap();
+
+        drop((reader, writer));
+        timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        offerer.close().await.unwrap();
+
+        // Each failed offer is answered later than the one before it
+        let (client, task) = start(&conn, "127.0.0.2:50000");
+        let (mut reader, mut writer) = split(client);
+        for _ in 0..MAX_FAILED_OFFERS {
+            send(&mut writer, &offer("alice", "wrong", &sdp)).await;
+
+            let response = timeout(Duration::from_secs(10), recv(&mut reader))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(response["type"], "error");
+        }
+
+        assert!(recv(&mut reader).await.is_none());
+        timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the key of the client to prove the server speaks WebSocket (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted from a client, SDP offers are a few KB
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Longest payload of a ping, pong or close frame
+const MAX_CONTROL_PAYLOAD: u8 = 125;
+
+/// Status codes the connection is closed with
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Value of the `Sec-WebSocket-Accept` header answering the `Sec-WebSocket-Key` of a client
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+
+    STANDARD.encode(hasher.finalize())
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Frame of a client breaking the protocol, the connection is closed with the status code
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: u16,
+    reason: &'static str,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for ProtocolError {}
+
+fn protocol_error(code: u16, reason: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, ProtocolError { code, reason })
+}
+
+/// Status code to close the connection with after failing to read a message
+pub fn close_code(error: &io::Error) -> u16 {
+    error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<ProtocolError>())
+        .map_or(CLOSE_NORMAL, |error| error.code)
+}
+
+/// Reads the messages of a client, reassembling fragmented ones.
+/// Clients must mask their frames, binary messages are not supported.
+pub struct WebSocketReader<R> {
+    reader: R,
+    fragments: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> WebSocketReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            fragments: Vec::new(),
+        }
+    }
+
+    pub async fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let mut header = [0u8; 2];
+            self.reader.read_exact(&mut header).await?;
+
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0F;
+
+            if header[1] & 0x80 == 0 {
+                return Err(protocol_error(
+                    CLOSE_PROTOCOL_ERROR,
+                    "Unmasked Client Frame",
+                ));
+            }
+
+            // Control frames may arrive between fragments, so they can not be fragmented themselves
+            if opcode & 0x08 != 0 {
+                if !fin {
+                    return Err(protocol_error(
+                        CLOSE_PROTOCOL_ERROR,
+                        "Fragmented Control Frame",
+                    ));
+                }
+
+                if header[1] & 0x7F > MAX_CONTROL_PAYLOAD {
+                    return Err(protocol_error(
+                        CLOSE_PROTOCOL_ERROR,
+                        "Control Frame Too Large",
+                    ));
+                }
+            }
+
+            let len = match header[1] & 0x7F {
+                126 => self.reader.read_u16().await? as u64,
+                127 => self.reader.read_u64().await?,
+                len => len as u64,
+            };
+
+            if len > (MAX_MESSAGE_SIZE - self.fragments.len()) as u64 {
+                return Err(protocol_error(CLOSE_MESSAGE_TOO_BIG, "Message Too Large"));
+            }
+
+            let mut mask = [0u8; 4];
+            self.reader.read_exact(&mut mask).await?;
+
+            let mut payload = vec![0u8; len as usize];
+            self.reader.read_exact(&mut payload).await?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                OPCODE_TEXT | OPCODE_CONTINUATION => {
+                    if opcode == OPCODE_TEXT && !self.fragments.is_empty() {
+                        return Err(protocol_error(CLOSE_PROTOCOL_ERROR, "Interleaved Message"));
+                    }
+
+                    self.fragments.extend_from_slice(&payload);
+                    if !fin {
+                        continue;
+                    }
+
+                    let message = std::mem::take(&mut self.fragments);
+                    return String::from_utf8(message).map(Message::Text).map_err(|_| {
+                        protocol_error(CLOSE_INVALID_PAYLOAD, "Invalid UTF-8 Message")
+                    });
+                }
+                OPCODE_PING => return Ok(Message::Ping(payload)),
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => return Ok(Message::Close),
+                OPCODE_BINARY => {
+                    return Err(protocol_error(
+                        CLOSE_UNSUPPORTED_DATA,
+                        "Binary Messages Not Supported",
+                    ))
+                }
+                _ => return Err(protocol_error(CLOSE_PROTOCOL_ERROR, "Unknown Opcode")),
+            }
+        }
+    }
+}
+
+/// Writes unfragmented and, as the server, unmasked frames to a client
+pub struct WebSocketWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> WebSocketWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(payload);
+        self.writer.write_all(&frame).await
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_pong(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_frame(OPCODE_PONG, payload).await
+    }
+
+    /// Sends a close frame with the status code, then shuts down the connection
+    pub async fn close(&mut self, code: u16) -> io::Result<()> {
+        self.write_frame(OPCODE_CLOSE, &code.to_be_bytes()).await?;
+        self.writer.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame of a client, masked
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+
+        let mut frame = vec![((fin as u8) << 7) | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        frame
+    }
+
+    async fn read(frames: &[Vec<u8>]) -> io::Result<Message> {
+        let frames = frames.concat();
+        WebSocketReader::new(frames.as_slice()).read_message().await
+    }
+
+    #[tokio::test]
+    async fn pings_between_fragments_are_read() {
+        let frames = [
+            frame(false, OPCODE_TEXT, b"of"),
+            frame(true, OPCODE_PING, &[1; MAX_CONTROL_PAYLOAD as usize]),
+            frame(true, OPCODE_CONTINUATION, b"fer"),
+        ];
+        let frames = frames.concat();
+        let mut reader = WebSocketReader::new(frames.as_slice());
+
+        match reader.read_message().await.unwrap() {
+            Message::Ping(payload) => assert_eq!(payload.len(), MAX_CONTROL_PAYLOAD as usize),
+            message => panic!("Expected Ping, Read {:?}", message),
+        }
+        match reader.read_message().await.unwrap() {
+            Message::Text(text) => assert_eq!(text, "offer"),
+            message => panic!("Expected Text, Read {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn long_or_fragmented_control_frames_are_protocol_errors() {
+        for opcode in [OPCODE_PING, OPCODE_PONG, OPCODE_CLOSE] {
+            let long = frame(true, opcode, &[0; MAX_CONTROL_PAYLOAD as usize + 1]);
+            let error = read(&[long]).await.unwrap_err();
+            assert_eq!(close_code(&error), CLOSE_PROTOCOL_ERROR);
+
+            let fragmented = frame(false, opcode, b"");
+            let error = read(&[fragmented]).await.unwrap_err();
+            assert_eq!(close_code(&error), CLOSE_PROTOCOL_ERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_with_the_status_code() {
+        let mut closed = Vec::new();
+        WebSocketWriter::new(&mut closed)
+            .close(CLOSE_PROTOCOL_ERROR)
+            .await
+            .unwrap();
+        assert_eq!(closed, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xEA]);
+
+        // Failing to read for any other reason closes the connection normally
+        let error = read(&[frame(true, OPCODE_TEXT, b"of")[..4].to_vec()])
+            .await
+            .unwrap_err();
+        assert_eq!(close_code(&error), CLOSE_NORMAL);
+    }
+}
@@ -1,20 +1,27 @@
 mod audio;
+mod audit;
 mod cli;
 mod conn;
+mod control;
+mod discovery;
 mod events;
+mod http;
+mod signals;
+#[cfg(test)]
+mod test_dir;
 mod video;
 
-use base64::{engine::general_purpose::STANDARD, Engine as _};
+use mrial_fs::ServerConfigs;
 use std::env;
 
-use cli::handle_cli;
+use cli::{apply_server_args, handle_cli, SERVER_FLAGS};
 use conn::ConnectionManager;
 use video::VideoServerTask;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
+    if args.len() > 1 && !SERVER_FLAGS.contains(&args[1].as_str()) {
         handle_cli(&args);
         return Ok(());
     }
@@ -23,17 +30,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting Mrial Server Version {}\n", VERSION);
 
     pretty_env_logger::init_timed();
-    let conn = ConnectionManager::new().await;
 
-    // TODO: Temporary code for testing
-    if let Ok(desc) = env::var("RTC") {
-        let desc_data = String::from_utf8(STANDARD.decode(desc)?)?;
+    let mut configs = ServerConfigs::new();
+    if let Err(e) = configs.load() {
+        log::warn!("Failed to Load Server Config, Using Defaults: {}", e);
+    }
 
-        if let Err(e) = conn.get_web().initialize_client(desc_data).await {
-            log::error!("Failed to initialize Web Client: {}", e);
-        }
+    let mut config = configs.get();
+    if let Err(e) = apply_server_args(&args[1..], &mut config) {
+        log::error!("{}", e);
+        return Ok(());
     }
 
+    for problem in config.validate() {
+        log::warn!("{}", problem);
+    }
+
+    let conn = ConnectionManager::new(&config)
+        .await
+        .with_server_args(&args[1..]);
+
     let conn_clone = conn.clone();
 
     let mut video_server = match VideoServerTask::new(conn_clone).await {
@@ -0,0 +1,159 @@
+use kanal::AsyncSender;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use crate::{conn::ConnectionManager, video::VideoServerAction};
+
+/// Stops the server gracefully on SIGTERM and SIGINT (Ctrl+C),
+/// and reloads the config and users on SIGHUP.
+pub struct SignalsTask {
+    conn: ConnectionManager,
+    video_server_ch_sender: AsyncSender<VideoServerAction>,
+}
+
+impl SignalsTask {
+    fn new(
+        conn: ConnectionManager,
+        video_server_ch_sender: AsyncSender<VideoServerAction>,
+    ) -> Self {
+        Self {
+            conn,
+            video_server_ch_sender,
+        }
+    }
+
+    async fn shutdown(&self, signal: &str) {
+        info!("Received {}, Shutting Down", signal);
+
+        if let Err(e) = self
+            .video_server_ch_sender
+            .send(VideoServerAction::Shutdown)
+            .await
+        {
+            error!("Error sending Shutdown action: {}", e);
+        }
+    }
+
+    /// Replaces the config with the saved one, overridden by the flags
+    /// the server was started with like on start
+    #[cfg(unix)]
+    fn reload_config(&self, mut config: mrial_fs::ServerConfig) {
+        use crate::cli::apply_server_args;
+        use log::warn;
+
+        match apply_server_args(self.conn.server_args(), &mut config) {
+            Ok(_) => self.conn.reload_config(config),
+            Err(e) => warn!("Failed to Reload Server Config: {}", e),
+        }
+    }
+
+    /// Reloads the config, users and access rules without dropping sessions
+    #[cfg(unix)]
+    fn reload(&self) {
+        use log::warn;
+        use mrial_fs::ServerConfigs;
+
+        info!("Received SIGHUP, Reloading Config and Users");
+
+        let mut configs = ServerConfigs::new();
+        match configs.load() {
+            Ok(_) => self.reload_config(configs.get()),
+            Err(e) => warn!("Failed to Reload Server Config: {}", e),
+        }
+
+        if let Err(e) = self.conn.get_app().reload_users() {
+            warn!("Failed to Reload Users: {}", e);
+        }
+
+        if let Err(e) = self.conn.get_access().reload() {
+            warn!("Failed to Reload Access Rules: {}", e);
+        }
+    }
+
+    #[cfg(unix)]
+    async fn signal_loop(&self) -> std::io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => {
+                    self.shutdown("SIGTERM").await;
+                    return Ok(());
+                }
+                _ = interrupt.recv() => {
+                    self.shutdown("SIGINT").await;
+                    return Ok(());
+                }
+                _ = hangup.recv() => self.reload(),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn signal_loop(&self) -> std::io::Result<()> {
+        tokio::signal::ctrl_c().await?;
+        self.shutdown("Ctrl+C").await;
+
+        Ok(())
+    }
+
+    pub fn run(
+        conn: ConnectionManager,
+        video_server_ch_sender: AsyncSender<VideoServerAction>,
+    ) -> JoinHandle<()> {
+        let handle = tokio::runtime::Handle::current();
+
+        handle.spawn(async move {
+            let signals_task = SignalsTask::new(conn, video_server_ch_sender);
+
+            if let Err(e) = signals_task.signal_loop().await {
+                error!("Failed to Listen for Signals: {}", e);
+            }
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use mrial_fs::{PortRange, ServerConfig};
+
+    use super::*;
+    use crate::{cli::apply_server_args, test_dir::TestDir};
+
+    #[tokio::test]
+    async fn reloaded_config_keeps_the_server_flags() {
+        let dir = TestDir::new("signals-reload");
+        let args = ["--web-port", "9000"].map(String::from);
+
+        let mut config = ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        apply_server_args(&args, &mut config).unwrap();
+        let conn = ConnectionManager::new_with_custom_dir(&config, dir.path())
+            .await
+            .with_server_args(&args);
+        let (sender, _receiver) = kanal::unbounded_async();
+        let signals_task = SignalsTask::new(conn.clone(), sender);
+
+        // The saved config has a port range, which is ignored as peers share the flag's port
+        let saved = ServerConfig {
+            web_port_range: Some(PortRange {
+                min: 50000,
+                max: 50100,
+            }),
+            keepalive_interval_ms: 3000,
+            ..Default::default()
+        };
+        signals_task.reload_config(saved);
+
+        let reloaded = conn.get_config();
+        assert_eq!(reloaded.web_port, Some(9000));
+        assert_eq!(reloaded.web_port_range, None);
+        assert_eq!(reloaded.keepalive_interval_ms, 3000);
+    }
+}
@@ -0,0 +1,26 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Data directory of a test, removed once it is dropped whether or not the test passed
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("mrial-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,56 +1,185 @@
+use mrial_proto::MonitorPayload;
 #[cfg(target_os = "linux")]
-use xrandr::{ScreenResources, XHandle, XrandrError};
+use xrandr::{Monitor, ScreenResources, XHandle, XrandrError};
+
+/// Area of the capture that is streamed, the rectangle of a monitor on the
+/// virtual desktop. Frames are cropped to it and input is offset by its origin.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DisplayArea {
+    pub x: i32,
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DisplayArea {
+    /// Area of the whole capture
+    pub fn whole(width: usize, height: usize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Area of a monitor, `None` if it does not lie within the capture
+    pub fn of_monitor(
+        monitor: &MonitorPayload,
+        capture_width: usize,
+        capture_height: usize,
+    ) -> Option<Self> {
+        if monitor.x < 0 || monitor.y < 0 || monitor.width == 0 || monitor.height == 0 {
+            return None;
+        }
+
+        let area = Self {
+            x: monitor.x,
+            y: monitor.y,
+            width: monitor.width as usize,
+            height: monitor.height as usize,
+        };
+
+        let fits = area.x as usize + area.width <= capture_width
+            && area.y as usize + area.height <= capture_height;
+        fits.then_some(area)
+    }
+
+    /// Position on the virtual desktop of a position within the area,
+    /// kept inside the area so it does not reach a neighbouring monitor.
+    pub fn to_desktop(self, x: i32, y: i32) -> (i32, i32) {
+        let x = x.clamp(0, (self.width as i32 - 1).max(0));
+        let y = y.clamp(0, (self.height as i32 - 1).max(0));
+
+        (self.x + x, self.y + y)
+    }
+
+    pub fn is_whole(&self, capture_width: usize, capture_height: usize) -> bool {
+        *self == Self::whole(capture_width, capture_height)
+    }
+
+    /// Copies the area out of an ARGB frame of the capture
+    pub fn crop(&self, argb: &[u8], capture_width: usize) -> Vec<u8> {
+        let start = 4 * self.x as usize;
+        let row_len = 4 * self.width;
+
+        let mut cropped = Vec::with_capacity(row_len * self.height);
+        argb.chunks_exact(4 * capture_width)
+            .skip(self.y as usize)
+            .take(self.height)
+            .for_each(|row| cropped.extend_from_slice(&row[start..start + row_len]));
+
+        cropped
+    }
+}
 
 pub struct DisplayMeta {}
 
 impl DisplayMeta {
+    /// Monitor by its name, or the primary monitor
+    pub fn find_monitor<'a>(
+        monitors: &'a [MonitorPayload],
+        name: Option<&str>,
+    ) -> Option<&'a MonitorPayload> {
+        match name {
+            Some(name) => monitors.iter().find(|monitor| monitor.name == name),
+            None => monitors
+                .iter()
+                .find(|monitor| monitor.primary)
+                .or(monitors.first()),
+        }
+    }
+
     #[cfg(target_os = "linux")]
-    #[allow(dead_code)]
-    pub fn get_current_resolution() -> Result<(usize, usize), XrandrError> {
-        let mut handle = XHandle::open().unwrap();
-        let mon1 = &handle.monitors()?[0];
+    fn find_xrandr_monitor(
+        handle: &mut XHandle,
+        name: Option<&str>,
+    ) -> Result<Option<Monitor>, XrandrError> {
+        let monitors = handle.monitors()?;
 
-        Ok((mon1.width_px as usize, mon1.height_px as usize))
+        Ok(match name {
+            Some(name) => monitors.into_iter().find(|monitor| monitor.name == name),
+            None => {
+                let primary = monitors.iter().position(|monitor| monitor.is_primary);
+                monitors.into_iter().nth(primary.unwrap_or(0))
+            }
+        })
     }
 
     #[cfg(target_os = "linux")]
-    pub fn get_display_resolutions() -> Result<(Vec<u16>, Vec<u16>), xrandr::XrandrError> {
-        let mut handle = XHandle::open().unwrap();
-        // let mon1 = &handle.monitors()?[0];
+    pub fn get_monitors() -> Result<Vec<MonitorPayload>, XrandrError> {
+        let mut handle = XHandle::open()?;
+        let res = ScreenResources::new(&mut handle)?;
 
-        let mut widths: Vec<u16> = Vec::new();
-        let mut heights: Vec<u16> = Vec::new();
+        let monitors = handle
+            .monitors()?
+            .into_iter()
+            .map(|monitor| {
+                // Modes supported by any output of the monitor, listed once per resolution
+                let mut resolutions: Vec<(u16, u16)> = Vec::new();
+                res.modes
+                    .iter()
+                    .filter(|m| monitor.outputs.iter().any(|o| o.modes.contains(&m.xid)))
+                    .for_each(|m| {
+                        let resolution = (m.width as u16, m.height as u16);
+                        if !resolutions.contains(&resolution) {
+                            resolutions.push(resolution);
+                        }
+                    });
+                let (widths, heights) = resolutions.into_iter().unzip();
 
-        let res = ScreenResources::new(&mut handle)?;
-        res.modes.iter().for_each(|m| {
-            widths.push(m.width as u16);
-            heights.push(m.height as u16);
-        });
+                MonitorPayload {
+                    name: monitor.name,
+                    x: monitor.x,
+                    y: monitor.y,
+                    width: monitor.width_px as u16,
+                    height: monitor.height_px as u16,
+                    primary: monitor.is_primary,
+                    widths,
+                    heights,
+                }
+            })
+            .collect();
 
-        Ok((widths, heights))
+        Ok(monitors)
     }
 
+    // TODO: Windows and MacOS implementation needed, the whole capture is streamed
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_monitors() -> Result<Vec<MonitorPayload>, std::io::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Sets the mode of the monitor by its name, or of the primary monitor
     #[cfg(target_os = "linux")]
     pub fn update_display_resolution(
+        monitor: Option<&str>,
         width: usize,
         height: usize,
     ) -> Result<bool, xrandr::XrandrError> {
-        let mut handle = XHandle::open().unwrap();
-        let mon1 = &handle.monitors()?[0];
+        let mut handle = XHandle::open()?;
+        let mon = match Self::find_xrandr_monitor(&mut handle, monitor)? {
+            Some(mon) => mon,
+            None => return Ok(false),
+        };
 
-        if mon1.width_px == width as i32 && mon1.height_px == height as i32 {
+        if mon.width_px == width as i32 && mon.height_px == height as i32 {
             return Ok(false);
         }
 
+        let output = match mon.outputs.first() {
+            Some(output) => output,
+            None => return Ok(false),
+        };
+
         let res = ScreenResources::new(&mut handle)?;
-        let requested_mode = res
-            .modes
-            .iter()
-            .find(|m| m.width == width as u32 && m.height == height as u32);
+        let requested_mode = res.modes.iter().find(|m| {
+            m.width == width as u32 && m.height == height as u32 && output.modes.contains(&m.xid)
+        });
 
         // TODO: Handle the possibility of the mode not existing
         if let Some(mode) = requested_mode {
-            handle.set_mode(&mon1.outputs[0], &mode)?;
+            handle.set_mode(output, mode)?;
             return Ok(true);
         }
 
@@ -58,12 +187,17 @@ impl DisplayMeta {
     }
 
     #[cfg(target_os = "windows")]
-    pub fn update_display_resolution(width: usize, height: usize) -> Result<bool, std::io::Error> {
+    pub fn update_display_resolution(
+        monitor: Option<&str>,
+        width: usize,
+        height: usize,
+    ) -> Result<bool, std::io::Error> {
         Ok(false)
     }
 
     #[cfg(target_os = "macos")]
     pub fn update_display_resolution(
+        _monitor: Option<&str>,
         _width: usize,
         _height: usize,
     ) -> Result<bool, std::io::Error> {
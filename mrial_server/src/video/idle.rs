@@ -0,0 +1,231 @@
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Side of the square tiles captures are compared in, in pixels
+const TILE_SIZE: usize = 64;
+
+/// Frames still encoded after the last change, so the encoder refines the
+/// still image instead of leaving it at the quality of a frame in motion
+const IDLE_AFTER_FRAMES: u32 = 30;
+
+/// Interval frames are encoded at while the capture is idle
+const IDLE_KEEPALIVE: Duration = Duration::from_secs(1);
+
+/// Interval the capture is polled at while idle, two frames at 60 fps, so a change
+/// is encoded within a couple of frames
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_micros(2 * 16667);
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a over 64-bit words, a change of any word changes the hash
+fn hash_bytes(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut words = bytes.chunks_exact(8);
+    for word in &mut words {
+        hash = (hash ^ u64::from_ne_bytes(word.try_into().unwrap())).wrapping_mul(FNV_PRIME);
+    }
+    for byte in words.remainder() {
+        hash = (hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+/// Detects captures that did not change by hashing the ARGB frame in tiles and
+/// comparing them to the tiles of the previous capture. Unchanged captures are
+/// not encoded once idle, except at a keepalive rate, and the first change
+/// returns the stream to the full frame rate.
+#[derive(Default)]
+pub struct IdleDetector {
+    tiles: Vec<u64>,
+    previous_tiles: Vec<u64>,
+    width: usize,
+    height: usize,
+    unchanged_frames: u32,
+    last_encoded: Option<Instant>,
+}
+
+impl IdleDetector {
+    /// Hashes the tiles of the capture, returning how many changed since the previous
+    /// capture. Every tile changed when the dimensions of the capture did.
+    fn changed_tiles(&mut self, argb: &[u8], width: usize, height: usize) -> usize {
+        let columns = width.div_ceil(TILE_SIZE);
+        let rows = height.div_ceil(TILE_SIZE);
+
+        std::mem::swap(&mut self.tiles, &mut self.previous_tiles);
+        self.tiles.clear();
+        self.tiles.resize(columns * rows, FNV_OFFSET);
+
+        for (y, row) in argb.chunks_exact(4 * width).take(height).enumerate() {
+            let tile_row = (y / TILE_SIZE) * columns;
+
+            for (column, pixels) in row.chunks(4 * TILE_SIZE).enumerate() {
+                let tile = &mut self.tiles[tile_row + column];
+                *tile = hash_bytes(*tile, pixels);
+            }
+        }
+
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            return self.tiles.len();
+        }
+
+        self.tiles
+            .iter()
+            .zip(self.previous_tiles.iter())
+            .filter(|(tile, previous)| tile != previous)
+            .count()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.unchanged_frames >= IDLE_AFTER_FRAMES
+    }
+
+    fn is_keepalive_due(&self) -> bool {
+        self.last_encoded
+            .is_none_or(|last_encoded| last_encoded.elapsed() >= IDLE_KEEPALIVE)
+    }
+
+    /// Whether the capture has to be copied and passed to `should_encode`, given the area
+    /// at `x`, `y` of the capture that is streamed. While idle, the tiles are hashed in place,
+    /// a row at a time until one changed, so a change anywhere is seen by the next poll.
+    /// Captures are also passed on once a keepalive frame is due.
+    pub fn needs_capture(
+        &mut self,
+        capture: &[u8],
+        capture_width: usize,
+        (x, y): (usize, usize),
+        (width, height): (usize, usize),
+    ) -> bool {
+        if !self.is_idle()
+            || (width, height) != (self.width, self.height)
+            || self.is_keepalive_due()
+        {
+            return true;
+        }
+
+        let columns = width.div_ceil(TILE_SIZE);
+
+        let mut tiles = vec![FNV_OFFSET; columns];
+        for tile_row in 0..height.div_ceil(TILE_SIZE) {
+            tiles.fill(FNV_OFFSET);
+
+            let top = tile_row * TILE_SIZE;
+            let rows = capture
+                .chunks_exact(4 * capture_width)
+                .skip(y + top)
+                .take(TILE_SIZE.min(height - top));
+            for row in rows {
+                let row = &row[4 * x..4 * (x + width)];
+                for (column, pixels) in row.chunks(4 * TILE_SIZE).enumerate() {
+                    tiles[column] = hash_bytes(tiles[column], pixels);
+                }
+            }
+
+            if tiles[..] != self.tiles[tile_row * columns..(tile_row + 1) * columns] {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Whether the capture is encoded, which it is while it changes, for a number
+    /// of frames after the last change and at the keepalive rate while idle.
+    pub fn should_encode(&mut self, argb: &[u8], width: usize, height: usize) -> bool {
+        let changed_tiles = self.changed_tiles(argb, width, height);
+
+        if changed_tiles > 0 {
+            if self.is_idle() {
+                debug!("Capture Changed, Resuming Full Frame Rate");
+            }
+            self.unchanged_frames = 0;
+        } else if !self.is_idle() {
+            self.unchanged_frames += 1;
+
+            if self.is_idle() {
+                debug!("Capture Idle, Encoding at Keepalive Rate");
+            }
+        }
+
+        let encode = !self.is_idle() || self.is_keepalive_due();
+
+        if encode {
+            self.last_encoded = Some(Instant::now());
+        }
+
+        encode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 256;
+    const HEIGHT: usize = 256;
+
+    fn capture(pixel: u8) -> Vec<u8> {
+        vec![pixel; 4 * WIDTH * HEIGHT]
+    }
+
+    /// Feeds unchanged captures until the detector is idle
+    fn idle_detector(argb: &[u8]) -> IdleDetector {
+        let mut idle = IdleDetector::default();
+        for _ in 0..IDLE_AFTER_FRAMES {
+            assert!(idle.should_encode(argb, WIDTH, HEIGHT));
+        }
+
+        // The first capture found idle is not encoded, the last one was
+        assert!(!idle.should_encode(argb, WIDTH, HEIGHT));
+        assert!(idle.is_idle());
+
+        idle
+    }
+
+    #[test]
+    fn unchanged_captures_are_encoded_at_keepalive_rate() {
+        let argb = capture(0);
+        let mut idle = idle_detector(&argb);
+
+        assert!(!idle.should_encode(&argb, WIDTH, HEIGHT));
+
+        idle.last_encoded = Some(Instant::now() - IDLE_KEEPALIVE);
+        assert!(idle.should_encode(&argb, WIDTH, HEIGHT));
+        assert!(!idle.should_encode(&argb, WIDTH, HEIGHT));
+    }
+
+    #[test]
+    fn first_change_resumes_full_frame_rate() {
+        let mut argb = capture(0);
+        let mut idle = idle_detector(&argb);
+
+        argb[4 * (WIDTH * 200 + 100)] = 1;
+        assert!(idle.should_encode(&argb, WIDTH, HEIGHT));
+        assert!(!idle.is_idle());
+        assert!(idle.should_encode(&argb, WIDTH, HEIGHT));
+
+        // Resized captures count as changed
+        assert!(idle.should_encode(&capture(1)[..4 * 128 * HEIGHT], 128, HEIGHT));
+    }
+
+    #[test]
+    fn changes_are_seen_within_two_frames() {
+        // A change is seen by the first poll after it, at most a poll interval later
+        assert!(IDLE_POLL_INTERVAL <= Duration::from_micros(2 * 16667));
+
+        let argb = capture(0);
+        let area = ((0, 0), (WIDTH, HEIGHT));
+        for y in (0..HEIGHT).step_by(TILE_SIZE / 2) {
+            let mut idle = idle_detector(&argb);
+            idle.last_encoded = Some(Instant::now());
+            assert!(!idle.needs_capture(&argb, WIDTH, area.0, area.1));
+
+            let mut changed = argb.clone();
+            changed[4 * (WIDTH * y + WIDTH - 1)] = 1;
+            assert!(idle.needs_capture(&changed, WIDTH, area.0, area.1));
+        }
+    }
+}
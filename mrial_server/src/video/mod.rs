@@ -1,16 +1,24 @@
 pub mod display;
+pub mod idle;
 pub mod session;
+pub mod simulcast;
 pub mod yuv;
 
-use display::DisplayMeta;
+use display::{DisplayArea, DisplayMeta};
+use idle::{IdleDetector, IDLE_POLL_INTERVAL};
 use kanal::{unbounded, unbounded_async, AsyncReceiver, AsyncSender, Receiver};
-use log::{debug, error, warn};
-use mrial_proto::{video::EColorSpace, ENalVariant, EPacketType};
+use log::{debug, error, info, warn};
+use mrial_proto::{
+    discovery::DISCOVERY_PORT, video::EColorSpace, EDisconnectReason, ENalVariant, EPacketType,
+};
 use scrap::{Capturer, Display};
 use session::{SessionSettingTask, Setting};
+use simulcast::{EncoderHeaders, EncoderRoutes, EncoderSettings, ProfileEncoder};
 use std::{
+    collections::HashMap,
     fs::File,
     io::{ErrorKind::WouldBlock, Write},
+    net::{Ipv4Addr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -18,35 +26,62 @@ use tokio::{
     sync::Mutex,
     time::{sleep, Instant},
 };
-use x264::{Encoder, Param, Picture};
-use yuv::YUVBuffer;
 
 use crate::{
     audio::{AudioServerAction, AudioServerTask},
-    conn::{BroadcastTaskError, ConnectionManager, ServerMeta},
+    conn::{
+        keepalive::KeepaliveTask, relay::RelayTask, BroadcastTaskError, ConnectionManager,
+        StreamProfile,
+    },
+    discovery::DiscoveryTask,
     events::{EventsTask, EventsTaskAction},
+    http::HttpServer,
+    signals::SignalsTask,
+};
+
+#[cfg(unix)]
+use crate::control::{control_socket_path, server::ControlServer};
+
+/// Web clients are sent the capture at its own dimensions, in a color space browsers decode
+const WEB_STREAM_PROFILE: StreamProfile = StreamProfile {
+    width: 0,
+    height: 0,
+    csp: EColorSpace::YUV420,
 };
 
 #[derive(PartialEq, Debug)]
 pub enum VideoServerAction {
     Inactive,
-    ConfigUpdate,
+    ConfigUpdate(StreamProfile),
+    /// Reopens every encoder, so all clients are sent a key frame
+    ForceKeyframe,
+    EncoderUpdate(EncoderSettings),
     RestartStream,
     RestartSession,
+    /// Streams the monitor by its name, requested by the client in control
+    SelectMonitor(String),
     #[cfg(target_os = "linux")]
     NewUserSession,
+    /// Disconnects every client and stops the server
+    Shutdown,
 }
 
 pub struct VideoServerTask {
     file: Option<File>,
     row_len: usize,
 
-    csp: EColorSpace,
-    par: Param,
-    pic: Picture,
     capturer: Option<Capturer>,
-    encoder: Encoder,
-    headers: Arc<Mutex<Option<Vec<u8>>>>,
+    /// Area of the capture that is streamed
+    area: DisplayArea,
+    /// Monitor requested to be streamed, the primary monitor when `None`
+    monitor: Option<String>,
+    encoders: HashMap<StreamProfile, ProfileEncoder>,
+    encoder_settings: EncoderSettings,
+    routes: EncoderRoutes,
+    /// Profiles version, web clients and dimensions of the capture the routes were made for
+    routes_key: Option<(u64, bool, usize, usize)>,
+    idle: IdleDetector,
+    headers: EncoderHeaders,
 
     conn: ConnectionManager,
 
@@ -60,6 +95,19 @@ pub struct VideoServerTask {
     audio_sender: AsyncSender<AudioServerAction>,
     audio_receiver: Receiver<AudioServerAction>,
     audio_thread: Option<tokio::task::JoinHandle<()>>,
+
+    #[cfg(unix)]
+    control_thread: Option<tokio::task::JoinHandle<()>>,
+
+    signals_thread: Option<tokio::task::JoinHandle<()>>,
+
+    keepalive_thread: Option<tokio::task::JoinHandle<()>>,
+
+    discovery_thread: Option<tokio::task::JoinHandle<()>>,
+
+    relay_thread: Option<tokio::task::JoinHandle<()>>,
+
+    http_thread: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl VideoServerTask {
@@ -69,21 +117,9 @@ impl VideoServerTask {
         #[cfg(target_os = "linux")]
         let setting = session::config_xenv()?;
 
-        let display: Display = Display::primary()?;
-        let capturer = Capturer::new(display)?;
-
-        conn.set_dimensions(capturer.width(), capturer.height())
-            .await;
-
+        let (capturer, area) = Self::open_capturer(&conn, None).await?;
         let row_len = 4 * capturer.width() * capturer.height();
 
-        let serde_meta = conn.get_meta().await;
-        let mut par: Param = VideoServerTask::get_parameters(&serde_meta);
-        let mut encoder = x264::Encoder::open(&mut par)?;
-        let header = encoder.get_headers()?.as_bytes().to_vec();
-
-        let pic = Picture::from_param(&par)?;
-
         let (events_sender, events_receiver) = unbounded_async::<EventsTaskAction>();
         let (audio_sender, audio_receiver) = unbounded::<AudioServerAction>();
 
@@ -91,12 +127,15 @@ impl VideoServerTask {
             row_len,
             file: None,
 
-            csp: serde_meta.csp,
-            par,
-            pic,
             capturer: Some(capturer),
-            encoder,
-            headers: Arc::new(Mutex::new(Some(header))),
+            area,
+            monitor: None,
+            encoders: HashMap::new(),
+            encoder_settings: EncoderSettings::default(),
+            routes: Vec::new(),
+            routes_key: None,
+            idle: IdleDetector::default(),
+            headers: Arc::new(Mutex::new(HashMap::new())),
 
             conn,
 
@@ -110,6 +149,19 @@ impl VideoServerTask {
             audio_thread: None,
             audio_sender: audio_sender.clone_async(),
             audio_receiver,
+
+            #[cfg(unix)]
+            control_thread: None,
+
+            signals_thread: None,
+
+            keepalive_thread: None,
+
+            discovery_thread: None,
+
+            relay_thread: None,
+
+            http_thread: None,
         })
     }
 
@@ -121,30 +173,6 @@ impl VideoServerTask {
         }
     }
 
-    fn get_parameters(server_meta: &ServerMeta) -> Param {
-        let mut par = Param::default_preset("ultrafast", "zerolatency").unwrap();
-
-        par = par.set_csp(server_meta.csp.into());
-        par = par.set_dimension(server_meta.height, server_meta.width);
-
-        if cfg!(target_os = "windows") {
-            par = par.set_fullrange(1);
-        }
-
-        par = par.param_parse("repeat_headers", "1").unwrap();
-        par = par.param_parse("annexb", "1").unwrap();
-        par = par.param_parse("bframes", "0").unwrap();
-        par = par.param_parse("crf", "20").unwrap();
-
-        if server_meta.csp != EColorSpace::YUV444 {
-            par = par.apply_profile("high").unwrap();
-        } else {
-            par = par.apply_profile("high444").unwrap();
-        }
-
-        par
-    }
-
     fn drop_capturer(&mut self) {
         if let Some(capturer) = self.capturer.take() {
             debug!("Dropping Capturer");
@@ -152,7 +180,7 @@ impl VideoServerTask {
         }
     }
 
-    async fn handle_app_broadcast(&self, buf: &[u8]) {
+    async fn handle_app_broadcast(&self, buf: &[u8], profiles: &[StreamProfile]) {
         // TODO: Find out why I-Frames are 7 instead of 5 and
         // TODO: make sure the 4th byte is always represents the NAL header
         
@@ -163,7 +191,12 @@ impl VideoServerTask {
         
         if let Err(e) = self
             .conn
-            .app_encrypted_broadcast(EPacketType::NAL, packet_type_variant, buf)
+            .app_encrypted_broadcast(
+                EPacketType::NAL,
+                packet_type_variant,
+                buf,
+                Some(profiles.to_vec()),
+            )
             .await
         {
             match e {
@@ -176,9 +209,6 @@ impl VideoServerTask {
                 BroadcastTaskError::TransferFailed(msg) => {
                     error!("App Broadcast Send Error: {msg}");
                 }
-                BroadcastTaskError::EncryptionFailed(msg) => {
-                    error!("App Broadcast Encryption Error: {msg}");
-                }
             }
         }
     }
@@ -195,44 +225,142 @@ impl VideoServerTask {
                 BroadcastTaskError::TransferFailed(msg) => {
                     error!("Web Broadcast Send Error: {msg}");
                 }
-                _ => {}
             }
         }
     }
 
-    async fn restart_stream(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let display = Display::primary()?;
-        let capturer: Capturer = Capturer::new(display)?;
+    /// Opens a capture of the virtual desktop and finds the area of the monitor in it,
+    /// falling back to the primary monitor and then to the whole capture.
+    async fn open_capturer(
+        conn: &ConnectionManager,
+        monitor: Option<&str>,
+    ) -> Result<(Capturer, DisplayArea), Box<dyn std::error::Error>> {
+        let display: Display = Display::primary()?;
+        let capturer = Capturer::new(display)?;
+        let (width, height) = (capturer.width(), capturer.height());
+
+        let monitors = DisplayMeta::get_monitors().unwrap_or_default();
+        let selected = DisplayMeta::find_monitor(&monitors, monitor)
+            .or_else(|| DisplayMeta::find_monitor(&monitors, None))
+            .and_then(|m| Some((DisplayArea::of_monitor(m, width, height)?, m.name.clone())));
+
+        let (area, name) = match selected {
+            Some((area, name)) => (area, Some(name)),
+            None => (DisplayArea::whole(width, height), None),
+        };
+
+        debug!(
+            "Streaming {} at {}x{}+{}+{}",
+            name.as_deref().unwrap_or("Whole Capture"),
+            area.width,
+            area.height,
+            area.x,
+            area.y
+        );
+        conn.set_display(area, name).await;
+
+        Ok((capturer, area))
+    }
 
-        let server_meta = self.conn.get_meta().await;
+    async fn restart_stream(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (capturer, area) = Self::open_capturer(&self.conn, self.monitor.as_deref()).await?;
 
         self.row_len = 4 * capturer.width() * capturer.height();
-        self.conn
-            .set_dimensions(capturer.width(), capturer.height())
-            .await;
-        self.csp = server_meta.csp;
-        self.par = VideoServerTask::get_parameters(&server_meta);
-        self.encoder = x264::Encoder::open(&mut self.par)?;
+        self.area = area;
 
-        let headers = self.encoder.get_headers()?;
-        let header_bytes = headers.as_bytes();
+        // Encoders are reopened at the new dimensions of the capture
+        self.encoders.clear();
+        self.routes.clear();
+        self.routes_key = None;
+        self.capturer = Some(capturer);
 
-        // Update the headers
-        let mut header_ref = self.headers.lock().await;
-        *header_ref = Some(header_bytes.to_vec());
+        Ok(())
+    }
+
+    /// Whether the profiles of the clients or the dimensions of the capture changed since
+    /// the encoders were last updated, or an encoder was closed to restart from a key frame
+    fn encoders_outdated(&self, width: usize, height: usize, has_web_clients: bool) -> bool {
+        let routes_key = (
+            self.conn.app_profiles_version(),
+            has_web_clients,
+            width,
+            height,
+        );
+
+        self.routes_key != Some(routes_key) || self.encoders.len() < self.routes.len()
+    }
+
+    /// Routes the profiles requested by clients to encoders, opening and closing
+    /// encoders as they change. Opened encoders send their headers to their clients.
+    async fn update_encoders(&mut self, width: usize, height: usize, has_web_clients: bool) {
+        // Read first, profiles changing while they are routed are routed again
+        let profiles_version = self.conn.app_profiles_version();
+        self.routes_key = Some((profiles_version, has_web_clients, width, height));
 
-        if self.conn.has_app_clients().await {
-            self.handle_app_broadcast(&header_bytes).await;
+        let mut requested = self.conn.get_app().profiles().await;
+        if has_web_clients && !requested.contains(&WEB_STREAM_PROFILE) {
+            requested.push(WEB_STREAM_PROFILE);
         }
 
-        if self.conn.has_web_clients().await {
-            self.handle_web_broadcast(&header_bytes);
+        let routes = simulcast::route_profiles(&requested, width, height);
+        if routes != self.routes {
+            for (encoder_profile, profiles) in routes.iter() {
+                for profile in profiles {
+                    if profile.normalized(width, height) != *encoder_profile {
+                        warn!(
+                            "Encoder Limit Reached, Sending {:?} Stream for Requested {:?}",
+                            encoder_profile, profile
+                        );
+                    }
+                }
+            }
+
+            self.routes = routes;
+            self.encoders
+                .retain(|profile, _| self.routes.iter().any(|(p, _)| p == profile));
+            self.headers
+                .lock()
+                .await
+                .retain(|profile, _| self.routes.iter().any(|(p, _)| p == profile));
         }
 
-        self.pic = Picture::from_param(&self.par)?;
-        self.capturer = Some(capturer);
+        let unopened: EncoderRoutes = self
+            .routes
+            .iter()
+            .filter(|(profile, _)| !self.encoders.contains_key(profile))
+            .cloned()
+            .collect();
 
-        Ok(())
+        for (profile, profiles) in unopened {
+            let mut encoder = match ProfileEncoder::open(profile, &self.encoder_settings) {
+                Ok(encoder) => encoder,
+                Err(e) => {
+                    error!("Error Opening Encoder for {:?}: {}", profile, e);
+                    continue;
+                }
+            };
+            debug!("Opened Encoder for {:?}", profile);
+
+            match encoder.get_headers() {
+                // Web clients are not sent the headers alone, key frames repeat them
+                Ok(headers) => {
+                    self.handle_app_broadcast(&headers, &profiles).await;
+
+                    self.headers.lock().await.insert(profile, headers);
+                }
+                Err(e) => error!("Error Getting Encoder Headers for {:?}: {}", profile, e),
+            }
+
+            self.encoders.insert(profile, encoder);
+        }
+    }
+
+    /// Profile of the encoder the requested profile is routed to
+    fn encoder_profile_of(&self, profile: &StreamProfile) -> Option<StreamProfile> {
+        self.routes
+            .iter()
+            .find(|(_, profiles)| profiles.contains(profile))
+            .map(|(encoder_profile, _)| *encoder_profile)
     }
 
     async fn handle_server_action(
@@ -279,7 +407,19 @@ impl VideoServerTask {
                 _ => {}
             },
             VideoServerAction::Inactive => {
-                self.encoder = x264::Encoder::open(&mut self.par)?;
+                self.encoders.clear();
+                self.routes.clear();
+                self.routes_key = None;
+            }
+            // Stops the run loop, so it is handled there
+            VideoServerAction::Shutdown => {}
+            VideoServerAction::ForceKeyframe => {
+                self.encoders.clear();
+            }
+            VideoServerAction::EncoderUpdate(settings) => {
+                debug!("Updating Encoder Settings: {:?}", settings);
+                self.encoder_settings = settings;
+                self.encoders.clear();
             }
             VideoServerAction::RestartStream => {
                 self.drop_capturer();
@@ -296,22 +436,51 @@ impl VideoServerTask {
                     }
                 }
             }
-            VideoServerAction::ConfigUpdate => {
-                let meta = self.conn.get_meta().await;
-
-                let requested_width = meta.width;
-                let requested_height = meta.height;
-
-                if let Err(e) =
-                    DisplayMeta::update_display_resolution(requested_width, requested_height)
+            VideoServerAction::SelectMonitor(monitor) => {
+                // Requested monitors that are not found fall back to the primary monitor
+                if self.monitor.as_ref() == Some(&monitor)
+                    || self.conn.get_meta().await.monitor.as_ref() == Some(&monitor)
                 {
-                    debug!("Error updating display resolution: {}", e);
+                    return Ok(());
                 }
 
+                debug!("Selecting Monitor: {}", monitor);
+                self.monitor = Some(monitor);
                 video_server_ch_sender
                     .send(VideoServerAction::RestartStream)
                     .await?;
             }
+            VideoServerAction::ConfigUpdate(profile) => {
+                // The display is only resized when every client requested the same profile
+                if self.conn.get_app().profiles().await == [profile] {
+                    match DisplayMeta::update_display_resolution(
+                        self.monitor.as_deref(),
+                        profile.width,
+                        profile.height,
+                    ) {
+                        Ok(true) => {
+                            video_server_ch_sender
+                                .send(VideoServerAction::RestartStream)
+                                .await?;
+                            return Ok(());
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            debug!("Error updating display resolution: {}", e);
+                        }
+                    }
+                }
+
+                let meta = self.conn.get_meta().await;
+                let has_web_clients = self.conn.has_web_clients().await;
+                self.update_encoders(meta.width, meta.height, has_web_clients)
+                    .await;
+
+                // Reopen the encoder the profile is routed to, so its clients start from a key frame
+                if let Some(encoder_profile) = self.encoder_profile_of(&profile) {
+                    self.encoders.remove(&encoder_profile);
+                }
+            }
         }
 
         Ok(())
@@ -352,9 +521,155 @@ impl VideoServerTask {
         Ok(())
     }
 
+    #[cfg(unix)]
+    fn start_control_thread(
+        &mut self,
+        ch_sender: AsyncSender<VideoServerAction>,
+    ) -> Result<(), ()> {
+        let has_control_thread = match &self.control_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_control_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+
+        self.control_thread = Some(ControlServer::run(conn, ch_sender));
+
+        Ok(())
+    }
+
+    fn start_signals_thread(&mut self, ch_sender: AsyncSender<VideoServerAction>) -> Result<(), ()> {
+        let has_signals_thread = match &self.signals_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_signals_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+
+        self.signals_thread = Some(SignalsTask::run(conn, ch_sender));
+
+        Ok(())
+    }
+
+    fn start_keepalive_thread(&mut self) -> Result<(), ()> {
+        let has_keepalive_thread = match &self.keepalive_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_keepalive_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+
+        self.keepalive_thread = Some(KeepaliveTask::run(conn));
+
+        Ok(())
+    }
+
+    fn start_discovery_thread(&mut self) -> Result<(), ()> {
+        let has_discovery_thread = match &self.discovery_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_discovery_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+        let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DISCOVERY_PORT);
+
+        self.discovery_thread = Some(DiscoveryTask::run(conn, address));
+
+        Ok(())
+    }
+
+    fn start_relay_thread(&mut self) -> Result<(), ()> {
+        let has_relay_thread = match &self.relay_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_relay_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+
+        self.relay_thread = Some(RelayTask::run(conn));
+
+        Ok(())
+    }
+
+    fn start_http_thread(&mut self) -> Result<(), ()> {
+        let has_http_thread = match &self.http_thread {
+            Some(handle) => !handle.is_finished(),
+            None => false,
+        };
+
+        if has_http_thread {
+            return Err(());
+        }
+
+        let conn = self.conn.clone();
+
+        self.http_thread = Some(HttpServer::run(conn));
+
+        Ok(())
+    }
+
+    /// Stops capturing, tells every client the server is shutting down and flushes the audit log
+    async fn shutdown(&mut self) {
+        self.drop_capturer();
+        self.encoders.clear();
+        self.routes.clear();
+        self.routes_key = None;
+
+        if let Some(handle) = self.keepalive_thread.take() {
+            handle.abort();
+        }
+
+        if let Some(handle) = self.discovery_thread.take() {
+            handle.abort();
+        }
+
+        if let Some(handle) = self.relay_thread.take() {
+            handle.abort();
+        }
+
+        if let Some(handle) = self.http_thread.take() {
+            handle.abort();
+        }
+
+        self.conn
+            .disconnect_clients(EDisconnectReason::Shutdown)
+            .await;
+
+        #[cfg(unix)]
+        if let Some(handle) = self.control_thread.take() {
+            handle.abort();
+            if let Err(e) = std::fs::remove_file(control_socket_path()) {
+                debug!("Failed to Remove Control Socket: {}", e);
+            }
+        }
+
+        self.conn.flush_audit().await;
+        info!("Server Shut Down");
+    }
+
     fn start_events_thread(
         &mut self,
-        headers: Arc<Mutex<Option<Vec<u8>>>>,
+        headers: EncoderHeaders,
         ch_sender: AsyncSender<VideoServerAction>,
     ) -> Result<(), ()> {
         let has_events_thread = match &self.events_thread {
@@ -411,12 +726,42 @@ impl VideoServerTask {
             error!("Error starting session thread.");
         }
 
+        #[cfg(unix)]
+        if let Err(_) = self.start_control_thread(ch_sender.clone()) {
+            error!("Error starting control thread.");
+        }
+
+        if self.start_signals_thread(ch_sender.clone()).is_err() {
+            error!("Error starting signals thread.");
+        }
+
+        if self.start_keepalive_thread().is_err() {
+            error!("Error starting keepalive thread.");
+        }
+
+        if self.conn.get_config().discovery && self.start_discovery_thread().is_err() {
+            error!("Error starting discovery thread.");
+        }
+
+        if self.start_relay_thread().is_err() {
+            error!("Error starting relay thread.");
+        }
+
+        if self.conn.get_config().http_port.is_some() && self.start_http_thread().is_err() {
+            error!("Error starting HTTP thread.");
+        }
+
         self.conn.get_app().start_broadcast_async_task();
         self.conn.get_web().start_broadcast_async_task();
 
         loop {
             while ch_receiver.len() > 0 {
                 if let Ok(Some(server_action)) = ch_receiver.try_recv_realtime() {
+                    if server_action == VideoServerAction::Shutdown {
+                        self.shutdown().await;
+                        return Ok(());
+                    }
+
                     if let Err(e) = self.handle_server_action(server_action, &ch_sender).await {
                         error!("Error handling server action: {}", e);
                     };
@@ -447,6 +792,16 @@ impl VideoServerTask {
 
             match capturer.frame() {
                 Ok(frame) => {
+                    // While idle, captures are sampled in place and only copied once they changed.
+                    // Clients joining meanwhile get their first frame with the next keepalive frame.
+                    let area = (self.area.x as usize, self.area.y as usize);
+                    let size = (self.area.width, self.area.height);
+                    if !self.idle.needs_capture(&frame, width, area, size) {
+                        let poll_delay = IDLE_POLL_INTERVAL.saturating_sub(sleep.elapsed());
+                        tokio::time::sleep(poll_delay).await;
+                        continue;
+                    }
+
                     let argb_frame = frame.chunks(self.row_len).next().unwrap().to_vec();
 
                     if (width * height * 4) != argb_frame.len() {
@@ -462,53 +817,60 @@ impl VideoServerTask {
                         continue;
                     }
 
-                    match self.csp {
-                        EColorSpace::YUV420 => {
-                            let yuv = YUVBuffer::with_argb_for_i420(
-                                width,
-                                height,
-                                &argb_frame[0..width * height * 4],
-                            );
-        
-                            let y_plane = self.pic.as_mut_slice(0).unwrap();
-                            y_plane.copy_from_slice(yuv.y());
-                            let u_plane = self.pic.as_mut_slice(1).unwrap();
-                            u_plane.copy_from_slice(yuv.u_420());
-                            let v_plane = self.pic.as_mut_slice(2).unwrap();
-                            v_plane.copy_from_slice(yuv.v_420());
-                        }
-                        EColorSpace::YUV444 => {
-                            let yuv = YUVBuffer::with_argb_for_444(
-                                width,
-                                height,
-                                &argb_frame[0..width * height * 4],
-                            );
-        
-                            let y_plane = self.pic.as_mut_slice(0).unwrap();
-                            y_plane.copy_from_slice(yuv.y());
-                            let u_plane = self.pic.as_mut_slice(1).unwrap();
-                            u_plane.copy_from_slice(yuv.u_444());
-                            let v_plane = self.pic.as_mut_slice(2).unwrap();
-                            v_plane.copy_from_slice(yuv.v_444());
+                    // The streamed monitor is cropped out of the capture of the virtual desktop
+                    let argb_frame = match self.area.is_whole(width, height) {
+                        true => argb_frame,
+                        false => self.area.crop(&argb_frame, width),
+                    };
+                    let (width, height) = (self.area.width, self.area.height);
+
+                    // Reopened below, web clients that joined or lost a frame start from its key frame
+                    if has_web_clients && self.conn.get_web().take_keyframe_request() {
+                        if let Some(encoder_profile) =
+                            self.encoder_profile_of(&WEB_STREAM_PROFILE)
+                        {
+                            self.encoders.remove(&encoder_profile);
                         }
-                    } 
+                    }
 
-                    // TODO: Is this important?
-                    // self.pic.set_timestamp(self.frame_count);
+                    if self.encoders_outdated(width, height, has_web_clients) {
+                        self.update_encoders(width, height, has_web_clients).await;
+                    }
+
+                    let argb = &argb_frame[0..width * height * 4];
+
+                    // Unchanged captures are skipped, except by encoders yet to send a key frame
+                    let encode = self.idle.should_encode(argb, width, height);
+                    let nals: Vec<(StreamProfile, Vec<u8>)> = self
+                        .encoders
+                        .iter_mut()
+                        .filter(|(_, encoder)| encode || encoder.is_new())
+                        .filter_map(|(profile, encoder)| {
+                            encoder.encode(argb, width, height).map(|nal| (*profile, nal))
+                        })
+                        .collect();
+
+                    for (encoder_profile, nal) in nals.iter() {
+                        let profiles = match self.routes.iter().find(|(p, _)| p == encoder_profile) {
+                            Some((_, profiles)) => profiles,
+                            None => continue,
+                        };
 
-                    if let Ok(Some((nal, _, _))) = self.encoder.encode(&self.pic) {
                         if has_app_clients {
-                            self.handle_app_broadcast(&nal.as_bytes()).await;
+                            self.handle_app_broadcast(nal, profiles).await;
                         }
 
-                        if has_web_clients {
-                            self.handle_web_broadcast(&nal.as_bytes());
+                        if has_web_clients && profiles.contains(&WEB_STREAM_PROFILE) {
+                            self.handle_web_broadcast(nal);
                         }
+                    }
 
+                    if !nals.is_empty() {
                         frames += 1;
                     }
 
-                    if fps_time.elapsed().as_secs() >= 1 && frames > 0 {
+                    // Also runs while idle, when fewer frames are encoded
+                    if fps_time.elapsed().as_secs() >= 1 {
                         self.conn.filter_clients().await;
                         debug!(
                             "FPS: {}",
@@ -517,14 +879,18 @@ impl VideoServerTask {
                         frames = 0;
                         fps_time = Instant::now();
 
-                        self.conn.app_drain_subpacket_cache().await;
+                        self.conn.app_evict_retransmit_caches().await;
                     }
 
+                    // Idle captures are polled at a lower rate, until a change is sampled
                     let current_elapsed = sleep.elapsed().as_micros();
-                    if current_elapsed > 0 && current_elapsed < 16667 {
+                    if self.idle.is_idle() {
+                        let poll_delay = IDLE_POLL_INTERVAL.saturating_sub(sleep.elapsed());
+                        tokio::time::sleep(poll_delay).await;
+                    } else if current_elapsed > 0 && current_elapsed < 16667 {
                         let requested_delay = 16667 - current_elapsed;
-                        self.frame_delay(Duration::from_micros(requested_delay as u64))
-                            .await;
+                        let delay = Duration::from_micros(requested_delay as u64);
+                        self.frame_delay(delay).await;
                     }
                 }
                 Err(ref e) if e.kind() == WouldBlock => {}
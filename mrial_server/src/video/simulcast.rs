@@ -0,0 +1,251 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mrial_proto::video::EColorSpace;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use x264::{Encoder, Param, Picture};
+
+use crate::conn::StreamProfile;
+
+use super::yuv::YUVBuffer;
+
+/// Encoders running at once, clients requesting profiles past the
+/// limit are sent the stream of the closest encoder instead.
+pub const MAX_STREAM_ENCODERS: usize = 3;
+
+/// Presets accepted by x264, from fastest to slowest
+pub const ENCODER_PRESETS: [&str; 10] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+pub const MAX_ENCODER_CRF: u8 = 51;
+
+/// Settings every encoder is opened with, changed over the control socket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncoderSettings {
+    pub preset: String,
+    pub crf: u8,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            preset: "ultrafast".to_string(),
+            crf: 20,
+        }
+    }
+}
+
+/// Profiles requested by clients, grouped by the profile of the encoder they are sent
+pub type EncoderRoutes = Vec<(StreamProfile, Vec<StreamProfile>)>;
+
+/// Headers of the open encoders, by the profile of the encoder
+pub type EncoderHeaders = Arc<Mutex<HashMap<StreamProfile, Vec<u8>>>>;
+
+/// Routes each requested profile to an encoder, in order of request,
+/// until the encoder limit is reached.
+pub fn route_profiles(
+    requested: &[StreamProfile],
+    capture_width: usize,
+    capture_height: usize,
+) -> EncoderRoutes {
+    let mut routes: EncoderRoutes = Vec::new();
+
+    for profile in requested {
+        let encoder_profile = profile.normalized(capture_width, capture_height);
+
+        if let Some((_, profiles)) = routes.iter_mut().find(|(p, _)| *p == encoder_profile) {
+            profiles.push(*profile);
+            continue;
+        }
+
+        if routes.len() < MAX_STREAM_ENCODERS {
+            routes.push((encoder_profile, vec![*profile]));
+            continue;
+        }
+
+        let area = |p: &StreamProfile| (p.width * p.height) as i64;
+        let closest = routes.iter_mut().min_by_key(|(p, _)| {
+            (
+                p.csp != encoder_profile.csp,
+                (area(p) - area(&encoder_profile)).abs(),
+            )
+        });
+
+        if let Some((_, profiles)) = closest {
+            profiles.push(*profile);
+        }
+    }
+
+    routes
+}
+
+/// x264 encoder of a single profile, fed from the shared capture
+pub struct ProfileEncoder {
+    profile: StreamProfile,
+    pic: Picture,
+    encoder: Encoder,
+    /// Frames the encoder was fed since it was opened
+    frames: u64,
+}
+
+impl ProfileEncoder {
+    pub fn open(
+        profile: StreamProfile,
+        settings: &EncoderSettings,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut par = ProfileEncoder::get_parameters(&profile, settings)?;
+        let encoder = Encoder::open(&mut par)?;
+        let pic = Picture::from_param(&par)?;
+
+        Ok(Self {
+            profile,
+            pic,
+            encoder,
+            frames: 0,
+        })
+    }
+
+    fn get_parameters(
+        profile: &StreamProfile,
+        settings: &EncoderSettings,
+    ) -> Result<Param, Box<dyn std::error::Error>> {
+        let mut par = Param::default_preset(&settings.preset, "zerolatency")?;
+
+        par = par.set_csp(profile.csp.into());
+        par = par.set_dimension(profile.height, profile.width);
+
+        if cfg!(target_os = "windows") {
+            par = par.set_fullrange(1);
+        }
+
+        par = par.param_parse("repeat_headers", "1").unwrap();
+        par = par.param_parse("annexb", "1").unwrap();
+        par = par.param_parse("bframes", "0").unwrap();
+        par = par.param_parse("crf", &settings.crf.to_string())?;
+
+        if profile.csp != EColorSpace::YUV444 {
+            par = par.apply_profile("high").unwrap();
+        } else {
+            par = par.apply_profile("high444").unwrap();
+        }
+
+        Ok(par)
+    }
+
+    /// Whether the encoder has not encoded a frame yet, clients of opened
+    /// encoders wait for its first key frame even while the capture is idle.
+    pub fn is_new(&self) -> bool {
+        self.frames == 0
+    }
+
+    pub fn get_headers(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.encoder.get_headers()?.as_bytes().to_vec())
+    }
+
+    /// Encodes the captured ARGB frame, scaling it first when the profile is of different dimensions
+    pub fn encode(&mut self, argb: &[u8], width: usize, height: usize) -> Option<Vec<u8>> {
+        let (profile_width, profile_height) = (self.profile.width, self.profile.height);
+
+        let scaled;
+        let argb = if profile_width == width && profile_height == height {
+            argb
+        } else {
+            scaled = YUVBuffer::scale_argb(argb, width, height, profile_width, profile_height);
+            &scaled
+        };
+
+        match self.profile.csp {
+            EColorSpace::YUV420 => {
+                let yuv = YUVBuffer::with_argb_for_i420(profile_width, profile_height, argb);
+
+                let y_plane = self.pic.as_mut_slice(0).unwrap();
+                y_plane.copy_from_slice(yuv.y());
+                let u_plane = self.pic.as_mut_slice(1).unwrap();
+                u_plane.copy_from_slice(yuv.u_420());
+                let v_plane = self.pic.as_mut_slice(2).unwrap();
+                v_plane.copy_from_slice(yuv.v_420());
+            }
+            EColorSpace::YUV444 => {
+                let yuv = YUVBuffer::with_argb_for_444(profile_width, profile_height, argb);
+
+                let y_plane = self.pic.as_mut_slice(0).unwrap();
+                y_plane.copy_from_slice(yuv.y());
+                let u_plane = self.pic.as_mut_slice(1).unwrap();
+                u_plane.copy_from_slice(yuv.u_444());
+                let v_plane = self.pic.as_mut_slice(2).unwrap();
+                v_plane.copy_from_slice(yuv.v_444());
+            }
+        }
+
+        self.frames += 1;
+
+        // TODO: Is this important?
+        // self.pic.set_timestamp(self.frame_count);
+
+        match self.encoder.encode(&self.pic) {
+            Ok(Some((nal, _, _))) => Some(nal.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(width: usize, height: usize, csp: EColorSpace) -> StreamProfile {
+        StreamProfile { width, height, csp }
+    }
+
+    #[test]
+    fn profiles_normalized_alike_share_an_encoder() {
+        let full = profile(0, 0, EColorSpace::YUV420);
+        let native = profile(1920, 1080, EColorSpace::YUV420);
+        let odd = profile(1281, 721, EColorSpace::YUV420);
+
+        let routes = route_profiles(&[full, native, odd], 1920, 1080);
+
+        assert_eq!(
+            routes,
+            vec![
+                (native, vec![full, native]),
+                (profile(1280, 720, EColorSpace::YUV420), vec![odd]),
+            ]
+        );
+    }
+
+    #[test]
+    fn profiles_past_the_encoder_limit_go_to_the_closest_encoder() {
+        let requested = [
+            profile(1920, 1080, EColorSpace::YUV420),
+            profile(1280, 720, EColorSpace::YUV420),
+            profile(1280, 720, EColorSpace::YUV444),
+            profile(1366, 768, EColorSpace::YUV420),
+            profile(1920, 1200, EColorSpace::YUV444),
+        ];
+        assert_eq!(MAX_STREAM_ENCODERS, 3);
+
+        let routes = route_profiles(&requested, 1920, 1080);
+        let encoder_of = |requested: &StreamProfile| {
+            routes
+                .iter()
+                .find(|(_, profiles)| profiles.contains(requested))
+                .map(|(encoder_profile, _)| *encoder_profile)
+        };
+
+        assert_eq!(routes.len(), MAX_STREAM_ENCODERS);
+        // Closest in area among the encoders of the same color space
+        assert_eq!(encoder_of(&requested[3]), Some(requested[1]));
+        assert_eq!(encoder_of(&requested[4]), Some(requested[2]));
+    }
+}
@@ -96,6 +96,38 @@ impl YUVBuffer {
         }
     }
 
+    /// Scales an ARGB frame with a box filter, used to feed encoders of smaller profiles
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn scale_argb(
+        argb: &[u8],
+        width: usize,
+        height: usize,
+        dst_width: usize,
+        dst_height: usize,
+    ) -> Vec<u8> {
+        use libyuv_sys::{ARGBScale, FilterMode_kFilterBox};
+
+        assert_eq!(argb.len(), width * height * 4);
+
+        let mut scaled = vec![0u8; 4 * dst_width * dst_height];
+
+        unsafe {
+            ARGBScale(
+                argb.as_ptr(),
+                (width * 4) as _,
+                width as _,
+                height as _,
+                scaled.as_mut_ptr(),
+                (dst_width * 4) as _,
+                dst_width as _,
+                dst_height as _,
+                FilterMode_kFilterBox,
+            );
+        }
+
+        scaled
+    }
+
     pub fn y(&self) -> &[u8] {
         &self.yuv[0..self.width * self.height]
     }